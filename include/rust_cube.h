@@ -0,0 +1,88 @@
+/*
+ * C interface to rust-cube, built into the cdylib with `--features capi`:
+ *
+ *   cargo build --release --lib --no-default-features --features capi
+ *
+ * and linked as librust_cube.so / rust_cube.dll / librust_cube.dylib.
+ */
+#ifndef RUST_CUBE_H
+#define RUST_CUBE_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Return codes */
+#define CUBEMAP_OK 0
+#define CUBEMAP_ERROR_INVALID_ARGUMENT (-1)
+#define CUBEMAP_ERROR_DECODE (-2)
+#define CUBEMAP_ERROR_ENCODE (-3)
+#define CUBEMAP_ERROR_PANIC (-4)
+
+/* Face data formats: raw pixels or an encoded image file */
+#define CUBEMAP_FORMAT_RGBA8 0   /* 4 bytes per pixel, rows top to bottom */
+#define CUBEMAP_FORMAT_RGBA32F 1 /* 4 native-endian floats per pixel, linear light */
+#define CUBEMAP_FORMAT_PNG 2
+#define CUBEMAP_FORMAT_JPEG 3
+#define CUBEMAP_FORMAT_TIFF 4
+#define CUBEMAP_FORMAT_EXR 5
+#define CUBEMAP_FORMAT_HDR 6
+#define CUBEMAP_FORMAT_WEBP 7    /* lossy, at the quality option */
+#define CUBEMAP_FORMAT_AVIF 8    /* 10-bit, at the quality option; needs the avif feature */
+
+/* Filters */
+#define CUBEMAP_FILTER_NEAREST 0
+#define CUBEMAP_FILTER_BILINEAR 1
+#define CUBEMAP_FILTER_BICUBIC 2
+#define CUBEMAP_FILTER_LANCZOS3 3
+
+/* Face projections */
+#define CUBEMAP_PROJECTION_STANDARD 0
+#define CUBEMAP_PROJECTION_EAC 1
+
+/* Faces, in the order they are delivered */
+#define CUBEMAP_FACE_RIGHT 0
+#define CUBEMAP_FACE_LEFT 1
+#define CUBEMAP_FACE_UP 2
+#define CUBEMAP_FACE_DOWN 3
+#define CUBEMAP_FACE_FRONT 4
+#define CUBEMAP_FACE_BACK 5
+
+typedef struct CubemapConvertOptions {
+    uint32_t size;       /* face size in pixels */
+    uint32_t format;     /* CUBEMAP_FORMAT_* */
+    uint32_t quality;    /* JPEG, WebP and AVIF quality, 1-100 */
+    uint32_t filter;     /* CUBEMAP_FILTER_* */
+    uint32_t projection; /* CUBEMAP_PROJECTION_* */
+    float yaw;           /* view rotation in degrees */
+    float pitch;
+    float roll;
+} CubemapConvertOptions;
+
+/* Receives one face; `data` is only valid for the duration of the call.
+ * Must not throw. */
+typedef void (*CubemapFaceCallback)(void *user_data, uint32_t face, uint32_t width, uint32_t height,
+                                    const uint8_t *data, size_t len);
+
+/* Fill `options` with the defaults: 1024 px RGBA8 faces, bilinear filter,
+ * standard projection, no rotation. */
+void cubemap_default_options(CubemapConvertOptions *options);
+
+/* Convert the encoded equirect panorama in `input` (JPEG, PNG, TIFF, EXR,
+ * HDR, ...) and call `callback` once per face. `options` may be NULL for the
+ * defaults. Returns CUBEMAP_OK or a negative CUBEMAP_ERROR_* code. */
+int32_t cubemap_convert(const uint8_t *input, size_t len, const CubemapConvertOptions *options,
+                        CubemapFaceCallback callback, void *user_data);
+
+/* Message for the last failed call on this thread, or NULL. Valid until the
+ * next call into the library on the same thread. */
+const char *cubemap_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* RUST_CUBE_H */
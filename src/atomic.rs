@@ -0,0 +1,224 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+
+// Temps sit next to their file as `.<name>.<host>.<pid>.<n>.tmp`, so the
+// rename stays on one filesystem, writers of the same file in one process
+// don't share a temp and the writer's process can be told apart
+const SUFFIX: &str = ".tmp";
+
+// Where a writer's process can't be looked up, temps this old are stale
+const STALE_AGE: Duration = Duration::from_secs(3600);
+
+// Temps this process has created
+static TEMPS: AtomicU64 = AtomicU64::new(0);
+
+/// A file written under a temporary name and renamed into place by
+/// `commit`, so a process killed part way through never leaves a truncated
+/// file under the real name. Dropped without `commit`, the temp is removed.
+pub struct AtomicFile {
+    writer: Option<BufWriter<File>>,
+    temp: PathBuf,
+    path: PathBuf,
+}
+
+impl AtomicFile {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<AtomicFile> {
+        let path = path.as_ref().to_path_buf();
+        let temp = temp_path(&path)?;
+        let file = File::create(&temp)?;
+        Ok(AtomicFile { writer: Some(BufWriter::with_capacity(65536, file)), temp, path })
+    }
+
+    /// Flush the data and give the file its real name, replacing any file
+    /// there.
+    pub fn commit(mut self) -> io::Result<()> {
+        let writer = self.writer.take().expect("only commit takes the writer");
+        writer.into_inner().map_err(io::IntoInnerError::into_error)?;
+        std::fs::rename(&self.temp, &self.path)
+    }
+
+    fn writer(&mut self) -> &mut BufWriter<File> {
+        self.writer.as_mut().expect("only commit takes the writer")
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if self.writer.take().is_some() {
+            let _ = std::fs::remove_file(&self.temp);
+        }
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer().flush()
+    }
+}
+
+impl Seek for AtomicFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.writer().seek(pos)
+    }
+}
+
+/// Write `data` to `path` through an `AtomicFile`.
+pub fn write_atomic(path: impl AsRef<Path>, data: &[u8]) -> io::Result<()> {
+    let mut file = AtomicFile::create(path)?;
+    file.write_all(data)?;
+    file.commit()
+}
+
+/// Remove the temps in `dir` that writers killed part way through left
+/// behind: those of processes on this host no longer running, or where
+/// that can't be told (other hosts sharing the directory), those untouched
+/// for an hour. Returns how many were removed.
+pub fn remove_stale_temps(dir: impl AsRef<Path>) -> io::Result<usize> {
+    let mut removed = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some((host, pid)) = name.to_str().and_then(temp_owner) else { continue };
+        let local = host == host_id() && host != "unknown";
+        if local && pid == std::process::id() || !entry.file_type()?.is_file() {
+            continue;
+        }
+        let stale = match process_running(pid).filter(|_| local) {
+            Some(running) => !running,
+            None => {
+                let modified = entry.metadata()?.modified()?;
+                SystemTime::now().duration_since(modified).is_ok_and(|age| age > STALE_AGE)
+            }
+        };
+        // Another cleanup may have got there first
+        if stale && std::fs::remove_file(entry.path()).is_ok() {
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+fn temp_path(path: &Path) -> io::Result<PathBuf> {
+    let Some(name) = path.file_name() else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} names no file", path.display())));
+    };
+    let n = TEMPS.fetch_add(1, Ordering::Relaxed);
+    let name = format!(".{}.{}.{}.{}{}", name.to_string_lossy(), host_id(), std::process::id(), n, SUFFIX);
+    Ok(path.with_file_name(name))
+}
+
+// The host and process id in a temp's name, or None for other files
+fn temp_owner(name: &str) -> Option<(&str, u32)> {
+    let mut parts = name.strip_prefix('.')?.strip_suffix(SUFFIX)?.rsplitn(4, '.');
+    let (n, pid, host, rest) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    if rest.is_empty() || n.parse::<u64>().is_err() {
+        return None;
+    }
+    Some((host, pid.parse().ok()?))
+}
+
+// This machine's name as it goes in temp names: without dots, and "unknown"
+// where it can't be read
+fn host_id() -> &'static str {
+    static HOST: OnceLock<String> = OnceLock::new();
+    HOST.get_or_init(|| {
+        let name = ["/proc/sys/kernel/hostname", "/etc/hostname"]
+            .iter()
+            .find_map(|path| std::fs::read_to_string(path).ok())
+            .or_else(|| std::env::var("HOSTNAME").ok())
+            .unwrap_or_default();
+        let name: String = name.trim().chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '-' }).collect();
+        if name.is_empty() { "unknown".to_string() } else { name }
+    })
+}
+
+// Whether `pid` is running, where /proc says
+fn process_running(pid: u32) -> Option<bool> {
+    let proc = Path::new("/proc");
+    proc.join("self").exists().then(|| proc.join(pid.to_string()).exists())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn temps_are_unique_and_owned() {
+        let path = Path::new("out/face.v2.jpg");
+        let (a, b) = (temp_path(path).unwrap(), temp_path(path).unwrap());
+        assert_ne!(a, b);
+        for temp in [&a, &b] {
+            let name = temp.file_name().unwrap().to_str().unwrap();
+            assert_eq!(temp_owner(name), Some((host_id(), std::process::id())));
+        }
+        assert_eq!(temp_owner(".face.jpg.box.12.3.tmp"), Some(("box", 12)));
+        for name in ["face.jpg.box.12.3.tmp", ".box.12.3.tmp", ".face.jpg.box.x.3.tmp", ".face.jpg.12.tmp"] {
+            assert_eq!(temp_owner(name), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn stale_temps_are_removed() {
+        let dir = std::env::temp_dir().join(format!("rust-cube-stale-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let temp = |host: &str, pid: u32, age: u64| {
+            let path = dir.join(format!(".face.jpg.{}.{}.0.tmp", host, pid));
+            let file = File::create(&path).unwrap();
+            file.set_modified(SystemTime::now() - Duration::from_secs(age)).unwrap();
+            path
+        };
+        // A dead process here, and an old temp from elsewhere
+        let dead = temp(host_id(), u32::MAX - 1, 0);
+        let old = temp("elsewhere", std::process::id(), 2 * STALE_AGE.as_secs());
+        // This process's own, and a recent one from another host whose
+        // process can't be looked up
+        let own = temp(host_id(), std::process::id(), 2 * STALE_AGE.as_secs());
+        let recent = temp("elsewhere", u32::MAX - 1, 0);
+
+        let removed = remove_stale_temps(&dir).unwrap();
+        let left: Vec<bool> = [&dead, &old, &own, &recent].iter().map(|path| path.exists()).collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let expected = match (host_id() != "unknown", process_running(std::process::id()).is_some()) {
+            (true, true) => [false, false, true, true],
+            // Without /proc only the hour-old temp goes, and without a host
+            // name this process's own can't be told apart either
+            (true, false) => [true, false, true, true],
+            (false, _) => [true, false, false, true],
+        };
+        assert_eq!(left, expected);
+        assert_eq!(removed, expected.iter().filter(|&&kept| !kept).count());
+    }
+
+    #[test]
+    fn atomic_writes_leave_no_partial_files() {
+        let dir = std::env::temp_dir().join(format!("rust-cube-atomic-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let names = || {
+            let mut names: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+            names.sort();
+            names
+        };
+        write_atomic(dir.join("a.bin"), b"data").unwrap();
+        drop(AtomicFile::create(dir.join("b.bin")).unwrap());
+        assert_eq!(names(), ["a.bin"]);
+
+        // An hour-old temp of another host goes, our own stays
+        let stale = std::fs::File::create(dir.join(".c.bin.elsewhere.1.0.tmp")).unwrap();
+        stale.set_modified(std::time::SystemTime::now() - std::time::Duration::from_secs(7200)).unwrap();
+        let ours = AtomicFile::create(dir.join("d.bin")).unwrap();
+        let removed = remove_stale_temps(&dir).unwrap();
+        drop(ours);
+        let left = names();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(removed, 1);
+        assert_eq!(left, ["a.bin"]);
+    }
+}
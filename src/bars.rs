@@ -0,0 +1,58 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use rust_cube::{Face, Progress};
+
+/// One bar per rendered face plus an overall bar with ETA, drawn on stderr
+/// and fed by the library's row callback. Hidden when stderr isn't a terminal.
+pub struct FaceBars {
+    multi: MultiProgress,
+    overall: ProgressBar,
+    faces: Vec<ProgressBar>,
+}
+
+impl FaceBars {
+    pub fn new(size: u32, rendered: &[Face]) -> FaceBars {
+        let multi = MultiProgress::new();
+        let face_style = ProgressStyle::with_template("{prefix:>6} [{bar:40}] {pos}/{len} rows")
+            .expect("valid template")
+            .progress_chars("=> ");
+        // Faces left out get a bar that's never drawn
+        let faces = Face::iter()
+            .map(|face| {
+                if !rendered.contains(&face) {
+                    return ProgressBar::hidden();
+                }
+                let bar = multi.add(ProgressBar::new(size as u64));
+                bar.set_style(face_style.clone());
+                bar.set_prefix(face.name());
+                bar
+            })
+            .collect();
+        let overall = multi.add(ProgressBar::new(size as u64 * rendered.len() as u64));
+        overall.set_style(
+            ProgressStyle::with_template("{prefix:>6} [{bar:40}] {percent:>3}% ETA {eta}")
+                .expect("valid template")
+                .progress_chars("=> "),
+        );
+        overall.set_prefix("total");
+        FaceBars { multi, overall, faces }
+    }
+
+    pub fn callback(&self) -> Progress {
+        let faces = self.faces.clone();
+        let overall = self.overall.clone();
+        Progress::new(move |face, rows_done, _| {
+            let bar = &faces[face.index()];
+            // Bands finish out of order; never move a bar backwards
+            bar.set_position(bar.position().max(rows_done as u64));
+            overall.set_position(faces.iter().map(ProgressBar::position).sum());
+        })
+    }
+
+    pub fn finish(self) {
+        for bar in &self.faces {
+            bar.finish_and_clear();
+        }
+        self.overall.finish_and_clear();
+        let _ = self.multi.clear();
+    }
+}
@@ -0,0 +1,88 @@
+use crate::{Channel, CubemapError, PixelDepth};
+use image::DynamicImage;
+use std::f32::consts::PI;
+
+const BASE83: &[u8; 83] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+// Placeholders only hold a few cosines, so a thumbnail gives the same hash
+// as the full image for a fraction of the work
+const SAMPLE_SIZE: u32 = 32;
+
+/// BlurHash (https://blurha.sh) of `img` with `components` (x, y) cosine
+/// terms, each 1 to 9: a short string a web client decodes into a blurred
+/// placeholder while the real image loads. Float images are clipped to
+/// [0, 1].
+pub fn blurhash(img: &DynamicImage, components: (u32, u32)) -> Result<String, CubemapError> {
+    let (cx, cy) = components;
+    if !(1..=9).contains(&cx) || !(1..=9).contains(&cy) {
+        return Err(CubemapError::Projection(format!(
+            "BlurHash components must be between 1 and 9, got {}x{}",
+            cx, cy
+        )));
+    }
+    let small = img.thumbnail_exact(SAMPLE_SIZE.min(img.width()).max(1), SAMPLE_SIZE.min(img.height()).max(1));
+    let (width, height) = (small.width() as usize, small.height() as usize);
+    let linear: Vec<f32> = match PixelDepth::of(&small) {
+        PixelDepth::F32 => small.to_rgb32f().into_raw(),
+        _ => small.to_rgb16().into_raw().into_iter().map(Channel::to_linear).collect(),
+    };
+
+    // Average colour first, then the cosine terms row by row
+    let mut factors = Vec::with_capacity((cx * cy) as usize);
+    for j in 0..cy {
+        for i in 0..cx {
+            let normalisation = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            let mut sum = [0.0f32; 3];
+            for y in 0..height {
+                let wy = (PI * j as f32 * y as f32 / height as f32).cos();
+                for x in 0..width {
+                    let basis = wy * (PI * i as f32 * x as f32 / width as f32).cos();
+                    let pixel = &linear[(y * width + x) * 3..][..3];
+                    for (sum, &value) in sum.iter_mut().zip(pixel) {
+                        *sum += basis * value;
+                    }
+                }
+            }
+            factors.push(sum.map(|sum| sum * normalisation / (width * height) as f32));
+        }
+    }
+
+    let mut hash = String::new();
+    encode83(&mut hash, (cx - 1) + (cy - 1) * 9, 1);
+    let (dc, ac) = factors.split_first().expect("at least one component");
+    let maximum = ac.iter().flatten().fold(0.0f32, |max, value| max.max(value.abs()));
+    let (quantised_maximum, maximum) = match ac.is_empty() {
+        true => (0, 1.0),
+        false => {
+            let quantised = (maximum * 166.0 - 0.5).floor().clamp(0.0, 82.0) as u32;
+            (quantised, (quantised + 1) as f32 / 166.0)
+        }
+    };
+    encode83(&mut hash, quantised_maximum, 1);
+    let [r, g, b] = dc.map(srgb8);
+    encode83(&mut hash, (r << 16) + (g << 8) + b, 4);
+    for value in ac {
+        let [r, g, b] = value.map(|value| {
+            let scaled = value / maximum;
+            (scaled.signum() * scaled.abs().sqrt() * 9.0 + 9.5).floor().clamp(0.0, 18.0) as u32
+        });
+        encode83(&mut hash, r * 19 * 19 + g * 19 + b, 2);
+    }
+    Ok(hash)
+}
+
+// sRGB code as the reference encoder rounds it, which decoders expect
+fn srgb8(value: f32) -> u32 {
+    let value = value.clamp(0.0, 1.0);
+    let encoded = match value <= 0.0031308 {
+        true => value * 12.92,
+        false => 1.055 * value.powf(1.0 / 2.4) - 0.055,
+    };
+    (encoded * 255.0 + 0.5) as u32
+}
+
+fn encode83(out: &mut String, value: u32, length: u32) {
+    for digit in (0..length).rev() {
+        out.push(BASE83[(value / 83u32.pow(digit) % 83) as usize] as char);
+    }
+}
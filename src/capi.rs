@@ -0,0 +1,272 @@
+use crate::{
+    decode_image, encode_image, equirect_to_cubemap_dynamic, pixel, CubeProjection, CubemapOptions, EncodeOptions,
+    Face, Filter, OutputFormat, PixelDepth, Rotation,
+};
+use image::DynamicImage;
+use std::cell::RefCell;
+use std::ffi::{c_char, c_void, CString};
+use std::io::Cursor;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+// Return codes; keep in sync with include/rust_cube.h
+const CUBEMAP_OK: i32 = 0;
+const CUBEMAP_ERROR_INVALID_ARGUMENT: i32 = -1;
+const CUBEMAP_ERROR_DECODE: i32 = -2;
+const CUBEMAP_ERROR_ENCODE: i32 = -3;
+const CUBEMAP_ERROR_PANIC: i32 = -4;
+
+// Face data formats
+const CUBEMAP_FORMAT_RGBA8: u32 = 0;
+const CUBEMAP_FORMAT_RGBA32F: u32 = 1;
+const CUBEMAP_FORMAT_PNG: u32 = 2;
+const CUBEMAP_FORMAT_JPEG: u32 = 3;
+const CUBEMAP_FORMAT_TIFF: u32 = 4;
+const CUBEMAP_FORMAT_EXR: u32 = 5;
+const CUBEMAP_FORMAT_HDR: u32 = 6;
+const CUBEMAP_FORMAT_WEBP: u32 = 7;
+const CUBEMAP_FORMAT_AVIF: u32 = 8;
+
+/// Settings for `cubemap_convert`, `CubemapConvertOptions` in C. Filter and
+/// projection are indices into `Filter::ALL` and `CubeProjection::ALL`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct CubemapConvertOptions {
+    pub size: u32,
+    pub format: u32,
+    pub quality: u32,
+    pub filter: u32,
+    pub projection: u32,
+    pub yaw: f32,
+    pub pitch: f32,
+    pub roll: f32,
+}
+
+impl Default for CubemapConvertOptions {
+    fn default() -> Self {
+        CubemapConvertOptions {
+            size: 1024,
+            format: CUBEMAP_FORMAT_RGBA8,
+            quality: 95,
+            filter: 1,
+            projection: 0,
+            yaw: 0.0,
+            pitch: 0.0,
+            roll: 0.0,
+        }
+    }
+}
+
+/// Receives each face in Face::ALL order; `data` is only valid during the call.
+pub type CubemapFaceCallback = Option<
+    unsafe extern "C" fn(user_data: *mut c_void, face: u32, width: u32, height: u32, data: *const u8, len: usize),
+>;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+// A failed call: its return code and message
+type Failure = (i32, String);
+
+/// Fill `options` with the defaults: 1024 px RGBA8 faces, bilinear
+/// filtering, standard projection, no rotation.
+///
+/// # Safety
+///
+/// `options` must be null or point to a writable `CubemapConvertOptions`.
+#[no_mangle]
+pub unsafe extern "C" fn cubemap_default_options(options: *mut CubemapConvertOptions) {
+    if !options.is_null() {
+        options.write(CubemapConvertOptions::default());
+    }
+}
+
+/// Convert the encoded equirect panorama in `input` and hand each face to
+/// `callback`. A null `options` uses the defaults. Returns `CUBEMAP_OK` or a
+/// negative error code; `cubemap_last_error` has the message.
+///
+/// # Safety
+///
+/// `input` must point to `len` readable bytes and `options` must be null or
+/// point to a valid `CubemapConvertOptions`. The callback must not unwind.
+#[no_mangle]
+pub unsafe extern "C" fn cubemap_convert(
+    input: *const u8,
+    len: usize,
+    options: *const CubemapConvertOptions,
+    callback: CubemapFaceCallback,
+    user_data: *mut c_void,
+) -> i32 {
+    if input.is_null() {
+        return fail((CUBEMAP_ERROR_INVALID_ARGUMENT, "input is null".to_string()));
+    }
+    let Some(callback) = callback else {
+        return fail((CUBEMAP_ERROR_INVALID_ARGUMENT, "callback is null".to_string()));
+    };
+    let input = std::slice::from_raw_parts(input, len);
+    let options = if options.is_null() { CubemapConvertOptions::default() } else { *options };
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        convert(input, &options, |face, img, data| {
+            callback(user_data, face.index() as u32, img.width(), img.height(), data.as_ptr(), data.len())
+        })
+    }));
+    match result {
+        Ok(Ok(())) => {
+            LAST_ERROR.with(|error| error.borrow_mut().take());
+            CUBEMAP_OK
+        }
+        Ok(Err(failure)) => fail(failure),
+        Err(_) => fail((CUBEMAP_ERROR_PANIC, "conversion panicked".to_string())),
+    }
+}
+
+/// Message of the last failed call on this thread, or null. Valid until the
+/// next call into the library on the same thread.
+#[no_mangle]
+pub extern "C" fn cubemap_last_error() -> *const c_char {
+    LAST_ERROR.with(|error| error.borrow().as_ref().map_or(ptr::null(), |message| message.as_ptr()))
+}
+
+fn convert(
+    input: &[u8],
+    options: &CubemapConvertOptions,
+    mut emit: impl FnMut(Face, &DynamicImage, &[u8]),
+) -> Result<(), Failure> {
+    let invalid = |message: String| (CUBEMAP_ERROR_INVALID_ARGUMENT, message);
+    let filter = *Filter::ALL
+        .get(options.filter as usize)
+        .ok_or_else(|| invalid(format!("unknown filter {}", options.filter)))?;
+    let projection = *CubeProjection::ALL
+        .get(options.projection as usize)
+        .ok_or_else(|| invalid(format!("unknown projection {}", options.projection)))?;
+    let encoded = match options.format {
+        CUBEMAP_FORMAT_RGBA8 | CUBEMAP_FORMAT_RGBA32F => None,
+        CUBEMAP_FORMAT_PNG => Some(OutputFormat::Png),
+        CUBEMAP_FORMAT_JPEG => Some(OutputFormat::Jpeg),
+        CUBEMAP_FORMAT_TIFF => Some(OutputFormat::Tiff),
+        CUBEMAP_FORMAT_EXR => Some(OutputFormat::Exr),
+        CUBEMAP_FORMAT_HDR => Some(OutputFormat::Hdr),
+        CUBEMAP_FORMAT_WEBP => Some(OutputFormat::WebP),
+        CUBEMAP_FORMAT_AVIF => Some(OutputFormat::Avif),
+        format => return Err(invalid(format!("unknown format {}", format))),
+    };
+    let cubemap_options = CubemapOptions {
+        size: options.size,
+        filter,
+        rotation: Rotation::from_euler_degrees(options.yaw, options.pitch, options.roll),
+        projection,
+        ..CubemapOptions::default()
+    };
+    cubemap_options.validate().map_err(|err| invalid(err.to_string()))?;
+
+    let img = decode_image(input).map_err(|err| (CUBEMAP_ERROR_DECODE, format!("failed to decode input: {}", err)))?;
+    let img = PixelDepth::of(&img).to_rgb(img);
+    let cubemap = equirect_to_cubemap_dynamic(&img, &cubemap_options);
+
+    let quality = options.quality.clamp(1, 100) as u8;
+    for (face, img) in cubemap.iter() {
+        let data = match encoded {
+            Some(format) => {
+                let encode = EncodeOptions { format, quality, ..EncodeOptions::default() };
+                let mut out = Cursor::new(Vec::new());
+                encode_image(img, &encode, &mut out).map_err(|err| (CUBEMAP_ERROR_ENCODE, err.to_string()))?;
+                out.into_inner()
+            }
+            // Linear light, as the header promises; float faces already are
+            None if options.format == CUBEMAP_FORMAT_RGBA32F => {
+                let linear = DynamicImage::ImageRgb32F(pixel::linearize(img));
+                linear.to_rgba32f().into_raw().iter().flat_map(|value| value.to_ne_bytes()).collect()
+            }
+            None => img.to_rgba8().into_raw(),
+        };
+        emit(face, img, &data);
+    }
+    Ok(())
+}
+
+fn fail((code, message): Failure) -> i32 {
+    // Messages come from our own formatting and never contain NULs
+    let message = CString::new(message).unwrap_or_default();
+    LAST_ERROR.with(|error| *error.borrow_mut() = Some(message));
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    // Faces handed to the callback, as (face, width, height, data)
+    type Faces = Vec<(u32, u32, u32, Vec<u8>)>;
+
+    unsafe extern "C" fn collect(user_data: *mut c_void, face: u32, w: u32, h: u32, data: *const u8, len: usize) {
+        let faces = &mut *(user_data as *mut Faces);
+        faces.push((face, w, h, std::slice::from_raw_parts(data, len).to_vec()));
+    }
+
+    fn png(img: RgbImage) -> Vec<u8> {
+        let encode = EncodeOptions { format: OutputFormat::Png, ..EncodeOptions::default() };
+        let mut out = Cursor::new(Vec::new());
+        encode_image(&DynamicImage::ImageRgb8(img), &encode, &mut out).unwrap();
+        out.into_inner()
+    }
+
+    fn last_error() -> String {
+        let message = cubemap_last_error();
+        assert!(!message.is_null());
+        unsafe { std::ffi::CStr::from_ptr(message) }.to_string_lossy().into_owned()
+    }
+
+    fn run(input: &[u8], options: &CubemapConvertOptions) -> (i32, Faces) {
+        let mut faces = Faces::new();
+        let code = unsafe {
+            let user_data = &mut faces as *mut Faces as *mut c_void;
+            cubemap_convert(input.as_ptr(), input.len(), options, Some(collect), user_data)
+        };
+        (code, faces)
+    }
+
+    #[test]
+    fn bad_arguments_are_reported() {
+        let input = png(RgbImage::new(16, 8));
+        let code = unsafe { cubemap_convert(input.as_ptr(), input.len(), ptr::null(), None, ptr::null_mut()) };
+        assert_eq!(code, CUBEMAP_ERROR_INVALID_ARGUMENT);
+        assert_eq!(last_error(), "callback is null");
+
+        let mut options = CubemapConvertOptions::default();
+        unsafe { cubemap_default_options(&mut options) };
+        options.filter = 9;
+        let (code, faces) = run(&input, &options);
+        assert_eq!((code, faces.len()), (CUBEMAP_ERROR_INVALID_ARGUMENT, 0));
+        assert_eq!(last_error(), "unknown filter 9");
+
+        let (code, _) = run(b"not an image", &CubemapConvertOptions::default());
+        assert_eq!(code, CUBEMAP_ERROR_DECODE);
+    }
+
+    #[test]
+    fn faces_round_trip() {
+        let input = png(RgbImage::from_pixel(32, 16, Rgb([128, 64, 255])));
+        let options = CubemapConvertOptions { size: 4, ..CubemapConvertOptions::default() };
+        let (code, faces) = run(&input, &options);
+        assert_eq!(code, CUBEMAP_OK);
+        assert!(cubemap_last_error().is_null());
+        assert_eq!(faces.iter().map(|face| face.0).collect::<Vec<_>>(), [0, 1, 2, 3, 4, 5]);
+        for (_, width, height, data) in &faces {
+            assert_eq!((*width, *height, data.len()), (4, 4, 64));
+            assert!(data.chunks_exact(4).all(|pixel| pixel == [128, 64, 255, 255]));
+        }
+
+        // Float faces are in linear light
+        let options = CubemapConvertOptions { format: CUBEMAP_FORMAT_RGBA32F, ..options };
+        let (code, faces) = run(&input, &options);
+        assert_eq!(code, CUBEMAP_OK);
+        let data = &faces[0].3;
+        assert_eq!(data.len(), 4 * 4 * 16);
+        let pixel: Vec<f32> = data[..16].chunks_exact(4).map(|b| f32::from_ne_bytes(b.try_into().unwrap())).collect();
+        let expected = [0.2158605, 0.0512695, 1.0, 1.0];
+        assert!(pixel.iter().zip(expected).all(|(a, b)| (a - b).abs() < 1e-4), "{:?}", pixel);
+    }
+}
@@ -0,0 +1,1295 @@
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use rust_cube::{
+    AvifDepth, ChromaSubsampling, Convention, CubeProjection, DdsFormat, Dither, EnvMapping, Face, Fill, Filter,
+    FisheyeLens, InputProjection, JpegBackend, Layout, OutputFormat, PixelType, PngCompression, Sharpen, StereoLayout,
+    Supercompression, TestPattern, TileViewer, ToneMap, ToneMapper, ViewProjection,
+};
+use std::ffi::OsString;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "rust-cube",
+    version,
+    about = "Convert equirectangular panoramas to cubemap faces",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Worker threads for rendering and encoding; defaults to one per CPU
+    #[arg(long, global = true, value_parser = clap::value_parser!(u32).range(1..))]
+    pub threads: Option<u32>,
+
+    /// Log more: -v adds details, -vv also the libraries' debug output
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Log less: only warnings and errors, or given twice only errors
+    #[arg(long, global = true, action = ArgAction::Count, conflicts_with = "verbose")]
+    pub quiet: u8,
+
+    /// How log lines are written: plain messages, or one JSON object per
+    /// line with the timings and names as fields, for log pipelines
+    #[arg(long, global = true, value_enum, default_value = "text")]
+    pub log_format: LogFormat,
+
+    #[command(flatten)]
+    pub convert: ConvertArgs,
+}
+
+impl Cli {
+    // For arguments that don't come from a terminal (jobs files, daemon
+    // requests): clap's message on one line, without its "error: " prefix
+    // or the usage and --help hint that follow it
+    pub fn parse_args<I, T>(args: I) -> anyhow::Result<Cli>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        Cli::try_parse_from(args).map_err(|err| {
+            let message = err.to_string();
+            let lines = message.lines().take_while(|line| !line.starts_with("Usage:") && !line.starts_with("For more"));
+            let message = lines.map(str::trim).filter(|line| !line.is_empty()).collect::<Vec<_>>().join(" ");
+            anyhow::anyhow!("{}", message.strip_prefix("error: ").unwrap_or(&message))
+        })
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Stitch cubemap faces back into an equirectangular panorama
+    Equirect(EquirectArgs),
+    /// Cut the cube faces into a multiresolution tile pyramid for a web viewer
+    Tiles(TilesArgs),
+    /// Resample an existing cubemap to another size, layout, orientation or
+    /// convention without going through an equirectangular panorama
+    Resample(ResampleArgs),
+    /// Render a flat perspective view of the panorama, e.g. for thumbnails
+    View(ViewArgs),
+    /// Render the panorama into a single image: an octahedral or dual
+    /// paraboloid environment map, or a cylindrical or Mercator panorama
+    Envmap(EnvMapArgs),
+    /// Convert the frames of a 360 video into cubemaps, or re-encode them
+    /// as a cubemap video, through ffmpeg
+    Video(VideoArgs),
+    /// Convert to a cubemap and back, and report how much quality each size
+    /// and filter loses (PSNR and SSIM, overall and by latitude)
+    Verify(VerifyArgs),
+    /// Generate a synthetic equirectangular panorama for checking face
+    /// orientation, conventions and aliasing
+    #[command(name = "testpattern")]
+    TestPattern(TestPatternArgs),
+    /// Run an HTTP service that converts posted panoramas on demand
+    #[cfg(feature = "serve")]
+    Serve(ServeArgs),
+    /// Stay running and do the conversions other invocations send with
+    /// --daemon, keeping threads, sampling tables and optionally the last
+    /// panorama warm between them
+    #[cfg(unix)]
+    Daemon(DaemonArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct ConvertArgs {
+    /// Equirectangular input image, an s3://bucket/key URL, - to read it
+    /// from stdin, or with --start and --end a numbered image sequence such
+    /// as frames/%05d.jpg
+    #[arg(short, long, required_unless_present_any = ["input_glob", "watch", "config", "layers"])]
+    pub input: Option<PathBuf>,
+
+    /// First frame number of an --input sequence
+    #[arg(long, requires_all = ["input", "end"])]
+    pub start: Option<u64>,
+
+    /// Last frame number of an --input sequence, inclusive; each frame
+    /// converts under its own directory, like a batch
+    #[arg(long, requires = "start")]
+    pub end: Option<u64>,
+
+    /// Run the conversions listed as [[job]] tables in a TOML file, one
+    /// after another; their keys are this command's long options, and a
+    /// [defaults] table applies to every job. Other convert options given
+    /// alongside are ignored
+    #[arg(long, value_name = "TOML", conflicts_with_all = ["input", "input_glob", "watch"])]
+    pub config: Option<PathBuf>,
+
+    /// Convert every file matching a glob (e.g. "panos/**/*.jpg"), mirroring
+    /// the directory structure under the output root
+    #[arg(long, conflicts_with = "input")]
+    pub input_glob: Option<String>,
+
+    /// Convert panoramas as they appear in a directory, until interrupted
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["input", "input_glob"])]
+    pub watch: Option<PathBuf>,
+
+    /// Quiet period before a watched file counts as completely written
+    #[arg(long, default_value_t = 1500, requires = "watch")]
+    pub debounce_ms: u64,
+
+    /// Output directory for each watched file, relative to the output root;
+    /// supports {stem}, {name}, {ext} and {dir}
+    #[arg(long, default_value = "{dir}/{stem}", requires = "watch")]
+    pub output_template: String,
+
+    /// Keep the bilinear sampling tables of each panorama and face size as
+    /// files in DIR, so later runs skip computing them; batches share them
+    /// in memory either way
+    #[arg(long, value_name = "DIR")]
+    pub lut_cache: Option<PathBuf>,
+
+    /// Hand this conversion to the daemon listening on SOCKET (see the
+    /// daemon command) instead of running it here; its status lines stay
+    /// in the daemon's log
+    #[cfg(unix)]
+    #[arg(long, value_name = "SOCKET", conflicts_with = "watch")]
+    pub daemon: Option<PathBuf>,
+
+    /// Images decoded and converted at the same time in batch mode
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u32).range(1..))]
+    pub jobs: u32,
+
+    /// Root directory for the generated cubemaps, or an s3://bucket/prefix
+    /// URL to upload each face as soon as it is encoded
+    #[arg(short, long, default_value = "output")]
+    pub output_dir: PathBuf,
+
+    /// Write everything into one zip archive (local or s3://), or with - a
+    /// tar stream on stdout, instead of the --output-dir tree, adding each
+    /// face as soon as it is encoded
+    #[arg(long, value_name = "ZIP", conflicts_with_all = ["output_dir", "watch"])]
+    pub output: Option<PathBuf>,
+
+    /// Don't write manifest.json, the record of the source, settings and
+    /// file checksums, into each cubemap directory
+    #[arg(long)]
+    pub no_manifest: bool,
+
+    /// Write a JSON report of the run to FILE when it ends: each input's
+    /// outcome, decode, render, encode and per-face times, and the path,
+    /// size and SHA-256 of every file written. Exit codes: 0 success, 1 an
+    /// error before converting, 2 invalid options, 3 some inputs failed, 4
+    /// all failed
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["watch", "config"])]
+    pub report: Option<PathBuf>,
+
+    /// What to do with a cubemap directory that already has a manifest.json,
+    /// which is written last: convert again, keep it, or fail that input
+    #[arg(long, value_enum, default_value = "overwrite", conflicts_with_all = ["output", "no_manifest"])]
+    pub if_exists: IfExists,
+
+    /// With --if-exists skip, only keep cubemaps whose files still match the
+    /// manifest's checksums and whose source is unchanged
+    #[arg(long)]
+    pub verify_existing: bool,
+
+    /// Byte-identical output for identical input and options: no metadata
+    /// carried over, CPU rendering, archive entries in face order, and the
+    /// manifest names the source by file name only
+    #[arg(long)]
+    pub deterministic: bool,
+
+    /// Face size in pixels; repeat or comma-separate for several sizes
+    #[arg(short, long = "size", value_delimiter = ',', default_values_t = [1024, 2048, 4096])]
+    pub sizes: Vec<u32>,
+
+    /// JPEG, WebP and AVIF quality (1-100)
+    #[arg(short, long, default_value_t = 95, value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub quality: u8,
+
+    /// Output format (jpeg, png, tiff, exr, hdr, webp, avif, or npy for raw
+    /// arrays at the faces' own precision); defaults to the format implied by
+    /// the input extension
+    #[arg(long)]
+    pub format: Option<OutputFormat>,
+
+    /// PNG compression level (fast, default, best)
+    #[arg(long, default_value = "default")]
+    pub png_compression: PngCompression,
+
+    #[command(flatten)]
+    pub tone: ToneMapArgs,
+
+    #[command(flatten)]
+    pub jpeg: JpegArgs,
+
+    #[command(flatten)]
+    pub webp: WebpArgs,
+
+    #[command(flatten)]
+    pub avif: AvifArgs,
+
+    /// Faces to write, comma-separated (right,left,up,down,front,back). Only
+    /// these are rendered, unless --irradiance, --sh, --reuse-largest or the
+    /// GPU need the whole cube
+    #[arg(short, long, value_delimiter = ',')]
+    pub faces: Vec<Face>,
+
+    /// Path of each face file under the output root. Variables: {stem} of
+    /// the input, {size}, {face} (right, left, ... or the --convention's
+    /// names), {face_px} (px, nx, py, ny, pz, nz), {face_index} (0-5) and
+    /// {ext}. The directory part also holds the size's manifest
+    #[arg(long, value_name = "TEMPLATE", default_value = "cubemap_{size}/{face}.{ext}")]
+    pub name_template: String,
+
+    /// Source sampling filter (nearest, bilinear, bicubic, lanczos3)
+    #[arg(long, default_value = "bilinear")]
+    pub filter: Filter,
+
+    /// Turn the view right by this many degrees before projecting
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub yaw: f32,
+
+    /// Tilt the view up by this many degrees
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub pitch: f32,
+
+    /// Roll the view clockwise by this many degrees
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub roll: f32,
+
+    /// CSV of per-panorama rotations for a whole shoot: rows of file
+    /// name, yaw, pitch and roll in degrees (pitch and roll optional),
+    /// matched by file name or stem and applied before --yaw, --pitch and
+    /// --roll. A listed panorama's GPano pose is ignored
+    #[arg(long, value_name = "CSV")]
+    pub rotations: Option<PathBuf>,
+
+    /// Level tilted captures: find the way up from the panorama's vertical
+    /// edges and correct the pitch and roll before --yaw, --pitch and --roll
+    /// apply; this replaces any GPano pitch and roll
+    #[arg(long, conflicts_with = "stream")]
+    pub auto_level: bool,
+
+    /// Face projection: standard (gnomonic) or eac (equi-angular)
+    #[arg(long, default_value = "standard")]
+    pub projection: CubeProjection,
+
+    /// Render an offset cubemap for viewport-adaptive VR video: directions
+    /// move towards the front by AMOUNT (0 to below 1), giving it up to
+    /// 1 + AMOUNT times the resolution; aim it with --yaw and --pitch and
+    /// pack it with --layout grid-3x2 as Transform360 does
+    #[arg(
+        long,
+        value_name = "AMOUNT",
+        default_value_t = 0.0,
+        conflicts_with_all = ["irradiance", "sh", "specular"]
+    )]
+    pub offset: f32,
+
+    /// Guard band for seamless GPU filtering: render N extra pixels past each
+    /// face edge, from the neighbouring directions, so faces are size + 2N
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 0,
+        conflicts_with_all = ["container", "irradiance", "sh", "reuse_largest"]
+    )]
+    pub bleed: u32,
+
+    /// Layout of the input image (equirect, dual-fisheye, mirrorball, angular)
+    #[arg(long, default_value = "equirect")]
+    pub input_projection: InputProjection,
+
+    /// Front lens of a dual-fisheye frame as x,y,radius,fov (pixels and
+    /// degrees); defaults to the left half with a 190 degree lens
+    #[arg(long, value_name = "X,Y,R,FOV")]
+    pub front_lens: Option<FisheyeLens>,
+
+    /// Back lens of a dual-fisheye frame; defaults to the right half
+    #[arg(long, value_name = "X,Y,R,FOV")]
+    pub back_lens: Option<FisheyeLens>,
+
+    /// Convert a stereo panorama into a cubemap per eye, under left/ and
+    /// right/ in the output directory: top-bottom (left eye on top),
+    /// side-by-side (left eye on the left) or auto to tell them apart by the
+    /// square or 4:1 frame
+    #[arg(long, value_name = "LAYOUT", conflicts_with = "stream")]
+    pub stereo: Option<StereoArg>,
+
+    /// Depth panorama paired with each input, converted alongside it with
+    /// the same rotation, projection and names into depth/ in the output
+    /// directory. Sampled nearest-neighbour so no depth is invented, and
+    /// written lossless: PNG, or EXR for float depth. {stem}, {name}, {ext}
+    /// and {dir} take the input's, e.g. {dir}/{stem}_depth.png
+    #[arg(long, value_name = "TEMPLATE", conflicts_with_all = ["stream", "stereo"])]
+    pub depth: Option<String>,
+
+    /// Aligned layers of one panorama as NAME=PATH[:FILTER][:TYPE],
+    /// comma-separated, e.g. color=a.jpg,normal=b.png:normal,rough=c.png:nearest,
+    /// with a --filter and --pixel-type of their own. The first takes the
+    /// place of --input; the rest are rendered from the same directions and
+    /// sampling tables, ungraded and unsharpened, in their own format unless
+    /// --format is given. Each goes into NAME/ in the output directory
+    #[arg(
+        long,
+        value_name = "LAYERS",
+        value_delimiter = ',',
+        conflicts_with_all = ["input", "input_glob", "watch", "stream", "stereo"]
+    )]
+    pub layers: Vec<LayerArg>,
+
+    /// Treat the input as a full panorama even if its XMP carries a GPano crop
+    #[arg(long)]
+    pub ignore_gpano: bool,
+
+    /// The equirect input covers all 360 degrees across but only DEG from
+    /// top to bottom (e.g. 150); the rest of the sphere gets --fill. Takes
+    /// the place of a GPano crop
+    #[arg(long, value_name = "DEG", conflicts_with = "stream")]
+    pub vertical_fov: Option<f32>,
+
+    /// Degrees the middle of --vertical-fov's band sits above the horizon
+    #[arg(long, value_name = "DEG", default_value_t = 0.0, requires = "vertical_fov", allow_negative_numbers = true)]
+    pub vertical_center: f32,
+
+    /// Take an equirect input wider than 2:1 with no GPano crop to cover 360
+    /// degrees across and as much vertically as its shape allows, centred on
+    /// the horizon, and pad the rest with --fill
+    #[arg(long, conflicts_with_all = ["vertical_fov", "stream", "stereo"])]
+    pub auto_pad: bool,
+
+    /// Don't turn the panorama by the GPano pose (PoseHeadingDegrees,
+    /// PosePitchDegrees, PoseRollDegrees) in its XMP; by default the pose is
+    /// undone so the front face looks north, before --yaw, --pitch and --roll
+    #[arg(long)]
+    pub ignore_pose: bool,
+
+    /// Don't copy the input's ICC profile and EXIF fields into JPEG and PNG
+    /// outputs
+    #[arg(long)]
+    pub strip_metadata: bool,
+
+    /// Composite this logo or patch image over the nadir to hide the tripod;
+    /// its top faces the front of the panorama
+    #[arg(long, value_name = "IMAGE")]
+    pub nadir_patch: Option<PathBuf>,
+
+    /// Angular diameter of the nadir patch in degrees
+    #[arg(long, default_value_t = 40.0, requires = "nadir_patch")]
+    pub nadir_diameter: f32,
+
+    /// Soft edge of the nadir patch as a fraction of its radius
+    #[arg(long, default_value_t = 0.1, requires = "nadir_patch")]
+    pub nadir_feather: f32,
+
+    /// Fill for directions a partial input doesn't cover: edge, inpaint (the
+    /// edge blurred into one color at the poles), blur (the nearest pixels
+    /// averaged), black, white, transparent or an rrggbb[aa] hex color.
+    /// Alpha below ff needs png, tiff, webp or exr output, or a --container
+    #[arg(long, default_value = "black")]
+    pub fill: Fill,
+
+    /// Supersample with up to N x N jittered samples per face pixel
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..=16))]
+    pub ssaa: u32,
+
+    /// Always take N x N samples instead of adapting to the local distortion
+    #[arg(long)]
+    pub ssaa_fixed: bool,
+
+    /// Filter in linear light instead of on the sRGB-encoded values; slower,
+    /// but blends and supersampling no longer darken
+    #[arg(long)]
+    pub linear: bool,
+
+    /// Convert the input to single-channel gray and render and write gray
+    /// faces (PNG and TIFF store them as such), a third of the work of RGB;
+    /// for depth and luminance panoramas. 8 and 16-bit gray inputs take
+    /// this path on their own; float inputs stay RGB
+    #[arg(long, conflicts_with = "stream")]
+    pub grayscale: bool,
+
+    /// What the panorama's pixels hold: color, normal for world-space
+    /// normals (0.5 + 0.5 * n, or n in float images), renormalized after
+    /// filtering and turned with the cube, or face-normal to also express
+    /// them in each face's tangent frame (x right, y up, z out of the face)
+    #[arg(
+        long,
+        value_name = "TYPE",
+        default_value = "color",
+        conflicts_with_all = ["linear", "grayscale", "reuse_largest"]
+    )]
+    pub pixel_type: PixelType,
+
+    /// White balance temperature shift, -100 (cooler) to 100 (warmer)
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub wb_temp: f32,
+
+    /// White balance tint shift, -100 (greener) to 100 (more magenta)
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub wb_tint: f32,
+
+    /// Color saturation: 0 is grayscale, 1 unchanged
+    #[arg(long, default_value_t = 1.0)]
+    pub saturation: f32,
+
+    /// Unsharp-mask each face before encoding: amount (1 = full strength),
+    /// blur radius in pixels and the threshold in 8-bit levels below which
+    /// differences are left alone
+    #[arg(long, value_name = "AMOUNT,RADIUS,THRESHOLD", conflicts_with = "specular")]
+    pub sharpen: Option<Sharpen>,
+
+    /// Render only the largest size from the panorama and derive the smaller
+    /// ones by gamma-correct downsampling of its faces
+    #[arg(long)]
+    pub reuse_largest: bool,
+
+    /// Decode TIFF and PNG panoramas a band of rows at a time instead of
+    /// whole, for panoramas too large to hold in memory
+    #[arg(long, conflicts_with = "nadir_patch")]
+    pub stream: bool,
+
+    /// Decoded source rows to hold at once when streaming, in MB
+    #[arg(
+        long,
+        value_name = "MB",
+        default_value_t = 256,
+        requires = "stream",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub stream_window: u32,
+
+    /// Render and encode at most N faces at a time, freeing each once it's
+    /// written; bounds memory at the cost of parallel encoding
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..=6),
+        conflicts_with_all = ["container", "irradiance", "sh", "reuse_largest", "stream"]
+    )]
+    pub concurrent_faces: Option<u32>,
+
+    /// Pick --concurrent-faces so the decoded panorama and the faces in
+    /// flight stay under MB megabytes
+    #[arg(
+        long,
+        value_name = "MB",
+        value_parser = clap::value_parser!(u32).range(1..),
+        conflicts_with_all = ["concurrent_faces", "container", "irradiance", "sh", "reuse_largest", "stream"]
+    )]
+    pub max_memory: Option<u32>,
+
+    /// Refuse panoramas of more than MP megapixels, checked from the file's
+    /// header before decoding; 0 for no limit
+    #[arg(long, value_name = "MP", default_value_t = 1024)]
+    pub max_megapixels: u32,
+
+    /// Largest face size accepted
+    #[arg(long, value_name = "PX", default_value_t = 32768)]
+    pub max_size: u32,
+
+    /// Refuse equirect panoramas that aren't 2:1 rather than warn about them
+    #[arg(long)]
+    pub strict_aspect: bool,
+
+    /// Convert even when the panorama and its faces look too large for the
+    /// memory available, which is otherwise an error
+    #[arg(long)]
+    pub skip_memory_check: bool,
+
+    /// Don't draw per-face progress bars while rendering; --quiet hides them too
+    #[arg(long)]
+    pub no_progress: bool,
+
+    /// Render faces with a compute shader, falling back to the CPU when no
+    /// adapter is available or the filter isn't supported on the GPU
+    #[cfg(feature = "gpu")]
+    #[arg(long)]
+    pub gpu: bool,
+
+    /// Engine preset for face names, order and orientation (opengl, vulkan,
+    /// directx, unity, unreal, threejs, babylon); threejs and babylon also
+    /// write cubemap.json listing the face URLs in their loader's order
+    #[arg(long, default_value_if("preset", "unity", "unity"))]
+    pub convention: Option<Convention>,
+
+    /// Write one file per face, or pack all six into a single cross or strip image
+    #[arg(long, value_enum, default_value_t = LayoutArg::Faces, default_value_if("preset", "unity", "cross-v"))]
+    pub layout: LayoutArg,
+
+    /// Target an engine's importer; unity writes a vertical cross with Unity's
+    /// face orientation. An explicit --convention or --layout still wins
+    #[arg(long, value_enum)]
+    pub preset: Option<PresetArg>,
+
+    /// Also write a Unity .meta sidecar next to the cross or strip image that
+    /// imports it as a cubemap, so it can be dropped straight into a project
+    #[arg(long, conflicts_with = "container")]
+    pub unity_meta: bool,
+
+    /// Add a BlurHash of every face image to the manifest, for web viewers to
+    /// show as a placeholder while the face loads
+    #[arg(long, conflicts_with = "no_manifest")]
+    pub blurhash: bool,
+
+    /// Also write preview.jpg, a small labelled strip or cross of the faces
+    /// for checking the result at a glance
+    #[arg(long, value_enum, value_name = "LAYOUT", conflicts_with_all = ["bleed", "concurrent_faces", "max_memory"])]
+    pub preview: Option<PreviewArg>,
+
+    /// Face size in the preview
+    #[arg(
+        long,
+        value_name = "PX",
+        default_value_t = 256,
+        requires = "preview",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub preview_size: u32,
+
+    /// Write all faces into a single GPU texture container, or one
+    /// safetensors file with a 6 x size x size x channels tensor for ML
+    /// pipelines, instead of images
+    #[arg(long, value_enum)]
+    pub container: Option<ContainerArg>,
+
+    /// Only store the base level in the container (no mip chain)
+    #[arg(long)]
+    pub no_mipmaps: bool,
+
+    /// KTX2 supercompression (none, zlib)
+    #[arg(long, default_value = "none")]
+    pub supercompression: Supercompression,
+
+    /// DDS pixel format (rgba8, bc7)
+    #[arg(long, default_value = "rgba8")]
+    pub dds_format: DdsFormat,
+
+    /// Fill the container's mip chain with GGX-prefiltered levels of rising
+    /// roughness for image-based lighting, instead of plain downsamples
+    #[arg(long, requires = "container", conflicts_with = "no_mipmaps")]
+    pub specular: bool,
+
+    /// GGX samples per texel for --specular
+    #[arg(long, default_value_t = 128, value_parser = clap::value_parser!(u32).range(1..), requires = "specular")]
+    pub specular_samples: u32,
+
+    /// Mip levels for --specular; defaults to halving down to 8x8 faces
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..), requires = "specular")]
+    pub specular_levels: Option<u32>,
+
+    /// Also write a diffuse irradiance cubemap with faces of this size, in
+    /// an "irradiance" directory (or irradiance.ktx2/.dds with --container)
+    #[arg(long, value_name = "SIZE", value_parser = clap::value_parser!(u32).range(1..))]
+    pub irradiance: Option<u32>,
+
+    /// Also write the lighting as 9 spherical harmonics coefficients per
+    /// channel, radiance and irradiance, to sh.json or sh.bin
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    pub sh: Option<ShFormat>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContainerArg {
+    Ktx2,
+    Dds,
+    Safetensors,
+}
+
+impl ContainerArg {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ContainerArg::Ktx2 => "ktx2",
+            ContainerArg::Dds => "dds",
+            ContainerArg::Safetensors => "safetensors",
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IfExists {
+    Overwrite,
+    /// Leave existing cubemaps alone; inputs with nothing left to do aren't
+    /// even decoded
+    Skip,
+    Error,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShFormat {
+    /// {"radiance": [[r, g, b] x 9], "irradiance": [...]}
+    Json,
+    /// 27 little-endian f32 radiance values, then 27 irradiance
+    Bin,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PreviewArg {
+    /// All six faces in a row
+    Strip,
+    /// Horizontal cross
+    Cross,
+}
+
+impl PreviewArg {
+    pub fn layout(self) -> Layout {
+        match self {
+            PreviewArg::Strip => Layout::StripHorizontal,
+            PreviewArg::Cross => Layout::CrossHorizontal,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PresetArg {
+    /// Vertical cross and Unity face names and orientation
+    Unity,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StereoArg {
+    Auto,
+    TopBottom,
+    SideBySide,
+}
+
+impl StereoArg {
+    /// The layout of a `width` x `height` frame, None for a mono one
+    pub fn layout(self, width: u32, height: u32) -> Option<StereoLayout> {
+        match self {
+            StereoArg::Auto => StereoLayout::detect(width, height),
+            StereoArg::TopBottom => Some(StereoLayout::TopBottom),
+            StereoArg::SideBySide => Some(StereoLayout::SideBySide),
+        }
+    }
+}
+
+/// One of --layers: the panorama at `path`, written under `name`, sampled
+/// with `filter` and `pixel_type` instead of --filter and --pixel-type if
+/// given.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LayerArg {
+    pub name: String,
+    pub path: PathBuf,
+    pub filter: Option<Filter>,
+    pub pixel_type: Option<PixelType>,
+}
+
+impl FromStr for LayerArg {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, path) = s.split_once('=').ok_or_else(|| format!("layer '{}' is not NAME=PATH[:FILTER]", s))?;
+        if name.is_empty() || name.contains(['/', '\\']) || name == "." || name == ".." {
+            return Err(format!("layer name '{}' is not a directory name", name));
+        }
+        // Suffixes that are neither a filter nor a pixel type are part of the
+        // path, as in s3://
+        let (mut path, mut filter, mut pixel_type) = (path, None, None);
+        while let Some((rest, suffix)) = path.rsplit_once(':') {
+            match (suffix.parse::<Filter>(), suffix.parse::<PixelType>()) {
+                (Ok(parsed), _) if filter.is_none() => filter = Some(parsed),
+                (_, Ok(parsed)) if pixel_type.is_none() => pixel_type = Some(parsed),
+                _ => break,
+            }
+            path = rest;
+        }
+        if path.is_empty() {
+            return Err(format!("layer '{}' has no path", name));
+        }
+        Ok(LayerArg { name: name.to_string(), path: PathBuf::from(path), filter, pixel_type })
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LayoutArg {
+    Faces,
+    CrossH,
+    CrossV,
+    StripH,
+    StripV,
+    /// YouTube-style 3x2 equi-angular layout; pair with --projection eac
+    Eac,
+    /// Transform360's 3x2 grid of upright faces, for offset cubemaps
+    #[value(name = "grid-3x2")]
+    Grid3x2,
+}
+
+impl LayoutArg {
+    pub fn layout(self) -> Option<Layout> {
+        match self {
+            LayoutArg::Faces => None,
+            LayoutArg::CrossH => Some(Layout::CrossHorizontal),
+            LayoutArg::CrossV => Some(Layout::CrossVertical),
+            LayoutArg::StripH => Some(Layout::StripHorizontal),
+            LayoutArg::StripV => Some(Layout::StripVertical),
+            LayoutArg::Eac => Some(Layout::Eac),
+            LayoutArg::Grid3x2 => Some(Layout::Grid3x2),
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct EquirectArgs {
+    /// Six face images in right,left,up,down,front,back order, or a single
+    /// cross or strip image
+    #[arg(required = true, num_args = 1..=6)]
+    pub faces: Vec<PathBuf>,
+
+    /// Output panorama
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    /// Panorama width in pixels (height is half); defaults to 4x the face size
+    #[arg(short, long)]
+    pub width: Option<u32>,
+
+    /// JPEG, WebP and AVIF quality (1-100)
+    #[arg(short, long, default_value_t = 95, value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub quality: u8,
+
+    /// Output format (jpeg, png, tiff, exr, hdr, webp, avif); defaults to the format implied by the output extension
+    #[arg(long)]
+    pub format: Option<OutputFormat>,
+
+    /// PNG compression level (fast, default, best)
+    #[arg(long, default_value = "default")]
+    pub png_compression: PngCompression,
+
+    #[command(flatten)]
+    pub tone: ToneMapArgs,
+
+    #[command(flatten)]
+    pub jpeg: JpegArgs,
+
+    #[command(flatten)]
+    pub webp: WebpArgs,
+
+    #[command(flatten)]
+    pub avif: AvifArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct TilesArgs {
+    /// Equirectangular input image
+    pub input: PathBuf,
+
+    /// Output directory for the tiles and viewer config
+    #[arg(short, long, default_value = "tiles")]
+    pub output_dir: PathBuf,
+
+    /// Viewer to lay the tiles out for (krpano, pannellum, marzipano)
+    #[arg(long, default_value = "krpano")]
+    pub viewer: TileViewer,
+
+    /// Tile edge length in pixels
+    #[arg(long, default_value_t = 512, value_parser = clap::value_parser!(u32).range(16..))]
+    pub tile_size: u32,
+
+    /// Face size of the most detailed level; defaults to the panorama width / pi
+    #[arg(short, long)]
+    pub size: Option<u32>,
+
+    /// Number of levels; defaults to halving until a level fits in one tile
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub levels: Option<u32>,
+
+    /// Source sampling filter (nearest, bilinear, bicubic, lanczos3)
+    #[arg(long, default_value = "bilinear")]
+    pub filter: Filter,
+
+    /// JPEG, WebP and AVIF quality (1-100)
+    #[arg(short, long, default_value_t = 90, value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub quality: u8,
+
+    /// Tile format (jpeg, png, webp, avif)
+    #[arg(long, default_value = "jpeg")]
+    pub format: OutputFormat,
+
+    #[command(flatten)]
+    pub tone: ToneMapArgs,
+
+    #[command(flatten)]
+    pub jpeg: JpegArgs,
+
+    #[command(flatten)]
+    pub webp: WebpArgs,
+
+    #[command(flatten)]
+    pub avif: AvifArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct ResampleArgs {
+    /// Six face images in right,left,up,down,front,back order (or the input
+    /// convention's slot order), or a single cross or strip image
+    #[arg(required = true, num_args = 1..=6)]
+    pub faces: Vec<PathBuf>,
+
+    /// Convention the input faces are stored in; defaults to the native one
+    #[arg(long)]
+    pub input_convention: Option<Convention>,
+
+    /// Root directory for the resampled cubemap
+    #[arg(short, long, default_value = "output")]
+    pub output_dir: PathBuf,
+
+    /// Output face size in pixels; defaults to the input face size
+    #[arg(short, long)]
+    pub size: Option<u32>,
+
+    /// Turn the view right by this many degrees
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub yaw: f32,
+
+    /// Tilt the view up by this many degrees
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub pitch: f32,
+
+    /// Roll the view clockwise by this many degrees
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub roll: f32,
+
+    /// Output face projection: standard (gnomonic) or eac (equi-angular)
+    #[arg(long, default_value = "standard")]
+    pub projection: CubeProjection,
+
+    /// Filter in linear light instead of on the sRGB-encoded values
+    #[arg(long)]
+    pub linear: bool,
+
+    /// Output convention for face names, order and orientation
+    #[arg(long)]
+    pub convention: Option<Convention>,
+
+    /// Write one file per face, or pack all six into a single cross or strip image
+    #[arg(long, value_enum, default_value_t = LayoutArg::Faces)]
+    pub layout: LayoutArg,
+
+    /// JPEG, WebP and AVIF quality (1-100)
+    #[arg(short, long, default_value_t = 95, value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub quality: u8,
+
+    /// Output format (jpeg, png, tiff, exr, hdr, webp, avif); defaults to the format of the first input
+    #[arg(long)]
+    pub format: Option<OutputFormat>,
+
+    /// PNG compression level (fast, default, best)
+    #[arg(long, default_value = "default")]
+    pub png_compression: PngCompression,
+
+    #[command(flatten)]
+    pub tone: ToneMapArgs,
+
+    #[command(flatten)]
+    pub jpeg: JpegArgs,
+
+    #[command(flatten)]
+    pub webp: WebpArgs,
+
+    #[command(flatten)]
+    pub avif: AvifArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct VerifyArgs {
+    /// Equirectangular input image
+    pub input: PathBuf,
+
+    /// Face sizes to try; repeat or comma-separate for several
+    #[arg(short, long = "size", value_delimiter = ',', default_values_t = [1024])]
+    pub sizes: Vec<u32>,
+
+    /// Source sampling filters to try; repeat or comma-separate for several
+    #[arg(long = "filter", value_delimiter = ',', default_value = "bilinear")]
+    pub filters: Vec<Filter>,
+
+    /// Supersample with up to N x N jittered samples per face pixel
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..=16))]
+    pub ssaa: u32,
+
+    /// Filter in linear light instead of on the sRGB-encoded values
+    #[arg(long)]
+    pub linear: bool,
+
+    /// Bands of latitude to report separately, north to south
+    #[arg(long, default_value_t = 6, value_parser = clap::value_parser!(u32).range(1..=180))]
+    pub bands: u32,
+}
+
+#[derive(Args, Debug)]
+pub struct ViewArgs {
+    /// Equirectangular input image
+    pub input: PathBuf,
+
+    /// Output image
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    /// Output width in pixels
+    #[arg(long, default_value_t = 1280)]
+    pub width: u32,
+
+    /// Output height in pixels
+    #[arg(long, default_value_t = 720)]
+    pub height: u32,
+
+    /// Horizontal field of view in degrees, or the angle across the image
+    /// circle for a fisheye
+    #[arg(long, default_value_t = 90.0)]
+    pub fov: f32,
+
+    /// View projection (perspective, fisheye)
+    #[arg(long, default_value = "perspective")]
+    pub projection: ViewProjection,
+
+    /// Dome master: aim the fisheye at the zenith, tilted towards the front
+    /// by this many degrees, with the front at the bottom of the image
+    #[arg(long, allow_negative_numbers = true, conflicts_with = "pitch")]
+    pub tilt: Option<f32>,
+
+    /// Turn the camera right by this many degrees
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub yaw: f32,
+
+    /// Tilt the camera up by this many degrees
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub pitch: f32,
+
+    /// Roll the camera clockwise by this many degrees
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub roll: f32,
+
+    /// Source sampling filter (nearest, bilinear, bicubic, lanczos3)
+    #[arg(long, default_value = "bilinear")]
+    pub filter: Filter,
+
+    /// JPEG, WebP and AVIF quality (1-100)
+    #[arg(short, long, default_value_t = 95, value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub quality: u8,
+
+    /// Output format (jpeg, png, tiff, exr, hdr, webp, avif); defaults to the format implied by the output extension
+    #[arg(long)]
+    pub format: Option<OutputFormat>,
+
+    /// PNG compression level (fast, default, best)
+    #[arg(long, default_value = "default")]
+    pub png_compression: PngCompression,
+
+    #[command(flatten)]
+    pub tone: ToneMapArgs,
+
+    #[command(flatten)]
+    pub jpeg: JpegArgs,
+
+    #[command(flatten)]
+    pub webp: WebpArgs,
+
+    #[command(flatten)]
+    pub avif: AvifArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct EnvMapArgs {
+    /// Equirectangular input image
+    pub input: PathBuf,
+
+    /// Output image
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    /// Layout of the image (octahedral, dual-paraboloid, cylindrical, mercator)
+    #[arg(long, default_value = "octahedral")]
+    pub mapping: EnvMapping,
+
+    /// Map width in pixels, or of each hemisphere of a dual paraboloid;
+    /// borders excluded. Cylindrical and Mercator heights follow from --fov
+    #[arg(short, long, default_value_t = 512)]
+    pub size: u32,
+
+    /// Pad the map with N pixels continuing it past its edges, for seamless
+    /// filtering on the GPU
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub border: u32,
+
+    /// Degrees each half of a dual paraboloid reaches past its hemisphere,
+    /// so the two overlap at the seam
+    #[arg(long, value_name = "DEGREES", default_value_t = 0.0)]
+    pub overlap: f32,
+
+    /// Vertical field of view of a cylindrical or Mercator map in degrees,
+    /// centred on the horizon; defaults to 120
+    #[arg(long, value_name = "DEGREES")]
+    pub fov: Option<f32>,
+
+    /// Turn the view right by this many degrees
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub yaw: f32,
+
+    /// Tilt the view up by this many degrees
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub pitch: f32,
+
+    /// Roll the view clockwise by this many degrees
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub roll: f32,
+
+    /// Source sampling filter (nearest, bilinear, bicubic, lanczos3)
+    #[arg(long, default_value = "bilinear")]
+    pub filter: Filter,
+
+    /// JPEG, WebP and AVIF quality (1-100)
+    #[arg(short, long, default_value_t = 95, value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub quality: u8,
+
+    /// Output format (jpeg, png, tiff, exr, hdr, webp, avif); defaults to the format implied by the output extension
+    #[arg(long)]
+    pub format: Option<OutputFormat>,
+
+    /// PNG compression level (fast, default, best)
+    #[arg(long, default_value = "default")]
+    pub png_compression: PngCompression,
+
+    #[command(flatten)]
+    pub tone: ToneMapArgs,
+
+    #[command(flatten)]
+    pub jpeg: JpegArgs,
+
+    #[command(flatten)]
+    pub webp: WebpArgs,
+
+    #[command(flatten)]
+    pub avif: AvifArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct VideoArgs {
+    /// Equirectangular 360 video, in any container and codec ffmpeg reads
+    pub input: PathBuf,
+
+    /// Directory for each converted frame: a directory of faces per frame,
+    /// or one image per frame with --layout
+    #[arg(short, long, required_unless_present = "video")]
+    pub output: Option<PathBuf>,
+
+    /// Also encode the frames, packed by --layout, into this video; the
+    /// input's audio is copied along
+    #[arg(long, value_name = "FILE")]
+    pub video: Option<PathBuf>,
+
+    /// Face size in pixels
+    #[arg(short, long, default_value_t = 1024)]
+    pub size: u32,
+
+    /// Convert every Nth frame only
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    pub every: u32,
+
+    /// Frames converted at the same time
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u32).range(1..))]
+    pub jobs: u32,
+
+    /// Face projection: standard (gnomonic) or eac (equi-angular)
+    #[arg(long, default_value = "standard")]
+    pub projection: CubeProjection,
+
+    /// Write one file per face, or pack all six into a single image
+    #[arg(long, value_enum, default_value_t = LayoutArg::Faces)]
+    pub layout: LayoutArg,
+
+    /// Turn the view right by this many degrees
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub yaw: f32,
+
+    /// Tilt the view up by this many degrees
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub pitch: f32,
+
+    /// Roll the view clockwise by this many degrees
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub roll: f32,
+
+    /// Source sampling filter (nearest, bilinear, bicubic, lanczos3)
+    #[arg(long, default_value = "bilinear")]
+    pub filter: Filter,
+
+    /// JPEG, WebP and AVIF quality (1-100) of frame images
+    #[arg(short, long, default_value_t = 95, value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub quality: u8,
+
+    /// Format of frame images (jpeg, png, tiff, webp, avif)
+    #[arg(long, default_value = "jpeg")]
+    pub format: OutputFormat,
+
+    /// Constant rate factor for --video; ffmpeg's default otherwise
+    #[arg(long)]
+    pub crf: Option<u32>,
+
+    /// The ffmpeg executable; ffprobe is looked for beside it
+    #[arg(long, value_name = "PATH", default_value = "ffmpeg")]
+    pub ffmpeg: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub struct TestPatternArgs {
+    /// Output image
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    /// Pattern (grid, faces, zone-plate)
+    #[arg(long, default_value = "grid")]
+    pub pattern: TestPattern,
+
+    /// Panorama width in pixels; the height is half of it
+    #[arg(long, default_value_t = 4096, value_parser = clap::value_parser!(u32).range(2..))]
+    pub width: u32,
+
+    /// JPEG, WebP and AVIF quality (1-100)
+    #[arg(short, long, default_value_t = 95, value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub quality: u8,
+
+    /// Output format (jpeg, png, tiff, exr, hdr, webp, avif); defaults to the format implied by the output extension
+    #[arg(long)]
+    pub format: Option<OutputFormat>,
+}
+
+/// `POST /convert` takes an encoded panorama as the request body, or
+/// `?url=` to fetch one, and answers with the six faces as
+/// multipart/mixed. Query parameters `size`, `format`, `quality`, `filter`,
+/// `projection`, `yaw`, `pitch` and `roll` override the defaults below.
+/// `GET /metrics` reports conversions, stage times, requests waiting for a
+/// slot and peak memory for Prometheus.
+#[cfg(feature = "serve")]
+#[derive(Args, Debug)]
+pub struct ServeArgs {
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    pub bind: SocketAddr,
+
+    /// Conversions running at once; further requests wait for a slot
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u32).range(1..))]
+    pub concurrency: u32,
+
+    /// Largest accepted upload or fetched panorama in bytes
+    #[arg(long, default_value_t = 256 << 20)]
+    pub max_bytes: usize,
+
+    /// Largest accepted panorama width or height in pixels
+    #[arg(long, default_value_t = 16384)]
+    pub max_dimension: u32,
+
+    /// Largest face size a request may ask for
+    #[arg(long, default_value_t = 4096)]
+    pub max_size: u32,
+
+    /// Only fetch `?url=` inputs under this URL prefix: the same scheme, host
+    /// and port, and a path below the prefix's; repeat for several. URL
+    /// inputs are refused when none is given
+    #[arg(long, value_name = "PREFIX")]
+    pub allow_url: Vec<String>,
+
+    /// Face size when the request doesn't give one
+    #[arg(long, default_value_t = 1024)]
+    pub size: u32,
+
+    /// Face format when the request doesn't give one
+    #[arg(long, default_value = "jpeg")]
+    pub format: OutputFormat,
+
+    /// JPEG quality when the request doesn't give one
+    #[arg(long, default_value_t = 95, value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub quality: u8,
+}
+
+/// Conversions arrive one at a time on a Unix socket from `rust-cube
+/// --daemon SOCKET ...`, each run with that invocation's options and
+/// working directory.
+#[cfg(unix)]
+#[derive(Args, Debug)]
+pub struct DaemonArgs {
+    /// Socket to listen on; a stale one left by a stopped daemon is replaced
+    #[arg(long)]
+    pub socket: PathBuf,
+
+    /// Also keep the sampling tables as files in DIR, for the next daemon
+    #[arg(long, value_name = "DIR")]
+    pub lut_cache: Option<PathBuf>,
+
+    /// Keep the last decoded panorama in memory, so converting the same
+    /// unchanged file again (another size or format) skips decoding it
+    #[arg(long)]
+    pub keep_source: bool,
+
+    /// Serve Prometheus metrics at http://ADDR/metrics: conversions by
+    /// result, decode, render and encode times, requests waiting, and peak
+    /// memory
+    #[arg(long, value_name = "ADDR")]
+    pub metrics: Option<SocketAddr>,
+}
+
+#[derive(Args, Debug)]
+pub struct ToneMapArgs {
+    /// Tone map float (HDR) inputs for 8 and 16-bit outputs (reinhard, aces,
+    /// gamma); without it values above 1.0 are clipped
+    #[arg(long)]
+    pub tonemap: Option<ToneMapper>,
+
+    /// Exposure adjustment in stops, applied before tone mapping; convert
+    /// also takes it without --tonemap and grades any input with it
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub exposure: f32,
+
+    /// Encoding gamma of the gamma tone mapper
+    #[arg(long, default_value_t = 2.2, requires = "tonemap")]
+    pub gamma: f32,
+
+    /// Dither 16-bit and float images going to 8-bit formats against banding
+    /// (none, blue-noise, floyd-steinberg)
+    #[arg(long, default_value_t)]
+    pub dither: Dither,
+}
+
+impl ToneMapArgs {
+    pub fn tone_map(&self) -> Result<Option<ToneMap>, String> {
+        if self.tonemap.is_none() && self.exposure != 0.0 {
+            return Err("--exposure needs --tonemap".to_string());
+        }
+        Ok(self.tonemap.map(|mapper| ToneMap { mapper, exposure: self.exposure, gamma: self.gamma }))
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct JpegArgs {
+    /// JPEG encoder: rust, turbo (libjpeg-turbo) or mozjpeg (smaller files,
+    /// slower); turbo and mozjpeg need a build with the mozjpeg feature
+    #[arg(long, default_value_t)]
+    pub jpeg_encoder: JpegBackend,
+
+    /// Write progressive JPEGs, which show a coarse image while loading
+    #[arg(long)]
+    pub jpeg_progressive: bool,
+
+    /// JPEG chroma resolution: 444 (full), 422 or 420 (smaller, for the web)
+    #[arg(long, value_name = "SUBSAMPLING", default_value_t)]
+    pub jpeg_subsampling: ChromaSubsampling,
+}
+
+#[derive(Args, Debug)]
+pub struct WebpArgs {
+    /// Write lossless WebP; --quality then sets how hard it compresses
+    #[arg(long)]
+    pub webp_lossless: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct AvifArgs {
+    /// AVIF encoder speed, from 1 (slowest, smallest files) to 10 (fastest)
+    #[arg(long, default_value_t = 6, value_parser = clap::value_parser!(u8).range(1..=10))]
+    pub avif_speed: u8,
+
+    /// Bit depth of AVIF output (8 or 10); 10 keeps gradients from banding
+    #[arg(long, value_name = "BITS", default_value_t)]
+    pub avif_depth: AvifDepth,
+}
@@ -0,0 +1,104 @@
+use crate::{Channel, CubemapError};
+
+/// Simple color corrections applied in linear light as faces are sampled:
+/// white balance, then exposure, then saturation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColorGrade {
+    /// Exposure adjustment in stops
+    pub exposure: f32,
+    /// White balance from -100 (cooler, bluer) to 100 (warmer, more amber)
+    pub temperature: f32,
+    /// White balance from -100 (greener) to 100 (more magenta)
+    pub tint: f32,
+    /// 0 is grayscale, 1 unchanged and above 1 more saturated
+    pub saturation: f32,
+}
+
+impl Default for ColorGrade {
+    fn default() -> Self {
+        ColorGrade::IDENTITY
+    }
+}
+
+// Rec. 709 luminance, which white balance keeps and saturation pivots on
+const LUMA: [f32; 3] = [0.2126, 0.7152, 0.0722];
+
+// Gain in stops of a full +100 temperature or tint shift
+const WB_STOPS: f32 = 0.5;
+
+impl ColorGrade {
+    pub const IDENTITY: ColorGrade = ColorGrade { exposure: 0.0, temperature: 0.0, tint: 0.0, saturation: 1.0 };
+
+    pub fn is_identity(&self) -> bool {
+        *self == ColorGrade::IDENTITY
+    }
+
+    /// Reject parameters `apply` can't honour.
+    pub fn validate(&self) -> Result<(), CubemapError> {
+        if !(-20.0..=20.0).contains(&self.exposure) {
+            return Err(CubemapError::Projection(format!(
+                "exposure must be between -20 and 20 stops, got {}",
+                self.exposure
+            )));
+        }
+        for (name, value) in [("temperature", self.temperature), ("tint", self.tint)] {
+            if !(-100.0..=100.0).contains(&value) {
+                return Err(CubemapError::Projection(format!(
+                    "white balance {} must be between -100 and 100, got {}",
+                    name, value
+                )));
+            }
+        }
+        if !(0.0..=4.0).contains(&self.saturation) {
+            return Err(CubemapError::Projection(format!(
+                "saturation must be between 0 and 4, got {}",
+                self.saturation
+            )));
+        }
+        Ok(())
+    }
+
+    /// Grade one linear RGB value.
+    pub fn apply(&self, rgb: [f32; 3]) -> [f32; 3] {
+        self.grade(self.gains(), rgb)
+    }
+
+    /// Grade the pixels of a row of `channels`-channel values in place; any
+    /// channels after the first three (alpha) are left alone. Single-channel
+    /// gray is graded as gray RGB and keeps the result's luminance.
+    pub(crate) fn apply_row<T: Channel>(&self, row: &mut [T], channels: usize) {
+        if self.is_identity() {
+            return;
+        }
+        let gains = self.gains();
+        if channels == 1 {
+            for value in row {
+                let graded = self.grade(gains, [value.to_linear(); 3]);
+                *value = T::from_linear(graded.iter().zip(LUMA).map(|(value, weight)| value * weight).sum());
+            }
+            return;
+        }
+        for pixel in row.chunks_exact_mut(channels) {
+            let graded = self.grade(gains, [pixel[0].to_linear(), pixel[1].to_linear(), pixel[2].to_linear()]);
+            for (value, graded) in pixel.iter_mut().zip(graded) {
+                *value = T::from_linear(graded);
+            }
+        }
+    }
+
+    // Per-channel multipliers for white balance and exposure, normalized so
+    // white balance alone leaves luminance as it was
+    fn gains(&self) -> [f32; 3] {
+        let warm = self.temperature / 100.0 * WB_STOPS;
+        let magenta = self.tint / 100.0 * WB_STOPS;
+        let balance = [warm.exp2(), (-magenta).exp2(), (-warm).exp2()];
+        let luma: f32 = balance.iter().zip(LUMA).map(|(gain, weight)| gain * weight).sum();
+        balance.map(|gain| gain / luma * self.exposure.exp2())
+    }
+
+    fn grade(&self, gains: [f32; 3], rgb: [f32; 3]) -> [f32; 3] {
+        let balanced = [rgb[0] * gains[0], rgb[1] * gains[1], rgb[2] * gains[2]];
+        let luma: f32 = balanced.iter().zip(LUMA).map(|(value, weight)| value * weight).sum();
+        balanced.map(|value| (luma + self.saturation * (value - luma)).max(0.0))
+    }
+}
@@ -0,0 +1,230 @@
+use crate::{CubemapFaces, Face};
+use image::DynamicImage;
+use std::fmt;
+use std::str::FromStr;
+
+/// How a rendered face is remapped into a slot: optional flips of the
+/// source image, then an optional transpose (swap of x and y).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FaceTransform {
+    pub flip_u: bool,
+    pub flip_v: bool,
+    pub transpose: bool,
+}
+
+impl FaceTransform {
+    const NONE: FaceTransform = FaceTransform { flip_u: false, flip_v: false, transpose: false };
+    const FLIP_U: FaceTransform = FaceTransform { flip_u: true, flip_v: false, transpose: false };
+    const FLIP_V: FaceTransform = FaceTransform { flip_u: false, flip_v: true, transpose: false };
+    const FLIP_UV: FaceTransform = FaceTransform { flip_u: true, flip_v: true, transpose: false };
+    const TRANSPOSE_FLIP_U: FaceTransform = FaceTransform { flip_u: true, flip_v: false, transpose: true };
+    const TRANSPOSE_FLIP_V: FaceTransform = FaceTransform { flip_u: false, flip_v: true, transpose: true };
+
+    pub fn apply(self, img: &DynamicImage) -> DynamicImage {
+        let mut img = img.clone();
+        if self.flip_u {
+            img = img.fliph();
+        }
+        if self.flip_v {
+            img = img.flipv();
+        }
+        if self.transpose {
+            img = img.rotate90().fliph();
+        }
+        img
+    }
+
+    /// Undo `apply`: transpose first, then the flips.
+    pub fn invert(self, img: &DynamicImage) -> DynamicImage {
+        let mut img = img.clone();
+        if self.transpose {
+            img = img.rotate90().fliph();
+        }
+        if self.flip_u {
+            img = img.fliph();
+        }
+        if self.flip_v {
+            img = img.flipv();
+        }
+        img
+    }
+}
+
+/// Engine preset for face naming, order and orientation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Convention {
+    /// Right-handed, +Y up, -Z forward
+    OpenGl,
+    /// Same world frame as OpenGL
+    Vulkan,
+    /// Left-handed, +Y up, +Z forward: the native layout
+    DirectX,
+    /// Left-handed, +Y up, +Z forward, faces named like Unity's importer
+    Unity,
+    /// Left-handed, +Z up, +X forward, +Y right
+    Unreal,
+    /// Right-handed, +Y up, -Z forward, for three.js, which mirrors cube
+    /// textures along X when it samples them
+    ThreeJs,
+    /// Left-handed, +Y up, +Z forward, Babylon.js's default
+    Babylon,
+}
+
+// Slot i of a convention is the engine's i-th face (+X, -X, +Y, -Y, +Z, -Z
+// in its own axes), filled from one of our faces
+struct Table {
+    names: [&'static str; 6],
+    slots: [(Face, FaceTransform); 6],
+}
+
+const NATIVE_SLOTS: [(Face, FaceTransform); 6] = [
+    (Face::Right, FaceTransform::NONE),
+    (Face::Left, FaceTransform::NONE),
+    (Face::Up, FaceTransform::NONE),
+    (Face::Down, FaceTransform::NONE),
+    (Face::Front, FaceTransform::NONE),
+    (Face::Back, FaceTransform::NONE),
+];
+
+// Flipping Z mirrors every face and swaps front and back
+const RIGHT_HANDED_SLOTS: [(Face, FaceTransform); 6] = [
+    (Face::Right, FaceTransform::FLIP_U),
+    (Face::Left, FaceTransform::FLIP_U),
+    (Face::Up, FaceTransform::FLIP_V),
+    (Face::Down, FaceTransform::FLIP_V),
+    (Face::Back, FaceTransform::FLIP_U),
+    (Face::Front, FaceTransform::FLIP_U),
+];
+
+const OPENGL: Table = Table { names: ["posx", "negx", "posy", "negy", "posz", "negz"], slots: RIGHT_HANDED_SLOTS };
+const VULKAN: Table = Table { names: ["px", "nx", "py", "ny", "pz", "nz"], slots: RIGHT_HANDED_SLOTS };
+const DIRECTX: Table = Table { names: ["px", "nx", "py", "ny", "pz", "nz"], slots: NATIVE_SLOTS };
+const UNITY: Table = Table { names: ["right", "left", "up", "down", "front", "back"], slots: NATIVE_SLOTS };
+// three.js's mirror along X turns its -Z forward into the native layout
+// turned half way round
+const THREEJS: Table = Table {
+    names: ["px", "nx", "py", "ny", "pz", "nz"],
+    slots: [
+        (Face::Left, FaceTransform::NONE),
+        (Face::Right, FaceTransform::NONE),
+        (Face::Up, FaceTransform::FLIP_UV),
+        (Face::Down, FaceTransform::FLIP_UV),
+        (Face::Back, FaceTransform::NONE),
+        (Face::Front, FaceTransform::NONE),
+    ],
+};
+const BABYLON: Table = Table { names: ["px", "nx", "py", "ny", "pz", "nz"], slots: NATIVE_SLOTS };
+const UNREAL: Table = Table {
+    names: ["px", "nx", "py", "ny", "pz", "nz"],
+    slots: [
+        (Face::Front, FaceTransform::TRANSPOSE_FLIP_U),
+        (Face::Back, FaceTransform::TRANSPOSE_FLIP_V),
+        (Face::Right, FaceTransform::FLIP_UV),
+        (Face::Left, FaceTransform::NONE),
+        (Face::Up, FaceTransform::TRANSPOSE_FLIP_U),
+        (Face::Down, FaceTransform::TRANSPOSE_FLIP_U),
+    ],
+};
+
+impl Convention {
+    pub const ALL: [Convention; 7] = [
+        Convention::OpenGl,
+        Convention::Vulkan,
+        Convention::DirectX,
+        Convention::Unity,
+        Convention::Unreal,
+        Convention::ThreeJs,
+        Convention::Babylon,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Convention::OpenGl => "opengl",
+            Convention::Vulkan => "vulkan",
+            Convention::DirectX => "directx",
+            Convention::Unity => "unity",
+            Convention::Unreal => "unreal",
+            Convention::ThreeJs => "threejs",
+            Convention::Babylon => "babylon",
+        }
+    }
+
+    fn table(self) -> &'static Table {
+        match self {
+            Convention::OpenGl => &OPENGL,
+            Convention::Vulkan => &VULKAN,
+            Convention::DirectX => &DIRECTX,
+            Convention::Unity => &UNITY,
+            Convention::Unreal => &UNREAL,
+            Convention::ThreeJs => &THREEJS,
+            Convention::Babylon => &BABYLON,
+        }
+    }
+
+    /// Slots in the order the engine's loader takes the six images, for
+    /// engines that load a list of URLs (three.js's `CubeTextureLoader`,
+    /// Babylon.js's `CubeTexture.CreateFromImages`).
+    pub fn loader_order(self) -> Option<[Face; 6]> {
+        match self {
+            Convention::ThreeJs => Some(Face::ALL),
+            Convention::Babylon => Some([Face::Right, Face::Up, Face::Front, Face::Left, Face::Down, Face::Back]),
+            _ => None,
+        }
+    }
+
+    /// File name stem for the face stored in `slot` (a position in
+    /// `Face::ALL` order).
+    pub fn face_name(self, slot: Face) -> &'static str {
+        self.table().names[slot.index()]
+    }
+
+    /// Our face and the transform that fill `slot`.
+    pub fn source(self, slot: Face) -> (Face, FaceTransform) {
+        self.table().slots[slot.index()]
+    }
+
+    /// Reorder and reorient native faces into this convention's slots; the
+    /// result is what containers and strips should store in order.
+    pub fn apply(self, cubemap: &CubemapFaces<DynamicImage>) -> CubemapFaces<DynamicImage> {
+        let faces = Face::iter()
+            .map(|slot| {
+                let (face, transform) = self.source(slot);
+                transform.apply(cubemap.get(face))
+            })
+            .collect();
+        CubemapFaces { size: cubemap.size, faces }
+    }
+
+    /// Inverse of `apply`: bring faces stored in this convention's slots
+    /// back to native faces.
+    pub fn unapply(self, cubemap: &CubemapFaces<DynamicImage>) -> CubemapFaces<DynamicImage> {
+        let mut faces = cubemap.faces.clone();
+        for (slot, img) in cubemap.iter() {
+            let (face, transform) = self.source(slot);
+            faces[face.index()] = transform.invert(img);
+        }
+        CubemapFaces { size: cubemap.size, faces }
+    }
+}
+
+impl fmt::Display for Convention {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Convention {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Convention::ALL
+            .into_iter()
+            .find(|convention| convention.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                format!(
+                    "unknown convention '{}' (expected opengl, vulkan, directx, unity, unreal, threejs or babylon)",
+                    s
+                )
+            })
+    }
+}
@@ -0,0 +1,195 @@
+use crate::cli::{Cli, DaemonArgs};
+use crate::storage::{self, LastDecoded};
+use crate::metrics::Metrics;
+use crate::{run_convert, Shared};
+use anyhow::{bail, Context, Result};
+use rayon::ThreadPool;
+use rust_cube::LutCache;
+use std::ffi::OsStr;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+// A request is the client's working directory and then its arguments, each
+// followed by a NUL byte, after which the client closes its side for
+// writing. The reply is "ok" or "error: <message>" on one line.
+
+/// Run the conversions sent to `args.socket` one after another until
+/// interrupted. Clients that connect meanwhile wait their turn.
+pub fn run_daemon(args: &DaemonArgs, pool: Arc<ThreadPool>) -> Result<()> {
+    // A socket left behind by a daemon that was stopped is replaced, one
+    // that still answers is not
+    if let Ok(meta) = std::fs::symlink_metadata(&args.socket) {
+        if !meta.file_type().is_socket() {
+            bail!("{} exists and is not a socket", args.socket.display());
+        }
+        if UnixStream::connect(&args.socket).is_ok() {
+            bail!("another daemon is listening on {}", args.socket.display());
+        }
+        std::fs::remove_file(&args.socket)?;
+    }
+    let listener =
+        UnixListener::bind(&args.socket).with_context(|| format!("cannot listen on {}", args.socket.display()))?;
+
+    // Requests change the working directory, so the table directory is
+    // fixed before the first one
+    let lut = match &args.lut_cache {
+        Some(dir) => LutCache::with_dir(std::path::absolute(dir)?),
+        None => LutCache::new(),
+    };
+    let metrics = Arc::new(Metrics::default());
+    if let Some(addr) = args.metrics {
+        let listener = TcpListener::bind(addr).with_context(|| format!("cannot listen on {}", addr))?;
+        info!("Metrics at http://{}/metrics", listener.local_addr()?);
+        let metrics = metrics.clone();
+        std::thread::spawn(move || serve_metrics(listener, &metrics));
+    }
+    let shared = Shared {
+        pool,
+        lut: Some(Arc::new(lut)),
+        last_decoded: args.keep_source.then(|| Arc::new(LastDecoded::default())),
+        metrics: args.metrics.map(|_| metrics.clone()),
+    };
+
+    // Connections are accepted as they come, so the ones waiting are counted
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if tx.send((stream, metrics.queue())).is_err() {
+                        break;
+                    }
+                }
+                Err(err) => warn!("Connection failed: {}", err),
+            }
+        }
+    });
+    info!("Listening on {} (Ctrl-C to stop)", args.socket.display());
+    for (mut stream, queued) in rx {
+        drop(queued);
+        if let Err(err) = handle(&mut stream, &shared) {
+            error!("Request failed: {:#}", err);
+        }
+    }
+    Ok(())
+}
+
+fn handle(stream: &mut UnixStream, shared: &Shared) -> Result<()> {
+    let start = Instant::now();
+    // Requests run one at a time, so a client that never finishes sending
+    // mustn't hold up the ones behind it
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut request = Vec::new();
+    stream.read_to_end(&mut request).context("the client stopped sending its request")?;
+    // Another daemon checking whether this one is still running
+    if request.is_empty() {
+        return Ok(());
+    }
+    let result = run(&request, shared);
+    if let Some(metrics) = &shared.metrics {
+        metrics.finished(result.is_ok());
+    }
+    let reply = match result {
+        Ok(()) => {
+            info!("Request done in {:?}", start.elapsed());
+            "ok\n".to_string()
+        }
+        Err(err) => {
+            error!("Request failed after {:?}: {:#}", start.elapsed(), err);
+            // The reply is one line
+            format!("error: {}\n", format!("{:#}", err).replace('\n', " "))
+        }
+    };
+    // The client may have given up waiting
+    let _ = stream.write_all(reply.as_bytes());
+    Ok(())
+}
+
+fn run(request: &[u8], shared: &Shared) -> Result<()> {
+    let Some(request) = request.strip_suffix(b"\0") else { bail!("incomplete request") };
+    let mut fields = request.split(|&byte| byte == 0).map(OsStr::from_bytes);
+    let dir = PathBuf::from(fields.next().expect("split yields at least one field"));
+    let args: Vec<&OsStr> = fields.collect();
+    let command: Vec<_> = args.iter().map(|arg| arg.to_string_lossy()).collect();
+    info!("Request in {}: {}", dir.display(), command.join(" "));
+
+    let cli = Cli::parse_args([OsStr::new("rust-cube")].into_iter().chain(args))?;
+    if cli.command.is_some() {
+        bail!("the daemon only runs conversions");
+    }
+    if cli.threads.is_some() {
+        bail!("--threads is fixed when the daemon starts");
+    }
+    let std_stream = |path: &Option<PathBuf>| path.as_deref().is_some_and(storage::is_std_stream);
+    if std_stream(&cli.convert.input) || std_stream(&cli.convert.output) {
+        bail!("the daemon can't read stdin or write stdout of the client; use files");
+    }
+    std::env::set_current_dir(&dir).with_context(|| format!("cannot change to {}", dir.display()))?;
+    match panic::catch_unwind(AssertUnwindSafe(|| run_convert(&cli.convert, shared))) {
+        Ok(result) => result,
+        Err(_) => bail!("the conversion panicked"),
+    }
+}
+
+/// Send this invocation's working directory and arguments to the daemon on
+/// `socket` and wait for the conversion to finish.
+pub fn send(socket: &Path) -> Result<()> {
+    let mut stream =
+        UnixStream::connect(socket).with_context(|| format!("no daemon is listening on {}", socket.display()))?;
+    let mut request = Vec::new();
+    for field in [std::env::current_dir()?.into_os_string()].into_iter().chain(std::env::args_os().skip(1)) {
+        request.extend_from_slice(field.as_bytes());
+        request.push(0);
+    }
+    stream.write_all(&request)?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    if reply == "ok\n" {
+        return Ok(());
+    }
+    match reply.strip_prefix("error: ") {
+        Some(message) => bail!("{}", message.trim_end()),
+        None => bail!("the daemon on {} stopped before finishing", socket.display()),
+    }
+}
+
+// Answers every request on `listener` with the metrics, or 404 for paths
+// other than /metrics
+fn serve_metrics(listener: TcpListener, metrics: &Metrics) {
+    for stream in listener.incoming().flatten() {
+        let _ = answer_metrics(stream, metrics);
+    }
+}
+
+fn answer_metrics(mut stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    // A scraper that stops talking mustn't hold up the next one
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Headers run up to the first blank line
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+        header.clear();
+    }
+    let (status, body) = match request.split_whitespace().nth(1) {
+        Some("/metrics") => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", "not found\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
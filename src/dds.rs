@@ -0,0 +1,197 @@
+use crate::bc7;
+use crate::ktx2::check_levels;
+use crate::{mip_chain, CubemapError, CubemapFaces};
+use image::DynamicImage;
+use rayon::prelude::*;
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+
+const DDSD_CAPS: u32 = 0x1;
+const DDSD_HEIGHT: u32 = 0x2;
+const DDSD_WIDTH: u32 = 0x4;
+const DDSD_PITCH: u32 = 0x8;
+const DDSD_PIXELFORMAT: u32 = 0x1000;
+const DDSD_MIPMAPCOUNT: u32 = 0x20000;
+const DDSD_LINEARSIZE: u32 = 0x80000;
+const DDPF_FOURCC: u32 = 0x4;
+const DDSCAPS_COMPLEX: u32 = 0x8;
+const DDSCAPS_TEXTURE: u32 = 0x1000;
+const DDSCAPS_MIPMAP: u32 = 0x40_0000;
+const DDSCAPS2_CUBEMAP_ALLFACES: u32 = 0xFE00;
+const DXGI_FORMAT_R8G8B8A8_UNORM_SRGB: u32 = 29;
+const DXGI_FORMAT_BC7_UNORM_SRGB: u32 = 99;
+const D3D10_RESOURCE_DIMENSION_TEXTURE2D: u32 = 3;
+const D3D11_RESOURCE_MISC_TEXTURECUBE: u32 = 0x4;
+const DDS_ALPHA_MODE_STRAIGHT: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DdsFormat {
+    #[default]
+    Rgba8,
+    Bc7,
+}
+
+impl fmt::Display for DdsFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DdsFormat::Rgba8 => "rgba8",
+            DdsFormat::Bc7 => "bc7",
+        })
+    }
+}
+
+impl FromStr for DdsFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "rgba8" => Ok(DdsFormat::Rgba8),
+            "bc7" => Ok(DdsFormat::Bc7),
+            _ => Err(format!("unknown DDS format '{}' (expected rgba8 or bc7)", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DdsOptions {
+    pub format: DdsFormat,
+    /// Write a full mip chain instead of the base level only
+    pub mipmaps: bool,
+}
+
+impl Default for DdsOptions {
+    fn default() -> Self {
+        DdsOptions { format: DdsFormat::Rgba8, mipmaps: true }
+    }
+}
+
+/// Write the cubemap as a DDS file with a DX10 header, faces in `Face::ALL`
+/// (+X, -X, +Y, -Y, +Z, -Z) order. DDS only gets 8-bit sRGB data, so deeper
+/// faces are quantized.
+pub fn write_dds<W: Write>(
+    cubemap: &CubemapFaces<DynamicImage>,
+    options: &DdsOptions,
+    writer: W,
+) -> Result<(), CubemapError> {
+    write_dds_levels(std::slice::from_ref(cubemap), options, writer)
+}
+
+/// `write_dds` with the mip levels given, e.g. from `prefilter_specular`,
+/// instead of box filtered from the base; `options.mipmaps` is ignored when
+/// there is more than one.
+pub fn write_dds_levels<W: Write>(
+    levels: &[CubemapFaces<DynamicImage>],
+    options: &DdsOptions,
+    mut writer: W,
+) -> Result<(), CubemapError> {
+    check_levels(levels)?;
+    let cubemap = &levels[0];
+    // Face-major: every face carries its own mip chain
+    let faces: Vec<Vec<Vec<u8>>> = (0..6)
+        .into_par_iter()
+        .map(|face| {
+            let chain = match levels {
+                [base] if options.mipmaps => mip_chain(base.faces[face].to_rgba8()),
+                levels => levels.iter().map(|level| level.faces[face].to_rgba8()).collect(),
+            };
+            chain
+                .iter()
+                .map(|level| match options.format {
+                    DdsFormat::Rgba8 => level.as_raw().clone(),
+                    DdsFormat::Bc7 => bc7::compress(level),
+                })
+                .collect()
+        })
+        .collect();
+
+    let size = cubemap.size;
+    let mip_count = faces[0].len() as u32;
+    let (dxgi_format, size_flag, pitch_or_linear_size) = match options.format {
+        DdsFormat::Rgba8 => (DXGI_FORMAT_R8G8B8A8_UNORM_SRGB, DDSD_PITCH, size * 4),
+        DdsFormat::Bc7 => (DXGI_FORMAT_BC7_UNORM_SRGB, DDSD_LINEARSIZE, size.div_ceil(4) * size.div_ceil(4) * 16),
+    };
+    let mut caps = DDSCAPS_TEXTURE | DDSCAPS_COMPLEX;
+    let mut flags = DDSD_CAPS | DDSD_HEIGHT | DDSD_WIDTH | DDSD_PIXELFORMAT | size_flag;
+    if mip_count > 1 {
+        caps |= DDSCAPS_MIPMAP;
+        flags |= DDSD_MIPMAPCOUNT;
+    }
+
+    let mut header = Vec::with_capacity(148);
+    header.extend_from_slice(b"DDS ");
+    for value in [124, flags, size, size, pitch_or_linear_size, 0, mip_count] {
+        header.extend(u32::to_le_bytes(value));
+    }
+    header.resize(header.len() + 11 * 4, 0); // dwReserved1
+    // DDS_PIXELFORMAT pointing at the DX10 extension header
+    for value in [32, DDPF_FOURCC, u32::from_le_bytes(*b"DX10"), 0, 0, 0, 0, 0] {
+        header.extend(u32::to_le_bytes(value));
+    }
+    for value in [caps, DDSCAPS2_CUBEMAP_ALLFACES, 0, 0, 0] {
+        header.extend(u32::to_le_bytes(value));
+    }
+    // DDS_HEADER_DXT10; arraySize counts cubes, not faces
+    for value in [
+        dxgi_format,
+        D3D10_RESOURCE_DIMENSION_TEXTURE2D,
+        D3D11_RESOURCE_MISC_TEXTURECUBE,
+        1,
+        DDS_ALPHA_MODE_STRAIGHT,
+    ] {
+        header.extend(u32::to_le_bytes(value));
+    }
+
+    writer.write_all(&header)?;
+    for level in faces.iter().flatten() {
+        writer.write_all(level)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn write(format: DdsFormat) -> Vec<u8> {
+        let face = DynamicImage::ImageRgb8(RgbImage::from_fn(8, 8, |x, y| Rgb([x as u8 * 30, y as u8 * 30, 90])));
+        let cubemap = CubemapFaces::from_faces(vec![face; 6]).unwrap();
+        let mut out = Vec::new();
+        write_dds(&cubemap, &DdsOptions { format, mipmaps: true }, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn mipmapped_cubemap_header() {
+        for (format, dxgi_format, pitch, payload) in [
+            // 8, 4, 2 and 1 pixels across, four bytes each, six faces
+            (DdsFormat::Rgba8, DXGI_FORMAT_R8G8B8A8_UNORM_SRGB, 8 * 4, 6 * (64 + 16 + 4 + 1) * 4),
+            // Four 4x4 blocks for the base, then one per level
+            (DdsFormat::Bc7, DXGI_FORMAT_BC7_UNORM_SRGB, 4 * 16, 6 * (4 + 1 + 1 + 1) * 16),
+        ] {
+            let out = write(format);
+            assert_eq!(&out[..4], b"DDS ");
+            assert_eq!(u32_at(&out, 4), 124);
+            let flags = u32_at(&out, 8);
+            assert_ne!(flags & DDSD_MIPMAPCOUNT, 0);
+            assert_eq!((u32_at(&out, 12), u32_at(&out, 16)), (8, 8));
+            assert_eq!(u32_at(&out, 20), pitch);
+            assert_eq!(u32_at(&out, 28), 4);
+            // Pixel format: a FourCC pointing at the DX10 header
+            assert_eq!((u32_at(&out, 76), u32_at(&out, 80)), (32, DDPF_FOURCC));
+            assert_eq!(&out[84..88], b"DX10");
+            assert_eq!(u32_at(&out, 108), DDSCAPS_TEXTURE | DDSCAPS_COMPLEX | DDSCAPS_MIPMAP);
+            assert_eq!(u32_at(&out, 112), DDSCAPS2_CUBEMAP_ALLFACES);
+            // DX10 header: one cube of 2D faces
+            let dx10: Vec<u32> = (0..5).map(|i| u32_at(&out, 128 + 4 * i)).collect();
+            assert_eq!(dx10, [dxgi_format, 3, D3D11_RESOURCE_MISC_TEXTURECUBE, 1, DDS_ALPHA_MODE_STRAIGHT]);
+            assert_eq!(out.len(), 148 + payload as usize, "{}", format);
+        }
+    }
+}
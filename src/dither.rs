@@ -0,0 +1,223 @@
+use crate::PixelDepth;
+use image::{DynamicImage, Rgba, RgbImage, RgbaImage};
+use rayon::prelude::*;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+/// How 16-bit and float images are rounded to 8 bits for formats that can't
+/// hold more, trading banding in smooth gradients for fine noise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Dither {
+    /// Round to the nearest level
+    #[default]
+    None,
+    /// Offset each value by a tiled blue-noise threshold: fine-grained noise
+    /// without clumps, the least visible of the two
+    BlueNoise,
+    /// Spread each rounding error to the pixels not yet rounded
+    FloydSteinberg,
+}
+
+impl Dither {
+    pub const ALL: [Dither; 3] = [Dither::None, Dither::BlueNoise, Dither::FloydSteinberg];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Dither::None => "none",
+            Dither::BlueNoise => "blue-noise",
+            Dither::FloydSteinberg => "floyd-steinberg",
+        }
+    }
+
+    /// `img` as 8-bit RGB. 16-bit values are scaled down and float ones
+    /// clamped to [0, 1], as `DynamicImage::to_rgb8` does, before rounding.
+    pub fn quantize(self, img: &DynamicImage) -> RgbImage {
+        let levels: Vec<f32> = match img {
+            DynamicImage::ImageRgb8(_) => return img.to_rgb8(),
+            _ if self == Dither::None => return img.to_rgb8(),
+            DynamicImage::ImageRgb16(rgb) => rgb.as_raw().par_iter().map(|&v| v as f32 / 257.0).collect(),
+            DynamicImage::ImageRgb32F(rgb) => rgb.as_raw().par_iter().map(|&v| v.clamp(0.0, 1.0) * 255.0).collect(),
+            other if PixelDepth::of(other) == PixelDepth::U8 => return other.to_rgb8(),
+            other => other.to_rgb32f().as_raw().par_iter().map(|&v| v.clamp(0.0, 1.0) * 255.0).collect(),
+        };
+        let (width, height) = (img.width(), img.height());
+        let data = match self {
+            Dither::None => unreachable!("rounded above"),
+            Dither::BlueNoise => blue_noise(&levels, width as usize),
+            Dither::FloydSteinberg => floyd_steinberg(levels, width as usize),
+        };
+        RgbImage::from_raw(width, height, data).expect("same dimensions")
+    }
+
+    /// `quantize` keeping `img`'s alpha, which is rounded rather than
+    /// dithered.
+    pub fn quantize_rgba(self, img: &DynamicImage) -> RgbaImage {
+        if let DynamicImage::ImageRgba8(rgba) = img {
+            return rgba.clone();
+        }
+        let rgb = self.quantize(img);
+        let mut rgba = img.to_rgba8();
+        for (pixel, &color) in rgba.pixels_mut().zip(rgb.pixels()) {
+            *pixel = Rgba([color[0], color[1], color[2], pixel[3]]);
+        }
+        rgba
+    }
+}
+
+impl fmt::Display for Dither {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Dither {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Dither::ALL
+            .into_iter()
+            .find(|dither| dither.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown dither '{}' (expected none, blue-noise or floyd-steinberg)", s))
+    }
+}
+
+// Each channel reads the tile at its own offset, so the channels' noise
+// doesn't line up into gray speckle
+const CHANNEL_OFFSETS: [(usize, usize); 3] = [(0, 0), (23, 41), (47, 13)];
+
+fn blue_noise(levels: &[f32], width: usize) -> Vec<u8> {
+    let tile = blue_noise_tile();
+    levels
+        .par_chunks(width * 3)
+        .enumerate()
+        .flat_map_iter(|(y, row)| {
+            row.iter().enumerate().map(move |(i, &value)| {
+                let (x, (dx, dy)) = (i / 3, CHANNEL_OFFSETS[i % 3]);
+                let threshold = tile[(y + dy) % TILE * TILE + (x + dx) % TILE];
+                (value + threshold).floor().clamp(0.0, 255.0) as u8
+            })
+        })
+        .collect()
+}
+
+// Serpentine, so errors don't all drift the same way
+fn floyd_steinberg(mut levels: Vec<f32>, width: usize) -> Vec<u8> {
+    let row_len = width * 3;
+    let height = levels.len() / row_len.max(1);
+    let mut out = vec![0u8; levels.len()];
+    for y in 0..height {
+        let reverse = y % 2 == 1;
+        for step in 0..width {
+            let x = if reverse { width - 1 - step } else { step };
+            // Ahead in this row, and behind, here and ahead in the next
+            let ahead = if reverse { x.checked_sub(1) } else { Some(x + 1).filter(|&x| x < width) };
+            let behind = if reverse { Some(x + 1).filter(|&x| x < width) } else { x.checked_sub(1) };
+            for c in 0..3 {
+                let i = y * row_len + x * 3 + c;
+                let rounded = levels[i].round().clamp(0.0, 255.0);
+                out[i] = rounded as u8;
+                let error = levels[i] - rounded;
+                let mut spread = |x: Option<usize>, dy: usize, weight: f32| {
+                    if let Some(x) = x.filter(|_| y + dy < height) {
+                        levels[(y + dy) * row_len + x * 3 + c] += error * weight;
+                    }
+                };
+                spread(ahead, 0, 7.0 / 16.0);
+                spread(behind, 1, 3.0 / 16.0);
+                spread(Some(x), 1, 5.0 / 16.0);
+                spread(ahead, 1, 1.0 / 16.0);
+            }
+        }
+    }
+    out
+}
+
+const TILE: usize = 64;
+
+// Thresholds in [0, 1) from the ranks of a void-and-cluster pattern
+// (Ulichney, "The void-and-cluster method for dither array generation",
+// 1993), built once on first use
+fn blue_noise_tile() -> &'static [f32] {
+    static TILE_THRESHOLDS: OnceLock<Vec<f32>> = OnceLock::new();
+    TILE_THRESHOLDS.get_or_init(|| {
+        let ranks = void_and_cluster();
+        ranks.iter().map(|&rank| (rank as f32 + 0.5) / (TILE * TILE) as f32).collect()
+    })
+}
+
+fn void_and_cluster() -> Vec<u32> {
+    let cells = TILE * TILE;
+    // Wrapped Gaussian falloff by offset, so the tile repeats seamlessly
+    let sigma = 1.5f32;
+    let falloff: Vec<f32> = (0..cells)
+        .map(|i| {
+            let wrap = |d: usize| d.min(TILE - d) as f32;
+            let (dx, dy) = (wrap(i % TILE), wrap(i / TILE));
+            (-(dx * dx + dy * dy) / (2.0 * sigma * sigma)).exp()
+        })
+        .collect();
+    let toggle = |energy: &mut [f32], at: usize, sign: f32| {
+        let (ax, ay) = (at % TILE, at / TILE);
+        for (i, value) in energy.iter_mut().enumerate() {
+            let (dx, dy) = ((i % TILE + TILE - ax) % TILE, (i / TILE + TILE - ay) % TILE);
+            *value += sign * falloff[dy * TILE + dx];
+        }
+    };
+    // Tightest cluster among set cells, or largest void among clear ones
+    let extreme = |energy: &[f32], set: &[bool], want: bool| {
+        let candidates = (0..cells).filter(|&i| set[i] == want);
+        match want {
+            true => candidates.max_by(|&a, &b| energy[a].total_cmp(&energy[b])),
+            false => candidates.min_by(|&a, &b| energy[a].total_cmp(&energy[b])),
+        }
+        .expect("a cell of each kind")
+    };
+
+    // A tenth of the cells set at random, then relaxed until moving the
+    // tightest cluster just puts it back
+    let mut state = 0x2545_f491u32;
+    let mut set = vec![false; cells];
+    let mut energy = vec![0.0f32; cells];
+    let mut placed = 0;
+    while placed < cells / 10 {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        let at = state as usize % cells;
+        if !set[at] {
+            set[at] = true;
+            toggle(&mut energy, at, 1.0);
+            placed += 1;
+        }
+    }
+    loop {
+        let cluster = extreme(&energy, &set, true);
+        set[cluster] = false;
+        toggle(&mut energy, cluster, -1.0);
+        let void = extreme(&energy, &set, false);
+        set[void] = true;
+        toggle(&mut energy, void, 1.0);
+        if void == cluster {
+            break;
+        }
+    }
+
+    // Rank the initial cells by taking clusters away, then the rest by
+    // filling voids
+    let mut ranks = vec![0u32; cells];
+    let (mut remaining, mut remaining_energy) = (set.clone(), energy.clone());
+    for rank in (0..placed).rev() {
+        let cluster = extreme(&remaining_energy, &remaining, true);
+        remaining[cluster] = false;
+        toggle(&mut remaining_energy, cluster, -1.0);
+        ranks[cluster] = rank as u32;
+    }
+    for rank in placed..cells {
+        let void = extreme(&energy, &set, false);
+        set[void] = true;
+        toggle(&mut energy, void, 1.0);
+        ranks[void] = rank as u32;
+    }
+    ranks
+}
@@ -0,0 +1,625 @@
+use crate::{write_npy, AtomicFile, CubemapError, Dither, Metadata, PixelDepth, ToneMap};
+use image::codecs::hdr::HdrEncoder;
+use image::codecs::openexr::OpenExrEncoder;
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use image::codecs::tiff::TiffEncoder;
+use image::error::{EncodingError, ImageFormatHint};
+use image::{DynamicImage, ImageEncoder, ImageError, ImageFormat, ImageResult, Rgb, RgbImage};
+use std::borrow::Cow;
+use std::fmt;
+use std::io::{Cursor, Seek, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Jpeg,
+    Png,
+    Tiff,
+    Exr,
+    Hdr,
+    /// Lossy, or lossless with `EncodeOptions::webp_lossless`; needs the
+    /// `webp` feature
+    WebP,
+    /// AV1 stills; needs the `avif` feature
+    Avif,
+    /// Raw NumPy array at the face's own precision, for ML pipelines
+    Npy,
+}
+
+impl OutputFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Png => "png",
+            OutputFormat::Tiff => "tif",
+            OutputFormat::Exr => "exr",
+            OutputFormat::Hdr => "hdr",
+            OutputFormat::WebP => "webp",
+            OutputFormat::Avif => "avif",
+            OutputFormat::Npy => "npy",
+        }
+    }
+
+    /// Media type for HTTP responses and manifests
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Png => "image/png",
+            OutputFormat::Tiff => "image/tiff",
+            OutputFormat::Exr => "image/x-exr",
+            OutputFormat::Hdr => "image/vnd.radiance",
+            OutputFormat::WebP => "image/webp",
+            OutputFormat::Avif => "image/avif",
+            OutputFormat::Npy => "application/x-npy",
+        }
+    }
+
+    /// Highest precision the format can store
+    pub fn max_depth(self) -> PixelDepth {
+        match self {
+            OutputFormat::Jpeg | OutputFormat::WebP | OutputFormat::Avif => PixelDepth::U8,
+            OutputFormat::Png | OutputFormat::Tiff => PixelDepth::U16,
+            OutputFormat::Exr | OutputFormat::Hdr | OutputFormat::Npy => PixelDepth::F32,
+        }
+    }
+
+    /// Whether the format keeps an alpha channel
+    pub fn has_alpha(self) -> bool {
+        matches!(
+            self,
+            OutputFormat::Png | OutputFormat::Tiff | OutputFormat::WebP | OutputFormat::Exr | OutputFormat::Npy
+        )
+    }
+
+    pub fn from_extension(ext: &str) -> Option<OutputFormat> {
+        match ext.to_ascii_lowercase().as_str() {
+            "jpg" | "jpeg" => Some(OutputFormat::Jpeg),
+            "png" => Some(OutputFormat::Png),
+            "tif" | "tiff" => Some(OutputFormat::Tiff),
+            "exr" => Some(OutputFormat::Exr),
+            "hdr" => Some(OutputFormat::Hdr),
+            "webp" => Some(OutputFormat::WebP),
+            "avif" => Some(OutputFormat::Avif),
+            "npy" => Some(OutputFormat::Npy),
+            _ => None,
+        }
+    }
+
+    pub fn from_path(path: &Path) -> Option<OutputFormat> {
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(OutputFormat::from_extension)
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OutputFormat::Jpeg => "jpeg",
+            OutputFormat::Png => "png",
+            OutputFormat::Tiff => "tiff",
+            OutputFormat::Exr => "exr",
+            OutputFormat::Hdr => "hdr",
+            OutputFormat::WebP => "webp",
+            OutputFormat::Avif => "avif",
+            OutputFormat::Npy => "npy",
+        })
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        OutputFormat::from_extension(s)
+            .ok_or_else(|| format!("unknown format '{}' (expected jpeg, png, tiff, exr, hdr, webp, avif or npy)", s))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PngCompression {
+    Fast,
+    #[default]
+    Default,
+    Best,
+}
+
+impl FromStr for PngCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "fast" => Ok(PngCompression::Fast),
+            "default" => Ok(PngCompression::Default),
+            "best" => Ok(PngCompression::Best),
+            _ => Err(format!("unknown PNG compression '{}' (expected fast, default or best)", s)),
+        }
+    }
+}
+
+/// Which JPEG encoder writes JPEG output. The libjpeg-turbo based ones need
+/// the `mozjpeg` feature and are the default in builds with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum JpegBackend {
+    /// The pure-Rust jpeg-encoder crate
+    #[cfg_attr(not(feature = "mozjpeg"), default)]
+    Rust,
+    /// libjpeg-turbo's settings: the fastest
+    #[cfg_attr(feature = "mozjpeg", default)]
+    Turbo,
+    /// mozjpeg's trellis quantization and optimized progressive scans:
+    /// smaller files at the same quality, several times slower; always
+    /// progressive
+    Mozjpeg,
+}
+
+impl JpegBackend {
+    pub const ALL: [JpegBackend; 3] = [JpegBackend::Rust, JpegBackend::Turbo, JpegBackend::Mozjpeg];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            JpegBackend::Rust => "rust",
+            JpegBackend::Turbo => "turbo",
+            JpegBackend::Mozjpeg => "mozjpeg",
+        }
+    }
+}
+
+impl fmt::Display for JpegBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for JpegBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        JpegBackend::ALL
+            .into_iter()
+            .find(|backend| backend.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown JPEG encoder '{}' (expected rust, turbo or mozjpeg)", s))
+    }
+}
+
+/// How much of the chroma resolution JPEG output keeps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChromaSubsampling {
+    /// Full resolution
+    #[default]
+    S444,
+    /// Half horizontally
+    S422,
+    /// Half in both directions; the usual choice for the web
+    S420,
+}
+
+impl ChromaSubsampling {
+    pub const ALL: [ChromaSubsampling; 3] = [ChromaSubsampling::S444, ChromaSubsampling::S422, ChromaSubsampling::S420];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ChromaSubsampling::S444 => "444",
+            ChromaSubsampling::S422 => "422",
+            ChromaSubsampling::S420 => "420",
+        }
+    }
+
+    // Chroma pixel size in luma pixels, across and down
+    fn factors(self) -> (u8, u8) {
+        match self {
+            ChromaSubsampling::S444 => (1, 1),
+            ChromaSubsampling::S422 => (2, 1),
+            ChromaSubsampling::S420 => (2, 2),
+        }
+    }
+}
+
+impl fmt::Display for ChromaSubsampling {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ChromaSubsampling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.replace(':', "");
+        ChromaSubsampling::ALL
+            .into_iter()
+            .find(|subsampling| subsampling.name() == name)
+            .ok_or_else(|| format!("unknown chroma subsampling '{}' (expected 444, 422 or 420)", s))
+    }
+}
+
+/// Precision of the AV1 data in AVIF output. 10 bits keeps smooth
+/// gradients from banding, and takes 16-bit and float faces without first
+/// rounding them to 8 bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AvifDepth {
+    Eight,
+    #[default]
+    Ten,
+}
+
+impl AvifDepth {
+    pub const ALL: [AvifDepth; 2] = [AvifDepth::Eight, AvifDepth::Ten];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AvifDepth::Eight => "8",
+            AvifDepth::Ten => "10",
+        }
+    }
+}
+
+impl fmt::Display for AvifDepth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for AvifDepth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        AvifDepth::ALL
+            .into_iter()
+            .find(|depth| depth.name() == s)
+            .ok_or_else(|| format!("unknown AVIF bit depth '{}' (expected 8 or 10)", s))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EncodeOptions {
+    pub format: OutputFormat,
+    /// JPEG and WebP quality (1-100)
+    pub quality: u8,
+    pub png_compression: PngCompression,
+    pub jpeg_backend: JpegBackend,
+    /// Lossless WebP; `quality` then trades encoding time for size
+    pub webp_lossless: bool,
+    /// AVIF encoder speed, 1 (smallest files) to 10 (fastest)
+    pub avif_speed: u8,
+    pub avif_depth: AvifDepth,
+    /// Progressive JPEG scans instead of a single baseline one
+    pub jpeg_progressive: bool,
+    pub jpeg_subsampling: ChromaSubsampling,
+    /// Tone mapping for float images going to an 8 or 16-bit format; without
+    /// one they are clipped at 1.0
+    pub tone_map: Option<ToneMap>,
+    /// How 16-bit and float images are rounded for 8-bit formats
+    pub dither: Dither,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        EncodeOptions {
+            format: OutputFormat::Jpeg,
+            quality: 95,
+            png_compression: PngCompression::Default,
+            jpeg_backend: JpegBackend::default(),
+            webp_lossless: false,
+            avif_speed: 6,
+            avif_depth: AvifDepth::default(),
+            jpeg_progressive: false,
+            jpeg_subsampling: ChromaSubsampling::default(),
+            tone_map: None,
+            dither: Dither::None,
+        }
+    }
+}
+
+impl EncodeOptions {
+    /// The deepest pixels these options write: the format's, except that
+    /// 10-bit AVIF takes 16-bit faces.
+    pub fn max_depth(&self) -> PixelDepth {
+        match self.format {
+            OutputFormat::Avif if self.avif_depth == AvifDepth::Ten => PixelDepth::U16,
+            format => format.max_depth(),
+        }
+    }
+}
+
+/// Encode `img` in the requested format. Images deeper than the options can
+/// hold (see `EncodeOptions::max_depth`) are converted down; float values are
+/// tone mapped with `options.tone_map` or else clamped to [0, 1] when that
+/// happens, and rounded to 8 bits with `options.dither`. PNG, TIFF, WebP,
+/// EXR and NumPy keep alpha (see `OutputFormat::has_alpha`); the others drop
+/// it. NumPy arrays keep the face's own precision and gray.
+pub fn encode_image<W: Write + Seek>(
+    img: &DynamicImage,
+    options: &EncodeOptions,
+    writer: W,
+) -> Result<(), CubemapError> {
+    let (width, height) = (img.width(), img.height());
+    let depth = options.max_depth();
+    // Dithering needs the finer levels to work from
+    let mapped = match options.dither {
+        Dither::None => depth,
+        _ => PixelDepth::U16,
+    };
+    let img = match (img, &options.tone_map) {
+        (DynamicImage::ImageRgb32F(hdr), Some(tone_map)) if depth < PixelDepth::F32 => {
+            Cow::Owned(tone_map.apply(hdr, mapped))
+        }
+        (DynamicImage::ImageRgba32F(hdr), Some(tone_map)) if depth < PixelDepth::F32 => {
+            Cow::Owned(tone_map.apply_rgba(hdr, mapped))
+        }
+        _ => Cow::Borrowed(img),
+    };
+    let img = img.as_ref();
+    let result = match options.format {
+        OutputFormat::Jpeg => {
+            let rgb = match img {
+                DynamicImage::ImageRgb8(rgb) => Cow::Borrowed(rgb),
+                other => Cow::Owned(options.dither.quantize(other)),
+            };
+            match options.jpeg_backend {
+                JpegBackend::Rust => encode_jpeg(&rgb, options, writer),
+                _ => encode_libjpeg(&rgb, options, writer),
+            }
+        }
+        OutputFormat::WebP => {
+            let img = match img {
+                DynamicImage::ImageRgb8(_) | DynamicImage::ImageRgba8(_) => Cow::Borrowed(img),
+                other if other.color().has_alpha() => {
+                    Cow::Owned(DynamicImage::ImageRgba8(options.dither.quantize_rgba(other)))
+                }
+                other => Cow::Owned(DynamicImage::ImageRgb8(options.dither.quantize(other))),
+            };
+            encode_webp(&img, options, writer)
+        }
+        OutputFormat::Avif => {
+            let img = match img {
+                DynamicImage::ImageRgb8(_) => Cow::Borrowed(img),
+                DynamicImage::ImageRgb16(_) if depth == PixelDepth::U16 => Cow::Borrowed(img),
+                other if depth == PixelDepth::U16 && PixelDepth::of(other) > PixelDepth::U8 => {
+                    Cow::Owned(DynamicImage::ImageRgb16(other.to_rgb16()))
+                }
+                other => Cow::Owned(DynamicImage::ImageRgb8(options.dither.quantize(other))),
+            };
+            encode_avif(&img, options, writer)
+        }
+        OutputFormat::Png => {
+            let (compression, filter) = match options.png_compression {
+                PngCompression::Fast => (CompressionType::Fast, FilterType::Sub),
+                PngCompression::Default => (CompressionType::Default, FilterType::Adaptive),
+                PngCompression::Best => (CompressionType::Best, FilterType::Adaptive),
+            };
+            let img = storable(img);
+            PngEncoder::new_with_quality(writer, compression, filter)
+                .write_image(img.as_bytes(), width, height, img.color())
+        }
+        OutputFormat::Tiff => {
+            let img = storable(img);
+            TiffEncoder::new(writer).write_image(img.as_bytes(), width, height, img.color())
+        }
+        OutputFormat::Exr => {
+            let img = match img {
+                DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => Cow::Borrowed(img),
+                other if other.color().has_alpha() => Cow::Owned(DynamicImage::ImageRgba32F(other.to_rgba32f())),
+                other => Cow::Owned(DynamicImage::ImageRgb32F(other.to_rgb32f())),
+            };
+            OpenExrEncoder::new(writer).write_image(img.as_bytes(), width, height, img.color())
+        }
+        OutputFormat::Hdr => {
+            // RGBE has a shared exponent and no sign, so negative lobes from
+            // sharper filters are clipped here rather than wrapped
+            let pixels: Vec<Rgb<f32>> = img
+                .to_rgb32f()
+                .pixels()
+                .map(|p| Rgb(p.0.map(|c| c.max(0.0))))
+                .collect();
+            HdrEncoder::new(writer).encode(&pixels, width as usize, height as usize)
+        }
+        OutputFormat::Npy => return write_npy(img, writer),
+    };
+    result.map_err(CubemapError::encode)
+}
+
+fn encode_jpeg<W: Write>(rgb: &RgbImage, options: &EncodeOptions, writer: W) -> ImageResult<()> {
+    let error = |err: String| {
+        ImageError::Encoding(EncodingError::new(ImageFormatHint::Exact(ImageFormat::Jpeg), err))
+    };
+    let (width, height) = match (u16::try_from(rgb.width()), u16::try_from(rgb.height())) {
+        (Ok(width), Ok(height)) => (width, height),
+        _ => return Err(error(format!("{}x{} is larger than JPEG allows", rgb.width(), rgb.height()))),
+    };
+    let mut encoder = jpeg_encoder::Encoder::new(writer, options.quality);
+    let (across, down) = options.jpeg_subsampling.factors();
+    encoder.set_sampling_factor(
+        jpeg_encoder::SamplingFactor::from_factors(across, down).expect("1x1, 2x1 and 2x2 are valid"),
+    );
+    encoder.set_progressive(options.jpeg_progressive);
+    encoder.encode(rgb.as_raw(), width, height, jpeg_encoder::ColorType::Rgb).map_err(|err| error(err.to_string()))
+}
+
+// libjpeg-turbo as built into mozjpeg: `Turbo` resets it to libjpeg's
+// defaults, `Mozjpeg` keeps its size-optimizing ones
+#[cfg(feature = "mozjpeg")]
+fn encode_libjpeg<W: Write>(rgb: &RgbImage, options: &EncodeOptions, writer: W) -> ImageResult<()> {
+    // libjpeg reports errors by unwinding out of the C code
+    let encode = std::panic::AssertUnwindSafe(|| -> std::io::Result<()> {
+        let mut compress = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+        if options.jpeg_backend == JpegBackend::Turbo {
+            compress.set_fastest_defaults();
+            if options.jpeg_progressive {
+                compress.set_progressive_mode();
+            }
+        }
+        compress.set_size(rgb.width() as usize, rgb.height() as usize);
+        compress.set_quality(options.quality as f32);
+        let chroma = options.jpeg_subsampling.factors();
+        compress.set_chroma_sampling_pixel_sizes(chroma, chroma);
+        let mut started = compress.start_compress(writer)?;
+        started.write_scanlines(rgb.as_raw())?;
+        started.finish()?;
+        Ok(())
+    });
+    std::panic::catch_unwind(encode).unwrap_or_else(|panic| {
+        let message = panic.downcast_ref::<String>().map_or("libjpeg failed", String::as_str);
+        Err(std::io::Error::other(message.to_string()))
+    })?;
+    Ok(())
+}
+
+#[cfg(not(feature = "mozjpeg"))]
+fn encode_libjpeg<W: Write>(_rgb: &RgbImage, options: &EncodeOptions, _writer: W) -> ImageResult<()> {
+    let message = format!("the {} encoder needs a build with the mozjpeg feature", options.jpeg_backend);
+    Err(ImageError::Encoding(EncodingError::new(ImageFormatHint::Exact(ImageFormat::Jpeg), message)))
+}
+
+// `img` is 8-bit RGB or RGBA
+#[cfg(feature = "webp")]
+fn encode_webp<W: Write>(img: &DynamicImage, options: &EncodeOptions, mut writer: W) -> ImageResult<()> {
+    let encoder = match img {
+        DynamicImage::ImageRgba8(rgba) => webp::Encoder::from_rgba(rgba.as_raw(), img.width(), img.height()),
+        _ => webp::Encoder::from_rgb(img.as_bytes(), img.width(), img.height()),
+    };
+    let encoded = encoder.encode_simple(options.webp_lossless, options.quality as f32).map_err(|err| {
+        let message = format!("libwebp failed: {:?}", err);
+        ImageError::Encoding(EncodingError::new(ImageFormatHint::Exact(ImageFormat::WebP), message))
+    })?;
+    writer.write_all(&encoded)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "webp"))]
+fn encode_webp<W: Write>(_img: &DynamicImage, _options: &EncodeOptions, _writer: W) -> ImageResult<()> {
+    let message = "WebP output needs a build with the webp feature";
+    Err(ImageError::Encoding(EncodingError::new(ImageFormatHint::Exact(ImageFormat::WebP), message)))
+}
+
+// `img` is 8-bit RGB, or 16-bit RGB for 10-bit output
+#[cfg(feature = "avif")]
+fn encode_avif<W: Write>(img: &DynamicImage, options: &EncodeOptions, mut writer: W) -> ImageResult<()> {
+    let error =
+        |message: String| ImageError::Encoding(EncodingError::new(ImageFormatHint::Exact(ImageFormat::Avif), message));
+    if !(1..=10).contains(&options.avif_speed) {
+        return Err(error(format!("AVIF speed must be between 1 and 10, got {}", options.avif_speed)));
+    }
+    // Threads come from the current rayon pool
+    let encoder = ravif::Encoder::new()
+        .with_quality(options.quality.clamp(1, 100) as f32)
+        .with_speed(options.avif_speed)
+        .with_bit_depth(match options.avif_depth {
+            AvifDepth::Eight => ravif::BitDepth::Eight,
+            AvifDepth::Ten => ravif::BitDepth::Ten,
+        });
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    let encoded = match img {
+        // ravif only takes 8-bit RGB, so deeper faces go in as the planes it
+        // would have made from them
+        DynamicImage::ImageRgb16(rgb) => {
+            let planes = rgb.pixels().map(|p| ycbcr_10_bit(p.0));
+            let (range, matrix) = (rav1e::prelude::PixelRange::Full, ravif::MatrixCoefficients::BT601);
+            encoder.encode_raw_planes_10_bit(width, height, planes, None::<[_; 0]>, range, matrix)
+        }
+        _ => {
+            let pixels: Vec<ravif::RGB8> = img.to_rgb8().pixels().map(|p| ravif::RGB8::new(p[0], p[1], p[2])).collect();
+            encoder.encode_rgb(ravif::Img::new(&pixels, width, height))
+        }
+    };
+    writer.write_all(&encoded.map_err(|err| error(err.to_string()))?.avif_file)?;
+    Ok(())
+}
+
+// Full range BT.601 Y, Cb and Cr of a 16-bit pixel, as ravif converts 8-bit
+// ones
+#[cfg(feature = "avif")]
+fn ycbcr_10_bit([r, g, b]: [u16; 3]) -> [u16; 3] {
+    const KR: f32 = 0.299;
+    const KB: f32 = 0.114;
+    let scale = 1023.0 / 65535.0;
+    let (r, g, b) = (r as f32 * scale, g as f32 * scale, b as f32 * scale);
+    let y = KR * r + (1.0 - KR - KB) * g + KB * b;
+    let cb = (b - y) * 0.5 / (1.0 - KB) + 512.0;
+    let cr = (r - y) * 0.5 / (1.0 - KR) + 512.0;
+    [y, cb, cr].map(|value| value.round().clamp(0.0, 1023.0) as u16)
+}
+
+#[cfg(not(feature = "avif"))]
+fn encode_avif<W: Write>(_img: &DynamicImage, _options: &EncodeOptions, _writer: W) -> ImageResult<()> {
+    let message = "AVIF output needs a build with the avif feature";
+    Err(ImageError::Encoding(EncodingError::new(ImageFormatHint::Exact(ImageFormat::Avif), message)))
+}
+
+// PNG and TIFF take 8 or 16 bit gray, RGB or RGBA as is; anything else is
+// converted to the closest of them, keeping alpha if it has any
+fn storable(img: &DynamicImage) -> Cow<'_, DynamicImage> {
+    match img {
+        DynamicImage::ImageRgb8(_) | DynamicImage::ImageRgb16(_) => Cow::Borrowed(img),
+        DynamicImage::ImageRgba8(_) | DynamicImage::ImageRgba16(_) => Cow::Borrowed(img),
+        DynamicImage::ImageLuma8(_) | DynamicImage::ImageLuma16(_) => Cow::Borrowed(img),
+        other if other.color().has_alpha() => match PixelDepth::of(other) {
+            PixelDepth::U8 => Cow::Owned(DynamicImage::ImageRgba8(other.to_rgba8())),
+            _ => Cow::Owned(DynamicImage::ImageRgba16(other.to_rgba16())),
+        },
+        other if PixelDepth::of(other) == PixelDepth::U8 => Cow::Owned(DynamicImage::ImageRgb8(other.to_rgb8())),
+        other => Cow::Owned(DynamicImage::ImageRgb16(other.to_rgb16())),
+    }
+}
+
+pub fn save_image(img: &DynamicImage, path: &Path, options: &EncodeOptions) -> Result<(), CubemapError> {
+    save_image_with_metadata(img, path, options, &Metadata::default())
+}
+
+/// `save_image` that also embeds the source's color profile and EXIF fields
+/// (JPEG and PNG only).
+pub fn save_image_with_metadata(
+    img: &DynamicImage,
+    path: &Path,
+    options: &EncodeOptions,
+    metadata: &Metadata,
+) -> Result<(), CubemapError> {
+    // Written aside and renamed, so an interrupted save leaves no partial file
+    let mut file = AtomicFile::create(path)?;
+    encode_image_with_metadata(img, options, metadata, &mut file)?;
+    file.commit()?;
+    Ok(())
+}
+
+/// `encode_image` with `metadata` spliced into the output, see
+/// `Metadata::embed`.
+pub fn encode_image_with_metadata<W: Write + Seek>(
+    img: &DynamicImage,
+    options: &EncodeOptions,
+    metadata: &Metadata,
+    mut writer: W,
+) -> Result<(), CubemapError> {
+    if metadata.is_empty() {
+        return encode_image(img, options, writer);
+    }
+    let mut encoded = Cursor::new(Vec::new());
+    encode_image(img, options, &mut encoded)?;
+    writer.write_all(&metadata.embed(encoded.into_inner(), options.format)?)?;
+    Ok(())
+}
+
+#[cfg(all(test, feature = "avif"))]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Rgb};
+
+    #[test]
+    fn deep_faces_keep_ten_bits_in_avif() {
+        assert_eq!(ycbcr_10_bit([0; 3]), [0, 512, 512]);
+        assert_eq!(ycbcr_10_bit([65535; 3]), [1023, 512, 512]);
+        // Halfway between 8-bit 100 and 101, which ravif makes 401 and 405
+        assert_eq!(ycbcr_10_bit([257 * 100 + 128; 3])[0], 403);
+
+        let options = EncodeOptions { format: OutputFormat::Avif, avif_speed: 10, ..EncodeOptions::default() };
+        assert_eq!(options.max_depth(), PixelDepth::U16);
+        let eight = EncodeOptions { avif_depth: AvifDepth::Eight, ..options };
+        assert_eq!(eight.max_depth(), PixelDepth::U8);
+        let ramp = ImageBuffer::from_fn(64, 16, |x, _| Rgb([x as u16 * 1024, 32768, 65535 - x as u16 * 1024]));
+        for options in [&options, &eight] {
+            let mut file = Cursor::new(Vec::new());
+            encode_image(&DynamicImage::ImageRgb16(ramp.clone()), options, &mut file).unwrap();
+            assert_eq!(&file.get_ref()[4..8], b"ftyp");
+        }
+    }
+}
@@ -0,0 +1,291 @@
+use crate::{reproject, Buffer, Channel, CubemapError, Equirectangular, Face, Filter, PixelDepth, Projection, Rotation};
+use image::{DynamicImage, Pixel};
+use std::f32::consts::{FRAC_PI_4, PI};
+use std::fmt;
+use std::str::FromStr;
+
+/// Single-image layouts of the sphere: environment maps for engines that
+/// store probes in one 2D texture instead of six faces, and partial
+/// panoramas for print and backgrounds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EnvMapping {
+    /// The sphere folded onto an octahedron and flattened into a square: up
+    /// (+Y) in the centre with +X to the right and +Z (front) below, like the
+    /// up face, and the lower hemisphere folded out into the corners
+    #[default]
+    Octahedral,
+    /// Two paraboloid projections side by side, each a disc covering a
+    /// hemisphere: the front (+Z) on the left and the back on the right,
+    /// oriented like the front and back faces
+    DualParaboloid,
+    /// Central cylindrical: the full 360 degrees across, with the height
+    /// growing as the tangent of the latitude, so vertical lines stay
+    /// straight but the top and bottom stretch quickly
+    Cylindrical,
+    /// Mercator: like cylindrical, but conformal, so shapes keep their
+    /// proportions and the stretch towards the poles is gentler
+    Mercator,
+}
+
+impl EnvMapping {
+    pub const ALL: [EnvMapping; 4] =
+        [EnvMapping::Octahedral, EnvMapping::DualParaboloid, EnvMapping::Cylindrical, EnvMapping::Mercator];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EnvMapping::Octahedral => "octahedral",
+            EnvMapping::DualParaboloid => "dual-paraboloid",
+            EnvMapping::Cylindrical => "cylindrical",
+            EnvMapping::Mercator => "mercator",
+        }
+    }
+
+    /// Number of tiles side by side in the image
+    pub fn tiles(self) -> u32 {
+        match self {
+            EnvMapping::DualParaboloid => 2,
+            _ => 1,
+        }
+    }
+
+    /// Whether the vertical field of view applies
+    pub fn is_partial(self) -> bool {
+        matches!(self, EnvMapping::Cylindrical | EnvMapping::Mercator)
+    }
+
+    // Tile coordinate for a latitude, and back, before scaling to the field
+    // of view
+    fn stretch(self, latitude: f32) -> f32 {
+        match self {
+            EnvMapping::Mercator => (FRAC_PI_4 + latitude / 2.0).tan().ln(),
+            _ => latitude.tan(),
+        }
+    }
+
+    fn unstretch(self, t: f32) -> f32 {
+        match self {
+            EnvMapping::Mercator => t.sinh().atan(),
+            _ => t.atan(),
+        }
+    }
+}
+
+impl fmt::Display for EnvMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for EnvMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        EnvMapping::ALL
+            .into_iter()
+            .find(|mapping| mapping.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                format!("unknown mapping '{}' (expected octahedral, dual-paraboloid, cylindrical or mercator)", s)
+            })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EnvMapOptions {
+    pub mapping: EnvMapping,
+    /// Width of each tile of the map in pixels, border excluded; a partial
+    /// map's height follows from its field of view
+    pub size: u32,
+    /// Extra pixels around each tile continuing it past its edges, so GPU
+    /// filtering across them doesn't pick up the wrong side
+    pub border: u32,
+    /// Degrees each dual paraboloid reaches past 90 from its axis, so the
+    /// two overlap at the seam
+    pub overlap: f32,
+    /// Vertical field of view of cylindrical and Mercator maps in degrees,
+    /// centred on the horizon
+    pub fov: f32,
+    /// Applied to the sampling directions to level or re-center the panorama
+    pub rotation: Rotation,
+    pub filter: Filter,
+}
+
+impl Default for EnvMapOptions {
+    fn default() -> Self {
+        EnvMapOptions {
+            mapping: EnvMapping::Octahedral,
+            size: 512,
+            border: 0,
+            overlap: 0.0,
+            fov: 120.0,
+            rotation: Rotation::IDENTITY,
+            filter: Filter::Bilinear,
+        }
+    }
+}
+
+impl EnvMapOptions {
+    pub fn validate(&self) -> Result<(), CubemapError> {
+        if self.size == 0 {
+            return Err(CubemapError::Projection("map size must be at least 1".to_string()));
+        }
+        if self.border > self.size / 2 {
+            return Err(CubemapError::Projection(format!(
+                "border of {} pixels is more than half the map size {}",
+                self.border, self.size
+            )));
+        }
+        if !(0.0..=45.0).contains(&self.overlap) {
+            return Err(CubemapError::Projection(format!(
+                "overlap must be between 0 and 45 degrees, got {}",
+                self.overlap
+            )));
+        }
+        if !(self.fov > 0.0 && self.fov < 180.0) {
+            return Err(CubemapError::Projection(format!(
+                "vertical field of view must be between 0 and 180, got {}",
+                self.fov
+            )));
+        }
+        Ok(())
+    }
+
+    /// Width and height of each tile, border excluded.
+    pub fn tile_dimensions(&self) -> (u32, u32) {
+        match self.mapping.is_partial() {
+            true => (self.size, (self.size as f32 * self.half_extent() / PI).round().max(1.0) as u32),
+            false => (self.size, self.size),
+        }
+    }
+
+    /// Width and height of the rendered map, borders included.
+    pub fn dimensions(&self) -> (u32, u32) {
+        let (width, height) = self.tile_dimensions();
+        ((width + 2 * self.border) * self.mapping.tiles(), height + 2 * self.border)
+    }
+
+    /// Unit direction for coordinates (x, y) in [-1, 1] of tile `tile`.
+    /// Coordinates past the edges continue into the directions the map does
+    /// there, so a border of them filters seamlessly.
+    pub fn direction(&self, tile: u32, x: f32, y: f32) -> [f32; 3] {
+        match self.mapping {
+            EnvMapping::Octahedral => {
+                let (x, y) = fold_octahedral(x, y);
+                let up = 1.0 - x.abs() - y.abs();
+                let (x, z) = match up < 0.0 {
+                    true => ((1.0 - y.abs()).copysign(x), (1.0 - x.abs()).copysign(y)),
+                    false => (x, y),
+                };
+                normalize([x, up, z])
+            }
+            EnvMapping::DualParaboloid => {
+                // The disc's radius is tan(angle from the axis / 2)
+                let scale = ((90.0 + self.overlap).to_radians() / 2.0).tan();
+                let (x, y) = (x * scale, y * scale);
+                let basis = [Face::Front, Face::Back][tile as usize].basis();
+                let r2 = x * x + y * y;
+                std::array::from_fn(|i| {
+                    (2.0 * x * basis.right[i] + 2.0 * y * basis.down[i] + (1.0 - r2) * basis.center[i]) / (1.0 + r2)
+                })
+            }
+            mapping => {
+                let longitude = x * PI;
+                let latitude = mapping.unstretch(-y * self.half_extent());
+                let (sin, cos) = latitude.sin_cos();
+                [cos * longitude.sin(), sin, cos * longitude.cos()]
+            }
+        }
+    }
+
+    /// Inverse of `direction`: tile and coordinates in [-1, 1] for a
+    /// direction of any length, from the tile whose hemisphere holds it.
+    pub fn position(&self, dir: [f32; 3]) -> (u32, f32, f32) {
+        match self.mapping {
+            EnvMapping::Octahedral => {
+                let sum = dir[0].abs() + dir[1].abs() + dir[2].abs();
+                let (x, up, z) = (dir[0] / sum, dir[1] / sum, dir[2] / sum);
+                let (x, y) = match up < 0.0 {
+                    true => ((1.0 - z.abs()).copysign(x), (1.0 - x.abs()).copysign(z)),
+                    false => (x, z),
+                };
+                (0, x, y)
+            }
+            EnvMapping::DualParaboloid => {
+                let tile = (dir[2] < 0.0) as u32;
+                let basis = [Face::Front, Face::Back][tile as usize].basis();
+                let dot = |axis: [f32; 3]| axis[0] * dir[0] + axis[1] * dir[1] + axis[2] * dir[2];
+                let length = dot(dir).sqrt();
+                let scale = ((90.0 + self.overlap).to_radians() / 2.0).tan() * (length + dot(basis.center));
+                (tile, dot(basis.right) / scale, dot(basis.down) / scale)
+            }
+            mapping => {
+                let horizontal = (dir[0] * dir[0] + dir[2] * dir[2]).sqrt();
+                let latitude = dir[1].atan2(horizontal);
+                (0, dir[0].atan2(dir[2]) / PI, -mapping.stretch(latitude) / self.half_extent())
+            }
+        }
+    }
+
+    // Stretched latitude at the top edge of a partial map
+    fn half_extent(&self) -> f32 {
+        self.mapping.stretch(self.fov.to_radians() / 2.0)
+    }
+}
+
+// Each edge of the square meets itself mirrored about its midpoint, so a
+// point past one edge is the point as far inside it, mirrored
+fn fold_octahedral(mut x: f32, mut y: f32) -> (f32, f32) {
+    if x.abs() > 1.0 {
+        (x, y) = ((2.0 - x.abs()).copysign(x), -y);
+    }
+    if y.abs() > 1.0 {
+        (x, y) = (-x, (2.0 - y.abs()).copysign(y));
+    }
+    (x, y)
+}
+
+fn normalize(v: [f32; 3]) -> [f32; 3] {
+    let length = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+    v.map(|value| value / length)
+}
+
+// Pixels are located by their centres, which the tiles' coordinates put
+// half a pixel in from the edges
+impl Projection for EnvMapOptions {
+    fn uv_to_dir(&self, u: f32, v: f32) -> Option<[f32; 3]> {
+        let (width, height) = self.dimensions();
+        let (tile_width, tile_height) = self.tile_dimensions();
+        let stride = tile_width + 2 * self.border;
+        let (x, y) = (u * width as f32, v * height as f32);
+        let tile = ((x + 0.5).max(0.0) as u32 / stride).min(self.mapping.tiles() - 1);
+        let coord = |p: f32, length: u32| 2.0 * (p + 0.5 - self.border as f32) / length as f32 - 1.0;
+        Some(self.direction(tile, coord(x - (tile * stride) as f32, tile_width), coord(y, tile_height)))
+    }
+
+    fn dir_to_uv(&self, dir: [f32; 3]) -> Option<(f32, f32)> {
+        let (width, height) = self.dimensions();
+        let (tile_width, tile_height) = self.tile_dimensions();
+        let (tile, a, b) = self.position(dir);
+        let pixel = |t: f32, length: u32| (t + 1.0) / 2.0 * length as f32 + self.border as f32 - 0.5;
+        let (x, y) = (pixel(a, tile_width) + (tile * (tile_width + 2 * self.border)) as f32, pixel(b, tile_height));
+        (-0.5..=height as f32 - 0.5).contains(&y).then(|| (x / width as f32, y / height as f32))
+    }
+}
+
+/// Render an environment map of the equirect panorama `src`.
+pub fn render_envmap<P>(src: &Buffer<P>, options: &EnvMapOptions) -> Buffer<P>
+where
+    P: Pixel + Send + Sync,
+    P::Subpixel: Channel,
+{
+    reproject(src, &Equirectangular, options, options.dimensions(), &options.rotation, options.filter)
+}
+
+/// `render_envmap` at the panorama's own precision.
+pub fn render_envmap_dynamic(src: &DynamicImage, options: &EnvMapOptions) -> DynamicImage {
+    match src {
+        DynamicImage::ImageRgb8(img) => render_envmap(img, options).into(),
+        DynamicImage::ImageRgb16(img) => render_envmap(img, options).into(),
+        DynamicImage::ImageRgb32F(img) => render_envmap(img, options).into(),
+        img => render_envmap_dynamic(&PixelDepth::of(img).to_rgb(img.clone()), options),
+    }
+}
@@ -0,0 +1,93 @@
+use crate::sampler::bilerp;
+use crate::{spherical_to_direction, texel_center, Buffer, Channel, CubemapFaces, Face, PixelDepth};
+use image::{DynamicImage, Pixel};
+use rayon::prelude::*;
+
+/// Stitch a cubemap back into a 2:1 equirectangular panorama.
+pub fn cubemap_to_equirect<P>(cubemap: &CubemapFaces<Buffer<P>>, width: u32) -> Buffer<P>
+where
+    P: Pixel + Send + Sync,
+    P::Subpixel: Channel,
+{
+    let height = (width / 2).max(1);
+    let mut equirect: Buffer<P> = Buffer::new(width, height);
+    let channels = P::CHANNEL_COUNT as usize;
+
+    equirect
+        .par_chunks_mut(width as usize * channels)
+        .enumerate()
+        .for_each(|(y, row)| {
+            let v = y as f32 / height as f32;
+            for (x, pixel) in row.chunks_exact_mut(channels).enumerate() {
+                let u = x as f32 / width as f32;
+                let sample = sample_cubemap(cubemap, spherical_to_direction(u, v));
+                pixel.copy_from_slice(sample.channels());
+            }
+        });
+
+    equirect
+}
+
+/// `cubemap_to_equirect` for faces of any depth; the panorama has the depth
+/// of the deepest face.
+pub fn cubemap_to_equirect_dynamic(cubemap: &CubemapFaces<DynamicImage>, width: u32) -> DynamicImage {
+    fn convert<T>(cubemap: &CubemapFaces<DynamicImage>, convert: fn(&DynamicImage) -> T) -> CubemapFaces<T> {
+        CubemapFaces { size: cubemap.size, faces: cubemap.faces.iter().map(convert).collect() }
+    }
+    match cubemap.faces.iter().map(PixelDepth::of).max().unwrap_or(PixelDepth::U8) {
+        PixelDepth::U8 => cubemap_to_equirect(&convert(cubemap, DynamicImage::to_rgb8), width).into(),
+        PixelDepth::U16 => cubemap_to_equirect(&convert(cubemap, DynamicImage::to_rgb16), width).into(),
+        PixelDepth::F32 => cubemap_to_equirect(&convert(cubemap, DynamicImage::to_rgb32f), width).into(),
+    }
+}
+
+/// Bilinearly sample the cubemap in direction `dir`. Taps that fall off the
+/// edge of a face are fetched from the neighbouring face so there are no
+/// visible seams.
+pub fn sample_cubemap<P>(cubemap: &CubemapFaces<Buffer<P>>, dir: [f32; 3]) -> P
+where
+    P: Pixel,
+    P::Subpixel: Channel,
+{
+    let (face, a, b) = Face::from_direction(dir);
+    let size = cubemap.size as f32;
+
+    // Texel centres sit at (2i + 1) / size - 1
+    let x = (a + 1.0) * size / 2.0 - 0.5;
+    let y = (b + 1.0) * size / 2.0 - 0.5;
+    let x0 = x.floor();
+    let y0 = y.floor();
+    let fx = x - x0;
+    let fy = y - y0;
+    let (x0, y0) = (x0 as i64, y0 as i64);
+
+    let p00 = texel(cubemap, face, x0, y0);
+    let p10 = texel(cubemap, face, x0 + 1, y0);
+    let p01 = texel(cubemap, face, x0, y0 + 1);
+    let p11 = texel(cubemap, face, x0 + 1, y0 + 1);
+
+    let mut out = p00;
+    for (c, value) in out.channels_mut().iter_mut().enumerate() {
+        *value = bilerp(p00.channels()[c], p10.channels()[c], p01.channels()[c], p11.channels()[c], fx, fy);
+    }
+    out
+}
+
+fn texel<P: Pixel>(cubemap: &CubemapFaces<Buffer<P>>, face: Face, x: i64, y: i64) -> P {
+    let size = cubemap.size as i64;
+    if (0..size).contains(&x) && (0..size).contains(&y) {
+        return *cubemap.get(face).get_pixel(x as u32, y as u32);
+    }
+
+    // Off the face: continue the face plane and look up the texel on
+    // whichever face that direction actually lands on
+    let a = texel_center(x as f32, cubemap.size);
+    let b = texel_center(y as f32, cubemap.size);
+    let basis = face.basis();
+    let dir = std::array::from_fn(|i| basis.center[i] + a * basis.right[i] + b * basis.down[i]);
+    let (face, a, b) = Face::from_direction(dir);
+
+    let x = (((a + 1.0) * size as f32 / 2.0).floor() as i64).clamp(0, size - 1);
+    let y = (((b + 1.0) * size as f32 / 2.0).floor() as i64).clamp(0, size - 1);
+    *cubemap.get(face).get_pixel(x as u32, y as u32)
+}
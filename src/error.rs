@@ -0,0 +1,60 @@
+use image::ImageError;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// Errors from the library, split by the stage that failed so callers can
+/// tell bad input from bad parameters from an unwritable destination.
+#[derive(Debug)]
+pub enum CubemapError {
+    /// The input image could not be read or is in an unsupported format;
+    /// `path` is empty for input decoded from memory
+    Decode { path: PathBuf, source: ImageError },
+    /// Invalid size, face set or layout for the requested projection
+    Projection(String),
+    /// An image could not be encoded in the requested output format
+    Encode(ImageError),
+    /// Writing the output failed, e.g. the directory is not writable
+    Io(io::Error),
+}
+
+impl CubemapError {
+    // Encoders report write failures as ImageError too; keep those as Io
+    pub(crate) fn encode(err: ImageError) -> CubemapError {
+        match err {
+            ImageError::IoError(err) => CubemapError::Io(err),
+            err => CubemapError::Encode(err),
+        }
+    }
+}
+
+impl fmt::Display for CubemapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CubemapError::Decode { path, source } if path.as_os_str().is_empty() => {
+                write!(f, "failed to decode input: {}", source)
+            }
+            CubemapError::Decode { path, source } => write!(f, "failed to open {}: {}", path.display(), source),
+            CubemapError::Projection(message) => f.write_str(message),
+            CubemapError::Encode(err) => write!(f, "failed to encode image: {}", err),
+            CubemapError::Io(err) => write!(f, "failed to write output: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for CubemapError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CubemapError::Decode { source, .. } => Some(source),
+            CubemapError::Projection(_) => None,
+            CubemapError::Encode(err) => Some(err),
+            CubemapError::Io(err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for CubemapError {
+    fn from(err: io::Error) -> Self {
+        CubemapError::Io(err)
+    }
+}
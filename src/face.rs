@@ -0,0 +1,177 @@
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Face {
+    Right,
+    Left,
+    Up,
+    Down,
+    Front,
+    Back,
+}
+
+/// Orientation of a face in world space (+X right, +Y up, +Z front).
+/// `center` is the direction through the middle of the face, `right` and
+/// `down` are the directions of increasing image x and y.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FaceBasis {
+    pub center: [f32; 3],
+    pub right: [f32; 3],
+    pub down: [f32; 3],
+}
+
+impl FaceBasis {
+    /// Direction through face-plane coordinates (a, b); not normalized.
+    pub fn direction(&self, a: f32, b: f32) -> [f32; 3] {
+        std::array::from_fn(|i| self.center[i] + a * self.right[i] + b * self.down[i])
+    }
+}
+
+const BASES: [FaceBasis; 6] = [
+    // Right
+    FaceBasis { center: [1.0, 0.0, 0.0], right: [0.0, 0.0, -1.0], down: [0.0, -1.0, 0.0] },
+    // Left
+    FaceBasis { center: [-1.0, 0.0, 0.0], right: [0.0, 0.0, 1.0], down: [0.0, -1.0, 0.0] },
+    // Up
+    FaceBasis { center: [0.0, 1.0, 0.0], right: [1.0, 0.0, 0.0], down: [0.0, 0.0, 1.0] },
+    // Down
+    FaceBasis { center: [0.0, -1.0, 0.0], right: [1.0, 0.0, 0.0], down: [0.0, 0.0, -1.0] },
+    // Front
+    FaceBasis { center: [0.0, 0.0, 1.0], right: [1.0, 0.0, 0.0], down: [0.0, -1.0, 0.0] },
+    // Back
+    FaceBasis { center: [0.0, 0.0, -1.0], right: [-1.0, 0.0, 0.0], down: [0.0, -1.0, 0.0] },
+];
+
+impl Face {
+    pub const ALL: [Face; 6] = [Face::Right, Face::Left, Face::Up, Face::Down, Face::Front, Face::Back];
+
+    pub fn iter() -> impl Iterator<Item = Face> {
+        Self::ALL.into_iter()
+    }
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Face::Right => "right",
+            Face::Left => "left",
+            Face::Up => "up",
+            Face::Down => "down",
+            Face::Front => "front",
+            Face::Back => "back",
+        }
+    }
+
+    pub fn basis(self) -> &'static FaceBasis {
+        &BASES[self.index()]
+    }
+
+    /// Face hit by `dir` and the face-plane coordinates in [-1, 1] (same
+    /// convention as `cube_to_spherical`).
+    pub fn from_direction(dir: [f32; 3]) -> (Face, f32, f32) {
+        let [x, y, z] = dir;
+        let (ax, ay, az) = (x.abs(), y.abs(), z.abs());
+        let face = if ax >= ay && ax >= az {
+            if x >= 0.0 { Face::Right } else { Face::Left }
+        } else if ay >= az {
+            if y >= 0.0 { Face::Up } else { Face::Down }
+        } else if z >= 0.0 {
+            Face::Front
+        } else {
+            Face::Back
+        };
+
+        let basis = face.basis();
+        let dot = |a: [f32; 3]| a[0] * x + a[1] * y + a[2] * z;
+        let depth = dot(basis.center);
+        (face, dot(basis.right) / depth, dot(basis.down) / depth)
+    }
+}
+
+/// How face-plane coordinates are spread over the face.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CubeProjection {
+    /// Gnomonic: pixels are uniform on the face plane, so they bunch up
+    /// towards the edges when measured in angle
+    #[default]
+    Standard,
+    /// Equi-angular (EAC): pixels are uniform in angle across each face
+    EquiAngular,
+}
+
+impl CubeProjection {
+    pub const ALL: [CubeProjection; 2] = [CubeProjection::Standard, CubeProjection::EquiAngular];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CubeProjection::Standard => "standard",
+            CubeProjection::EquiAngular => "eac",
+        }
+    }
+
+    /// Face-plane coordinate for a pixel coordinate in [-1, 1]
+    #[inline]
+    pub fn warp(self, t: f32) -> f32 {
+        match self {
+            CubeProjection::Standard => t,
+            CubeProjection::EquiAngular => (t * std::f32::consts::FRAC_PI_4).tan(),
+        }
+    }
+
+    /// Inverse of `warp`
+    #[inline]
+    pub fn unwarp(self, t: f32) -> f32 {
+        match self {
+            CubeProjection::Standard => t,
+            CubeProjection::EquiAngular => t.atan() / std::f32::consts::FRAC_PI_4,
+        }
+    }
+
+    /// d(warp)/dt, the local stretch of the face plane per pixel
+    #[inline]
+    pub fn stretch(self, t: f32) -> f32 {
+        match self {
+            CubeProjection::Standard => 1.0,
+            CubeProjection::EquiAngular => {
+                let cos = (t * std::f32::consts::FRAC_PI_4).cos();
+                std::f32::consts::FRAC_PI_4 / (cos * cos)
+            }
+        }
+    }
+}
+
+impl fmt::Display for CubeProjection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for CubeProjection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        CubeProjection::ALL
+            .into_iter()
+            .find(|projection| projection.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown projection '{}' (expected standard or eac)", s))
+    }
+}
+
+impl fmt::Display for Face {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Face {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Face::iter()
+            .find(|face| face.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown face '{}' (expected one of right, left, up, down, front, back)", s))
+    }
+}
@@ -0,0 +1,101 @@
+use crate::{CubemapError, PanoCrop, Rotation};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Read the GPano crop of a partial panorama from the XMP packet embedded in
+/// `path`. `None` when the file carries no (complete) GPano crop.
+pub fn read_gpano(path: &Path) -> Result<Option<PanoCrop>, CubemapError> {
+    let bytes = std::fs::read(path)?;
+    Ok(find_gpano(&bytes))
+}
+
+/// `read_gpano` for an encoded image already in memory.
+pub fn find_gpano(bytes: &[u8]) -> Option<PanoCrop> {
+    find_xmp(bytes).and_then(parse_gpano)
+}
+
+/// GPano crop fields of an XMP packet, in attribute or element form.
+pub fn parse_gpano(xmp: &str) -> Option<PanoCrop> {
+    let crop = PanoCrop {
+        full_width: field(xmp, "FullPanoWidthPixels")?,
+        full_height: field(xmp, "FullPanoHeightPixels")?,
+        left: field(xmp, "CroppedAreaLeftPixels")?,
+        top: field(xmp, "CroppedAreaTopPixels")?,
+        width: field(xmp, "CroppedAreaImageWidthPixels")?,
+        height: field(xmp, "CroppedAreaImageHeightPixels")?,
+    };
+    let fits = crop.width > 0
+        && crop.height > 0
+        && crop.width <= crop.full_width
+        && crop.top + crop.height <= crop.full_height;
+    fits.then_some(crop)
+}
+
+/// Which way the camera faced, from the GPano `Pose*Degrees` fields: the
+/// compass heading of the image centre, its pitch above the horizon and the
+/// roll that turns the horizon counterclockwise in the image.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct PanoPose {
+    pub heading: f32,
+    pub pitch: f32,
+    pub roll: f32,
+}
+
+impl PanoPose {
+    /// Rotation for `CubemapOptions::rotation` that undoes the pose, so the
+    /// front face looks north and the horizon is level.
+    pub fn rotation(&self) -> Rotation {
+        Rotation::from_euler_degrees(self.heading, self.pitch, self.roll).inverse()
+    }
+}
+
+/// Read the GPano pose from the XMP packet embedded in `path`. `None` when
+/// the file carries none of the pose fields.
+pub fn read_gpano_pose(path: &Path) -> Result<Option<PanoPose>, CubemapError> {
+    let bytes = std::fs::read(path)?;
+    Ok(find_gpano_pose(&bytes))
+}
+
+/// `read_gpano_pose` for an encoded image already in memory.
+pub fn find_gpano_pose(bytes: &[u8]) -> Option<PanoPose> {
+    find_xmp(bytes).and_then(parse_gpano_pose)
+}
+
+/// GPano pose fields of an XMP packet; missing ones count as 0.
+pub fn parse_gpano_pose(xmp: &str) -> Option<PanoPose> {
+    let [heading, pitch, roll] = ["PoseHeadingDegrees", "PosePitchDegrees", "PoseRollDegrees"]
+        .map(|name| field::<f32>(xmp, name).filter(|value| value.is_finite()));
+    if heading.is_none() && pitch.is_none() && roll.is_none() {
+        return None;
+    }
+    Some(PanoPose { heading: heading.unwrap_or(0.0), pitch: pitch.unwrap_or(0.0), roll: roll.unwrap_or(0.0) })
+}
+
+// The XMP packet, wherever the container stores it (JPEG APP1, PNG iTXt,
+// TIFF tag): it is always plain text between these markers
+fn find_xmp(bytes: &[u8]) -> Option<&str> {
+    let start = find(bytes, b"<x:xmpmeta")?;
+    let end = start + find(&bytes[start..], b"</x:xmpmeta>")? + b"</x:xmpmeta>".len();
+    std::str::from_utf8(&bytes[start..end]).ok()
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+// `GPano:name="123"` (or single quotes) or `<GPano:name>123</GPano:name>`
+fn field<T: FromStr>(xmp: &str, name: &str) -> Option<T> {
+    let tag = format!("GPano:{}", name);
+    let mut rest = xmp;
+    while let Some(index) = rest.find(&tag) {
+        rest = &rest[index + tag.len()..];
+        let value = match rest.strip_prefix('=') {
+            Some(attribute) => attribute.get(1..).and_then(|quoted| quoted.split(['"', '\'']).next()),
+            None => rest.strip_prefix('>').and_then(|element| element.split('<').next()),
+        };
+        if let Some(value) = value.and_then(|value| value.trim().parse().ok()) {
+            return Some(value);
+        }
+    }
+    None
+}
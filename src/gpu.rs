@@ -0,0 +1,328 @@
+use crate::{
+    CubeProjection, CubemapFaces, CubemapOptions, Face, FaceBasis, Filter, InputProjection, PixelDepth, PixelType,
+};
+use image::{DynamicImage, Rgb32FImage};
+use std::sync::mpsc;
+
+const SHADER: &str = r#"
+struct Params {
+    center: vec4<f32>,
+    right: vec4<f32>,
+    down: vec4<f32>,
+    size: u32,
+    row_offset: u32,
+    rows: u32,
+    filter_mode: u32,
+    eac: u32,
+}
+
+@group(0) @binding(0) var src: texture_2d<f32>;
+@group(0) @binding(1) var<storage, read_write> dst: array<vec4<f32>>;
+@group(0) @binding(2) var<uniform> params: Params;
+
+const PI: f32 = 3.14159265358979;
+
+fn texel(x: i32, y: i32) -> vec4<f32> {
+    let dims = vec2<i32>(textureDimensions(src));
+    return textureLoad(src, vec2<i32>(((x % dims.x) + dims.x) % dims.x, clamp(y, 0, dims.y - 1)), 0);
+}
+
+fn warp(t: f32) -> f32 {
+    if (params.eac != 0u) {
+        return tan(t * PI / 4.0);
+    }
+    return t;
+}
+
+@compute @workgroup_size(8, 8)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    if (id.x >= params.size || id.y >= params.rows) {
+        return;
+    }
+    // Texel centres, as face_coord on the CPU
+    let a = warp((2.0 * f32(id.x) + 1.0) / f32(params.size) - 1.0);
+    let b = warp((2.0 * f32(id.y + params.row_offset) + 1.0) / f32(params.size) - 1.0);
+    let dir = params.center.xyz + a * params.right.xyz + b * params.down.xyz;
+
+    // Same mapping and wrapping as cube_to_spherical + sample on the CPU
+    let u = atan2(dir.x, dir.z) / (2.0 * PI) + 0.5;
+    let v = acos(clamp(dir.y / length(dir), -1.0, 1.0)) / PI;
+    let dims = vec2<f32>(textureDimensions(src));
+    let p = vec2<f32>(u, v) * dims;
+
+    var color: vec4<f32>;
+    if (params.filter_mode == 0u) {
+        color = texel(i32(floor(p.x + 0.5)), i32(floor(p.y + 0.5)));
+    } else {
+        let p0 = floor(p);
+        let f = p - p0;
+        let x = i32(p0.x);
+        let y = i32(p0.y);
+        let top = mix(texel(x, y), texel(x + 1, y), f.x);
+        let bottom = mix(texel(x, y + 1), texel(x + 1, y + 1), f.x);
+        color = mix(top, bottom, f.y);
+    }
+    dst[id.y * params.size + id.x] = color;
+}
+"#;
+
+/// Compute-shader implementation of `equirect_to_cubemap_dynamic`. Create it
+/// once and reuse it across sizes; device setup is the expensive part.
+pub struct GpuContext {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    pipeline: wgpu::ComputePipeline,
+    adapter_name: String,
+}
+
+impl GpuContext {
+    /// Pick the default adapter, or `None` if the system has no usable GPU.
+    pub fn new() -> Option<GpuContext> {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::new_without_display_handle_from_env());
+        let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            ..Default::default()
+        }))
+        .ok()?;
+        let (device, queue) = pollster::block_on(adapter.request_device(&wgpu::DeviceDescriptor {
+            label: Some("rust-cube"),
+            required_limits: adapter.limits(),
+            ..Default::default()
+        }))
+        .ok()?;
+
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("equirect_to_cube"),
+            source: wgpu::ShaderSource::Wgsl(SHADER.into()),
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("equirect_to_cube"),
+            layout: None,
+            module: &module,
+            entry_point: Some("main"),
+            compilation_options: Default::default(),
+            cache: None,
+        });
+
+        Some(GpuContext { device, queue, pipeline, adapter_name: adapter.get_info().name })
+    }
+
+    pub fn adapter_name(&self) -> &str {
+        &self.adapter_name
+    }
+
+    /// Render all six faces on the GPU. Returns `None` for what the shader
+    /// doesn't implement (bicubic/Lanczos filters, supersampling, color
+    /// grading, normal maps, offset cubemaps, inputs other than equirect,
+    /// alpha, gray) or sources larger than the device's texture limit;
+    /// callers fall back to the CPU.
+    pub fn render(&self, src: &DynamicImage, options: &CubemapOptions) -> Option<CubemapFaces<DynamicImage>> {
+        let limits = self.device.limits();
+        let filter = filter_mode(src, options, limits.max_texture_dimension_2d)?;
+
+        let texture = self.upload(src);
+        let view = texture.create_view(&Default::default());
+        let size = options.size;
+
+        // Faces are rendered in bands of rows that fit one storage binding
+        let row_bytes = size as u64 * 16;
+        let max_binding = limits.max_storage_buffer_binding_size.min(limits.max_buffer_size);
+        let band_rows = (max_binding / row_bytes).clamp(1, size as u64) as u32;
+        let band_bytes = band_rows as u64 * row_bytes;
+
+        let output = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("face_band"),
+            size: band_bytes,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let readback = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("readback"),
+            size: band_bytes,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let params = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("params"),
+            size: 80,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &self.pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry { binding: 0, resource: wgpu::BindingResource::TextureView(&view) },
+                wgpu::BindGroupEntry { binding: 1, resource: output.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 2, resource: params.as_entire_binding() },
+            ],
+        });
+
+        let mut faces = Vec::with_capacity(6);
+        for face in Face::ALL {
+            let basis = options.rotation.apply_basis(face.basis());
+            let mut data = Vec::with_capacity(size as usize * size as usize * 3);
+            for row_offset in (0..size).step_by(band_rows as usize) {
+                let rows = band_rows.min(size - row_offset);
+                let uniform = face_params(&basis, size, row_offset, rows, filter, options.projection);
+                self.queue.write_buffer(&params, 0, &uniform);
+
+                let mut encoder = self.device.create_command_encoder(&Default::default());
+                {
+                    let mut pass = encoder.begin_compute_pass(&Default::default());
+                    pass.set_pipeline(&self.pipeline);
+                    pass.set_bind_group(0, &bind_group, &[]);
+                    pass.dispatch_workgroups(size.div_ceil(8), rows.div_ceil(8), 1);
+                }
+                let bytes = rows as u64 * row_bytes;
+                encoder.copy_buffer_to_buffer(&output, 0, &readback, 0, bytes);
+                self.queue.submit([encoder.finish()]);
+
+                let slice = readback.slice(..bytes);
+                let (tx, rx) = mpsc::channel();
+                slice.map_async(wgpu::MapMode::Read, move |result| {
+                    let _ = tx.send(result);
+                });
+                self.device.poll(wgpu::PollType::wait_indefinitely()).ok()?;
+                rx.recv().ok()?.ok()?;
+                {
+                    let mapped = slice.get_mapped_range().ok()?;
+                    // RGBA in the shader, RGB in the pipeline
+                    for texel in mapped.chunks_exact(16) {
+                        for channel in texel[..12].chunks_exact(4) {
+                            data.push(f32::from_le_bytes(channel.try_into().unwrap()));
+                        }
+                    }
+                }
+                readback.unmap();
+                if let Some(progress) = &options.progress {
+                    progress.report(face, row_offset + rows, size);
+                }
+            }
+            let img = Rgb32FImage::from_raw(size, size, data)?;
+            faces.push(options.post_process(face, PixelDepth::of(src).to_rgb(DynamicImage::ImageRgb32F(img))));
+        }
+
+        Some(CubemapFaces { size, faces })
+    }
+
+    fn upload(&self, src: &DynamicImage) -> wgpu::Texture {
+        let (width, height) = (src.width(), src.height());
+        let extent = wgpu::Extent3d { width, height, depth_or_array_layers: 1 };
+        let texture = self.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("equirect"),
+            size: extent,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba32Float,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        let bytes: Vec<u8> = src.to_rgba32f().into_raw().into_iter().flat_map(f32::to_le_bytes).collect();
+        self.queue.write_texture(
+            texture.as_image_copy(),
+            &bytes,
+            wgpu::TexelCopyBufferLayout { offset: 0, bytes_per_row: Some(width * 16), rows_per_image: Some(height) },
+            extent,
+        );
+        texture
+    }
+}
+
+// The shader's filter_mode for rendering `src` with `options`, or None
+// where it falls short and the CPU renders instead
+fn filter_mode(src: &DynamicImage, options: &CubemapOptions, max_texture: u32) -> Option<u32> {
+    let filter = match options.filter {
+        Filter::Nearest => 0,
+        Filter::Bilinear => 1,
+        Filter::Bicubic | Filter::Lanczos3 => return None,
+    };
+    let unsupported = options.ssaa > 1
+        || options.linear
+        || options.bleed > 0
+        || options.offset > 0.0
+        || !options.grade.is_identity()
+        || options.pixel_type != PixelType::Color
+        || options.input != InputProjection::Equirect
+        || src.color().has_alpha()
+        || src.color().channel_count() < 3
+        || !options.fill.is_opaque()
+        || src.width().max(src.height()) > max_texture;
+    (!unsupported).then_some(filter)
+}
+
+// Params uniform, laid out as in the shader
+fn face_params(
+    basis: &FaceBasis,
+    size: u32,
+    row_offset: u32,
+    rows: u32,
+    filter: u32,
+    projection: CubeProjection,
+) -> Vec<u8> {
+    let mut out = Vec::with_capacity(80);
+    for v in [basis.center, basis.right, basis.down] {
+        for c in [v[0], v[1], v[2], 0.0] {
+            out.extend(f32::to_le_bytes(c));
+        }
+    }
+    let eac = (projection == CubeProjection::EquiAngular) as u32;
+    for value in [size, row_offset, rows, filter, eac, 0, 0, 0] {
+        out.extend(value.to_le_bytes());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Fill, Rotation};
+    use image::{RgbImage, RgbaImage};
+
+    #[test]
+    fn unsupported_options_fall_back() {
+        let rgb = DynamicImage::ImageRgb8(RgbImage::new(64, 32));
+        let options = CubemapOptions { filter: Filter::Nearest, ..CubemapOptions::default() };
+        assert_eq!(filter_mode(&rgb, &options, 8192), Some(0));
+        let options = CubemapOptions { filter: Filter::Bilinear, ..options };
+        assert_eq!(filter_mode(&rgb, &options, 8192), Some(1));
+
+        let unsupported = [
+            CubemapOptions { filter: Filter::Lanczos3, ..options.clone() },
+            CubemapOptions { ssaa: 2, ..options.clone() },
+            CubemapOptions { linear: true, ..options.clone() },
+            CubemapOptions { offset: 0.5, ..options.clone() },
+            CubemapOptions { pixel_type: PixelType::Normal, ..options.clone() },
+            CubemapOptions { fill: Fill::TRANSPARENT, ..options.clone() },
+        ];
+        for options in &unsupported {
+            assert_eq!(filter_mode(&rgb, options, 8192), None, "{:?}", options);
+        }
+        let rgba = DynamicImage::ImageRgba8(RgbaImage::new(64, 32));
+        let gray = DynamicImage::ImageLuma8(image::GrayImage::new(64, 32));
+        assert_eq!(filter_mode(&rgba, &options, 8192), None);
+        assert_eq!(filter_mode(&gray, &options, 8192), None);
+        // Wider than the device's textures
+        assert_eq!(filter_mode(&rgb, &options, 32), None);
+        // And without a device nothing is attempted
+        if let Some(gpu) = GpuContext::new() {
+            assert!(gpu.render(&rgba, &options).is_none());
+        }
+    }
+
+    #[test]
+    fn params_match_the_shader_layout() {
+        let basis = Rotation::default().apply_basis(Face::Up.basis());
+        let params = face_params(&basis, 512, 64, 32, 1, CubeProjection::EquiAngular);
+        // Three vec4s, five u32s, padded to the struct's 16-byte alignment
+        assert_eq!(params.len(), 80);
+        let f32_at = |offset: usize| f32::from_le_bytes(params[offset..offset + 4].try_into().unwrap());
+        let u32_at = |offset: usize| u32::from_le_bytes(params[offset..offset + 4].try_into().unwrap());
+        assert_eq!([f32_at(0), f32_at(4), f32_at(8), f32_at(12)], [0.0, 1.0, 0.0, 0.0]);
+        assert_eq!([f32_at(16), f32_at(20), f32_at(24)], basis.right);
+        assert_eq!([f32_at(32), f32_at(36), f32_at(40)], basis.down);
+        assert_eq!([u32_at(48), u32_at(52), u32_at(56), u32_at(60), u32_at(64)], [512, 64, 32, 1, 1]);
+        assert_eq!(params[68..], [0; 12]);
+    }
+}
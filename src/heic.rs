@@ -0,0 +1,84 @@
+use image::error::{DecodingError, ImageFormatHint};
+use image::{DynamicImage, ImageError, ImageResult};
+use std::path::Path;
+
+/// Whether `path` names an HEIC/HEIF file by its extension.
+pub(crate) fn is_heif_path(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ["heic", "heif", "hif"].iter().any(|known| ext.eq_ignore_ascii_case(known)))
+}
+
+/// Whether `bytes` start with an ISO BMFF `ftyp` box naming an HEIF brand.
+pub(crate) fn is_heif(bytes: &[u8]) -> bool {
+    bytes.get(4..8) == Some(b"ftyp")
+        && matches!(
+            bytes.get(8..12),
+            Some(b"heic" | b"heix" | b"heim" | b"heis" | b"hevc" | b"hevx" | b"mif1" | b"msf1")
+        )
+}
+
+fn hint() -> ImageFormatHint {
+    ImageFormatHint::Name("HEIF".to_string())
+}
+
+pub(crate) fn load_heif(path: &Path) -> ImageResult<DynamicImage> {
+    decode_heif(&std::fs::read(path).map_err(ImageError::IoError)?)
+}
+
+/// The primary image, rotated and mirrored as the file asks; deeper than
+/// 8-bit (10- and 12-bit HEVC) decodes to 16-bit.
+#[cfg(feature = "heic")]
+pub(crate) fn decode_heif(bytes: &[u8]) -> ImageResult<DynamicImage> {
+    use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+    let error = |err: libheif_rs::HeifError| ImageError::Decoding(DecodingError::new(hint(), err));
+    let context = HeifContext::read_from_bytes(bytes).map_err(error)?;
+    let handle = context.primary_image_handle().map_err(error)?;
+    let deep = handle.luma_bits_per_pixel() > 8;
+    let chroma = match (deep, handle.has_alpha_channel()) {
+        (false, false) => RgbChroma::Rgb,
+        (false, true) => RgbChroma::Rgba,
+        (true, false) => RgbChroma::HdrRgbLe,
+        (true, true) => RgbChroma::HdrRgbaLe,
+    };
+    let image = LibHeif::new().decode(&handle, ColorSpace::Rgb(chroma), None).map_err(error)?;
+    let planes = image.planes();
+    let plane = planes.interleaved.ok_or_else(|| ImageError::Decoding(DecodingError::new(hint(), "no RGB plane")))?;
+    let (width, height) = (plane.width, plane.height);
+    let channels = if handle.has_alpha_channel() { 4 } else { 3 };
+    let row_len = width as usize * channels * if deep { 2 } else { 1 };
+    // Rows are padded out to `stride`
+    let rows = plane.data.chunks(plane.stride).take(height as usize).map(|row| &row[..row_len]);
+
+    let image = if deep {
+        // Scale 10 or 12 significant bits up to the full 16
+        let bits = plane.bits_per_pixel as u32;
+        let widen = |sample: u16| {
+            let sample = sample as u32;
+            (sample << (16 - bits) | sample >> (2 * bits).saturating_sub(16)) as u16
+        };
+        let data: Vec<u16> = rows
+            .flat_map(|row| row.chunks_exact(2).map(|pair| widen(u16::from_le_bytes([pair[0], pair[1]]))))
+            .collect();
+        if channels == 4 {
+            image::ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba16)
+        } else {
+            image::ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb16)
+        }
+    } else {
+        let data: Vec<u8> = rows.flatten().copied().collect();
+        if channels == 4 {
+            image::ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba8)
+        } else {
+            image::ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb8)
+        }
+    };
+    image.ok_or_else(|| ImageError::Decoding(DecodingError::new(hint(), "short image plane")))
+}
+
+#[cfg(not(feature = "heic"))]
+pub(crate) fn decode_heif(_bytes: &[u8]) -> ImageResult<DynamicImage> {
+    let message = "HEIC input needs a build with the heic feature";
+    Err(ImageError::Decoding(DecodingError::new(hint(), message)))
+}
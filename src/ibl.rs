@@ -0,0 +1,298 @@
+use crate::{
+    pixel, sample_cubemap, texel_center, Buffer, CubeProjection, CubemapFaces, Face, PixelDepth, SphericalHarmonics,
+};
+use image::{DynamicImage, Rgb};
+use rayon::prelude::*;
+use std::f32::consts::PI;
+
+/// Settings for `prefilter_specular`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpecularOptions {
+    /// GGX samples per output texel
+    pub samples: u32,
+    /// Mip levels to write; defaults to halving down to 8x8 faces
+    pub levels: Option<u32>,
+}
+
+impl Default for SpecularOptions {
+    fn default() -> Self {
+        SpecularOptions { samples: 128, levels: None }
+    }
+}
+
+impl SpecularOptions {
+    /// Number of levels for a cubemap of `size`, at most a full mip chain.
+    pub fn level_count(&self, size: u32) -> u32 {
+        let full = size.max(1).ilog2() + 1;
+        self.levels.unwrap_or(full.saturating_sub(3)).clamp(1, full)
+    }
+}
+
+// One GGX sample in tangent space (+Z along the normal): the light
+// direction, its cosine weight and the source mip level to read it from
+struct Tap {
+    light: [f32; 3],
+    weight: f32,
+    lod: f32,
+}
+
+/// Prefilter a linear cubemap for split-sum image-based lighting. Level `i`
+/// is `cubemap.size >> i` texels across and convolved with the GGX lobe of
+/// perceptual roughness `i / (levels - 1)`, the mapping engines use to pick a
+/// level for a roughness; level 0 is the input unchanged.
+pub fn prefilter_specular(
+    cubemap: &CubemapFaces<Buffer<Rgb<f32>>>,
+    options: &SpecularOptions,
+) -> Vec<CubemapFaces<Buffer<Rgb<f32>>>> {
+    let count = options.level_count(cubemap.size);
+
+    // Box-filtered copies of the source: samples that each stand for many
+    // texels read a smaller one, which keeps bright spots from sparkling
+    let mut source = vec![cubemap.clone()];
+    while let Some(last) = source.last().filter(|level| level.size > 1) {
+        let next = last.downsample(last.size / 2);
+        source.push(next);
+    }
+
+    let mut levels = vec![cubemap.clone()];
+    for level in 1..count {
+        let roughness = level as f32 / (count - 1) as f32;
+        let size = (cubemap.size >> level).max(1);
+        let taps = ggx_taps(roughness, options.samples.max(1), cubemap.size);
+        levels.push(convolve(&source, size, &taps));
+    }
+    levels
+}
+
+/// `prefilter_specular` for faces of any depth. 8 and 16-bit faces are
+/// convolved in linear light and come back at their own depth.
+pub fn prefilter_specular_dynamic(
+    cubemap: &CubemapFaces<DynamicImage>,
+    options: &SpecularOptions,
+) -> Vec<CubemapFaces<DynamicImage>> {
+    let depth = cubemap.faces.iter().map(PixelDepth::of).max().unwrap_or(PixelDepth::U8);
+    let linear = CubemapFaces { size: cubemap.size, faces: cubemap.faces.iter().map(pixel::linearize).collect() };
+    prefilter_specular(&linear, options)
+        .into_iter()
+        .map(|level| level.map(|_, face| pixel::delinearize(face, depth)))
+        .collect()
+}
+
+/// Diffuse irradiance cubemap of `size` for an environment with radiance
+/// `sh`: each texel is the light a white Lambertian surface facing that way
+/// reflects. Nine coefficients capture the cosine-convolved signal to within
+/// a few percent, so this is far cheaper than convolving the faces.
+pub fn irradiance_cubemap(
+    sh: &SphericalHarmonics,
+    size: u32,
+    projection: CubeProjection,
+) -> CubemapFaces<Buffer<Rgb<f32>>> {
+    let irradiance = sh.irradiance();
+    let faces = Face::ALL
+        .par_iter()
+        .map(|&face| {
+            let basis = face.basis();
+            Buffer::from_fn(size, size, |x, y| {
+                let a = projection.warp(texel_center(x as f32, size));
+                let b = projection.warp(texel_center(y as f32, size));
+                // Ringing can dip below zero opposite a strong light
+                Rgb(irradiance.eval(basis.direction(a, b)).map(|c| c.max(0.0)))
+            })
+        })
+        .collect();
+    CubemapFaces { size, faces }
+}
+
+/// `irradiance_cubemap` at `depth`, sRGB encoded for 8 and 16-bit.
+pub fn irradiance_cubemap_dynamic(
+    sh: &SphericalHarmonics,
+    size: u32,
+    projection: CubeProjection,
+    depth: PixelDepth,
+) -> CubemapFaces<DynamicImage> {
+    irradiance_cubemap(sh, size, projection).map(|_, face| pixel::delinearize(face, depth))
+}
+
+// Importance-sample the GGX distribution with the view along the normal
+// (Karis, "Real Shading in Unreal Engine 4"); the mip level per sample
+// follows the solid angle it covers (Colbert and Krivanek, GPU Gems 3)
+fn ggx_taps(roughness: f32, samples: u32, base_size: u32) -> Vec<Tap> {
+    let alpha = roughness * roughness;
+    let alpha2 = alpha * alpha;
+    let texel_solid_angle = 4.0 * PI / (6.0 * base_size as f32 * base_size as f32);
+    // Below 8x8 the seams take up most of a face and the edge taps lose
+    // energy, so blur no further than that
+    let max_lod = base_size.max(1).ilog2().saturating_sub(3) as f32;
+
+    (0..samples)
+        .filter_map(|i| {
+            let (xi1, xi2) = hammersley(i, samples);
+            let phi = 2.0 * PI * xi1;
+            let cos_theta = ((1.0 - xi2) / (1.0 + (alpha2 - 1.0) * xi2)).sqrt();
+            let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+            let half = [sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta];
+
+            // Reflect the view (= normal) about the half vector
+            let light = [2.0 * cos_theta * half[0], 2.0 * cos_theta * half[1], 2.0 * cos_theta * cos_theta - 1.0];
+            let n_dot_l = light[2];
+            if n_dot_l <= 0.0 {
+                return None;
+            }
+
+            // pdf = D * NdotH / (4 * VdotH), and NdotH = VdotH here
+            let denom = cos_theta * cos_theta * (alpha2 - 1.0) + 1.0;
+            let pdf = alpha2 / (PI * denom * denom) / 4.0;
+            let sample_solid_angle = 1.0 / (samples as f32 * pdf);
+            let lod = if roughness == 0.0 { 0.0 } else { 0.5 * (sample_solid_angle / texel_solid_angle).log2() + 1.0 };
+            Some(Tap { light, weight: n_dot_l, lod: lod.clamp(0.0, max_lod) })
+        })
+        .collect()
+}
+
+fn convolve(
+    source: &[CubemapFaces<Buffer<Rgb<f32>>>],
+    size: u32,
+    taps: &[Tap],
+) -> CubemapFaces<Buffer<Rgb<f32>>> {
+    let faces = Face::ALL
+        .par_iter()
+        .map(|&face| {
+            let basis = face.basis();
+            let mut out: Buffer<Rgb<f32>> = Buffer::new(size, size);
+            out.par_chunks_mut(size as usize * 3).enumerate().for_each(|(y, row)| {
+                let b = texel_center(y as f32, size);
+                for (x, pixel) in row.chunks_exact_mut(3).enumerate() {
+                    let a = texel_center(x as f32, size);
+                    pixel.copy_from_slice(&integrate(source, normalize(basis.direction(a, b)), taps));
+                }
+            });
+            out
+        })
+        .collect();
+    CubemapFaces { size, faces }
+}
+
+// Cosine-weighted average of the taps rotated onto `normal`
+fn integrate(source: &[CubemapFaces<Buffer<Rgb<f32>>>], normal: [f32; 3], taps: &[Tap]) -> [f32; 3] {
+    let up = if normal[1].abs() < 0.999 { [0.0, 1.0, 0.0] } else { [1.0, 0.0, 0.0] };
+    let tangent = normalize(cross(up, normal));
+    let bitangent = cross(normal, tangent);
+
+    let mut sum = [0.0f32; 3];
+    let mut total = 0.0;
+    for tap in taps {
+        let [lx, ly, lz] = tap.light;
+        let dir: [f32; 3] = std::array::from_fn(|i| tangent[i] * lx + bitangent[i] * ly + normal[i] * lz);
+        let value = sample_lod(source, dir, tap.lod);
+        for (s, v) in sum.iter_mut().zip(value) {
+            *s += v * tap.weight;
+        }
+        total += tap.weight;
+    }
+    if total > 0.0 {
+        sum.map(|s| s / total)
+    } else {
+        sample_lod(source, normal, 0.0)
+    }
+}
+
+// Blend of the two source levels around `lod`
+fn sample_lod(source: &[CubemapFaces<Buffer<Rgb<f32>>>], dir: [f32; 3], lod: f32) -> [f32; 3] {
+    let lower = (lod.floor() as usize).min(source.len() - 1);
+    let upper = (lower + 1).min(source.len() - 1);
+    let t = lod - lower as f32;
+    let a = sample_level(source, lower, dir);
+    if upper == lower || t <= 0.0 {
+        return a;
+    }
+    let b = sample_level(source, upper, dir);
+    std::array::from_fn(|i| a[i] * (1.0 - t) + b[i] * t)
+}
+
+// Level `k` averages blocks of 2^k x 2^k base texels, and with texels
+// addressed by their centres each sits over the centre of its block, so
+// every level is sampled along the same direction
+fn sample_level(source: &[CubemapFaces<Buffer<Rgb<f32>>>], k: usize, dir: [f32; 3]) -> [f32; 3] {
+    sample_cubemap(&source[k], dir).0
+}
+
+// Low-discrepancy point i of n in the unit square
+fn hammersley(i: u32, n: u32) -> (f32, f32) {
+    (i as f32 / n as f32, i.reverse_bits() as f32 / (1u64 << 32) as f32)
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+fn normalize(v: [f32; 3]) -> [f32; 3] {
+    let len = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+    v.map(|c| c / len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn constant(size: u32, value: [f32; 3]) -> CubemapFaces<Buffer<Rgb<f32>>> {
+        CubemapFaces { size, faces: (0..6).map(|_| Buffer::from_pixel(size, size, Rgb(value))).collect() }
+    }
+
+    #[test]
+    fn constant_environments_stay_constant() {
+        let value = [0.5, 1.0, 4.0];
+        let options = SpecularOptions { samples: 64, levels: Some(6) };
+        let levels = prefilter_specular(&constant(32, value), &options);
+        assert_eq!(levels.iter().map(|level| level.size).collect::<Vec<_>>(), [32, 16, 8, 4, 2, 1]);
+        for (i, level) in levels.iter().enumerate() {
+            for face in &level.faces {
+                assert_eq!(face.dimensions(), (level.size, level.size));
+                for pixel in face.pixels() {
+                    let close = pixel.0.iter().zip(value).all(|(a, b)| (a - b).abs() < 1e-4 * b);
+                    assert!(close, "level {}: {:?}", i, pixel);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn smaller_levels_line_up() {
+        // A ramp across every face survives box filtering unchanged at the
+        // texel centres, so each level reads the same value in the interior
+        let ramp = Buffer::from_fn(32, 32, |x, _| Rgb([x as f32, 0.0, 1.0]));
+        let mut source = vec![CubemapFaces { size: 32, faces: vec![ramp; 6] }];
+        for size in [16, 8, 4] {
+            let next = source[source.len() - 1].downsample(size);
+            source.push(next);
+        }
+        for face in Face::ALL {
+            for a in [-0.5, 0.0, 0.3] {
+                let dir = face.basis().direction(a, 0.1);
+                let base = sample_level(&source, 0, dir);
+                for k in 1..4 {
+                    let level = sample_level(&source, k, dir);
+                    assert!((level[0] - base[0]).abs() < 1e-3, "{} level {} at {}: {:?}", face, k, a, level);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn taps_follow_roughness() {
+        // A mirror reflects the normal itself, from the base level
+        for tap in ggx_taps(0.0, 16, 256) {
+            assert_eq!(tap.light, [0.0, 0.0, 1.0]);
+            assert_eq!(tap.lod, 0.0);
+        }
+        // Rougher lobes spread wider and read smaller levels, never below
+        // the 8x8 one
+        let spread = |roughness: f32| {
+            let taps = ggx_taps(roughness, 256, 256);
+            assert!(taps.iter().all(|tap| tap.weight > 0.0 && (0.0..=5.0).contains(&tap.lod)));
+            let n = taps.len() as f32;
+            (taps.iter().map(|tap| tap.light[2]).sum::<f32>() / n, taps.iter().map(|tap| tap.lod).sum::<f32>() / n)
+        };
+        let (smooth, rough) = (spread(0.25), spread(0.75));
+        assert!(smooth.0 > rough.0 && smooth.1 < rough.1, "{:?} {:?}", smooth, rough);
+    }
+}
@@ -0,0 +1,172 @@
+use crate::cli::{Cli, ConvertArgs};
+use crate::report::Failures;
+use crate::{expand_input, run_convert, Shared};
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+use std::time::Instant;
+use toml::{Table, Value};
+use tracing::{error, info};
+
+// A jobs file lists conversions as `[[job]]` tables whose keys are the
+// convert command's long options, plus an optional `[defaults]` table that
+// every job starts from:
+//
+//   [defaults]
+//   format = "webp"
+//   size = [512, 1024]
+//
+//   [[job]]
+//   name = "lobby"
+//   input = "panos/lobby.jpg"
+//   output-dir = "site/{stem}"
+//   yaw = -90
+//
+// Strings become the option's value, numbers likewise, `true` a bare flag
+// and arrays a repeated option. String values may use {stem}, {name} and
+// {ext} of the job's input. Relative paths are relative to the working
+// directory, as on the command line.
+
+// Options that belong to the whole run, or would never finish
+const RESERVED: [&str; 4] = ["config", "daemon", "threads", "watch"];
+
+/// Run every job in `path` in order. A failed job is reported and the rest
+/// still run.
+pub fn run_jobs(path: &Path, shared: &Shared) -> Result<()> {
+    let total_start = Instant::now();
+    let text = std::fs::read_to_string(path).with_context(|| format!("cannot read {}", path.display()))?;
+    let mut file: Table = text.parse().with_context(|| format!("{} is not valid TOML", path.display()))?;
+    let defaults = match file.remove("defaults") {
+        Some(Value::Table(defaults)) => defaults,
+        Some(_) => bail!("[defaults] in {} must be a table", path.display()),
+        None => Table::new(),
+    };
+    let jobs = match file.remove("job") {
+        Some(Value::Array(jobs)) => jobs,
+        Some(_) => bail!("jobs in {} must be [[job]] tables", path.display()),
+        None => bail!("{} has no [[job]] tables", path.display()),
+    };
+    if let Some(key) = file.keys().next() {
+        bail!("unknown top-level key '{}' in {}; options go in [defaults] or [[job]]", key, path.display());
+    }
+
+    // Check every job before running any, so a typo in the last one doesn't
+    // surface after an hour of converting
+    let jobs = jobs
+        .iter()
+        .enumerate()
+        .map(|(i, job)| parse_job(i + 1, job, &defaults))
+        .collect::<Result<Vec<(String, ConvertArgs)>>>()?;
+
+    let mut failed = 0;
+    for (i, (name, args)) in jobs.iter().enumerate() {
+        info!("== {} ({} of {}) ==", name, i + 1, jobs.len());
+        if let Err(err) = run_convert(args, shared) {
+            error!(job = %name, "{} failed: {:#}", name, err);
+            failed += 1;
+        }
+    }
+
+    info!("{} jobs from {} processed in {:?}", jobs.len(), path.display(), total_start.elapsed());
+    match failed {
+        0 => Ok(()),
+        failed => Err(Failures { failed, total: jobs.len(), what: "jobs failed" }.into()),
+    }
+}
+
+// Job `number`'s name and options, laid over the defaults
+fn parse_job(number: usize, job: &Value, defaults: &Table) -> Result<(String, ConvertArgs)> {
+    let Value::Table(job) = job else { bail!("job {} is not a table", number) };
+    // output_dir and output-dir are the same option
+    let options = |table: &Table| table.clone().into_iter().map(|(key, value)| (key.replace('_', "-"), value));
+    let mut merged: Table = options(defaults).collect();
+    merged.extend(options(job));
+    let name = match merged.remove("name") {
+        Some(Value::String(name)) => name,
+        Some(_) => bail!("job {}: name must be a string", number),
+        None => format!("job {}", number),
+    };
+    let args = job_args(&merged).with_context(|| format!("invalid {}", name))?;
+    Ok((name, args))
+}
+
+// Parse a job's options, keys already in kebab case, exactly as the command
+// line would be
+fn job_args(job: &Table) -> Result<ConvertArgs> {
+    let input = match job.get("input") {
+        Some(Value::String(input)) => Some(Path::new(input)),
+        _ => None,
+    };
+    let mut argv = vec!["rust-cube".to_string()];
+    for (option, value) in job {
+        if RESERVED.contains(&option.as_str()) {
+            bail!("'{}' can't be set per job", option);
+        }
+        push_option(&mut argv, option, value, input)?;
+    }
+    Ok(Cli::parse_args(&argv)?.convert)
+}
+
+fn push_option(argv: &mut Vec<String>, option: &str, value: &Value, input: Option<&Path>) -> Result<()> {
+    match value {
+        Value::Boolean(true) => argv.push(format!("--{}", option)),
+        Value::Boolean(false) => {}
+        // `--option=value`, so values starting with '-' aren't taken for options
+        Value::String(text) if option == "input" => argv.push(format!("--{}={}", option, text)),
+        Value::String(text) => {
+            let text = input.map_or_else(|| text.clone(), |input| expand_input(text, input));
+            argv.push(format!("--{}={}", option, text))
+        }
+        Value::Integer(number) => argv.push(format!("--{}={}", option, number)),
+        Value::Float(number) => argv.push(format!("--{}={}", option, number)),
+        Value::Array(values) => {
+            for value in values {
+                if matches!(value, Value::Array(_) | Value::Boolean(_)) {
+                    bail!("'{}' must be a list of strings or numbers", option);
+                }
+                push_option(argv, option, value, input)?;
+            }
+        }
+        _ => bail!("'{}' must be a string, number, boolean or list", option),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_cube::OutputFormat;
+    use std::path::PathBuf;
+
+    fn parse(defaults: &str, job: &str) -> Result<(String, ConvertArgs)> {
+        let defaults: Table = defaults.parse().unwrap();
+        parse_job(2, &Value::Table(job.parse().unwrap()), &defaults)
+    }
+
+    #[test]
+    fn jobs_lay_over_the_defaults() {
+        let defaults = "format = \"webp\"\nsize = [512, 1024]\nyaw = 10";
+        let job = "name = \"lobby\"\ninput = \"panos/lobby.jpg\"\noutput_dir = \"site/{stem}.{ext}\"\nyaw = -90";
+        let (name, args) = parse(defaults, job).unwrap();
+        assert_eq!(name, "lobby");
+        assert_eq!(args.format, Some(OutputFormat::WebP));
+        // Arrays repeat the option; the job's own values win
+        assert_eq!(args.sizes, [512, 1024]);
+        assert_eq!(args.yaw, -90.0);
+        assert_eq!(args.output_dir, PathBuf::from("site/lobby.jpg"));
+
+        let (name, _) = parse("", "input = \"a.jpg\"").unwrap();
+        assert_eq!(name, "job 2");
+    }
+
+    #[test]
+    fn bad_jobs_are_refused() {
+        let error = |defaults, job| format!("{:#}", parse(defaults, job).unwrap_err());
+        assert_eq!(error("threads = 4", "input = \"a.jpg\""), "invalid job 2: 'threads' can't be set per job");
+        assert!(error("", "input = \"a.jpg\"\nsize = [true]").ends_with("'size' must be a list of strings or numbers"));
+        assert!(error("", "input = \"a.jpg\"\nname = 3").ends_with("name must be a string"));
+        assert!(error("", "input = \"a.jpg\"\nyaw = \"left\"").contains("'left'"));
+        // Missing arguments are named
+        let missing = "invalid job 2: the following required arguments were not provided: --input <INPUT>";
+        assert_eq!(error("", "size = 8"), missing);
+    }
+}
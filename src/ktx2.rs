@@ -0,0 +1,370 @@
+use crate::{mip_chain, pixel, Buffer, Channel, CubemapError, CubemapFaces, PixelDepth};
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use image::{DynamicImage, Pixel, Rgba};
+use rayon::prelude::*;
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+const IDENTIFIER: [u8; 12] = [0xAB, b'K', b'T', b'X', b' ', b'2', b'0', 0xBB, b'\r', b'\n', 0x1A, b'\n'];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Supercompression {
+    #[default]
+    None,
+    Zlib,
+}
+
+impl Supercompression {
+    fn scheme(self) -> u32 {
+        match self {
+            Supercompression::None => 0,
+            Supercompression::Zlib => 3,
+        }
+    }
+}
+
+impl fmt::Display for Supercompression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Supercompression::None => "none",
+            Supercompression::Zlib => "zlib",
+        })
+    }
+}
+
+impl FromStr for Supercompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Supercompression::None),
+            "zlib" => Ok(Supercompression::Zlib),
+            _ => Err(format!("unknown supercompression '{}' (expected none or zlib)", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ktx2Options {
+    /// Write a full mip chain instead of the base level only
+    pub mipmaps: bool,
+    pub supercompression: Supercompression,
+}
+
+impl Default for Ktx2Options {
+    fn default() -> Self {
+        Ktx2Options { mipmaps: true, supercompression: Supercompression::None }
+    }
+}
+
+// Uncompressed RGBA formats we emit, one per pipeline depth
+struct TexelFormat {
+    vk_format: u32,
+    type_size: u32,
+    srgb: bool,
+    float: bool,
+    lower: u32,
+    upper: u32,
+}
+
+const RGBA8_SRGB: TexelFormat =
+    TexelFormat { vk_format: 43, type_size: 1, srgb: true, float: false, lower: 0, upper: 255 };
+const RGBA16_UNORM: TexelFormat =
+    TexelFormat { vk_format: 91, type_size: 2, srgb: false, float: false, lower: 0, upper: 65535 };
+const RGBA32_SFLOAT: TexelFormat = TexelFormat {
+    vk_format: 109,
+    type_size: 4,
+    srgb: false,
+    float: true,
+    lower: 0xBF80_0000, // -1.0
+    upper: 0x3F80_0000, // 1.0
+};
+
+/// Write the cubemap as a KTX2 texture. Faces are stored in `Face::ALL`
+/// order, which is the +X, -X, +Y, -Y, +Z, -Z order KTX2 requires. 8-bit
+/// faces become `R8G8B8A8_SRGB`, 16-bit `R16G16B16A16_UNORM` and float
+/// `R32G32B32A32_SFLOAT`. There is no sRGB 16-bit format, so 16-bit faces
+/// are decoded to linear light first.
+pub fn write_ktx2<W: Write>(
+    cubemap: &CubemapFaces<DynamicImage>,
+    options: &Ktx2Options,
+    writer: W,
+) -> Result<(), CubemapError> {
+    write_ktx2_levels(std::slice::from_ref(cubemap), options, writer)
+}
+
+/// `write_ktx2` with the mip levels given, e.g. from `prefilter_specular`,
+/// instead of box filtered from the base; `options.mipmaps` is ignored when
+/// there is more than one.
+pub fn write_ktx2_levels<W: Write>(
+    levels: &[CubemapFaces<DynamicImage>],
+    options: &Ktx2Options,
+    mut writer: W,
+) -> Result<(), CubemapError> {
+    check_levels(levels)?;
+    let cubemap = &levels[0];
+    let mipmaps = options.mipmaps;
+    let depth = levels.iter().flat_map(|level| &level.faces).map(PixelDepth::of).max().unwrap_or(PixelDepth::U8);
+    let (format, levels) = match depth {
+        PixelDepth::U8 => (RGBA8_SRGB, level_data(levels, DynamicImage::to_rgba8, mipmaps, |v, out| out.push(*v))),
+        PixelDepth::U16 => (
+            RGBA16_UNORM,
+            level_data(levels, linear_rgba16, mipmaps, |v, out| out.extend(v.to_le_bytes())),
+        ),
+        PixelDepth::F32 => (
+            RGBA32_SFLOAT,
+            level_data(levels, DynamicImage::to_rgba32f, mipmaps, |v, out| out.extend(v.to_le_bytes())),
+        ),
+    };
+
+    // Levels are compressed independently; the level index keeps both sizes
+    let uncompressed_lengths: Vec<u64> = levels.iter().map(|level| level.len() as u64).collect();
+    let levels = match options.supercompression {
+        Supercompression::None => levels,
+        Supercompression::Zlib => levels
+            .into_par_iter()
+            .map(|level| {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(&level)?;
+                encoder.finish()
+            })
+            .collect::<io::Result<_>>()?,
+    };
+
+    let supercompressed = options.supercompression != Supercompression::None;
+    let dfd = data_format_descriptor(&format, supercompressed);
+    let kvd = key_value_data();
+
+    let level_count = levels.len() as u32;
+    let dfd_offset = 80 + 24 * level_count;
+    let kvd_offset = dfd_offset + dfd.len() as u32;
+    let data_start = (kvd_offset + kvd.len() as u32) as u64;
+
+    // Level data goes smallest mip first, each level aligned to the texel size
+    let alignment = if supercompressed { 1 } else { (format.type_size * 4) as u64 };
+    let mut offsets = vec![0u64; levels.len()];
+    let mut cursor = data_start;
+    for (i, level) in levels.iter().enumerate().rev() {
+        cursor = cursor.div_ceil(alignment) * alignment;
+        offsets[i] = cursor;
+        cursor += level.len() as u64;
+    }
+
+    let mut out = Vec::with_capacity(cursor as usize);
+    out.extend_from_slice(&IDENTIFIER);
+    for value in [
+        format.vk_format,
+        format.type_size,
+        cubemap.size,
+        cubemap.size,
+        0, // pixelDepth
+        0, // layerCount
+        6, // faceCount
+        level_count,
+        options.supercompression.scheme(),
+    ] {
+        out.extend(value.to_le_bytes());
+    }
+    for value in [dfd_offset, dfd.len() as u32, kvd_offset, kvd.len() as u32] {
+        out.extend(value.to_le_bytes());
+    }
+    out.extend(0u64.to_le_bytes()); // sgdByteOffset
+    out.extend(0u64.to_le_bytes()); // sgdByteLength
+    for ((offset, level), uncompressed) in offsets.iter().zip(&levels).zip(&uncompressed_lengths) {
+        out.extend(offset.to_le_bytes());
+        out.extend((level.len() as u64).to_le_bytes());
+        out.extend(uncompressed.to_le_bytes());
+    }
+    out.extend_from_slice(&dfd);
+    out.extend_from_slice(&kvd);
+    for (offset, level) in offsets.iter().zip(&levels).rev() {
+        out.resize(*offset as usize, 0);
+        out.extend_from_slice(level);
+    }
+
+    writer.write_all(&out)?;
+    Ok(())
+}
+
+/// Reject mip levels that don't halve from the first: level `i` of a
+/// cubemap of `size` must be `size >> i` (at least 1) across.
+pub(crate) fn check_levels(levels: &[CubemapFaces<DynamicImage>]) -> Result<(), CubemapError> {
+    let Some(base) = levels.first() else {
+        return Err(CubemapError::Projection("no mip levels to write".to_string()));
+    };
+    if levels.len() as u32 > base.size.max(1).ilog2() + 1 {
+        return Err(CubemapError::Projection(format!("{} mip levels for a {} cubemap", levels.len(), base.size)));
+    }
+    for (i, level) in levels.iter().enumerate() {
+        let expected = (base.size >> i).max(1);
+        if level.size != expected {
+            return Err(CubemapError::Projection(format!(
+                "mip level {} is {}x{}, expected {}x{}",
+                i, level.size, level.size, expected, expected
+            )));
+        }
+    }
+    Ok(())
+}
+
+// Raw bytes of every mip level, each holding all six faces in order
+fn level_data<P>(
+    levels: &[CubemapFaces<DynamicImage>],
+    convert: fn(&DynamicImage) -> Buffer<P>,
+    mipmaps: bool,
+    push: fn(&P::Subpixel, &mut Vec<u8>),
+) -> Vec<Vec<u8>>
+where
+    P: Pixel + Send + Sync,
+    P::Subpixel: Channel,
+{
+    // Face-major: box filtered from the base level, or the levels given
+    let chains: Vec<Vec<Buffer<P>>> = (0..6)
+        .into_par_iter()
+        .map(|face| match levels {
+            [base] if mipmaps => mip_chain(convert(&base.faces[face])),
+            levels => levels.iter().map(|level| convert(&level.faces[face])).collect(),
+        })
+        .collect();
+
+    (0..chains[0].len())
+        .map(|level| {
+            let mut data = Vec::new();
+            for chain in &chains {
+                for value in chain[level].as_raw() {
+                    push(value, &mut data);
+                }
+            }
+            data
+        })
+        .collect()
+}
+
+// `img` in linear light as 16-bit RGBA, what `RGBA16_UNORM` is read as
+fn linear_rgba16(img: &DynamicImage) -> Buffer<Rgba<u16>> {
+    let linear = pixel::linearize_rgba(img);
+    let data = linear.as_raw().par_iter().map(|&value| u16::from_f32(value.clamp(0.0, 1.0) * 65535.0)).collect();
+    Buffer::from_raw(linear.width(), linear.height(), data).expect("same dimensions")
+}
+
+// Khronos basic data format descriptor for four RGBA samples
+fn data_format_descriptor(format: &TexelFormat, supercompressed: bool) -> Vec<u8> {
+    const SAMPLE_LINEAR: u32 = 0x10;
+    const SAMPLE_SIGNED: u32 = 0x40;
+    const SAMPLE_FLOAT: u32 = 0x80;
+
+    let block_size = 24 + 16 * 4;
+    let transfer = if format.srgb { 2 } else { 1 };
+    // Supercompressed data has no fixed plane size
+    let bytes_plane0 = if supercompressed { 0 } else { format.type_size * 4 };
+    let bits = format.type_size * 8;
+
+    let mut words = vec![
+        (4 + block_size) as u32, // dfdTotalSize
+        0,                       // vendorId = Khronos, descriptorType = basic
+        2 | ((block_size as u32) << 16),
+        1 | (1 << 8) | (transfer << 16), // RGBSDA model, BT.709 primaries, straight alpha
+        0,                           // 1x1x1x1 texel block
+        bytes_plane0,
+        0,
+    ];
+    for (i, channel) in [0u32, 1, 2, 15].into_iter().enumerate() {
+        let mut qualifiers = 0;
+        if format.float {
+            qualifiers |= SAMPLE_SIGNED | SAMPLE_FLOAT;
+        }
+        if format.srgb && channel == 15 {
+            qualifiers |= SAMPLE_LINEAR;
+        }
+        words.push((i as u32 * bits) | ((bits - 1) << 16) | ((channel | qualifiers) << 24));
+        words.push(0); // sample position
+        words.push(format.lower);
+        words.push(format.upper);
+    }
+
+    words.into_iter().flat_map(u32::to_le_bytes).collect()
+}
+
+fn key_value_data() -> Vec<u8> {
+    let mut entry = b"KTXwriter\0".to_vec();
+    entry.extend_from_slice(concat!("rust-cube ", env!("CARGO_PKG_VERSION"), "\0").as_bytes());
+
+    let mut kvd = (entry.len() as u32).to_le_bytes().to_vec();
+    kvd.extend_from_slice(&entry);
+    kvd.resize(kvd.len().div_ceil(4) * 4, 0);
+    kvd
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(data: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn write(faces: Vec<DynamicImage>) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_ktx2(&CubemapFaces::from_faces(faces).unwrap(), &Ktx2Options::default(), &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn sixteen_bit_faces_are_linear() {
+        let face = Buffer::<Rgb<u16>>::from_pixel(4, 4, Rgb([32768, 0, 65535]));
+        let out = write(vec![DynamicImage::ImageRgb16(face); 6]);
+
+        assert_eq!(out[..12], IDENTIFIER);
+        // vkFormat, typeSize, width, height, depth, layers, faces, levels,
+        // supercompression
+        let header: Vec<u32> = (0..9).map(|i| u32_at(&out, 12 + 4 * i)).collect();
+        assert_eq!(header, [91, 2, 4, 4, 0, 0, 6, 3, 0]);
+
+        // Each level holds six faces of 8-byte texels, aligned to them
+        let level_index = |i: usize| (0..3).map(|j| u64_at(&out, 80 + 24 * i + 8 * j)).collect::<Vec<_>>();
+        for (i, size) in [4u64, 2, 1].into_iter().enumerate() {
+            let [offset, length, uncompressed] = level_index(i)[..] else { unreachable!() };
+            assert_eq!((length, uncompressed), (6 * size * size * 8, 6 * size * size * 8));
+            assert_eq!(offset % 8, 0);
+            assert!(offset + length <= out.len() as u64);
+        }
+        // Smallest mip first, the base level last
+        assert_eq!(level_index(0)[0] + level_index(0)[1], out.len() as u64);
+        assert!(level_index(2)[0] < level_index(1)[0] && level_index(1)[0] < level_index(0)[0]);
+
+        // sRGB 0.5 is about 0.214 in linear light; alpha stays opaque
+        let base = level_index(0)[0] as usize;
+        let texel: Vec<u16> = (0..4).map(|i| u16::from_le_bytes([out[base + 2 * i], out[base + 2 * i + 1]])).collect();
+        assert_eq!(texel, [14028, 0, 65535, 65535]);
+
+        // The data format descriptor says linear, with no alpha qualifier
+        let (dfd_offset, dfd_length) = (u32_at(&out, 48) as usize, u32_at(&out, 52) as usize);
+        assert_eq!(dfd_offset, 80 + 24 * 3);
+        assert_eq!(u32_at(&out, dfd_offset), dfd_length as u32);
+        assert_eq!(dfd_length, 4 + 24 + 16 * 4);
+        let transfer = (u32_at(&out, dfd_offset + 12) >> 16) & 0xFF;
+        assert_eq!(transfer, 1);
+        assert_eq!(u32_at(&out, dfd_offset + 20), 8);
+        // Alpha: bits 48 to 63, channel 15
+        assert_eq!(u32_at(&out, dfd_offset + 28 + 16 * 3), 48 | (15 << 16) | (15 << 24));
+    }
+
+    #[test]
+    fn eight_bit_faces_stay_srgb() {
+        let out = write(vec![DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, Rgb([128, 0, 255]))); 6]);
+        assert_eq!((u32_at(&out, 12), u32_at(&out, 16)), (43, 1));
+        let dfd_offset = u32_at(&out, 48) as usize;
+        assert_eq!((u32_at(&out, dfd_offset + 12) >> 16) & 0xFF, 2);
+        // Alpha is marked linear within the sRGB data
+        assert_eq!(u32_at(&out, dfd_offset + 28 + 16 * 3) >> 24, 15 | 0x10);
+        let base = u64_at(&out, 80) as usize;
+        assert_eq!(out[base..base + 4], [128, 0, 255, 255]);
+    }
+}
@@ -0,0 +1,332 @@
+use crate::pattern::GLYPHS;
+use crate::{Buffer, CubemapError, CubemapFaces, Face, PixelDepth};
+use image::{imageops, DynamicImage, GenericImage, ImageBuffer, Pixel, Rgba};
+use std::fmt;
+use std::str::FromStr;
+
+/// Arrangement of the six faces in a single image.
+///
+/// Horizontal cross (4x3):     Vertical cross (3x4):
+///
+///   .  up    .     .            .  up    .
+///   left front right back       left front right
+///   .  down  .     .            .  down  .
+///                               .  back* .
+///
+/// `*` the back face is stored rotated by 180 degrees so that its edges meet
+/// the down face.
+///
+/// The strips (6x1 and 1x6) hold the faces in `Face::ALL` order.
+///
+/// EAC (3x2), the YouTube equi-angular layout:
+///
+///   left   front  right
+///   down<  back>  up<
+///
+/// `>` rotated 90 degrees clockwise, `<` 90 degrees counter-clockwise, so
+/// the bottom row is one continuous band through down, back and up.
+///
+/// 3x2 grid, the Transform360 packing used for offset cubemaps, with every
+/// face upright:
+///
+///   right  left   up
+///   down   front  back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    CrossHorizontal,
+    CrossVertical,
+    StripHorizontal,
+    StripVertical,
+    Eac,
+    Grid3x2,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 6] = [
+        Layout::CrossHorizontal,
+        Layout::CrossVertical,
+        Layout::StripHorizontal,
+        Layout::StripVertical,
+        Layout::Eac,
+        Layout::Grid3x2,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::CrossHorizontal => "cross-h",
+            Layout::CrossVertical => "cross-v",
+            Layout::StripHorizontal => "strip-h",
+            Layout::StripVertical => "strip-v",
+            Layout::Eac => "eac",
+            Layout::Grid3x2 => "grid-3x2",
+        }
+    }
+
+    /// Grid size in faces (columns, rows)
+    pub fn grid(self) -> (u32, u32) {
+        match self {
+            Layout::CrossHorizontal => (4, 3),
+            Layout::CrossVertical => (3, 4),
+            Layout::StripHorizontal => (6, 1),
+            Layout::StripVertical => (1, 6),
+            Layout::Eac | Layout::Grid3x2 => (3, 2),
+        }
+    }
+
+    /// Cell (column, row) of `face` and its rotation in clockwise quarter turns
+    pub fn cell(self, face: Face) -> (u32, u32, u8) {
+        match (self, face) {
+            (Layout::CrossHorizontal, Face::Up) => (1, 0, 0),
+            (Layout::CrossHorizontal, Face::Left) => (0, 1, 0),
+            (Layout::CrossHorizontal, Face::Front) => (1, 1, 0),
+            (Layout::CrossHorizontal, Face::Right) => (2, 1, 0),
+            (Layout::CrossHorizontal, Face::Back) => (3, 1, 0),
+            (Layout::CrossHorizontal, Face::Down) => (1, 2, 0),
+            (Layout::CrossVertical, Face::Up) => (1, 0, 0),
+            (Layout::CrossVertical, Face::Left) => (0, 1, 0),
+            (Layout::CrossVertical, Face::Front) => (1, 1, 0),
+            (Layout::CrossVertical, Face::Right) => (2, 1, 0),
+            (Layout::CrossVertical, Face::Down) => (1, 2, 0),
+            (Layout::CrossVertical, Face::Back) => (1, 3, 2),
+            (Layout::StripHorizontal, face) => (face.index() as u32, 0, 0),
+            (Layout::StripVertical, face) => (0, face.index() as u32, 0),
+            (Layout::Eac, Face::Left) => (0, 0, 0),
+            (Layout::Eac, Face::Front) => (1, 0, 0),
+            (Layout::Eac, Face::Right) => (2, 0, 0),
+            (Layout::Eac, Face::Down) => (0, 1, 3),
+            (Layout::Eac, Face::Back) => (1, 1, 1),
+            (Layout::Eac, Face::Up) => (2, 1, 3),
+            (Layout::Grid3x2, face) => (face.index() as u32 % 3, face.index() as u32 / 3, 0),
+        }
+    }
+
+    /// Guess the layout of a single-image cubemap from its dimensions. The
+    /// 3x2 layouts are never guessed: they have the same shape, and EAC faces
+    /// also need the equi-angular unwarp.
+    pub fn detect(width: u32, height: u32) -> Option<Layout> {
+        Layout::ALL
+            .into_iter()
+            .filter(|&layout| layout.grid() != (3, 2))
+            .find(|layout| {
+                let (cols, rows) = layout.grid();
+                width.is_multiple_of(cols) && width / cols * rows == height
+            })
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Layout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Layout::ALL
+            .into_iter()
+            .find(|layout| layout.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                format!("unknown layout '{}' (expected cross-h, cross-v, strip-h, strip-v, eac or grid-3x2)", s)
+            })
+    }
+}
+
+/// Cut a single-image cubemap into its six faces.
+pub fn split_layout<P>(img: &Buffer<P>, layout: Layout) -> Result<CubemapFaces<Buffer<P>>, CubemapError>
+where
+    P: Pixel + 'static,
+    P::Subpixel: 'static,
+{
+    let (cols, rows) = layout.grid();
+    let size = img.width() / cols;
+    if size == 0 || img.width() != size * cols || img.height() != size * rows {
+        return Err(CubemapError::Projection(format!(
+            "{}x{} image does not match a {}x{} face grid",
+            img.width(), img.height(), cols, rows
+        )));
+    }
+
+    let faces = Face::iter()
+        .map(|face| {
+            let (col, row, turns) = layout.cell(face);
+            let view = imageops::crop_imm(img, col * size, row * size, size, size).to_image();
+            // Undo the cell rotation
+            match turns {
+                1 => imageops::rotate270(&view),
+                2 => imageops::rotate180(&view),
+                3 => imageops::rotate90(&view),
+                _ => view,
+            }
+        })
+        .collect();
+
+    CubemapFaces::from_faces(faces)
+}
+
+/// Pack the six faces into a single image.
+pub fn assemble_layout<P: Pixel>(cubemap: &CubemapFaces<Buffer<P>>, layout: Layout) -> Buffer<P> {
+    let faces: Vec<&[P::Subpixel]> = cubemap.faces.iter().map(|face| face.as_raw().as_slice()).collect();
+    let (cols, rows) = layout.grid();
+    let mut out: Buffer<P> = Buffer::new(cols * cubemap.size, rows * cubemap.size);
+    assemble_into(&faces, cubemap.size, P::CHANNEL_COUNT as usize, layout, &mut out);
+    out
+}
+
+/// `split_layout` at the image's own precision, keeping alpha if it has any
+/// and gray if it is 8 or 16-bit gray.
+pub fn split_layout_dynamic(img: &DynamicImage, layout: Layout) -> Result<CubemapFaces<DynamicImage>, CubemapError> {
+    match img {
+        DynamicImage::ImageLuma8(img) => return Ok(split_layout(img, layout)?.map(|_, f| f.into())),
+        DynamicImage::ImageLuma16(img) => return Ok(split_layout(img, layout)?.map(|_, f| f.into())),
+        _ => {}
+    }
+    let depth = PixelDepth::of(img);
+    if img.color().has_alpha() {
+        return Ok(match depth.to_rgba(img.clone()) {
+            DynamicImage::ImageRgba16(img) => split_layout(&img, layout)?.map(|_, f| f.into()),
+            DynamicImage::ImageRgba32F(img) => split_layout(&img, layout)?.map(|_, f| f.into()),
+            img => split_layout(&img.into_rgba8(), layout)?.map(|_, f| f.into()),
+        });
+    }
+    Ok(match depth.to_rgb(img.clone()) {
+        DynamicImage::ImageRgb16(img) => split_layout(&img, layout)?.map(|_, f| f.into()),
+        DynamicImage::ImageRgb32F(img) => split_layout(&img, layout)?.map(|_, f| f.into()),
+        img => split_layout(&img.into_rgb8(), layout)?.map(|_, f| f.into()),
+    })
+}
+
+/// `assemble_layout` for faces from `equirect_to_cubemap_dynamic`, keeping
+/// their precision and alpha. Cells no face fills are transparent black
+/// when the faces have alpha.
+pub fn assemble_layout_dynamic(cubemap: &CubemapFaces<DynamicImage>, layout: Layout) -> DynamicImage {
+    let (cols, rows) = layout.grid();
+    let (size, width, height) = (cubemap.size, cols * cubemap.size, rows * cubemap.size);
+
+    if let Some(faces) = raw_faces(cubemap, DynamicImage::as_rgb8) {
+        let mut out = ImageBuffer::new(width, height);
+        assemble_into(&faces, size, 3, layout, &mut out);
+        return DynamicImage::ImageRgb8(out);
+    }
+    if let Some(faces) = raw_faces(cubemap, DynamicImage::as_rgb16) {
+        let mut out = ImageBuffer::new(width, height);
+        assemble_into(&faces, size, 3, layout, &mut out);
+        return DynamicImage::ImageRgb16(out);
+    }
+    if let Some(faces) = raw_faces(cubemap, DynamicImage::as_rgb32f) {
+        let mut out = ImageBuffer::new(width, height);
+        assemble_into(&faces, size, 3, layout, &mut out);
+        return DynamicImage::ImageRgb32F(out);
+    }
+    if let Some(faces) = raw_faces(cubemap, DynamicImage::as_luma8) {
+        let mut out = ImageBuffer::new(width, height);
+        assemble_into(&faces, size, 1, layout, &mut out);
+        return DynamicImage::ImageLuma8(out);
+    }
+    if let Some(faces) = raw_faces(cubemap, DynamicImage::as_luma16) {
+        let mut out = ImageBuffer::new(width, height);
+        assemble_into(&faces, size, 1, layout, &mut out);
+        return DynamicImage::ImageLuma16(out);
+    }
+    if let Some(faces) = raw_faces(cubemap, DynamicImage::as_rgba8) {
+        let mut out = ImageBuffer::new(width, height);
+        assemble_into(&faces, size, 4, layout, &mut out);
+        return DynamicImage::ImageRgba8(out);
+    }
+    if let Some(faces) = raw_faces(cubemap, DynamicImage::as_rgba16) {
+        let mut out = ImageBuffer::new(width, height);
+        assemble_into(&faces, size, 4, layout, &mut out);
+        return DynamicImage::ImageRgba16(out);
+    }
+    if let Some(faces) = raw_faces(cubemap, DynamicImage::as_rgba32f) {
+        let mut out = ImageBuffer::new(width, height);
+        assemble_into(&faces, size, 4, layout, &mut out);
+        return DynamicImage::ImageRgba32F(out);
+    }
+
+    // Mixed or other faces: bring them all to the deepest precision first,
+    // and to RGBA if any has alpha
+    let depth = cubemap.faces.iter().map(PixelDepth::of).max().unwrap_or(PixelDepth::U8);
+    let converted = match cubemap.faces.iter().any(|face| face.color().has_alpha()) {
+        true => cubemap.clone().map(|_, face| depth.to_rgba(face)),
+        false => cubemap.clone().map(|_, face| depth.to_rgb(face)),
+    };
+    assemble_layout_dynamic(&converted, layout)
+}
+
+/// A small montage for checking a conversion at a glance: `layout` with
+/// faces `face_size` across, each labelled with its initial in the top-left
+/// corner. Keeps the faces' precision, for the encoder to tone map.
+pub fn preview_montage(cubemap: &CubemapFaces<DynamicImage>, layout: Layout, face_size: u32) -> DynamicImage {
+    let mut montage = if face_size < cubemap.size {
+        assemble_layout_dynamic(&cubemap.downsample_dynamic(face_size), layout)
+    } else {
+        assemble_layout_dynamic(cubemap, layout)
+    };
+    let size = face_size.min(cubemap.size);
+    // Dots of the 5x7 glyph, on a dark plate one dot wider all round
+    let dot = (size / 48).max(1);
+    for face in Face::iter() {
+        let (col, row, _) = layout.cell(face);
+        let (left, top) = (col * size + dot, row * size + dot);
+        for y in 0..9 * dot {
+            for x in 0..7 * dot {
+                let (gx, gy) = ((x / dot) as i32 - 1, (y / dot) as i32 - 1);
+                let lit = (0..5).contains(&gx)
+                    && (0..7).contains(&gy)
+                    && GLYPHS[face.index()][gy as usize] & (0b10000 >> gx) != 0;
+                let value = if lit { 255 } else { 0 };
+                if left + x < montage.width() && top + y < montage.height() {
+                    montage.put_pixel(left + x, top + y, Rgba([value, value, value, 255]));
+                }
+            }
+        }
+    }
+    montage
+}
+
+fn raw_faces<'a, P: Pixel + 'a>(
+    cubemap: &'a CubemapFaces<DynamicImage>,
+    typed: impl Fn(&'a DynamicImage) -> Option<&'a Buffer<P>>,
+) -> Option<Vec<&'a [P::Subpixel]>> {
+    cubemap.faces.iter().map(|face| typed(face).map(|buf| buf.as_raw().as_slice())).collect()
+}
+
+fn assemble_into<T: Copy>(faces: &[&[T]], size: u32, channels: usize, layout: Layout, data: &mut [T]) {
+    let (cols, _) = layout.grid();
+    let size = size as usize;
+    let row_len = size * channels;
+    let stride = cols as usize * row_len;
+
+    for face in Face::iter() {
+        let (col, row, turns) = layout.cell(face);
+        let src = faces[face.index()];
+        let texel = |x: usize, y: usize| &src[(y * size + x) * channels..][..channels];
+        for y in 0..size {
+            let dst_start = (row as usize * size + y) * stride + col as usize * row_len;
+            let dst = &mut data[dst_start..dst_start + row_len];
+            match turns {
+                0 => dst.copy_from_slice(&src[y * row_len..(y + 1) * row_len]),
+                // 180 degrees: last source row first, pixels reversed
+                2 => {
+                    let src_row = &src[(size - 1 - y) * row_len..(size - y) * row_len];
+                    for (d, s) in dst.chunks_exact_mut(channels).zip(src_row.chunks_exact(channels).rev()) {
+                        d.copy_from_slice(s);
+                    }
+                }
+                // Quarter turns read a source column per destination row
+                1 => {
+                    for (x, d) in dst.chunks_exact_mut(channels).enumerate() {
+                        d.copy_from_slice(texel(y, size - 1 - x));
+                    }
+                }
+                _ => {
+                    for (x, d) in dst.chunks_exact_mut(channels).enumerate() {
+                        d.copy_from_slice(texel(size - 1 - y, x));
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use crate::{spherical_to_direction, Rotation};
+use image::imageops::FilterType;
+use image::DynamicImage;
+use std::f32::consts::PI;
+
+// Analysis size; tilts of a few tenths of a degree still move edges here
+const WIDTH: u32 = 2048;
+
+// Edges closer than this to vertical in the image are taken for vertical
+// lines in the scene, bent by the tilt
+const MAX_LEAN: f32 = 0.5;
+
+// Below this fraction of the other directions' spread the edges agree on an
+// up direction
+const MAX_SPREAD: f32 = 0.2;
+
+const MIN_EDGES: usize = 500;
+
+// Refinements, each halving how far an edge's circle may miss the estimate
+const ROUNDS: u32 = 4;
+
+/// The way up in an equirect panorama, found from its vertical edges: walls,
+/// poles and door frames project onto great circles through the zenith, so
+/// up is the direction most nearly perpendicular to all of their planes.
+/// None when there are too few edges, or they don't agree.
+pub fn detect_up(img: &DynamicImage) -> Option<[f32; 3]> {
+    let (width, height) = (WIDTH, WIDTH / 2);
+    let small = img.resize_exact(width, height, FilterType::Triangle).to_luma32f();
+    // Compress HDR highlights so a few bright edges don't outweigh the rest
+    let luma: Vec<f32> = small.into_raw().into_iter().map(|value| value / (1.0 + value)).collect();
+    let at = |x: i64, y: u32| luma[(y * width) as usize + x.rem_euclid(width as i64) as usize];
+
+    // Normals of the planes of the edges' great circles, weighted by
+    // contrast; rows near the poles are left out, where everything converges
+    let mut edges = Vec::new();
+    for y in height / 6..height * 5 / 6 {
+        for x in 0..width as i64 {
+            // Scharr rather than Sobel weights, which keep edge angles
+            // within a fraction of a degree
+            let gx = 3.0 * (at(x + 1, y - 1) + at(x + 1, y + 1) - at(x - 1, y - 1) - at(x - 1, y + 1))
+                + 10.0 * (at(x + 1, y) - at(x - 1, y));
+            let gy = 3.0 * (at(x - 1, y + 1) + at(x + 1, y + 1) - at(x - 1, y - 1) - at(x + 1, y - 1))
+                + 10.0 * (at(x, y + 1) - at(x, y - 1));
+            let magnitude = (gx * gx + gy * gy).sqrt();
+            if magnitude < 0.5 || gy.abs() > MAX_LEAN * gx.abs() {
+                continue;
+            }
+            // The edge runs across the gradient; longitude and latitude
+            // steps per pixel are the same size in a 2:1 image
+            let (u, v) = (x as f32 / width as f32, y as f32 / height as f32);
+            let (phi, theta) = ((u - 0.5) * 2.0 * PI, v * PI);
+            let along_phi = [theta.sin() * phi.cos(), 0.0, -theta.sin() * phi.sin()];
+            let along_theta = [theta.cos() * phi.sin(), -theta.sin(), theta.cos() * phi.cos()];
+            let (tx, ty) = (-gy / magnitude, gx / magnitude);
+            let tangent: [f32; 3] = std::array::from_fn(|i| tx * along_phi[i] + ty * along_theta[i]);
+            edges.push((normalize(cross(spherical_to_direction(u, v), tangent)), magnitude));
+        }
+    }
+
+    // Edges of things that aren't vertical pull the estimate off, so each
+    // round only keeps those whose circles pass close to the last one
+    let mut up = [0.0, 1.0, 0.0];
+    let mut kept = (0.0, 0.0);
+    for round in 0..ROUNDS {
+        let tolerance = MAX_LEAN / (1 << round) as f32;
+        let inliers: Vec<_> = edges.iter().filter(|(normal, _)| dot(*normal, up).abs() < tolerance).collect();
+        if inliers.len() < MIN_EDGES {
+            return None;
+        }
+        let mut scatter = [[0.0f32; 3]; 3];
+        for (normal, weight) in &inliers {
+            for (i, row) in scatter.iter_mut().enumerate() {
+                for (j, value) in row.iter_mut().enumerate() {
+                    *value += weight * normal[i] * normal[j];
+                }
+            }
+        }
+        // Direction of least spread: power iteration on trace - scatter
+        let trace = scatter[0][0] + scatter[1][1] + scatter[2][2];
+        for _ in 0..64 {
+            let spread = multiply(&scatter, up);
+            up = normalize(std::array::from_fn(|i| trace * up[i] - spread[i]));
+        }
+        let least = dot(up, multiply(&scatter, up));
+        kept = (least, trace - least);
+    }
+    let (least, rest) = kept;
+    if least > MAX_SPREAD * rest / 2.0 {
+        return None;
+    }
+    Some(if up[1] < 0.0 { up.map(|value| -value) } else { up })
+}
+
+/// Rotation that levels a panorama whose way up is `up`, for
+/// `CubemapOptions::rotation`, and the tilt it corrects in degrees.
+pub fn level_rotation(up: [f32; 3]) -> (Rotation, f32) {
+    let tilt = up[1].clamp(-1.0, 1.0).acos().to_degrees();
+    (Rotation::from_to([0.0, 1.0, 0.0], up), tilt)
+}
+
+fn multiply(m: &[[f32; 3]; 3], v: [f32; 3]) -> [f32; 3] {
+    std::array::from_fn(|i| dot(m[i], v))
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+fn normalize(v: [f32; 3]) -> [f32; 3] {
+    let length = dot(v, v).sqrt();
+    v.map(|value| value / length)
+}
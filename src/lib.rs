@@ -0,0 +1,782 @@
+use image::codecs::hdr::HdrDecoder;
+use image::{DynamicImage, GenericImageView, ImageFormat, Pixel, RgbImage};
+use rayon::prelude::*;
+use rayon::ThreadPool;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+mod atomic;
+mod bc7;
+mod blurhash;
+#[cfg(feature = "capi")]
+mod capi;
+mod color;
+mod conventions;
+mod dds;
+mod dither;
+mod encode;
+mod envmap;
+mod equirect;
+mod error;
+mod face;
+mod gpano;
+mod heic;
+#[cfg(feature = "gpu")]
+mod gpu;
+mod ibl;
+mod ktx2;
+mod layout;
+mod level;
+mod lut;
+mod metadata;
+mod mipmap;
+mod nadir;
+mod normal;
+mod pattern;
+mod pixel;
+mod postprocess;
+mod progress;
+mod projection;
+#[cfg(feature = "python")]
+mod python;
+mod quality;
+mod resample;
+mod rotation;
+mod sampler;
+mod sh;
+mod sharpen;
+mod simd;
+mod source;
+mod stereo;
+mod stream;
+mod ssaa;
+mod tensor;
+mod tiles;
+mod tonemap;
+mod view;
+#[cfg(feature = "wasm")]
+mod wasm;
+
+pub use atomic::{remove_stale_temps, write_atomic, AtomicFile};
+pub use blurhash::blurhash;
+pub use color::ColorGrade;
+pub use conventions::{Convention, FaceTransform};
+pub use dds::{write_dds, write_dds_levels, DdsFormat, DdsOptions};
+pub use dither::Dither;
+pub use encode::{
+    encode_image, encode_image_with_metadata, save_image, save_image_with_metadata, AvifDepth, ChromaSubsampling,
+    EncodeOptions, JpegBackend, OutputFormat, PngCompression,
+};
+pub use envmap::{render_envmap, render_envmap_dynamic, EnvMapOptions, EnvMapping};
+pub use equirect::{cubemap_to_equirect, cubemap_to_equirect_dynamic, sample_cubemap};
+pub use error::CubemapError;
+pub use face::{CubeProjection, Face, FaceBasis};
+pub use gpano::{
+    find_gpano, find_gpano_pose, parse_gpano, parse_gpano_pose, read_gpano, read_gpano_pose, PanoPose,
+};
+#[cfg(feature = "gpu")]
+pub use gpu::GpuContext;
+pub use ibl::{
+    irradiance_cubemap, irradiance_cubemap_dynamic, prefilter_specular, prefilter_specular_dynamic, SpecularOptions,
+};
+pub use ktx2::{write_ktx2, write_ktx2_levels, Ktx2Options, Supercompression};
+pub use layout::{
+    assemble_layout, assemble_layout_dynamic, preview_montage, split_layout, split_layout_dynamic, Layout,
+};
+pub use level::{detect_up, level_rotation};
+pub use lut::LutCache;
+pub use metadata::Metadata;
+pub use mipmap::{downsample_half, downsample_linear, mip_chain};
+pub use nadir::NadirPatch;
+pub use normal::PixelType;
+pub use pattern::TestPattern;
+pub use pixel::{Buffer, Channel, PixelDepth};
+pub use postprocess::PostProcess;
+pub use progress::Progress;
+pub use projection::{reproject, CubeFace, Equirectangular, Fisheye, Projection, Stereographic};
+pub use quality::{compare_equirect, BandQuality, QualityReport};
+pub use resample::{resample_cubemap, resample_cubemap_dynamic};
+pub use rotation::Rotation;
+pub use sampler::{sample, Filter};
+pub use sh::SphericalHarmonics;
+pub use sharpen::Sharpen;
+pub use source::{DualFisheye, Fill, FisheyeLens, InputProjection, PanoCrop};
+pub use stereo::StereoLayout;
+pub use stream::{equirect_to_cubemap_streaming, ScanlineReader};
+pub use tensor::{write_npy, write_safetensors};
+pub use tiles::{cut_tiles, preview_strip, TilePyramid, TileViewer};
+pub use tonemap::{ToneMap, ToneMapper};
+pub use view::{render_view, render_view_dynamic, ViewOptions, ViewProjection};
+
+#[derive(Debug, Clone)]
+pub struct CubemapOptions {
+    pub size: u32,
+    pub filter: Filter,
+    /// Supersampling: up to `ssaa` x `ssaa` samples per face pixel (1 = off)
+    pub ssaa: u32,
+    /// Scale the sample count with the local source-to-face texel ratio
+    /// instead of always taking `ssaa` x `ssaa`
+    pub ssaa_adaptive: bool,
+    /// Applied to the sampling directions to level or re-center the panorama
+    pub rotation: Rotation,
+    pub projection: CubeProjection,
+    /// Offset cubemap (as in Transform360, for viewport-adaptive VR video):
+    /// each direction on the cube is moved towards the front by this much
+    /// before sampling, so the front gets up to `1 + offset` times the
+    /// resolution and the back less. 0 is a regular cubemap; below 1
+    pub offset: f32,
+    /// Layout of the source image
+    pub input: InputProjection,
+    /// Used where a partial source doesn't cover the sphere
+    pub fill: Fill,
+    /// Filter 8 and 16-bit sources in linear light rather than on their
+    /// sRGB-encoded values, so blends don't darken
+    pub linear: bool,
+    /// Exposure, white balance and saturation, applied in linear light to
+    /// every sample
+    pub grade: ColorGrade,
+    /// What the source's pixels hold; normals are renormalized after
+    /// filtering and turned to match the cube
+    pub pixel_type: PixelType,
+    /// Guard band: extra pixels rendered past every face edge, continuing the
+    /// face plane into the neighbouring directions, so faces come out
+    /// `size + 2 * bleed` across
+    pub bleed: u32,
+    /// Row-level progress of each face as it renders
+    pub progress: Option<Progress>,
+    /// Run on each face once it's rendered, before it's returned or encoded
+    pub post_process: Option<PostProcess>,
+    /// Pool to render on; None uses the caller's current rayon pool. The
+    /// library never configures rayon's global pool itself
+    pub pool: Option<Arc<ThreadPool>>,
+    /// Sampling tables shared by renders of same-sized panoramas
+    pub lut: Option<Arc<LutCache>>,
+}
+
+impl Default for CubemapOptions {
+    fn default() -> Self {
+        CubemapOptions {
+            size: 1024,
+            filter: Filter::Bilinear,
+            ssaa: 1,
+            ssaa_adaptive: true,
+            rotation: Rotation::IDENTITY,
+            projection: CubeProjection::Standard,
+            offset: 0.0,
+            input: InputProjection::Equirect,
+            fill: Fill::default(),
+            linear: false,
+            grade: ColorGrade::IDENTITY,
+            pixel_type: PixelType::Color,
+            bleed: 0,
+            progress: None,
+            post_process: None,
+            pool: None,
+            lut: None,
+        }
+    }
+}
+
+impl CubemapOptions {
+    /// Reject parameters the renderers can't honour.
+    pub fn validate(&self) -> Result<(), CubemapError> {
+        if self.size == 0 {
+            return Err(CubemapError::Projection("face size must be at least 1".to_string()));
+        }
+        if !(1..=16).contains(&self.ssaa) {
+            return Err(CubemapError::Projection(format!("ssaa must be between 1 and 16, got {}", self.ssaa)));
+        }
+        if !(0.0..1.0).contains(&self.offset) {
+            return Err(CubemapError::Projection(format!(
+                "offset must be at least 0 and below 1, got {}",
+                self.offset
+            )));
+        }
+        if self.bleed > self.size / 2 {
+            return Err(CubemapError::Projection(format!(
+                "bleed of {} pixels is more than half the face size {}",
+                self.bleed, self.size
+            )));
+        }
+        if self.pixel_type != PixelType::Color && (self.linear || !self.grade.is_identity()) {
+            return Err(CubemapError::Projection(format!(
+                "{} pixels are vectors, not colors; they can't be filtered in linear light or graded",
+                self.pixel_type
+            )));
+        }
+        self.grade.validate()
+    }
+
+    /// Width and height of the rendered faces, guard band included.
+    pub fn face_size(&self) -> u32 {
+        self.size + 2 * self.bleed
+    }
+
+    /// Run `op` on `pool`, or right here without one.
+    pub(crate) fn install<R: Send>(&self, op: impl FnOnce() -> R + Send) -> R {
+        match &self.pool {
+            Some(pool) => pool.install(op),
+            None => op(),
+        }
+    }
+
+    /// `face` after the post-processing hook, if there is one.
+    pub(crate) fn post_process(&self, face: Face, img: DynamicImage) -> DynamicImage {
+        match &self.post_process {
+            Some(hook) => hook.apply_dynamic(face, img),
+            None => img,
+        }
+    }
+
+    /// Direction to sample for the cube direction `dir`: `dir` itself, or
+    /// for an offset cubemap, normalized and moved towards the (rotated)
+    /// front.
+    pub(crate) fn offset_direction(&self, dir: [f32; 3]) -> [f32; 3] {
+        if self.offset == 0.0 {
+            return dir;
+        }
+        let front = self.rotation.apply([0.0, 0.0, 1.0]);
+        let length = (dir[0] * dir[0] + dir[1] * dir[1] + dir[2] * dir[2]).sqrt();
+        std::array::from_fn(|i| dir[i] / length + self.offset * front[i])
+    }
+
+    /// Face-plane coordinate for the centre of pixel `i` of a rendered face.
+    pub(crate) fn face_coord(&self, i: u32) -> f32 {
+        self.projection.warp(texel_center(i as f32 - self.bleed as f32, self.size))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CubemapFaces<I = RgbImage> {
+    pub size: u32,
+    // Faces in Face::ALL order
+    pub faces: Vec<I>,
+}
+
+impl<I> CubemapFaces<I> {
+    pub fn iter(&self) -> impl Iterator<Item = (Face, &I)> {
+        Face::iter().zip(self.faces.iter())
+    }
+
+    pub fn get(&self, face: Face) -> &I {
+        &self.faces[face.index()]
+    }
+
+    /// Convert every face, e.g. to change the pixel type.
+    pub fn map<J>(self, mut f: impl FnMut(Face, I) -> J) -> CubemapFaces<J> {
+        CubemapFaces {
+            size: self.size,
+            faces: Face::iter().zip(self.faces).map(|(face, img)| f(face, img)).collect(),
+        }
+    }
+}
+
+impl<P> CubemapFaces<Buffer<P>>
+where
+    P: Pixel + Send + Sync,
+    P::Subpixel: Channel,
+{
+    /// Derive a smaller cubemap by gamma-correct downsampling of each face,
+    /// instead of rendering `size` again from the panorama.
+    pub fn downsample(&self, size: u32) -> CubemapFaces<Buffer<P>> {
+        let faces = self.faces.par_iter().map(|face| downsample_linear(face, size, size)).collect();
+        CubemapFaces { size, faces }
+    }
+}
+
+impl CubemapFaces<DynamicImage> {
+    /// `downsample` at each face's own precision.
+    pub fn downsample_dynamic(&self, size: u32) -> CubemapFaces<DynamicImage> {
+        let faces = self.faces.par_iter().map(|face| downsample_face_dynamic(face, size)).collect();
+        CubemapFaces { size, faces }
+    }
+}
+
+impl CubemapFaces<DynamicImage> {
+    /// Every face encoded in memory, in parallel.
+    pub fn encode(&self, encode: &EncodeOptions) -> Result<CubemapFaces<Vec<u8>>, CubemapError> {
+        let faces = self
+            .faces
+            .par_iter()
+            .map(|face| {
+                let mut data = std::io::Cursor::new(Vec::new());
+                encode_image(face, encode, &mut data)?;
+                Ok(data.into_inner())
+            })
+            .collect::<Result<_, CubemapError>>()?;
+        Ok(CubemapFaces { size: self.size, faces })
+    }
+}
+
+fn downsample_face_dynamic(face: &DynamicImage, size: u32) -> DynamicImage {
+    match face {
+        DynamicImage::ImageRgb8(img) => DynamicImage::ImageRgb8(downsample_linear(img, size, size)),
+        DynamicImage::ImageRgb16(img) => DynamicImage::ImageRgb16(downsample_linear(img, size, size)),
+        DynamicImage::ImageRgb32F(img) => DynamicImage::ImageRgb32F(downsample_linear(img, size, size)),
+        DynamicImage::ImageRgba8(img) => DynamicImage::ImageRgba8(downsample_linear(img, size, size)),
+        DynamicImage::ImageRgba16(img) => DynamicImage::ImageRgba16(downsample_linear(img, size, size)),
+        DynamicImage::ImageRgba32F(img) => DynamicImage::ImageRgba32F(downsample_linear(img, size, size)),
+        DynamicImage::ImageLuma8(img) => DynamicImage::ImageLuma8(downsample_linear(img, size, size)),
+        DynamicImage::ImageLuma16(img) => DynamicImage::ImageLuma16(downsample_linear(img, size, size)),
+        img if img.color().has_alpha() => downsample_face_dynamic(&PixelDepth::of(img).to_rgba(img.clone()), size),
+        img => downsample_face_dynamic(&PixelDepth::of(img).to_rgb(img.clone()), size),
+    }
+}
+
+impl<I: GenericImageView> CubemapFaces<I> {
+    /// Build a cubemap from six square faces of equal size in Face::ALL order.
+    pub fn from_faces(faces: Vec<I>) -> Result<Self, CubemapError> {
+        if faces.len() != 6 {
+            return Err(CubemapError::Projection(format!("expected 6 faces, got {}", faces.len())));
+        }
+        let size = faces[0].width();
+        for (face, img) in Face::iter().zip(&faces) {
+            if img.width() != size || img.height() != size {
+                return Err(CubemapError::Projection(format!(
+                    "face {} is {}x{}, expected {}x{}",
+                    face, img.width(), img.height(), size, size
+                )));
+            }
+        }
+        Ok(CubemapFaces { size, faces })
+    }
+}
+
+/// Render all faces at the source's pixel type. `options.post_process` isn't
+/// applied here, as the hook works on 8-bit faces; the `_dynamic` renderers
+/// run it.
+pub fn equirect_to_cubemap<P>(src: &Buffer<P>, options: &CubemapOptions) -> CubemapFaces<Buffer<P>>
+where
+    P: Pixel + Send + Sync,
+    P::Subpixel: Channel,
+{
+    let faces = options.install(|| Face::ALL.par_iter().map(|&face| render_face(src, face, options)).collect());
+
+    CubemapFaces { size: options.face_size(), faces }
+}
+
+/// Open and decode an input image. Radiance HDR files decode to float RGB;
+/// HEIC/HEIF needs the heic feature.
+pub fn load_image(path: &Path) -> Result<DynamicImage, CubemapError> {
+    let decoded = match ImageFormat::from_path(path) {
+        _ if heic::is_heif_path(path) => heic::load_heif(path),
+        // image's own Radiance adapter hands out clipped 8-bit RGB
+        Ok(ImageFormat::Hdr) => load_hdr(path),
+        // image's TIFF adapter refuses anything over 512MB, and 16-bit
+        // (Big)TIFF panoramas from stitchers easily run past that
+        Ok(ImageFormat::Tiff) => return ScanlineReader::open(path)?.read_image(),
+        _ => image::open(path),
+    };
+    decoded.map_err(|source| CubemapError::Decode { path: path.to_path_buf(), source })
+}
+
+/// `load_image` for an encoded image already in memory; the format is
+/// sniffed from its header.
+pub fn decode_image(bytes: &[u8]) -> image::ImageResult<DynamicImage> {
+    match stream::guess_format(bytes) {
+        _ if heic::is_heif(bytes) => heic::decode_heif(bytes),
+        Ok(ImageFormat::Hdr) => decode_hdr(bytes),
+        Ok(ImageFormat::Tiff) => stream::decode_tiff(bytes),
+        _ => image::load_from_memory(bytes),
+    }
+}
+
+/// Convert an encoded panorama held in memory, its format sniffed as in
+/// `decode_image`, to encoded faces without touching the filesystem.
+pub fn convert_bytes(
+    input: &[u8],
+    options: &CubemapOptions,
+    encode: &EncodeOptions,
+) -> Result<CubemapFaces<Vec<u8>>, CubemapError> {
+    options.validate()?;
+    let img = decode_image(input).map_err(|source| CubemapError::Decode { path: PathBuf::new(), source })?;
+    let img = color_model(img, options);
+    let cubemap = equirect_to_cubemap_dynamic(&img, options);
+    options.install(|| cubemap.encode(encode))
+}
+
+fn load_hdr(path: &Path) -> image::ImageResult<DynamicImage> {
+    let file = std::fs::File::open(path).map_err(image::ImageError::IoError)?;
+    decode_hdr(std::io::BufReader::new(file))
+}
+
+fn decode_hdr(reader: impl std::io::BufRead) -> image::ImageResult<DynamicImage> {
+    let decoder = HdrDecoder::new(reader)?;
+    let (width, height) = (decoder.metadata().width, decoder.metadata().height);
+    let data = decoder.read_image_hdr()?.into_iter().flat_map(|pixel| pixel.0).collect();
+    let img = Buffer::from_raw(width, height, data).expect("decoder returns width x height pixels");
+    Ok(DynamicImage::ImageRgb32F(img))
+}
+
+/// Render all faces at the input's precision: 16-bit inputs stay 16-bit,
+/// float (HDR) inputs stay float, everything else is processed as 8-bit.
+/// Inputs with alpha, and any with a see-through `options.fill`, render as
+/// RGBA; 8 and 16-bit gray ones as gray; the rest as RGB.
+pub fn equirect_to_cubemap_dynamic(src: &DynamicImage, options: &CubemapOptions) -> CubemapFaces<DynamicImage> {
+    // All six faces in one group, as `equirect_to_cubemap` renders them
+    let faces = equirect_to_cubemap_each(src, &Face::ALL, options, Face::ALL.len(), |_, img| Ok(img));
+    let faces = faces.unwrap_or_else(|never: std::convert::Infallible| match never {});
+    CubemapFaces { size: options.face_size(), faces }
+}
+
+// Whether `img` is already in the layout `color_model` would give it
+fn renders_directly(img: &DynamicImage, options: &CubemapOptions) -> bool {
+    match img {
+        DynamicImage::ImageRgb8(_) | DynamicImage::ImageRgb16(_) | DynamicImage::ImageRgb32F(_) => {
+            options.fill.is_opaque()
+        }
+        DynamicImage::ImageRgba8(_) | DynamicImage::ImageRgba16(_) | DynamicImage::ImageRgba32F(_) => true,
+        img => is_luma(img) && options.fill.is_opaque(),
+    }
+}
+
+// Single-channel gray, which renders as it is: a third of the work of RGB
+fn is_luma(img: &DynamicImage) -> bool {
+    matches!(img, DynamicImage::ImageLuma8(_) | DynamicImage::ImageLuma16(_))
+}
+
+// Faces get an alpha channel when the source has one or the fill can leave
+// them see-through
+fn keeps_alpha(img: &DynamicImage, options: &CubemapOptions) -> bool {
+    img.color().has_alpha() || !options.fill.is_opaque()
+}
+
+// `img` as RGB, or as RGBA when it `keeps_alpha`
+fn color_model(img: DynamicImage, options: &CubemapOptions) -> DynamicImage {
+    let depth = PixelDepth::of(&img);
+    match keeps_alpha(&img, options) {
+        true => depth.to_rgba(img),
+        false => depth.to_rgb(img),
+    }
+}
+
+/// `equirect_to_cubemap_dynamic` for callers that don't need the faces
+/// together: renders `faces` at most `concurrent` at a time and hands each
+/// to `sink` as it finishes, so no more than `concurrent` face buffers are
+/// alive at once. Returns what `sink` returned, in `faces` order, or the
+/// first error it returned.
+pub fn equirect_to_cubemap_each<T, E>(
+    src: &DynamicImage,
+    faces: &[Face],
+    options: &CubemapOptions,
+    concurrent: usize,
+    sink: impl Fn(Face, DynamicImage) -> Result<T, E> + Sync,
+) -> Result<Vec<T>, E>
+where
+    T: Send,
+    E: Send,
+{
+    let sink = &|face, img| sink(face, options.post_process(face, img));
+    options.install(|| {
+        let depth = PixelDepth::of(src);
+        if options.linear && depth != PixelDepth::F32 && keeps_alpha(src, options) {
+            let linear = pixel::linearize_rgba(src);
+            let finish = |face| pixel::delinearize_rgba(face, depth);
+            return render_each(&linear, faces, options, concurrent, finish, sink);
+        }
+        if options.linear && depth != PixelDepth::F32 && renders_directly(src, options) && is_luma(src) {
+            let linear = pixel::linearize_luma(src);
+            let finish = |face| pixel::delinearize_luma(face, depth);
+            return render_each(&linear, faces, options, concurrent, finish, sink);
+        }
+        if options.linear && depth != PixelDepth::F32 {
+            let linear = pixel::linearize(src);
+            return render_each(&linear, faces, options, concurrent, |face| pixel::delinearize(face, depth), sink);
+        }
+        let converted;
+        let src = match src {
+            img if renders_directly(img, options) => src,
+            img => {
+                converted = color_model(img.clone(), options);
+                &converted
+            }
+        };
+        match src {
+            DynamicImage::ImageRgb8(img) => render_each(img, faces, options, concurrent, DynamicImage::ImageRgb8, sink),
+            DynamicImage::ImageRgb16(img) => {
+                render_each(img, faces, options, concurrent, DynamicImage::ImageRgb16, sink)
+            }
+            DynamicImage::ImageRgb32F(img) => {
+                render_each(img, faces, options, concurrent, DynamicImage::ImageRgb32F, sink)
+            }
+            DynamicImage::ImageRgba8(img) => {
+                render_each(img, faces, options, concurrent, DynamicImage::ImageRgba8, sink)
+            }
+            DynamicImage::ImageRgba16(img) => {
+                render_each(img, faces, options, concurrent, DynamicImage::ImageRgba16, sink)
+            }
+            DynamicImage::ImageRgba32F(img) => {
+                render_each(img, faces, options, concurrent, DynamicImage::ImageRgba32F, sink)
+            }
+            DynamicImage::ImageLuma8(img) => {
+                render_each(img, faces, options, concurrent, DynamicImage::ImageLuma8, sink)
+            }
+            DynamicImage::ImageLuma16(img) => {
+                render_each(img, faces, options, concurrent, DynamicImage::ImageLuma16, sink)
+            }
+            _ => unreachable!("converted to RGB or RGBA above"),
+        }
+    })
+}
+
+fn render_each<P, T, E>(
+    src: &Buffer<P>,
+    faces: &[Face],
+    options: &CubemapOptions,
+    concurrent: usize,
+    finish: impl Fn(Buffer<P>) -> DynamicImage + Sync,
+    sink: &(impl Fn(Face, DynamicImage) -> Result<T, E> + Sync),
+) -> Result<Vec<T>, E>
+where
+    P: Pixel + Send + Sync,
+    P::Subpixel: Channel,
+    T: Send,
+    E: Send,
+{
+    let mut results = Vec::with_capacity(faces.len());
+    // Each face's rows still render in parallel, so a group of one keeps
+    // every thread busy until it's encoded
+    for group in faces.chunks(concurrent.max(1)) {
+        let done = group.par_iter().map(|&face| sink(face, finish(render_face(src, face, options))));
+        results.extend(done.collect::<Result<Vec<_>, _>>()?);
+    }
+    Ok(results)
+}
+
+pub fn render_face<P>(src: &Buffer<P>, face: Face, options: &CubemapOptions) -> Buffer<P>
+where
+    P: Pixel + Send + Sync,
+    P::Subpixel: Channel,
+{
+    let size = options.face_size();
+    let basis = options.rotation.apply_basis(face.basis());
+    // Face-plane coordinate of every pixel column (and row)
+    let coords: Vec<f32> = (0..size).map(|i| options.face_coord(i)).collect();
+    let mut face_buffer: Buffer<P> = Buffer::new(size, size);
+    let channels = P::CHANNEL_COUNT as usize;
+    let lut = options.lut.as_ref().and_then(|cache| cache.face(src.width(), src.height(), face, options));
+    let frame = options.pixel_type.frame(face, &options.rotation);
+
+    // One task per row of the raw buffer; pixel positions follow from the
+    // indices
+    let rows_done = AtomicU32::new(0);
+    face_buffer
+        .par_chunks_mut(size as usize * channels)
+        .enumerate()
+        .for_each(|(y, row)| {
+            let b = coords[y];
+            if options.ssaa > 1 {
+                for (x, pixel) in row.chunks_exact_mut(channels).enumerate() {
+                    *P::from_slice_mut(pixel) = ssaa::supersample(src, &basis, x as u32, y as u32, options);
+                }
+            } else if let Some(lut) = &lut {
+                lut.render_row(src, y, row);
+            } else if options.input != InputProjection::Equirect || options.offset > 0.0 {
+                for (pixel, &a) in row.chunks_exact_mut(channels).zip(&coords) {
+                    let dir = options.offset_direction(basis.direction(a, b));
+                    *P::from_slice_mut(pixel) =
+                        source::sample_direction(src, &options.input, options.fill, dir, options.filter);
+                }
+            } else {
+                for (group, a) in row.chunks_mut(simd::LANES * channels).zip(coords.chunks(simd::LANES)) {
+                    simd::render_pixels(src, &basis, a, b, options.filter, group);
+                }
+            }
+            options.grade.apply_row(row, channels);
+            if let Some(frame) = &frame {
+                normal::apply_row(frame, row, channels);
+            }
+            if let Some(progress) = &options.progress {
+                progress.report(face, rows_done.fetch_add(1, Ordering::Relaxed) + 1, size);
+            }
+        });
+
+    face_buffer
+}
+
+/// Face-plane coordinate in [-1, 1] of the centre of texel `i` of a face
+/// `size` texels across, so the first and last texels sit half a texel in
+/// from either edge, where engines sampling at `(i + 0.5) / size` read them.
+pub(crate) fn texel_center(i: f32, size: u32) -> f32 {
+    (2.0 * i + 1.0) / size as f32 - 1.0
+}
+
+/// Direction through the centre of pixel (x, y) of a `size` x `size` face,
+/// built from the face's basis vectors; not normalized.
+pub fn cube_to_direction(x: u32, y: u32, size: u32, face: Face) -> [f32; 3] {
+    face.basis().direction(texel_center(x as f32, size), texel_center(y as f32, size))
+}
+
+/// Equirect (u, v) for pixel (x, y) of a `size` x `size` face: its direction,
+/// converted to longitude and latitude.
+pub fn cube_to_spherical(x: u32, y: u32, size: u32, face: Face) -> (f32, f32) {
+    direction_to_spherical(cube_to_direction(x, y, size, face))
+}
+
+/// Equirect (u, v) for face-plane coordinates (x, y) in [-1, 1].
+pub fn face_to_spherical(x: f32, y: f32, face: Face) -> (f32, f32) {
+    basis_to_spherical(x, y, face.basis())
+}
+
+/// Equirect (u, v) for face-plane coordinates on an arbitrary (e.g. rotated)
+/// face basis.
+pub fn basis_to_spherical(x: f32, y: f32, basis: &FaceBasis) -> (f32, f32) {
+    direction_to_spherical(basis.direction(x, y))
+}
+
+/// Equirect (u, v) for a direction of any length.
+pub fn direction_to_spherical(dir: [f32; 3]) -> (f32, f32) {
+    let r = (dir[0] * dir[0] + dir[1] * dir[1] + dir[2] * dir[2]).sqrt();
+
+    // Longitude around +Y with the front face at the centre of the panorama,
+    // latitude measured down from the zenith
+    let phi = dir[0].atan2(dir[2]);
+    let theta = (dir[1] / r).acos();
+    (phi / (2.0 * std::f32::consts::PI) + 0.5, theta / std::f32::consts::PI)
+}
+
+/// Inverse of `direction_to_spherical`: unit direction for equirect coordinates.
+pub fn spherical_to_direction(u: f32, v: f32) -> [f32; 3] {
+    let phi = (u - 0.5) * 2.0 * std::f32::consts::PI;
+    let theta = v * std::f32::consts::PI;
+    [theta.sin() * phi.sin(), theta.cos(), theta.sin() * phi.cos()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f32 = 1e-5;
+
+    fn assert_uv((u, v): (f32, f32), (expected_u, expected_v): (f32, f32)) {
+        assert!(
+            (u - expected_u).abs() < EPSILON && (v - expected_v).abs() < EPSILON,
+            "got ({}, {}), expected ({}, {})",
+            u, v, expected_u, expected_v
+        );
+    }
+
+    #[test]
+    fn face_centers() {
+        // An odd size has a pixel whose centre is the middle of the face
+        let size = 65;
+        assert_uv(cube_to_spherical(32, 32, size, Face::Front), (0.5, 0.5));
+        assert_uv(cube_to_spherical(32, 32, size, Face::Right), (0.75, 0.5));
+        assert_uv(cube_to_spherical(32, 32, size, Face::Left), (0.25, 0.5));
+        // The back is on the seam: u = 1 and u = 0 are the same longitude
+        let (u, v) = cube_to_spherical(32, 32, size, Face::Back);
+        assert_uv((u.rem_euclid(1.0), v), (0.0, 0.5));
+        assert!((cube_to_spherical(32, 32, size, Face::Up).1).abs() < EPSILON);
+        assert!((cube_to_spherical(32, 32, size, Face::Down).1 - 1.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn face_corners() {
+        // Top left of the front face looks left, up and forward
+        let v = (1.0 / 3f32.sqrt()).acos() / std::f32::consts::PI;
+        assert_eq!(Face::Front.basis().direction(-1.0, -1.0), [-1.0, 1.0, 1.0]);
+        assert_uv(face_to_spherical(-1.0, -1.0, Face::Front), (0.375, v));
+        assert_uv(face_to_spherical(1.0, 1.0, Face::Front), (0.625, 1.0 - v));
+        // Top left of the right face is its corner with the front
+        assert_uv(face_to_spherical(-1.0, -1.0, Face::Right), (0.625, v));
+        // The up face's top edge borders the back, its bottom edge the front
+        assert_eq!(Face::Up.basis().direction(-1.0, -1.0), [-1.0, 1.0, -1.0]);
+        assert_eq!(Face::Up.basis().direction(1.0, 1.0), [1.0, 1.0, 1.0]);
+        assert_eq!(Face::Down.basis().direction(-1.0, -1.0), [-1.0, -1.0, 1.0]);
+    }
+
+    #[test]
+    fn face_edges() {
+        assert_uv(face_to_spherical(-1.0, 0.0, Face::Front), (0.375, 0.5));
+        assert_uv(face_to_spherical(0.0, -1.0, Face::Front), (0.5, 0.25));
+        // Neighbouring faces meet along the same directions
+        for t in [-1.0, -0.5, 0.0, 0.25, 1.0] {
+            let edge = |face: Face, a: f32, b: f32| face.basis().direction(a, b);
+            assert_eq!(edge(Face::Front, 1.0, t), edge(Face::Right, -1.0, t));
+            assert_eq!(edge(Face::Right, 1.0, t), edge(Face::Back, -1.0, t));
+            assert_eq!(edge(Face::Back, 1.0, t), edge(Face::Left, -1.0, t));
+            assert_eq!(edge(Face::Left, 1.0, t), edge(Face::Front, -1.0, t));
+            assert_eq!(edge(Face::Up, t, 1.0), edge(Face::Front, t, -1.0));
+            assert_eq!(edge(Face::Down, t, -1.0), edge(Face::Front, t, 1.0));
+        }
+    }
+
+    #[test]
+    fn pixels_sample_their_centres() {
+        // The first and last pixels sit half a pixel in from the edges,
+        // mirror images of each other
+        assert_eq!(cube_to_direction(0, 0, 64, Face::Front), [-63.0 / 64.0, 63.0 / 64.0, 1.0]);
+        assert_eq!(cube_to_direction(63, 63, 64, Face::Front), [63.0 / 64.0, -63.0 / 64.0, 1.0]);
+        for face in Face::ALL {
+            for y in [0, 17, 63] {
+                let (first, last) = (cube_to_direction(0, y, 64, face), cube_to_direction(63, y, 64, face));
+                let basis = face.basis();
+                let along = |dir: [f32; 3]| (0..3).map(|i| dir[i] * basis.right[i]).sum::<f32>();
+                assert!((along(first) + along(last)).abs() < EPSILON, "{} row {}", face, y);
+            }
+        }
+        // Pixels either side of an edge are as far from it as each other
+        let front = cube_to_direction(63, 20, 64, Face::Front);
+        let right = cube_to_direction(0, 20, 64, Face::Right);
+        assert_eq!([front[0], front[1], front[2]], [right[2], right[1], right[0]]);
+
+        // Sampling a face back at a pixel's centre returns that pixel
+        let pixel = |face: Face, x: u32, y: u32| image::Rgb([x as u8, y as u8, face.index() as u8]);
+        let faces = Face::ALL.map(|face| RgbImage::from_fn(8, 8, |x, y| pixel(face, x, y)));
+        let faces = CubemapFaces::from_faces(faces.to_vec()).unwrap();
+        for face in Face::ALL {
+            for (x, y) in [(0, 0), (7, 0), (3, 5), (7, 7)] {
+                assert_eq!(sample_cubemap(&faces, cube_to_direction(x, y, 8, face)), pixel(face, x, y));
+            }
+        }
+    }
+
+    #[test]
+    fn directions_round_trip() {
+        for face in Face::ALL {
+            for (a, b) in [(0.0, 0.0), (-0.5, 0.25), (0.9, -0.9)] {
+                let dir = face.basis().direction(a, b);
+                let (hit, a2, b2) = Face::from_direction(dir);
+                assert_eq!(hit, face);
+                assert!((a - a2).abs() < EPSILON && (b - b2).abs() < EPSILON, "{}: ({}, {})", face, a, b);
+
+                let (u, v) = direction_to_spherical(dir);
+                let back = spherical_to_direction(u, v);
+                let length = dir.iter().map(|c| c * c).sum::<f32>().sqrt();
+                for (c, expected) in back.iter().zip(dir) {
+                    assert!((c - expected / length).abs() < EPSILON, "{}: ({}, {})", face, a, b);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn transparent_fill_renders_alpha() {
+        let src = DynamicImage::ImageRgb8(RgbImage::from_pixel(64, 16, image::Rgb([200, 100, 50])));
+        let options = CubemapOptions {
+            size: 8,
+            input: InputProjection::PartialEquirect(PanoCrop::vertical_band(64, 90.0, 0.0)),
+            fill: Fill::TRANSPARENT,
+            ..CubemapOptions::default()
+        };
+        let faces = equirect_to_cubemap_dynamic(&src, &options);
+        let DynamicImage::ImageRgba8(front) = faces.get(Face::Front) else { panic!("expected RGBA faces") };
+        let DynamicImage::ImageRgba8(up) = faces.get(Face::Up) else { panic!("expected RGBA faces") };
+        assert_eq!(front.get_pixel(4, 4).0, [200, 100, 50, 255]);
+        assert_eq!(up.get_pixel(4, 4).0[3], 0);
+    }
+
+    #[test]
+    fn gray_renders_match_rgb() {
+        let gray = image::GrayImage::from_fn(96, 48, |x, y| image::Luma([((x * 3 + y * 5) % 256) as u8]));
+        let rgb = DynamicImage::ImageLuma8(gray.clone()).to_rgb8();
+        let options = CubemapOptions { size: 16, filter: Filter::Bicubic, ..CubemapOptions::default() };
+        let faces = equirect_to_cubemap_dynamic(&DynamicImage::ImageLuma8(gray), &options);
+        let expected = equirect_to_cubemap(&rgb, &options);
+        for (face, rgb) in faces.faces.iter().zip(&expected.faces) {
+            let DynamicImage::ImageLuma8(face) = face else { panic!("expected gray faces") };
+            assert!(face.pixels().zip(rgb.pixels()).all(|(gray, rgb)| gray[0] == rgb[0]));
+        }
+    }
+}
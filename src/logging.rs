@@ -0,0 +1,84 @@
+use crate::cli::LogFormat;
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, MakeWriter};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+
+// Log lines go to stdout, or to stderr while stdout carries the output
+// itself (`--output -`); warnings and errors always go to stderr
+pub static STDOUT_IS_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+/// Send this tool's events at the level `verbose` and `quiet` pick (info
+/// without either) to stdout or stderr in `format`. Other crates' events
+/// show from warnings up, or from debug with -vv.
+pub fn init(verbose: u8, quiet: u8, format: LogFormat) {
+    let (level, others) = match (verbose, quiet) {
+        (0, 0) => (LevelFilter::INFO, LevelFilter::WARN),
+        (1, _) => (LevelFilter::DEBUG, LevelFilter::WARN),
+        (_, 0) => (LevelFilter::TRACE, LevelFilter::DEBUG),
+        (_, 1) => (LevelFilter::WARN, LevelFilter::WARN),
+        _ => (LevelFilter::ERROR, LevelFilter::ERROR),
+    };
+    let targets = Targets::new().with_target(env!("CARGO_CRATE_NAME"), level).with_default(others);
+    let registry = tracing_subscriber::registry().with(targets);
+    let layer = tracing_subscriber::fmt::layer().with_writer(Streams);
+    match format {
+        LogFormat::Text => registry.with(layer.event_format(Plain)).init(),
+        // The file and size a line belongs to come along as span fields
+        LogFormat::Json => registry.with(layer.json().flatten_event(true)).init(),
+    }
+}
+
+// Picks the stream for each line by its level
+struct Streams;
+
+impl<'a> MakeWriter<'a> for Streams {
+    type Writer = Box<dyn Write + 'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        match STDOUT_IS_OUTPUT.load(Ordering::Relaxed) {
+            true => Box::new(io::stderr().lock()),
+            false => Box::new(io::stdout().lock()),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        match *meta.level() <= Level::WARN {
+            true => Box::new(io::stderr().lock()),
+            false => self.make_writer(),
+        }
+    }
+}
+
+// Just the message, as the tool printed before it had log levels; the
+// fields are for the JSON format
+struct Plain;
+
+impl<S, N> FormatEvent<S, N> for Plain
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(&self, _: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        let mut message = Message(String::new());
+        event.record(&mut message);
+        writeln!(writer, "{}", message.0)
+    }
+}
+
+struct Message(String);
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{:?}", value);
+        }
+    }
+}
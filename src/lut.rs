@@ -0,0 +1,255 @@
+use crate::sampler::{premultiply, unpremultiply};
+use crate::{
+    direction_to_spherical, simd, write_atomic, Buffer, Channel, CubemapOptions, Face, Filter, InputProjection,
+};
+use image::Pixel;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// Marks a table file; the number goes up whenever the layout changes
+const MAGIC: &[u8; 8] = b"RCUBLUT2";
+
+/// Precomputed sampling for bilinear renders of equirect panoramas. The
+/// panorama position every face pixel reads depends only on the panorama's
+/// size and the face geometry, so batches, sequences and video frames of
+/// one size can look it up instead of redoing the trigonometry per pixel.
+///
+/// Tables take 16 bytes per face pixel and stay in memory for the life of
+/// the cache; with a directory they are also written there and read back
+/// by later runs. Renders the tables don't cover (other filters, `ssaa`,
+/// other input projections) ignore the cache.
+#[derive(Debug, Default)]
+pub struct LutCache {
+    dir: Option<PathBuf>,
+    tables: Mutex<HashMap<LutKey, Arc<FaceLut>>>,
+}
+
+// What a face's taps depend on: panorama size, face, face size, guard band,
+// projection, offset and every entry of the rotation matrix
+type LutKey = [u32; 16];
+
+/// Where each pixel of one face reads the panorama, row by row.
+#[derive(Debug)]
+pub(crate) struct FaceLut {
+    taps: Vec<Tap>,
+}
+
+// Top left texel of a pixel's 2x2 bilinear neighbourhood and its weights
+// towards the right and lower texels
+#[derive(Debug, Clone, Copy)]
+struct Tap {
+    x0: u32,
+    y0: u32,
+    fx: f32,
+    fy: f32,
+}
+
+impl LutCache {
+    /// A cache held in memory only.
+    pub fn new() -> LutCache {
+        LutCache::default()
+    }
+
+    /// A cache that also keeps its tables as files in `dir`, created if
+    /// need be. Files that can't be read or written are rebuilt or skipped.
+    pub fn with_dir(dir: impl Into<PathBuf>) -> LutCache {
+        LutCache { dir: Some(dir.into()), tables: Mutex::default() }
+    }
+
+    /// Number of face tables held in memory.
+    pub fn len(&self) -> usize {
+        self.tables.lock().expect("lut cache lock").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The table for `face` of a `width` x `height` panorama rendered with
+    /// `options`, built on first use; None where tables don't apply.
+    pub(crate) fn face(&self, width: u32, height: u32, face: Face, options: &CubemapOptions) -> Option<Arc<FaceLut>> {
+        if options.filter != Filter::Bilinear || options.ssaa > 1 || options.input != InputProjection::Equirect {
+            return None;
+        }
+        let key = key(width, height, face, options);
+        if let Some(table) = self.tables.lock().expect("lut cache lock").get(&key) {
+            return Some(table.clone());
+        }
+        // Built outside the lock: another thread may build the same table
+        // meanwhile, which costs time but not correctness
+        let path = self.dir.as_ref().map(|dir| dir.join(file_name(&key)));
+        let table = match path.as_deref().and_then(|path| FaceLut::load(path, &key).ok()) {
+            Some(table) => table,
+            None => {
+                let table = FaceLut::build(width, height, face, options);
+                if let Some(path) = &path {
+                    let _ = table.save(path, &key);
+                }
+                table
+            }
+        };
+        let table = Arc::new(table);
+        Some(self.tables.lock().expect("lut cache lock").entry(key).or_insert(table).clone())
+    }
+}
+
+impl FaceLut {
+    fn build(width: u32, height: u32, face: Face, options: &CubemapOptions) -> FaceLut {
+        let size = options.face_size();
+        let basis = options.rotation.apply_basis(face.basis());
+        let coords: Vec<f32> = (0..size).map(|i| options.face_coord(i)).collect();
+        // The same positions as the renderers compute: the SIMD path for
+        // regular cubemaps, the scalar one for offset ones
+        let taps = coords
+            .par_iter()
+            .flat_map_iter(|&b| {
+                let mut row = Vec::with_capacity(size as usize);
+                if options.offset > 0.0 {
+                    row.extend(coords.iter().map(|&a| {
+                        let (u, v) = direction_to_spherical(options.offset_direction(basis.direction(a, b)));
+                        let x = (u * width as f32).rem_euclid(width as f32);
+                        let y = (v * height as f32).clamp(0.0, (height - 1) as f32);
+                        Tap { x0: x.floor() as u32 % width, y0: y.floor() as u32, fx: x.fract(), fy: y.fract() }
+                    }));
+                } else {
+                    for a in coords.chunks(simd::LANES) {
+                        let (x0, y0, fx, fy) = simd::bilinear_taps(width, height, &basis, a, b);
+                        row.extend((0..a.len()).map(|i| Tap { x0: x0[i], y0: y0[i], fx: fx[i], fy: fy[i] }));
+                    }
+                }
+                row
+            })
+            .collect();
+        FaceLut { taps }
+    }
+
+    /// Sample row `y` of the face from `src` into `row`'s raw channels.
+    pub(crate) fn render_row<P>(&self, src: &Buffer<P>, y: usize, row: &mut [P::Subpixel])
+    where
+        P: Pixel,
+        P::Subpixel: Channel,
+    {
+        let channels = P::CHANNEL_COUNT as usize;
+        let size = row.len() / channels;
+        let (width, height) = (src.width(), src.height());
+        for (pixel, tap) in row.chunks_exact_mut(channels).zip(&self.taps[y * size..][..size]) {
+            let x1 = (tap.x0 + 1) % width;
+            let y1 = (tap.y0 + 1).min(height - 1);
+            let p00 = premultiply(src.get_pixel(tap.x0, tap.y0).channels());
+            let p10 = premultiply(src.get_pixel(x1, tap.y0).channels());
+            let p01 = premultiply(src.get_pixel(tap.x0, y1).channels());
+            let p11 = premultiply(src.get_pixel(x1, y1).channels());
+            let mut blended = [0.0f32; 4];
+            for (c, value) in blended.iter_mut().enumerate() {
+                let c0 = p00[c] * (1.0 - tap.fx) + p10[c] * tap.fx;
+                let c1 = p01[c] * (1.0 - tap.fx) + p11[c] * tap.fx;
+                *value = c0 * (1.0 - tap.fy) + c1 * tap.fy;
+            }
+            unpremultiply(blended, pixel);
+        }
+    }
+
+    // Magic, key, tap count, then the taps; all little-endian
+    fn save(&self, path: &Path, key: &LutKey) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut data = Vec::with_capacity(MAGIC.len() + 4 * key.len() + 8 + 16 * self.taps.len());
+        data.extend_from_slice(MAGIC);
+        key.iter().for_each(|value| data.extend_from_slice(&value.to_le_bytes()));
+        data.extend_from_slice(&(self.taps.len() as u64).to_le_bytes());
+        for tap in &self.taps {
+            for value in [tap.x0, tap.y0, tap.fx.to_bits(), tap.fy.to_bits()] {
+                data.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        // Written aside and renamed, so a concurrent reader never sees half
+        write_atomic(path, &data)
+    }
+
+    fn load(path: &Path, key: &LutKey) -> io::Result<FaceLut> {
+        let mut data = Vec::new();
+        std::fs::File::open(path)?.read_to_end(&mut data)?;
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "not a table for these options");
+        let header = MAGIC.len() + 4 * key.len() + 8;
+        if data.len() < header || &data[..MAGIC.len()] != MAGIC {
+            return Err(invalid());
+        }
+        let word = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().expect("4 bytes"));
+        if (0..key.len()).any(|i| word(MAGIC.len() + 4 * i) != key[i]) {
+            return Err(invalid());
+        }
+        let count = u64::from_le_bytes(data[header - 8..header].try_into().expect("8 bytes")) as usize;
+        if data.len() != header + 16 * count {
+            return Err(invalid());
+        }
+        let taps = (0..count)
+            .map(|i| {
+                let at = header + 16 * i;
+                Tap {
+                    x0: word(at),
+                    y0: word(at + 4),
+                    fx: f32::from_bits(word(at + 8)),
+                    fy: f32::from_bits(word(at + 12)),
+                }
+            })
+            .collect();
+        Ok(FaceLut { taps })
+    }
+}
+
+fn key(width: u32, height: u32, face: Face, options: &CubemapOptions) -> LutKey {
+    let mut key = [0; 16];
+    key[..7].copy_from_slice(&[
+        width,
+        height,
+        face as u32,
+        options.size,
+        options.bleed,
+        options.projection as u32,
+        options.offset.to_bits(),
+    ]);
+    let matrix = options.rotation.matrix();
+    for (slot, value) in key[7..].iter_mut().zip(matrix.iter().flatten()) {
+        *slot = value.to_bits();
+    }
+    key
+}
+
+// FNV-1a of the key, which unlike std's hasher stays the same between builds
+fn file_name(key: &LutKey) -> String {
+    let hash = key.iter().flat_map(|value| value.to_le_bytes()).fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{}_{}_{:016x}.lut", Face::ALL[key[2] as usize].name(), key[3], hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{equirect_to_cubemap, Rotation};
+    use image::RgbImage;
+
+    #[test]
+    fn lut_renders_match() {
+        let src = RgbImage::from_fn(96, 48, |x, y| {
+            image::Rgb([(x * 2) as u8, (y * 5) as u8, ((x + y) % 7 * 30) as u8])
+        });
+        for offset in [0.0, 0.4] {
+            let options = CubemapOptions {
+                size: 20,
+                rotation: Rotation::from_euler_degrees(30.0, 10.0, 0.0),
+                offset,
+                ..CubemapOptions::default()
+            };
+            let cached = CubemapOptions { lut: Some(Arc::new(LutCache::new())), ..options.clone() };
+            for _ in 0..2 {
+                assert_eq!(equirect_to_cubemap(&src, &cached).faces, equirect_to_cubemap(&src, &options).faces);
+            }
+            assert_eq!(cached.lut.as_ref().map(|lut| lut.len()), Some(6));
+        }
+    }
+}
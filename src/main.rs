@@ -1,163 +1,1981 @@
-use anyhow::Result;
-use image::{ImageBuffer, Rgb, codecs::jpeg::JpegEncoder};
-use std::fs::File;
-use std::io::BufWriter;
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use image::{DynamicImage, Pixel};
+use rust_cube::{
+    assemble_layout_dynamic, blurhash, compare_equirect, cubemap_to_equirect, cubemap_to_equirect_dynamic, cut_tiles,
+    detect_up, equirect_to_cubemap_dynamic, equirect_to_cubemap_each, equirect_to_cubemap_streaming,
+    irradiance_cubemap_dynamic, level_rotation, load_image, prefilter_specular_dynamic, preview_montage, preview_strip,
+    render_envmap_dynamic, render_view_dynamic, resample_cubemap_dynamic, save_image, split_layout,
+    split_layout_dynamic, write_atomic, write_dds_levels, write_ktx2_levels, write_safetensors, Buffer, Channel,
+    ColorGrade, Convention, CubeProjection, CubemapFaces, CubemapOptions, DdsOptions, DualFisheye, EncodeOptions,
+    EnvMapOptions, EnvMapping, Face, Fill, Filter, InputProjection, Ktx2Options, Layout, LutCache, Metadata, NadirPatch,
+    OutputFormat, PanoCrop, PanoPose, PixelDepth, PixelType, PngCompression, Rotation, SpecularOptions,
+    SphericalHarmonics, ViewOptions,
+};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use rayon::prelude::*;
-use std::time::Instant;
-use num_cpus;
+use rayon::ThreadPool;
+use serde_json::json;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, info_span, warn, Level, Span};
 
-fn init_rayon() {
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(num_cpus::get())
-        .build_global()
-        .unwrap();
+mod bars;
+mod cli;
+#[cfg(unix)]
+mod daemon;
+mod jobs;
+mod logging;
+mod manifest;
+mod metrics;
+mod report;
+mod rotations;
+#[cfg(feature = "serve")]
+mod serve;
+mod storage;
+mod video;
+mod watch;
+
+use bars::FaceBars;
+use cli::{
+    AvifArgs, Cli, Command, ContainerArg, ConvertArgs, EnvMapArgs, EquirectArgs, IfExists, JpegArgs, ResampleArgs,
+    ShFormat, TestPatternArgs, TilesArgs, ToneMapArgs, VerifyArgs, ViewArgs, WebpArgs,
+};
+use manifest::{pretty, Manifest, ManifestFile, ManifestRecord, ManifestSource};
+use metrics::{Metrics, Stage};
+use report::{Failures, InputFailed, InputReport, Report};
+use rotations::RotationTable;
+use storage::{encode_to_vec, sha256_hex, Destination, LastDecoded, Source, StoredFile};
+
+// Our own pool rather than rayon's global one; each command enters it
+// around its parallel work
+fn thread_pool(threads: Option<u32>) -> Result<Arc<ThreadPool>> {
+    let threads = threads.map_or_else(num_cpus::get, |threads| threads as usize);
+    Ok(Arc::new(rayon::ThreadPoolBuilder::new().num_threads(threads).build()?))
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    logging::init(cli.verbose, cli.quiet, cli.log_format);
+    match run(cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            error!("Error: {:#}", err);
+            ExitCode::from(report::exit_code(&Err(err)))
+        }
+    }
+}
+
+fn run(cli: Cli) -> Result<()> {
+    #[cfg(unix)]
+    if let (None, Some(socket)) = (&cli.command, &cli.convert.daemon) {
+        return daemon::send(socket);
+    }
+    let pool = thread_pool(cli.threads)?;
+
+    match cli.command {
+        Some(Command::Equirect(args)) => pool.install(|| run_equirect(&args)),
+        Some(Command::Tiles(args)) => pool.install(|| run_tiles(&args)),
+        Some(Command::Resample(args)) => pool.install(|| run_resample(&args)),
+        Some(Command::View(args)) => pool.install(|| run_view(&args)),
+        Some(Command::Envmap(args)) => pool.install(|| run_envmap(&args)),
+        Some(Command::Video(args)) => pool.install(|| video::run_video(&args)),
+        Some(Command::Verify(args)) => pool.install(|| run_verify(&args)),
+        Some(Command::TestPattern(args)) => pool.install(|| run_test_pattern(&args)),
+        // Requests, batch jobs and watched files enter the pool one by one
+        #[cfg(feature = "serve")]
+        Some(Command::Serve(args)) => serve::run_serve(&args, pool),
+        #[cfg(unix)]
+        Some(Command::Daemon(args)) => daemon::run_daemon(&args, pool),
+        None => run_convert(&cli.convert, &Shared::new(pool)),
+    }
+}
+
+// What outlives a single convert command: the thread pool, and in the
+// daemon the sampling tables, the last decoded panorama and the metrics
+#[derive(Clone)]
+struct Shared {
+    pool: Arc<ThreadPool>,
+    lut: Option<Arc<LutCache>>,
+    last_decoded: Option<Arc<LastDecoded>>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl Shared {
+    fn new(pool: Arc<ThreadPool>) -> Shared {
+        Shared { pool, lut: None, last_decoded: None, metrics: None }
+    }
+}
+
+// Face renderer: the GPU when built with `gpu` and requested, otherwise rayon
+struct Renderer {
+    #[cfg(feature = "gpu")]
+    gpu: Option<rust_cube::GpuContext>,
+    progress: bool,
+    pool: Arc<ThreadPool>,
+    // Sampling tables shared by every file of a batch
+    lut: Option<Arc<LutCache>>,
+    last_decoded: Option<Arc<LastDecoded>>,
+    metrics: Option<Arc<Metrics>>,
+    report: Option<Arc<Report>>,
+}
+
+impl Renderer {
+    fn new(cli: &ConvertArgs, shared: &Shared, report: Option<Arc<Report>>) -> Renderer {
+        // Concurrent batch jobs would draw over each other's bars
+        let batch = cli.input_glob.is_some() || cli.start.is_some() || cli.watch.is_some();
+        let progress = !cli.no_progress && (!batch || cli.jobs == 1) && tracing::enabled!(Level::INFO);
+        let lut = match (&cli.lut_cache, &shared.lut) {
+            (Some(dir), _) => Some(Arc::new(LutCache::with_dir(dir))),
+            (None, Some(lut)) => Some(lut.clone()),
+            (None, None) => batch.then(|| Arc::new(LutCache::new())),
+        };
+        let (pool, last_decoded, metrics) = (shared.pool.clone(), shared.last_decoded.clone(), shared.metrics.clone());
+        #[cfg(feature = "gpu")]
+        {
+            let gpu = if cli.gpu { rust_cube::GpuContext::new() } else { None };
+            match &gpu {
+                Some(gpu) => info!("Using GPU: {}", gpu.adapter_name()),
+                None if cli.gpu => info!("No GPU adapter found, rendering on the CPU"),
+                None => {}
+            }
+            Renderer { gpu, progress, pool, lut, last_decoded, metrics, report }
+        }
+        #[cfg(not(feature = "gpu"))]
+        Renderer { progress, pool, lut, last_decoded, metrics, report }
+    }
+
+    // Time since `start` spent on `stage`, for services that export metrics
+    fn observe(&self, stage: Stage, start: Instant) {
+        if let Some(metrics) = &self.metrics {
+            metrics.observe(stage, start.elapsed());
+        }
+    }
+
+    fn load_image(&self, source: &Source) -> Result<DynamicImage> {
+        let start = Instant::now();
+        let img = match &self.last_decoded {
+            Some(last) => last.load(source)?,
+            None => source.load_image()?,
+        };
+        self.observe(Stage::Decode, start);
+        Ok(img)
+    }
+
+    fn render(&self, panorama: &Panorama, options: &CubemapOptions) -> Result<CubemapFaces<DynamicImage>> {
+        let start = Instant::now();
+        let bars = self.progress.then(|| FaceBars::new(options.size, &Face::ALL));
+        let options = CubemapOptions { progress: bars.as_ref().map(FaceBars::callback), ..options.clone() };
+        let cubemap = match panorama {
+            Panorama::Decoded(img) => self.render_with(img, &options),
+            // Rows are read again for every size; streamed renders stay on the CPU
+            Panorama::Streamed { source, input, window } => {
+                equirect_to_cubemap_streaming(source.scanlines(input)?, &options, *window)?
+            }
+        };
+        if let Some(bars) = bars {
+            bars.finish();
+        }
+        self.observe(Stage::Render, start);
+        Ok(cubemap)
+    }
+
+    // Faces `concurrent` at a time, each handed to `sink`; always on the CPU
+    fn render_each<T: Send>(
+        &self,
+        img: &DynamicImage,
+        faces: &[Face],
+        options: &CubemapOptions,
+        concurrent: usize,
+        sink: impl Fn(Face, DynamicImage) -> Result<T> + Sync,
+    ) -> Result<Vec<T>> {
+        let start = Instant::now();
+        let bars = self.progress.then(|| FaceBars::new(options.size, faces));
+        let options = CubemapOptions { progress: bars.as_ref().map(FaceBars::callback), ..options.clone() };
+        let results = equirect_to_cubemap_each(img, faces, &options, concurrent, sink);
+        if let Some(bars) = bars {
+            bars.finish();
+        }
+        // Faces are encoded as they render, so this counts both
+        self.observe(Stage::Render, start);
+        results
+    }
+
+    // The GPU always renders all six faces
+    fn on_gpu(&self) -> bool {
+        #[cfg(feature = "gpu")]
+        return self.gpu.is_some();
+        #[cfg(not(feature = "gpu"))]
+        false
+    }
+
+    fn render_with(&self, img: &DynamicImage, options: &CubemapOptions) -> CubemapFaces<DynamicImage> {
+        #[cfg(feature = "gpu")]
+        if let Some(cubemap) = self.gpu.as_ref().and_then(|gpu| gpu.render(img, options)) {
+            return cubemap;
+        }
+        equirect_to_cubemap_dynamic(img, options)
+    }
 }
 
-fn main() -> Result<()> {
-    init_rayon();
-    
+// What faces render from: the decoded panorama, or with --stream the source
+// read a window of `window` bytes of rows at a time
+enum Panorama<'a> {
+    Decoded(DynamicImage),
+    Streamed { source: &'a Source, input: &'a Path, window: usize },
+}
+
+fn run_convert(cli: &ConvertArgs, shared: &Shared) -> Result<()> {
+    if let Some(path) = &cli.config {
+        return jobs::run_jobs(path, shared);
+    }
+    let Some(path) = &cli.report else { return convert_all(cli, shared, None) };
+    // Runs stopped by bad options are reported too
+    let report = Arc::new(Report::new());
+    let result = convert_all(cli, shared, Some(report.clone()));
+    report.write(path, &result)?;
+    result
+}
+
+fn convert_all(cli: &ConvertArgs, shared: &Shared, report: Option<Arc<Report>>) -> Result<()> {
+    if (cli.layout.layout().is_some() || cli.container.is_some()) && !cli.faces.is_empty() {
+        bail!("--faces cannot be combined with --layout or --container; they always contain all six faces");
+    }
+    if cli.layout.layout().is_some() && cli.container.is_some() {
+        bail!("--layout and --container are mutually exclusive");
+    }
+    if cli.unity_meta && !matches!(cli.layout.layout(), Some(layout) if layout.grid() != (3, 2)) {
+        bail!("--unity-meta needs a cross or strip --layout; Unity imports a cubemap from a single image");
+    }
+    if cli.verify_existing && cli.if_exists != IfExists::Skip {
+        bail!("--verify-existing needs --if-exists skip");
+    }
+    #[cfg(feature = "gpu")]
+    if cli.gpu && cli.deterministic {
+        bail!("--gpu cannot be combined with --deterministic; GPU results vary between adapters and drivers");
+    }
+    #[cfg(feature = "gpu")]
+    if cli.gpu && cli.stream {
+        bail!("--gpu cannot be combined with --stream; the GPU needs the whole panorama");
+    }
+    #[cfg(feature = "gpu")]
+    if cli.gpu && (cli.concurrent_faces.is_some() || cli.max_memory.is_some()) {
+        bail!("--gpu cannot be combined with --concurrent-faces or --max-memory; it renders all six faces together");
+    }
+    if cli.layout.layout().is_some() && (cli.concurrent_faces.is_some() || cli.max_memory.is_some()) {
+        bail!("--concurrent-faces and --max-memory write one file per face; a --layout needs all six at once");
+    }
+    if cli.stream && (cli.ssaa > 1 || !matches!(cli.input_projection, InputProjection::Equirect)) {
+        bail!("--stream renders full equirect panoramas without --ssaa");
+    }
+    if (cli.front_lens.is_some() || cli.back_lens.is_some())
+        && !matches!(cli.input_projection, InputProjection::DualFisheye(_))
+    {
+        bail!("--front-lens and --back-lens need --input-projection dual-fisheye");
+    }
+    if let Some(size) = cli.sizes.iter().find(|&&size| size > cli.max_size) {
+        bail!("--size {} is over --max-size {}", size, cli.max_size);
+    }
+    for &size in &cli.sizes {
+        cubemap_options(cli, size).validate()?;
+    }
+    let names = NameTemplate::parse(&cli.name_template, Path::new(""))?;
+    if cli.sizes.len() > 1 && !names.dir.contains("{size}") {
+        bail!("--name-template needs {{size}} in its directory part to convert several sizes");
+    }
+    if (cli.vertical_fov.is_some() || cli.auto_pad) && !matches!(cli.input_projection, InputProjection::Equirect) {
+        bail!("--vertical-fov and --auto-pad need an equirect input");
+    }
+    if let Some(fov) = cli.vertical_fov.filter(|fov| !(*fov > 0.0 && *fov <= 180.0)) {
+        bail!("--vertical-fov must be above 0 and at most 180 degrees, got {}", fov);
+    }
+    if cli.grayscale && !cli.fill.is_opaque() {
+        bail!("--grayscale cannot be combined with a see-through --fill, which needs an alpha channel");
+    }
+    if !cli.fill.is_opaque() && cli.stream {
+        bail!("a see-through --fill cannot be combined with --stream, which renders RGB only");
+    }
+    let image_format = cli.format.filter(|_| cli.container.is_none());
+    if let Some(format) = image_format.filter(|format| !cli.fill.is_opaque() && !format.has_alpha()) {
+        bail!("--fill {} is see-through, which needs png, tiff, webp or exr output, not {}", cli.fill, format);
+    }
+    let primary_type = cli.layers.first().and_then(|layer| layer.pixel_type).unwrap_or(cli.pixel_type);
+    if cli.reuse_largest && primary_type != PixelType::Color {
+        bail!("--reuse-largest averages {} pixels without renormalizing them; leave it out", primary_type);
+    }
+    let mut layer_names = BTreeSet::new();
+    if let Some(layer) = cli.layers.iter().find(|layer| !layer_names.insert(&layer.name)) {
+        bail!("--layers names {} twice; each layer needs its own directory", layer.name);
+    }
+    if cli.stereo.is_some() && !matches!(cli.input_projection, InputProjection::Equirect) {
+        bail!("--stereo needs an equirect input");
+    }
+    if cli.nadir_patch.is_some() {
+        if !matches!(cli.input_projection, InputProjection::Equirect) {
+            bail!("--nadir-patch needs an equirect input");
+        }
+        NadirPatch { image: DynamicImage::new_rgb8(1, 1), diameter: cli.nadir_diameter, feather: cli.nadir_feather }
+            .validate()?;
+    }
+
+    if cli.watch.is_some() && storage::is_remote(&cli.output_dir) {
+        bail!("--watch writes to a local --output-dir");
+    }
+
+    let rotations = cli.rotations.as_deref().map(RotationTable::load).transpose()?;
+    let renderer = Renderer::new(cli, shared, report);
+    if let Some(dir) = &cli.watch {
+        return watch::run_watch(dir, cli, &renderer, rotations.as_ref());
+    }
+    // Archive entries are named relative to the archive's root
+    let (destination, output_root) = match &cli.output {
+        Some(path) if storage::is_std_stream(path) => {
+            logging::STDOUT_IS_OUTPUT.store(true, Ordering::Relaxed);
+            (Destination::tar_stdout()?, Path::new(""))
+        }
+        Some(path) if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("zip")) => {
+            (Destination::zip(path)?, Path::new(""))
+        }
+        Some(path) => bail!("--output {} is neither a .zip archive nor - for stdout", path.display()),
+        None => (Destination::Files, cli.output_dir.as_path()),
+    };
+    let result = match (&cli.input_glob, cli.start.zip(cli.end)) {
+        (Some(pattern), _) => glob_inputs(pattern, output_root)
+            .and_then(|inputs| run_batch(&inputs, cli, &renderer, &destination, rotations.as_ref())),
+        (None, Some((start, end))) => {
+            let pattern = cli.input.as_deref().expect("--start needs --input");
+            sequence_inputs(pattern, start, end, output_root)
+                .and_then(|inputs| run_batch(&inputs, cli, &renderer, &destination, rotations.as_ref()))
+        }
+        (None, None) => {
+            let input = cli.input.as_deref().or(cli.layers.first().map(|layer| layer.path.as_path()));
+            let input = input.expect("--input or --layers is required");
+            // A lone input's error is the run's, but counts as every input failing
+            renderer
+                .pool
+                .install(|| convert_file(input, output_root, cli, &renderer, &destination, rotations.as_ref()))
+                .map_err(|err| InputFailed(err).into())
+        }
+    };
+    // A batch with failures still archives the files that converted
+    if let Some(path) = &cli.output {
+        destination.finish()?;
+        match storage::is_std_stream(path) {
+            true => info!("Tar stream written to stdout"),
+            false => info!("Archive {} written", path.display()),
+        }
+    }
+    result
+}
+
+// Convert each input into its output root with up to `--jobs` images in
+// flight. Each image still renders its faces on the shared rayon pool.
+fn run_batch(
+    inputs: &[(PathBuf, PathBuf)],
+    cli: &ConvertArgs,
+    renderer: &Renderer,
+    destination: &Destination,
+    rotations: Option<&RotationTable>,
+) -> Result<()> {
     let total_start = Instant::now();
-    
-    // Load and convert image once
-    let input_path = "images/LightRoom-7.jpg";
-    let img = image::open(input_path)?;
-    let rgb_img = img.to_rgb8();
-    
-    let sizes = [1024, 2048, 4096];
-    for &size in &sizes {
-        println!("\nProcessing size: {}", size);
-        convert_jpg_to_cubemap(&rgb_img, size, 95)?;
-    }
-    
-    println!("\nTotal processing time for all sizes: {:?}", total_start.elapsed());
+
+    // Concurrent jobs would interleave their entries in an archive
+    let jobs = if cli.deterministic && cli.output.is_some() { 1 } else { cli.jobs as usize };
+    let next = AtomicUsize::new(0);
+    let failed = AtomicUsize::new(0);
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.min(inputs.len()) {
+            scope.spawn(|| {
+                while let Some((input, output_root)) = inputs.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let result = renderer
+                        .pool
+                        .install(|| convert_file(input, output_root, cli, renderer, destination, rotations));
+                    if let Err(err) = result {
+                        error!(input = %input.display(), "Failed to convert {}: {:#}", input.display(), err);
+                        failed.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            });
+        }
+    });
+
+    let elapsed = total_start.elapsed();
+    info!(elapsed_ms = elapsed.as_secs_f64() * 1e3, "\nBatch of {} files processed in {:?}", inputs.len(), elapsed);
+    match failed.into_inner() {
+        0 => Ok(()),
+        failed => Err(Failures { failed, total: inputs.len(), what: "files failed to convert" }.into()),
+    }
+}
+
+// Every file matching `pattern`, each with its output root:
+// panos/a/b.jpg -> <output>/a/b/cubemap_<size>
+fn glob_inputs(pattern: &str, output_root: &Path) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let base = glob_base(pattern);
+    let inputs = glob::glob(pattern)
+        .with_context(|| format!("invalid glob pattern '{}'", pattern))?
+        .filter_map(|entry| entry.ok())
+        .filter(|path| path.is_file())
+        .map(|input| {
+            let relative = input.strip_prefix(&base).unwrap_or(&input);
+            let output_root = output_root.join(relative.with_extension(""));
+            (input, output_root)
+        })
+        .collect::<Vec<_>>();
+    if inputs.is_empty() {
+        bail!("no files match '{}'", pattern);
+    }
+    info!("Converting {} files matching {}", inputs.len(), pattern);
+    Ok(inputs)
+}
+
+// Leading directories of a glob pattern that contain no wildcards
+fn glob_base(pattern: &str) -> PathBuf {
+    Path::new(pattern)
+        .components()
+        .take_while(|component| !component.as_os_str().to_string_lossy().contains(['*', '?', '[']))
+        .collect()
+}
+
+// Frames `start` to `end` of a numbered sequence such as frames/%05d.jpg,
+// each converted under its own number: <output>/00042/cubemap_<size>.
+// Missing frames fail like unreadable ones, so gaps are reported
+fn sequence_inputs(pattern: &Path, start: u64, end: u64, output_root: &Path) -> Result<Vec<(PathBuf, PathBuf)>> {
+    if start > end {
+        bail!("--start {} is after --end {}", start, end);
+    }
+    let text = pattern.to_string_lossy();
+    let Some((prefix, width, suffix)) = frame_placeholder(&text) else {
+        bail!("--start and --end need a frame number placeholder such as %05d in --input {}", text);
+    };
+    let inputs = (start..=end)
+        .map(|frame| {
+            let input = PathBuf::from(format!("{}{:0width$}{}", prefix, frame, suffix, width = width));
+            let output_root = output_root.join(input.file_stem().unwrap_or_default());
+            (input, output_root)
+        })
+        .collect::<Vec<_>>();
+    info!("Converting frames {} to {} of {}", start, end, text);
+    Ok(inputs)
+}
+
+// Text before and after a printf-style %d or %0Nd, and its zero-padded width
+fn frame_placeholder(pattern: &str) -> Option<(&str, usize, &str)> {
+    let index = pattern.find('%')?;
+    let rest = &pattern[index + 1..];
+    let digits = rest.find('d')?;
+    let width = match &rest[..digits] {
+        "" => 0,
+        spec if spec.starts_with('0') => spec.parse().ok()?,
+        _ => return None,
+    };
+    Some((&pattern[..index], width, &rest[digits + 1..]))
+}
+
+fn convert_file(
+    input: &Path,
+    output_root: &Path,
+    cli: &ConvertArgs,
+    renderer: &Renderer,
+    destination: &Destination,
+    rotations: Option<&RotationTable>,
+) -> Result<()> {
+    let Some(report) = &renderer.report else {
+        return convert_input(input, output_root, cli, renderer, destination, rotations, None);
+    };
+    let record = InputReport::new(input, output_root);
+    let result = convert_input(input, output_root, cli, renderer, destination, rotations, Some(&record));
+    report.add(record, &result);
+    result
+}
+
+fn convert_input(
+    input: &Path,
+    output_root: &Path,
+    cli: &ConvertArgs,
+    renderer: &Renderer,
+    destination: &Destination,
+    rotations: Option<&RotationTable>,
+    record: Option<&InputReport>,
+) -> Result<()> {
+    let total_start = Instant::now();
+    // Every line about this file carries its name in the JSON log
+    let _span = info_span!("convert", input = %input.display()).entered();
+    info!("Converting {}", input.display());
+
+    // Remote inputs are downloaded here
+    let source = Source::open(input)?;
+    let mut sha256 = None;
+    let names = NameTemplate::parse(&cli.name_template, input)?;
+    // The first of several layers is written into its own directory too
+    let primary_root = match cli.layers.first() {
+        Some(layer) => output_root.join(&layer.name),
+        None => output_root.to_path_buf(),
+    };
+    // Both eyes are written together, so the left one stands for the pair
+    let checked_root = match cli.stereo {
+        Some(_) => output_root.join("left"),
+        None => primary_root.clone(),
+    };
+    let sizes = pending_sizes(&checked_root, &names, cli, &source, &mut sha256)?;
+    if sizes.is_empty() {
+        info!("Skipped {}: every size is already converted", input.display());
+        if let Some(record) = record {
+            record.skipped();
+        }
+        return Ok(());
+    }
+
+    preflight(input, &source, cli, &sizes)?;
+
+    // Load and convert image once, or with --stream only check it can be read
+    let (img, depth, (width, height)) = if cli.stream {
+        let reader = source.scanlines(input)?;
+        info!("Streaming {}x{} {} panorama", reader.width(), reader.height(), reader.depth());
+        let window = cli.stream_window as usize * 1024 * 1024;
+        (Panorama::Streamed { source: &source, input, window }, reader.depth(), (reader.width(), reader.height()))
+    } else {
+        let start = Instant::now();
+        let img = renderer.load_image(&source)?;
+        if let Some(record) = record {
+            record.decoded(start.elapsed());
+        }
+        let depth = PixelDepth::of(&img);
+        let size = (img.width(), img.height());
+        // Alpha, the input's or a see-through fill's, is kept to the output,
+        // and so is gray
+        let gray = cli.grayscale || matches!(img, DynamicImage::ImageLuma8(_) | DynamicImage::ImageLuma16(_));
+        let img = match img.color().has_alpha() || !cli.fill.is_opaque() {
+            true => depth.to_rgba(img),
+            false if gray => depth.to_luma(img),
+            false => depth.to_rgb(img),
+        };
+        (Panorama::Decoded(img), depth, size)
+    };
+
+    let format = cli.format.or_else(|| OutputFormat::from_path(input)).unwrap_or(OutputFormat::Jpeg);
+    // Convert grades the exposure into the samples, so the tone map leaves it out
+    let tone = ToneMapArgs { exposure: 0.0, ..cli.tone };
+    let encode = EncodeOptions {
+        png_compression: cli.png_compression,
+        ..encode_options(format, cli.quality, &cli.jpeg, &cli.webp, &cli.avif, &tone)?
+    };
+    debug!("Encode options: {:?}", encode);
+    // Formats taken from the input's extension are only known here
+    let alpha = matches!(&img, Panorama::Decoded(img) if img.color().has_alpha());
+    if alpha && cli.container.is_none() && !encode.format.has_alpha() {
+        if !cli.fill.is_opaque() {
+            let format = encode.format;
+            bail!("--fill {} is see-through, which needs png, tiff, webp or exr output, not {}", cli.fill, format);
+        }
+        info!("Note: {} output has no alpha channel; the input's will be dropped", encode.format);
+    }
+    let metadata = if cli.strip_metadata || cli.deterministic { Metadata::default() } else { source.metadata()? };
+    if depth > encode.max_depth() {
+        info!(
+            "Note: {} input will be reduced to {} for {} output",
+            depth, encode.max_depth(), encode.format
+        );
+        if depth == PixelDepth::F32 && encode.tone_map.is_none() {
+            info!("Note: values above 1.0 will be clipped; --tonemap maps them instead");
+        }
+    }
+
+    // Phone panoramas that cover less than the full sphere say where they sit
+    let mut input_projection = cubemap_options(cli, 0).input;
+    if let Some(fov) = cli.vertical_fov {
+        info!("Vertical coverage: {} degrees centred {} degrees above the horizon", fov, cli.vertical_center);
+        input_projection = InputProjection::PartialEquirect(PanoCrop::vertical_band(width, fov, cli.vertical_center));
+    }
+    if input_projection == InputProjection::Equirect && !cli.ignore_gpano {
+        if let Some(crop) = source.gpano()?.filter(|crop| !crop.is_full()) {
+            info!(
+                "GPano: {}x{} crop at ({}, {}) of a {}x{} panorama",
+                crop.width, crop.height, crop.left, crop.top, crop.full_width, crop.full_height
+            );
+            input_projection = InputProjection::PartialEquirect(crop);
+        }
+    }
+    // Wider than 2:1 reads as a band around the horizon, e.g. 360x150
+    if input_projection == InputProjection::Equirect && cli.auto_pad && width > 2 * height + width / 100 {
+        let fov = 360.0 * height as f32 / width as f32;
+        info!("Auto-pad: {}x{} covers {:.1} degrees vertically; the rest gets --fill", width, height, fov);
+        input_projection = InputProjection::PartialEquirect(PanoCrop::vertical_band(width, fov, 0.0));
+    }
+    // A rig's logged angles apply under the user's own rotation and take the
+    // place of the pose the camera recorded
+    let logged = rotations.and_then(|table| table.get(input));
+    match logged {
+        Some([yaw, pitch, roll]) => info!("Rotations: yaw {}, pitch {}, roll {} degrees", yaw, pitch, roll),
+        None if rotations.is_some() => info!("Note: {} has no row in --rotations; not rotated", input.display()),
+        None => {}
+    }
+    let logged = logged.map_or(Rotation::IDENTITY, |[yaw, pitch, roll]| Rotation::from_euler_degrees(yaw, pitch, roll));
+
+    // Undo the camera's recorded pose, under the user's own rotation
+    let mut pose = None;
+    let equirect = matches!(input_projection, InputProjection::Equirect | InputProjection::PartialEquirect(_));
+    if equirect && !cli.ignore_pose && logged.is_identity() {
+        pose = source.gpano_pose()?;
+        if let Some(pose) = pose {
+            info!(
+                "GPano pose: heading {}, pitch {}, roll {} degrees",
+                pose.heading, pose.pitch, pose.roll
+            );
+        }
+    }
+    let mut orientation = pose.map_or(Rotation::IDENTITY, |pose| pose.rotation());
+    if cli.stream && input_projection != InputProjection::Equirect {
+        bail!("--stream needs a full equirect panorama; {} covers only part of the sphere", input.display());
+    }
+
+    // A stereo panorama converts as two, one per eye, cut from the one decode
+    let mut eyes = match (cli.stereo, img) {
+        (Some(stereo), Panorama::Decoded(img)) => match stereo.layout(img.width(), img.height()) {
+            Some(layout) => {
+                info!("Stereo: {} panorama, converting each eye", layout);
+                let [left, right] = layout.split(&img);
+                vec![(Some("left"), Panorama::Decoded(left)), (Some("right"), Panorama::Decoded(right))]
+            }
+            None => {
+                let (width, height) = (img.width(), img.height());
+                info!("Note: a {}x{} frame is not a stereo layout; converting it as one panorama", width, height);
+                vec![(None, Panorama::Decoded(img))]
+            }
+        },
+        (_, img) => vec![(None, img)],
+    };
+
+    // Depth and the other layers render after each size from the same
+    // directions, and from the same sampling tables when the sizes match
+    let mut companions = Vec::new();
+    if let Some(template) = &cli.depth {
+        let panorama = load_companion(&depth_path(template, input), "Depth", (width, height))?;
+        let format = match matches!(&panorama, Panorama::Decoded(img) if PixelDepth::of(img) == PixelDepth::F32) {
+            true => OutputFormat::Exr,
+            false => OutputFormat::Png,
+        };
+        // Blending fills would make up depths; uncovered ones are 0 otherwise
+        let fill = match cli.fill {
+            Fill::Edge => Fill::Edge,
+            _ => Fill::default(),
+        };
+        companions.push(Companion {
+            panorama,
+            root: primary_root.join("depth"),
+            filter: Filter::Nearest,
+            pixel_type: PixelType::Color,
+            fill: Some(fill),
+            encode: EncodeOptions { format, png_compression: cli.png_compression, ..EncodeOptions::default() },
+        });
+    }
+    for layer in cli.layers.iter().skip(1) {
+        let panorama = load_companion(&layer.path, &format!("Layer {}", layer.name), (width, height))?;
+        let format = cli.format.or_else(|| OutputFormat::from_path(&layer.path)).unwrap_or(encode.format);
+        companions.push(Companion {
+            panorama,
+            root: output_root.join(&layer.name),
+            filter: layer.filter.unwrap_or(cli.filter),
+            pixel_type: layer.pixel_type.unwrap_or(cli.pixel_type),
+            fill: None,
+            encode: EncodeOptions { format, ..encode },
+        });
+    }
+    let lut = match renderer.lut.clone() {
+        None if !companions.is_empty() => Some(Arc::new(LutCache::new())),
+        lut => lut,
+    };
+    let filter = cli.layers.first().and_then(|layer| layer.filter).unwrap_or(cli.filter);
+    let pixel_type = cli.layers.first().and_then(|layer| layer.pixel_type).unwrap_or(cli.pixel_type);
+
+    // Paint the patch into the panorama so every size and projection sees it
+    if let Some(path) = &cli.nadir_patch {
+        if input_projection != InputProjection::Equirect {
+            bail!("--nadir-patch needs a full equirect panorama; {} covers only part of the sphere", input.display());
+        }
+        let patch = NadirPatch { image: load_image(path)?, diameter: cli.nadir_diameter, feather: cli.nadir_feather };
+        let start = Instant::now();
+        for (_, img) in &mut eyes {
+            if let Panorama::Decoded(img) = img {
+                patch.apply_dynamic(img);
+            }
+        }
+        info!("Nadir patch applied in {:?}", start.elapsed());
+    }
+
+    // The measured level replaces the pose's pitch and roll; its heading still
+    // applies. Both eyes share the rig's tilt, so the left one is measured
+    if let (true, Some((_, Panorama::Decoded(img)))) = (cli.auto_level, eyes.first()) {
+        let start = Instant::now();
+        if input_projection != InputProjection::Equirect {
+            info!("Note: --auto-level needs a full equirect panorama; left as is");
+        } else {
+            match detect_up(img).map(level_rotation) {
+                Some((_, tilt)) if tilt > MAX_AUTO_LEVEL => {
+                    info!("Auto-level: measured tilt of {:.1} degrees looks wrong; left as is", tilt)
+                }
+                Some((rotation, tilt)) => {
+                    info!("Auto-level: corrected a {:.2} degree tilt in {:?}", tilt, start.elapsed());
+                    let heading = pose.map(|pose| PanoPose { pitch: 0.0, roll: 0.0, ..pose });
+                    orientation = rotation.compose(&heading.map_or(Rotation::IDENTITY, |pose| pose.rotation()));
+                }
+                None => info!("Auto-level: no consistent vertical edges found; left as is"),
+            }
+        }
+    }
+
+    // Reuse mode goes largest first so every size derives from the one above
+    let mut sizes = sizes;
+    if cli.reuse_largest {
+        sizes.sort_unstable_by(|a, b| b.cmp(a));
+        sizes.dedup();
+    }
+    let manifest_source = if cli.no_manifest {
+        None
+    } else {
+        // The same file converted from another directory hashes the same
+        let path = match input.file_name() {
+            Some(name) if cli.deterministic => name.to_string_lossy().into_owned(),
+            _ => input.display().to_string(),
+        };
+        let sha256 = match sha256 {
+            Some(sha256) => sha256,
+            None => source.sha256()?,
+        };
+        Some(ManifestSource { path, sha256 })
+    };
+    let output = ImageOutput {
+        encode,
+        metadata,
+        destination,
+        source: manifest_source,
+        ordered: cli.deterministic,
+        names,
+        blurhash: cli.blurhash,
+        report: record,
+    };
+    for (eye, img) in &eyes {
+        let output_root = match eye {
+            Some(eye) => output_root.join(eye),
+            None => primary_root.clone(),
+        };
+        let mut previous = None;
+        for &size in &sizes {
+            let _span = info_span!("size", size, eye = *eye).entered();
+            match eye {
+                Some(eye) => info!("Processing size: {} ({} eye)", size, eye),
+                None => info!("Processing size: {}", size),
+            }
+            let options = cubemap_options(cli, size);
+            let rotation = orientation.compose(&logged).compose(&options.rotation);
+            let lut = lut.clone();
+            let options = CubemapOptions { input: input_projection, rotation, filter, pixel_type, lut, ..options };
+            debug!("Cubemap options: {:?}", options);
+            let cubemap = convert_to_cubemap(img, &options, &output_root, cli, &output, renderer, previous.as_ref())?;
+            if cli.reuse_largest {
+                previous = cubemap;
+            }
+            for companion in &companions {
+                write_companion(companion, &options, cli, &output, renderer)?;
+            }
+        }
+    }
+
+    let elapsed = total_start.elapsed();
+    info!(elapsed_ms = elapsed.as_secs_f64() * 1e3, "\nTotal processing time for all sizes: {:?}", elapsed);
     Ok(())
 }
 
-fn cube_to_spherical(x: u32, y: u32, size: u32, face: &str) -> (f32, f32) {
-    let x = (2.0 * x as f32 / size as f32) - 1.0;
-    let y = (2.0 * y as f32 / size as f32) - 1.0;
-
-    match face {
-        "right" => {
-            let r = (x * x + y * y + 1.0).sqrt();
-            let phi = (y).atan2(1.0);
-            let theta = (x / r).acos();
-            ((phi / (2.0 * std::f32::consts::PI) + 0.5),
-             (theta / std::f32::consts::PI))
-        }
-        "left" => {
-            let r = (x * x + y * y + 1.0).sqrt();
-            let phi = (y).atan2(-1.0);
-            let theta = (-x / r).acos();
-            ((phi / (2.0 * std::f32::consts::PI) + 0.5),
-             (theta / std::f32::consts::PI))
-        }
-        "up" => {
-            let r = (x * x + y * y + 1.0).sqrt();
-            let phi = (-x).atan2(y);
-            let theta = (1.0 / r).acos();
-            ((phi / (2.0 * std::f32::consts::PI) + 0.5),
-             (theta / std::f32::consts::PI))
-        }
-        "down" => {
-            let r = (x * x + y * y + 1.0).sqrt();
-            let phi = (x).atan2(-y);
-            let theta = (-1.0 / r).acos();
-            ((phi / (2.0 * std::f32::consts::PI) + 0.5),
-             (theta / std::f32::consts::PI))
-        }
-        "front" => {
-            let r = (x * x + y * y + 1.0).sqrt();
-            let phi = (x).atan2(1.0);
-            let theta = (y / r).acos();
-            ((phi / (2.0 * std::f32::consts::PI) + 0.5),
-             (theta / std::f32::consts::PI))
-        }
-        "back" => {
-            let r = (x * x + y * y + 1.0).sqrt();
-            let phi = (-x).atan2(-1.0);
-            let theta = (-y / r).acos();
-            ((phi / (2.0 * std::f32::consts::PI) + 0.5),
-             (theta / std::f32::consts::PI))
-        }
-        _ => (0.0, 0.0)
-    }
-}
-
-fn convert_jpg_to_cubemap(rgb_img: &image::RgbImage, size: u32, quality: u8) -> Result<()> {
+// Larger measured tilts are more likely misread edges than a real tilt
+const MAX_AUTO_LEVEL: f32 = 20.0;
+
+// --name-template split at its last '/': the directory part names each
+// size's cubemap directory, the rest each face file in it
+#[derive(Clone)]
+struct NameTemplate {
+    dir: String,
+    file: String,
+}
+
+impl Default for NameTemplate {
+    fn default() -> NameTemplate {
+        NameTemplate { dir: "cubemap_{size}".to_string(), file: "{face}.{ext}".to_string() }
+    }
+}
+
+const FACE_VARIABLES: [&str; 3] = ["{face}", "{face_px}", "{face_index}"];
+
+impl NameTemplate {
+    // `template` with {stem} filled in from `input`; {ext} is the output's
+    fn parse(template: &str, input: &Path) -> Result<NameTemplate> {
+        let (variable, stem) = &input_variables(input)[0];
+        let template = template.replace(variable, stem);
+        let (dir, file) = template.rsplit_once('/').unwrap_or(("", &template));
+        let names = NameTemplate { dir: dir.to_string(), file: file.to_string() };
+        if let Some(variable) = FACE_VARIABLES.iter().find(|variable| dir.contains(*variable)) {
+            bail!("--name-template puts {} in a directory; the faces of one size share their directory", variable);
+        }
+        if !FACE_VARIABLES.iter().any(|variable| file.contains(variable)) {
+            bail!("--name-template needs {{face}}, {{face_px}} or {{face_index}} in the file name");
+        }
+        let rendered = format!("{}/{}", names.dir(Path::new(""), 0).display(), names.file(0, Face::Right, None, ""));
+        if let Some(start) = rendered.find('{') {
+            let variable = rendered[start..].split_inclusive('}').next().unwrap_or_default();
+            bail!("unknown variable {} in --name-template", variable);
+        }
+        Ok(names)
+    }
+
+    // Where the cubemap of one size goes
+    fn dir(&self, output_root: &Path, size: u32) -> PathBuf {
+        output_root.join(self.dir.replace("{size}", &size.to_string()))
+    }
+
+    // File name of the face in `slot`, counted in the convention's slots
+    fn file(&self, size: u32, slot: Face, convention: Option<Convention>, extension: &str) -> String {
+        const PX: [&str; 6] = ["px", "nx", "py", "ny", "pz", "nz"];
+        let name = convention.map_or(slot.name(), |convention| convention.face_name(slot));
+        self.file
+            .replace("{size}", &size.to_string())
+            .replace("{face}", name)
+            .replace("{face_px}", PX[slot.index()])
+            .replace("{face_index}", &slot.index().to_string())
+            .replace("{ext}", extension)
+    }
+}
+
+// Checks on the panorama's header before anything is decoded: its size
+// against --max-megapixels, its shape against 2:1 and what converting it
+// would take against the memory available
+fn preflight(input: &Path, source: &Source, cli: &ConvertArgs, sizes: &[u32]) -> Result<()> {
+    let Some((width, height, depth, channels)) = source.probe() else {
+        debug!("Size of {} unknown before decoding; limits not checked", input.display());
+        return Ok(());
+    };
+    let pixels = width as u64 * height as u64;
+    if cli.max_megapixels > 0 && pixels > cli.max_megapixels as u64 * 1_000_000 {
+        bail!(
+            "{} is {}x{}, {} megapixels, over --max-megapixels {}",
+            input.display(), width, height, pixels / 1_000_000, cli.max_megapixels
+        );
+    }
+
+    // Partial panoramas say where they sit, and stereo frames hold two
+    let options = cubemap_options(cli, sizes.iter().copied().max().unwrap_or(0));
+    let partial = !cli.ignore_gpano && source.gpano()?.is_some_and(|crop| !crop.is_full())
+        || cli.vertical_fov.is_some()
+        || cli.auto_pad && width > 2 * height;
+    let full = options.input == InputProjection::Equirect && cli.stereo.is_none() && !partial;
+    if full && width.abs_diff(2 * height) > width / 100 {
+        let message = format!("{} is {}x{}, not 2:1 like a full equirect panorama", input.display(), width, height);
+        if cli.strict_aspect {
+            bail!("{}", message);
+        }
+        warn!("{}; converting it stretched (--strict-aspect refuses such inputs)", message);
+    }
+
+    // The decoded panorama, its linear-light copy and the faces in flight,
+    // for it and each companion panorama (depth, layers),
+    // at their own precision and in the channels convert keeps
+    let in_flight = match (cli.concurrent_faces, cli.max_memory) {
+        (Some(concurrent), _) => concurrent as u64,
+        (None, Some(_)) => 1,
+        (None, None) if !cli.faces.is_empty() => cli.faces.len().min(6) as u64,
+        (None, None) => 6,
+    };
+    let face_pixels = in_flight * options.face_size() as u64 * options.face_size() as u64;
+    // Float gray decodes to RGB, as image has no float gray
+    let panorama = |pixels: u64, depth: PixelDepth, channels: u64, decoded: bool| {
+        let channels = if depth == PixelDepth::F32 { channels.max(3) } else { channels };
+        let sample = channels * match depth {
+            PixelDepth::U8 => 1,
+            PixelDepth::U16 => 2,
+            PixelDepth::F32 => 4,
+        };
+        let mut needed = face_pixels * sample;
+        if decoded {
+            needed += pixels * sample;
+            if options.linear && depth != PixelDepth::F32 {
+                needed += pixels * channels * 4;
+            }
+        }
+        needed
+    };
+    let mut needed = panorama(pixels, depth, kept_channels(channels, cli), !cli.stream);
+    let companions = cli.depth.iter().map(|template| depth_path(template, input));
+    for path in companions.chain(cli.layers.iter().skip(1).map(|layer| layer.path.clone())) {
+        // Those that can't be probed here (S3, stdin) are taken to be like the input
+        let (width, height, depth, channels) = Source::Local(path).probe().unwrap_or((width, height, depth, channels));
+        let channels = if channels == 2 { 4 } else { channels as u64 };
+        needed += panorama(width as u64 * height as u64, depth, channels, true);
+    }
+    let megabytes = |bytes: u64| bytes.div_ceil(1024 * 1024);
+    debug!("Converting {}x{} {} needs about {} MB", width, height, depth, megabytes(needed));
+    match available_memory() {
+        Some(available) if needed > available && !cli.skip_memory_check => bail!(
+            "converting {} needs about {} MB but only {} MB are available; try --stream, --max-memory, a smaller \
+             --size, or --skip-memory-check",
+            input.display(), megabytes(needed), megabytes(available)
+        ),
+        _ => Ok(()),
+    }
+}
+
+// Channels convert keeps of a panorama that decodes to `channels`: RGBA
+// where there is alpha or the fill can add it, gray where it is or
+// --grayscale asks, RGB otherwise
+fn kept_channels(channels: u8, cli: &ConvertArgs) -> u64 {
+    match channels {
+        2 | 4 => 4,
+        _ if !cli.fill.is_opaque() => 4,
+        1 => 1,
+        _ if cli.grayscale => 1,
+        _ => 3,
+    }
+}
+
+// Memory the kernel could hand out without swapping; Linux only
+fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find_map(|line| line.strip_prefix("MemAvailable:"))?;
+    let kilobytes: u64 = line.trim().strip_suffix("kB")?.trim().parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+// The sizes --if-exists leaves to convert. Verifying hashes the source once
+// into `sha256`, for the new manifests to reuse.
+fn pending_sizes(
+    output_root: &Path,
+    names: &NameTemplate,
+    cli: &ConvertArgs,
+    source: &Source,
+    sha256: &mut Option<String>,
+) -> Result<Vec<u32>> {
+    if cli.if_exists == IfExists::Overwrite {
+        return Ok(cli.sizes.clone());
+    }
+    let mut pending = Vec::new();
+    for &size in &cli.sizes {
+        let out_dir = names.dir(output_root, size);
+        let manifest_path = out_dir.join("manifest.json");
+        let Some(manifest) = storage::read_if_exists(&manifest_path)? else {
+            pending.push(size);
+            continue;
+        };
+        if cli.if_exists == IfExists::Error {
+            bail!("{} already exists", manifest_path.display());
+        }
+        if cli.verify_existing {
+            if sha256.is_none() {
+                *sha256 = Some(source.sha256()?);
+            }
+            let source_sha256 = sha256.as_deref().expect("hashed above");
+            if let Err(reason) = verify_existing(&out_dir, &manifest, source_sha256) {
+                info!("Converting size {} again: {}", size, reason);
+                pending.push(size);
+                continue;
+            }
+        }
+        info!("Skipping size {}: {} exists", size, manifest_path.display());
+    }
+    Ok(pending)
+}
+
+// Whether every file the manifest lists is intact and came from this source
+fn verify_existing(out_dir: &Path, manifest: &[u8], source_sha256: &str) -> Result<(), String> {
+    let record = std::str::from_utf8(manifest)
+        .ok()
+        .and_then(ManifestRecord::parse)
+        .ok_or("manifest.json is unreadable")?;
+    if record.source_sha256 != source_sha256 {
+        return Err("the source has changed".to_string());
+    }
+    for file in &record.files {
+        let path = out_dir.join(&file.name);
+        match storage::read_if_exists(&path) {
+            Ok(Some(data)) if sha256_hex(&data) == file.sha256 => {}
+            Ok(Some(_)) => return Err(format!("{} doesn't match its checksum", path.display())),
+            Ok(None) => return Err(format!("{} is missing", path.display())),
+            Err(err) => return Err(format!("cannot read {}: {:#}", path.display(), err)),
+        }
+    }
+    Ok(())
+}
+
+fn convert_to_cubemap(
+    panorama: &Panorama,
+    options: &CubemapOptions,
+    output_root: &Path,
+    cli: &ConvertArgs,
+    output: &ImageOutput,
+    renderer: &Renderer,
+    previous: Option<&CubemapFaces<DynamicImage>>,
+) -> Result<Option<CubemapFaces<DynamicImage>>> {
     let start = Instant::now();
-    println!("Starting conversion at {}x{}", size, size);
+    let size = options.size;
+    info!("Starting conversion at {}x{}", size, size);
 
-    let width = rgb_img.width();
-    let height = rgb_img.height();
-    
-    println!("Starting processing at {:?}", start.elapsed());
-    
     // Create output directory
-    std::fs::create_dir_all(format!("output/cubemap_{}", size))?;
-    
-    // Process faces in parallel with larger chunks
-    let faces = ["right", "left", "up", "down", "front", "back"];
-    faces.par_iter().try_for_each(|face| -> Result<()> {
+    let out_dir = output.names.dir(output_root, size);
+    output.destination.create_dir_all(&out_dir)?;
+
+    // Under a memory budget faces go straight from render to file, as do
+    // --faces subsets when nothing else needs the whole cube
+    let budget = face_budget(panorama, options, cli);
+    let subset = !cli.faces.is_empty()
+        && !cli.reuse_largest
+        && cli.irradiance.is_none()
+        && cli.sh.is_none()
+        && cli.preview.is_none()
+        && !renderer.on_gpu();
+    if let (Panorama::Decoded(img), Some(concurrent)) = (panorama, budget.or(subset.then_some(6))) {
+        if budget.is_some() {
+            info!("Rendering {} face{} at a time", concurrent, if concurrent == 1 { "" } else { "s" });
+        }
+        let mut written = write_faces_each(img, options, concurrent, &out_dir, cli, output, renderer)?;
+        written.extend(write_loader_urls(&out_dir, size, cli, output)?.map(|file| (None, file)));
+        write_manifest(&written, &out_dir, options, cli, output)?;
+        // Faces encode as they render, so neither stage has a time of its own
+        record_size(&written, &out_dir, options, cli, output, [None, None], start.elapsed());
+        info!("Total conversion time: {:?}", start.elapsed());
+        return Ok(None);
+    }
+
+    // Everything derives from the native orientation; a convention only
+    // changes how the faces are stored
+    let cubemap = match previous {
+        Some(previous) => {
+            let cubemap = previous.downsample_dynamic(size);
+            info!("Faces downsampled at {:?}", start.elapsed());
+            cubemap
+        }
+        None => {
+            let cubemap = renderer.render(panorama, options)?;
+            let elapsed = start.elapsed();
+            info!(elapsed_ms = elapsed.as_secs_f64() * 1e3, "Faces rendered at {:?}", elapsed);
+            cubemap
+        }
+    };
+    let render = start.elapsed();
+    let mut written = Vec::new();
+    if cli.irradiance.is_some() || cli.sh.is_some() {
+        written = write_ambient(&cubemap, options.projection, &out_dir, cli, output)?;
+        info!("Ambient lighting written at {:?}", start.elapsed());
+    }
+
+    // Sharpened copies are only stored; ambient lighting and smaller sizes
+    // derive from the faces as rendered
+    let mut converted = cli.convention.map(|convention| convention.apply(&cubemap));
+    if let Some(sharpen) = &cli.sharpen {
+        let faces = converted.get_or_insert_with(|| cubemap.clone());
+        faces.faces.par_iter_mut().for_each(|face| sharpen.apply_dynamic(face));
+        info!("Faces sharpened at {:?}", start.elapsed());
+    }
+    let stored = converted.as_ref().unwrap_or(&cubemap);
+
+    let encode_start = Instant::now();
+    if let Some(container) = cli.container {
+        let prefiltered: Vec<_>;
+        let levels = if cli.specular {
+            let options = SpecularOptions { samples: cli.specular_samples, levels: cli.specular_levels };
+            let levels = prefilter_specular_dynamic(&cubemap, &options);
+            info!("{} specular levels prefiltered at {:?}", levels.len(), start.elapsed());
+            prefiltered = match cli.convention {
+                Some(convention) => levels.iter().map(|level| convention.apply(level)).collect(),
+                None => levels,
+            };
+            prefiltered.as_slice()
+        } else {
+            std::slice::from_ref(stored)
+        };
+        let output_path = out_dir.join(format!("cubemap.{}", container.extension()));
+        written.insert(0, (None, write_container(levels, &output_path, container, cli, output)?));
+        info!("Container {} written at {:?}", output_path.display(), start.elapsed());
+    } else {
+        let mut images = write_images(stored, &out_dir, cli.layout.layout(), &cli.faces, cli.convention, output)?;
+        images.append(&mut written);
+        written = images;
+    }
+
+    if let Some(preview) = cli.preview {
+        let montage = preview_montage(&cubemap, preview.layout(), cli.preview_size);
+        let encode = EncodeOptions { format: OutputFormat::Jpeg, quality: 90, ..output.encode };
+        let path = out_dir.join("preview.jpg");
+        written.push((None, output.destination.save_image(&montage, &path, &encode, &Metadata::default())?));
+        info!("Preview written at {:?}", start.elapsed());
+    }
+    written.extend(write_loader_urls(&out_dir, size, cli, output)?.map(|file| (None, file)));
+    written.extend(write_unity_meta(&out_dir, size, cli, output)?.map(|file| (None, file)));
+
+    write_manifest(&written, &out_dir, options, cli, output)?;
+    renderer.observe(Stage::Encode, encode_start);
+    let stages = [Some(render), Some(encode_start.elapsed())];
+    record_size(&written, &out_dir, options, cli, output, stages, start.elapsed());
+
+    let elapsed = start.elapsed();
+    info!(elapsed_ms = elapsed.as_secs_f64() * 1e3, "Total conversion time: {:?}", elapsed);
+    Ok(Some(cubemap))
+}
+
+// A panorama converted alongside the input from the same directions, so
+// their faces stay pixel-aligned: --depth's, or one of --layers after the
+// first. It is neither graded nor sharpened
+struct Companion {
+    panorama: Panorama<'static>,
+    // Where its cubemaps go, named like the input's
+    root: PathBuf,
+    filter: Filter,
+    pixel_type: PixelType,
+    // In place of the input's --fill
+    fill: Option<Fill>,
+    encode: EncodeOptions,
+}
+
+// --depth's template filled in from `input`; {dir} is the input's directory
+fn depth_path(template: &str, input: &Path) -> PathBuf {
+    let dir = match input.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_string_lossy().into_owned(),
+        _ => ".".to_string(),
+    };
+    PathBuf::from(expand_input(template, input).replace("{dir}", &dir))
+}
+
+// {stem}, {name} and {ext} of `input`, as path templates use them
+fn input_variables(input: &Path) -> [(&'static str, String); 3] {
+    let part = |s: Option<&std::ffi::OsStr>| s.map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    [("{stem}", part(input.file_stem())), ("{name}", part(input.file_name())), ("{ext}", part(input.extension()))]
+}
+
+// `template` with the `input_variables` of `input` filled in
+pub(crate) fn expand_input(template: &str, input: &Path) -> String {
+    input_variables(input).iter().fold(template.to_string(), |text, (variable, value)| text.replace(variable, value))
+}
+
+// A companion panorama at its own precision, gray if it is, with alpha if
+// it has it; its shape should match the `width` x `height` input's
+fn load_companion(path: &Path, what: &str, (width, height): (u32, u32)) -> Result<Panorama<'static>> {
+    let img = Source::open(path)?.load_image()?;
+    let depth = PixelDepth::of(&img);
+    info!("{}: {}x{} {} panorama {}", what, img.width(), img.height(), depth, path.display());
+    // Another resolution samples the same directions; another shape doesn't
+    let (companion_width, companion_height) = (img.width() as u64, img.height() as u64);
+    let expected = width as u64 * companion_height;
+    if (companion_width * height as u64).abs_diff(expected) > expected / 100 {
+        warn!(
+            "{} is {}x{}, not the shape of the {}x{} input",
+            path.display(), companion_width, companion_height, width, height
+        );
+    }
+    let img = match img {
+        img if img.color().has_alpha() => depth.to_rgba(img),
+        DynamicImage::ImageLuma8(_) | DynamicImage::ImageLuma16(_) => depth.to_luma(img),
+        img => depth.to_rgb(img),
+    };
+    Ok(Panorama::Decoded(img))
+}
+
+// The companion's faces of one size. Nearest-neighbour takes one sample per
+// pixel, so each is a value from the panorama
+fn write_companion(
+    companion: &Companion,
+    options: &CubemapOptions,
+    cli: &ConvertArgs,
+    output: &ImageOutput,
+    renderer: &Renderer,
+) -> Result<()> {
+    let start = Instant::now();
+    let options = CubemapOptions {
+        filter: companion.filter,
+        pixel_type: companion.pixel_type,
+        ssaa: if companion.filter == Filter::Nearest { 1 } else { options.ssaa },
+        fill: companion.fill.unwrap_or(options.fill),
+        linear: false,
+        grade: ColorGrade::IDENTITY,
+        post_process: None,
+        ..options.clone()
+    };
+    let cubemap = renderer.render(&companion.panorama, &options)?;
+    let cubemap = match cli.convention {
+        Some(convention) => convention.apply(&cubemap),
+        None => cubemap,
+    };
+
+    let companion_output = ImageOutput {
+        encode: companion.encode,
+        metadata: Metadata::default(),
+        destination: output.destination,
+        source: None,
+        ordered: output.ordered,
+        names: output.names.clone(),
+        blurhash: false,
+        report: None,
+    };
+    let out_dir = output.names.dir(&companion.root, options.size);
+    output.destination.create_dir_all(&out_dir)?;
+    write_images(&cubemap, &out_dir, cli.layout.layout(), &cli.faces, cli.convention, &companion_output)?;
+    info!("Faces under {} written at {:?}", companion.root.display(), start.elapsed());
+    Ok(())
+}
+
+// cubemap.json for conventions whose engine loads the faces from a list of
+// URLs, when all six are written as images:
+//   { "urls": ["px.jpg", ...], "size": 1024, "format": "jpeg" }
+fn write_loader_urls(out_dir: &Path, size: u32, cli: &ConvertArgs, output: &ImageOutput) -> Result<Option<StoredFile>> {
+    let Some(order) = cli.convention.and_then(Convention::loader_order) else {
+        return Ok(None);
+    };
+    let all_faces = Face::ALL.iter().all(|face| cli.faces.is_empty() || cli.faces.contains(face));
+    if cli.layout.layout().is_some() || cli.container.is_some() || !all_faces {
+        return Ok(None);
+    }
+    let extension = output.encode.format.extension();
+    let urls: Vec<String> =
+        order.iter().map(|&slot| output.names.file(size, slot, cli.convention, extension)).collect();
+    let json = pretty(&json!({ "urls": urls, "size": size, "format": output.encode.format.to_string() }));
+    Ok(Some(output.destination.write(&out_dir.join("cubemap.json"), json.as_bytes())?))
+}
+
+// Unity TextureImporter settings that import the packed image as a cubemap
+// (texture shape Cube, mapping Auto), with a GUID derived from its path so
+// reruns keep references to it
+fn write_unity_meta(out_dir: &Path, size: u32, cli: &ConvertArgs, output: &ImageOutput) -> Result<Option<StoredFile>> {
+    let Some(layout) = cli.layout.layout().filter(|_| cli.unity_meta) else {
+        return Ok(None);
+    };
+    let format = output.encode.format;
+    let image = out_dir.join(format!("{}.{}", layout, format.extension()));
+    let (columns, rows) = layout.grid();
+    let max_texture_size = (size * columns.max(rows)).next_power_of_two().clamp(32, 16384);
+    let srgb = format.max_depth() != PixelDepth::F32;
+    let meta = format!(
+        concat!(
+            "fileFormatVersion: 2\n",
+            "guid: {}\n",
+            "TextureImporter:\n",
+            "  serializedVersion: 12\n",
+            "  mipmaps:\n",
+            "    enableMipMap: 1\n",
+            "    sRGBTexture: {}\n",
+            "  isReadable: 0\n",
+            "  textureSettings:\n",
+            "    serializedVersion: 2\n",
+            "    filterMode: 1\n",
+            "    aniso: 1\n",
+            "    wrapU: 1\n",
+            "    wrapV: 1\n",
+            "    wrapW: 1\n",
+            "  nPOTScale: 0\n",
+            "  textureType: 0\n",
+            "  textureShape: 2\n",
+            "  generateCubemap: 6\n",
+            "  cubemapConvolution: 0\n",
+            "  seamlessCubemap: 1\n",
+            "  maxTextureSize: {}\n",
+            "  userData: \n",
+            "  assetBundleName: \n",
+            "  assetBundleVariant: \n",
+        ),
+        &sha256_hex(image.to_string_lossy().as_bytes())[..32],
+        srgb as u8,
+        max_texture_size
+    );
+    let path = out_dir.join(format!("{}.{}.meta", layout, format.extension()));
+    Ok(Some(output.destination.write(&path, meta.as_bytes())?))
+}
+
+fn write_manifest(
+    written: &[(Option<Face>, StoredFile)],
+    out_dir: &Path,
+    options: &CubemapOptions,
+    cli: &ConvertArgs,
+    output: &ImageOutput,
+) -> Result<()> {
+    if let Some(source) = &output.source {
+        let files: Vec<_> = written.iter().map(|(face, file)| ManifestFile::new(file, out_dir, *face)).collect();
+        let manifest = Manifest {
+            source,
+            size: options.size,
+            filter: options.filter,
+            projection: options.projection,
+            convention: cli.convention,
+            layout: cli.layout.layout(),
+            format: cli.container.map_or(output.encode.format.extension(), ContainerArg::extension),
+            quality: output.encode.quality,
+            files: &files,
+        };
+        output.destination.write(&out_dir.join("manifest.json"), manifest.to_json().as_bytes())?;
+    }
+    Ok(())
+}
+
+// Add the size to --report's record of the input: the settings the manifest
+// also lists, the render and encode times, and the files
+fn record_size(
+    written: &[(Option<Face>, StoredFile)],
+    out_dir: &Path,
+    options: &CubemapOptions,
+    cli: &ConvertArgs,
+    output: &ImageOutput,
+    [render, encode]: [Option<Duration>; 2],
+    total: Duration,
+) {
+    let Some(report) = output.report else { return };
+    let settings = [
+        ("dir", json!(out_dir.display().to_string())),
+        ("filter", json!(options.filter.to_string())),
+        ("projection", json!(options.projection.to_string())),
+        ("format", json!(cli.container.map_or(output.encode.format.extension(), ContainerArg::extension))),
+        ("quality", json!(output.encode.quality)),
+        ("convention", json!(cli.convention.map(Convention::name))),
+        ("layout", json!(cli.layout.layout().map(|layout| layout.to_string()))),
+    ];
+    report.size(options.size, &settings, render, encode, total, written);
+}
+
+// Faces in flight under --concurrent-faces or --max-memory; None renders
+// all six together
+fn face_budget(panorama: &Panorama, options: &CubemapOptions, cli: &ConvertArgs) -> Option<usize> {
+    if let Some(concurrent) = cli.concurrent_faces {
+        return Some(concurrent as usize);
+    }
+    let (Some(max_memory), Panorama::Decoded(img)) = (cli.max_memory, panorama) else {
+        return None;
+    };
+    let budget = max_memory as u64 * 1024 * 1024;
+    let pixel_bytes = img.color().bytes_per_pixel() as u64;
+    let pixels = img.width() as u64 * img.height() as u64;
+    let face_pixels = options.face_size() as u64 * options.face_size() as u64;
+    // Linear light renders from and into float RGB copies
+    let linear = options.linear && PixelDepth::of(img) != PixelDepth::F32;
+    let (resident, render_bytes) = match linear {
+        true => (pixels * (pixel_bytes + 12), 12),
+        false => (pixels * pixel_bytes, pixel_bytes),
+    };
+    // A face in flight holds its rendered pixels, the stored ones and,
+    // while it's written, its encoded file
+    let per_face = face_pixels * (render_bytes + 2 * pixel_bytes);
+    let concurrent = budget.saturating_sub(resident) / per_face;
+    if concurrent == 0 {
+        info!(
+            "Note: --max-memory {} MB is below the {} MB the panorama and one face need",
+            max_memory,
+            (resident + per_face).div_ceil(1024 * 1024)
+        );
+    }
+    Some(concurrent.clamp(1, 6) as usize)
+}
+
+// --faces, or all six, rendered `concurrent` at a time and each written as
+// soon as it's encoded
+fn write_faces_each(
+    img: &DynamicImage,
+    options: &CubemapOptions,
+    concurrent: usize,
+    out_dir: &Path,
+    cli: &ConvertArgs,
+    output: &ImageOutput,
+    renderer: &Renderer,
+) -> Result<Vec<(Option<Face>, StoredFile)>> {
+    let start = Instant::now();
+    // --faces and file names count in the convention's slots; each slot
+    // holds one of our faces, possibly flipped
+    let slots = if cli.faces.is_empty() { Face::ALL.to_vec() } else { cli.faces.clone() };
+    let source = |slot: Face| cli.convention.map_or((slot, None), |convention| {
+        let (face, transform) = convention.source(slot);
+        (face, Some(transform))
+    });
+    let faces: Vec<Face> = slots.iter().map(|&slot| source(slot).0).collect();
+    let slot_of = |face: Face| slots[faces.iter().position(|&f| f == face).expect("rendering only these faces")];
+    let name = |slot: Face| cli.convention.map_or(slot.name(), |convention| convention.face_name(slot));
+    let extension = output.encode.format.extension();
+    let path = |slot: Face| out_dir.join(output.names.file(options.size, slot, cli.convention, extension));
+    // Ordered output keeps the (much smaller) encoded files until all are done
+    let span = Span::current();
+    let encoded = renderer.render_each(img, &faces, options, concurrent, |face, mut img| {
+        let _span = span.enter();
         let face_start = Instant::now();
-        let mut face_buffer: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::new(size, size);
-        
-        // Use larger chunks for better cache utilization
-        let chunk_size = (size * 16) as usize; // Adjust chunk size based on face size
-        face_buffer.enumerate_pixels_mut()
-            .collect::<Vec<_>>()
-            .par_chunks_mut(chunk_size.min(size as usize * size as usize))
-            .for_each(|chunk| {
-                for (x, y, pixel) in chunk {
-                    let (u, v) = cube_to_spherical(*x, *y, size, face);
-                    
-                    let x = (u * width as f32).rem_euclid(width as f32);
-                    let y = (v * height as f32).rem_euclid(height as f32);
-                    
-                    let x0 = x.floor() as u32;
-                    let y0 = y.floor() as u32;
-                    let x1 = (x0 + 1) % width;
-                    let y1 = (y0 + 1) % height;
-                    
-                    let fx = x.fract();
-                    let fy = y.fract();
-                    
-                    let p00 = rgb_img.get_pixel(x0, y0);
-                    let p10 = rgb_img.get_pixel(x1, y0);
-                    let p01 = rgb_img.get_pixel(x0, y1);
-                    let p11 = rgb_img.get_pixel(x1, y1);
-                    
-                    **pixel = Rgb([
-                        bilerp(p00[0], p10[0], p01[0], p11[0], fx, fy),
-                        bilerp(p00[1], p10[1], p01[1], p11[1], fx, fy),
-                        bilerp(p00[2], p10[2], p01[2], p11[2], fx, fy),
-                    ]);
-                }
-            });
-        
-        // Save with optimized buffer size
-        let output_path = format!("output/cubemap_{}/{}.jpg", size, face);
-        let file = File::create(&output_path)?;
-        let buf_writer = BufWriter::with_capacity(65536, file); // 64KB buffer
-        let mut encoder = JpegEncoder::new_with_quality(buf_writer, quality);
-        encoder.encode(
-            face_buffer.as_raw(),
-            size,
-            size,
-            image::ColorType::Rgb8
-        )?;
-        
-        println!("Face {} completed in {:?}", face, face_start.elapsed());
-        Ok(())
+        let slot = slot_of(face);
+        if let Some(sharpen) = &cli.sharpen {
+            sharpen.apply_dynamic(&mut img);
+        }
+        let img = match source(slot).1 {
+            Some(transform) => transform.apply(&img),
+            None => img,
+        };
+        let data = encode_to_vec(&img, &output.encode, &output.metadata)?;
+        let blurhash = output.blurhash_of(&img)?;
+        if output.ordered {
+            output.face_done(name(slot), face_start.elapsed());
+            return Ok((slot, Some(data), None, blurhash));
+        }
+        let file = output.destination.write(&path(slot), &data)?;
+        output.face_done(name(slot), face_start.elapsed());
+        let (name, elapsed) = (name(slot), start.elapsed());
+        info!(face = name, elapsed_ms = elapsed.as_secs_f64() * 1e3, "Face {} written at {:?}", name, elapsed);
+        Ok((slot, None, Some(file), blurhash))
     })?;
-    
-    println!("Total conversion time: {:?}", start.elapsed());
+    let written = encoded
+        .into_iter()
+        .map(|(slot, data, file, blurhash)| {
+            let file = match (data, file) {
+                (Some(data), _) => output.destination.write(&path(slot), &data)?,
+                (None, file) => file.expect("written as it was encoded"),
+            };
+            Ok((Some(slot), StoredFile { blurhash, ..file }))
+        })
+        .collect();
+    info!("Faces written in {:?}", start.elapsed());
+    written
+}
+
+fn write_container(
+    levels: &[CubemapFaces<DynamicImage>],
+    path: &Path,
+    container: ContainerArg,
+    cli: &ConvertArgs,
+    output: &ImageOutput,
+) -> Result<StoredFile> {
+    let mut data = Vec::new();
+    match container {
+        ContainerArg::Ktx2 => {
+            let options = Ktx2Options { mipmaps: !cli.no_mipmaps, supercompression: cli.supercompression };
+            write_ktx2_levels(levels, &options, &mut data)?;
+        }
+        ContainerArg::Dds => {
+            let options = DdsOptions { format: cli.dds_format, mipmaps: !cli.no_mipmaps };
+            write_dds_levels(levels, &options, &mut data)?;
+        }
+        ContainerArg::Safetensors => write_safetensors(levels, &mut data)?,
+    }
+    output.destination.write(path, &data)
+}
+
+// Diffuse irradiance cubemap and/or spherical harmonics of the native faces
+fn write_ambient(
+    cubemap: &CubemapFaces<DynamicImage>,
+    projection: CubeProjection,
+    out_dir: &Path,
+    cli: &ConvertArgs,
+    output: &ImageOutput,
+) -> Result<Vec<(Option<Face>, StoredFile)>> {
+    let sh = SphericalHarmonics::project_dynamic(cubemap, projection);
+    let mut written = Vec::new();
+    match cli.sh {
+        Some(ShFormat::Json) => {
+            let json = format!(
+                "{{\n  \"radiance\": {},\n  \"irradiance\": {}\n}}\n",
+                sh.to_json(),
+                sh.irradiance().to_json()
+            );
+            written.push((None, output.destination.write(&out_dir.join("sh.json"), json.as_bytes())?));
+        }
+        Some(ShFormat::Bin) => {
+            let mut bytes = sh.to_bytes();
+            bytes.extend(sh.irradiance().to_bytes());
+            written.push((None, output.destination.write(&out_dir.join("sh.bin"), &bytes)?));
+        }
+        None => {}
+    }
+
+    let Some(size) = cli.irradiance else {
+        return Ok(written);
+    };
+    let depth = cubemap.faces.iter().map(PixelDepth::of).max().unwrap_or(PixelDepth::U8);
+    let irradiance = irradiance_cubemap_dynamic(&sh, size, projection, depth);
+    let irradiance = match cli.convention {
+        Some(convention) => convention.apply(&irradiance),
+        None => irradiance,
+    };
+    match cli.container {
+        Some(container) => {
+            let path = out_dir.join(format!("irradiance.{}", container.extension()));
+            written.push((None, write_container(std::slice::from_ref(&irradiance), &path, container, cli, output)?));
+        }
+        None => {
+            let dir = out_dir.join("irradiance");
+            output.destination.create_dir_all(&dir)?;
+            written.extend(write_images(&irradiance, &dir, cli.layout.layout(), &[], cli.convention, output)?);
+        }
+    }
+    Ok(written)
+}
+
+// How the images of one input are encoded, and what they carry over from it
+struct ImageOutput<'a> {
+    encode: EncodeOptions,
+    metadata: Metadata,
+    destination: &'a Destination,
+    // Set when each cubemap directory gets a manifest.json
+    source: Option<ManifestSource>,
+    // Store faces in face order rather than as each one finishes encoding
+    ordered: bool,
+    names: NameTemplate,
+    // Record a BlurHash of every face image in the manifest
+    blurhash: bool,
+    // --report's record of the input being converted
+    report: Option<&'a InputReport>,
+}
+
+// Enough detail for a recognisable placeholder in 28 characters
+const BLURHASH_COMPONENTS: (u32, u32) = (4, 3);
+
+impl ImageOutput<'_> {
+    // The BlurHash of face `img`, if one was asked for
+    fn blurhash_of(&self, img: &DynamicImage) -> Result<Option<String>> {
+        match self.blurhash {
+            true => Ok(Some(blurhash(img, BLURHASH_COMPONENTS)?)),
+            false => Ok(None),
+        }
+    }
+
+    // Face `name` took `elapsed` to encode (and write, unless ordered)
+    fn face_done(&self, name: &str, elapsed: Duration) {
+        if let Some(report) = self.report {
+            report.face(name, elapsed);
+        }
+    }
+}
+
+// One image per face (optionally only `faces`), or a single packed layout
+fn write_images(
+    cubemap: &CubemapFaces<DynamicImage>,
+    out_dir: &Path,
+    layout: Option<Layout>,
+    faces: &[Face],
+    convention: Option<Convention>,
+    output: &ImageOutput,
+) -> Result<Vec<(Option<Face>, StoredFile)>> {
+    let start = Instant::now();
+    let encode = &output.encode;
+    if let Some(layout) = layout {
+        let packed = assemble_layout_dynamic(cubemap, layout);
+        let output_path = out_dir.join(format!("{}.{}", layout, encode.format.extension()));
+        let file = output.destination.save_image(&packed, &output_path, encode, &output.metadata)?;
+
+        info!("Layout {} written in {:?}", layout, start.elapsed());
+        return Ok(vec![(None, file)]);
+    }
+
+    let selected: Vec<_> = cubemap.iter().filter(|(face, _)| faces.is_empty() || faces.contains(face)).collect();
+    let path = |face: Face| out_dir.join(output.names.file(cubemap.size, face, convention, encode.format.extension()));
+    if output.ordered {
+        let encoded = selected
+            .par_iter()
+            .map(|(face, face_buffer)| {
+                let face_start = Instant::now();
+                let data = encode_to_vec(face_buffer, encode, &output.metadata)?;
+                let name = convention.map_or(face.name(), |convention| convention.face_name(*face));
+                output.face_done(name, face_start.elapsed());
+                Ok(data)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let written = selected
+            .iter()
+            .zip(encoded)
+            .map(|(&(face, face_buffer), data)| {
+                let file = output.destination.write(&path(face), &data)?;
+                Ok((Some(face), StoredFile { blurhash: output.blurhash_of(face_buffer)?, ..file }))
+            })
+            .collect();
+        info!("Faces written in {:?}", start.elapsed());
+        return written;
+    }
+
+    // The faces encode on other threads, which don't know the file and size
+    let span = Span::current();
+    selected
+        .par_iter()
+        .map(|(face, face_buffer)| {
+            let _span = span.enter();
+            let face_start = Instant::now();
+
+            let output_path = path(*face);
+            let file = output.destination.save_image(face_buffer, &output_path, encode, &output.metadata)?;
+            let file = StoredFile { blurhash: output.blurhash_of(face_buffer)?, ..file };
+
+            let name = convention.map_or(face.name(), |convention| convention.face_name(*face));
+            let elapsed = face_start.elapsed();
+            output.face_done(name, elapsed);
+            info!(face = name, elapsed_ms = elapsed.as_secs_f64() * 1e3, "Face {} encoded in {:?}", name, elapsed);
+            Ok((Some(*face), file))
+        })
+        .collect()
+}
+
+fn cubemap_options(cli: &ConvertArgs, size: u32) -> CubemapOptions {
+    CubemapOptions {
+        size,
+        filter: cli.filter,
+        ssaa: cli.ssaa,
+        ssaa_adaptive: !cli.ssaa_fixed,
+        rotation: Rotation::from_euler_degrees(cli.yaw, cli.pitch, cli.roll),
+        projection: cli.projection,
+        offset: cli.offset,
+        input: match cli.input_projection {
+            InputProjection::DualFisheye(_) => {
+                InputProjection::DualFisheye(DualFisheye { front: cli.front_lens, back: cli.back_lens })
+            }
+            input => input,
+        },
+        fill: cli.fill,
+        linear: cli.linear,
+        pixel_type: cli.pixel_type,
+        grade: ColorGrade {
+            exposure: cli.tone.exposure,
+            temperature: cli.wb_temp,
+            tint: cli.wb_tint,
+            saturation: cli.saturation,
+        },
+        bleed: cli.bleed,
+        progress: None,
+        post_process: None,
+        pool: None,
+        lut: None,
+    }
+}
+
+// Encoder settings from a subcommand's shared argument groups; PNG
+// compression is left at the default for the callers that take it
+fn encode_options(
+    format: OutputFormat,
+    quality: u8,
+    jpeg: &JpegArgs,
+    webp: &WebpArgs,
+    avif: &AvifArgs,
+    tone: &ToneMapArgs,
+) -> Result<EncodeOptions> {
+    Ok(EncodeOptions {
+        format,
+        quality,
+        png_compression: PngCompression::Default,
+        jpeg_backend: jpeg.jpeg_encoder,
+        webp_lossless: webp.webp_lossless,
+        avif_speed: avif.avif_speed,
+        avif_depth: avif.avif_depth,
+        jpeg_progressive: jpeg.jpeg_progressive,
+        jpeg_subsampling: jpeg.jpeg_subsampling,
+        tone_map: tone.tone_map().map_err(anyhow::Error::msg)?,
+        dither: tone.dither,
+    })
+}
+
+fn run_tiles(args: &TilesArgs) -> Result<()> {
+    let encode = encode_options(args.format, args.quality, &args.jpeg, &args.webp, &args.avif, &args.tone)?;
+    let start = Instant::now();
+
+    let img = load_image(&args.input)?;
+    let img = PixelDepth::of(&img).to_rgb(img);
+
+    // A face this size matches the panorama's resolution at the face centre;
+    // a multiple of 8 keeps the first few halvings exact
+    let face_size = args
+        .size
+        .unwrap_or_else(|| ((img.width() as f32 / std::f32::consts::PI / 8.0).round() as u32).max(1) * 8);
+    let pyramid = args.viewer.pyramid(face_size, args.tile_size, args.levels);
+    info!("Cutting {} levels of {}px tiles: {:?}", pyramid.level_sizes.len(), args.tile_size, pyramid.level_sizes);
+
+    let bars = FaceBars::new(pyramid.face_size(), &Face::ALL);
+    let options = CubemapOptions {
+        size: pyramid.face_size(),
+        filter: args.filter,
+        progress: Some(bars.callback()),
+        ..CubemapOptions::default()
+    };
+    let mut cubemap = equirect_to_cubemap_dynamic(&img, &options);
+    bars.finish();
+    info!("Faces rendered at {:?}", start.elapsed());
+
+    let extension = args.format.extension();
+    // Largest level first so each smaller one derives from the level above
+    for (index, &size) in pyramid.level_sizes.iter().enumerate().rev() {
+        if cubemap.size != size {
+            cubemap = cubemap.downsample_dynamic(size);
+        }
+        let level = index + 1;
+        let tiles: Vec<_> = cubemap
+            .iter()
+            .flat_map(|(face, buffer)| cut_tiles(buffer, args.tile_size).into_iter().map(move |tile| (face, tile)))
+            .collect();
+        let path = |(face, (row, col, _)): &(Face, _)| {
+            args.output_dir.join(args.viewer.tile_path(*face, level, *row, *col, extension))
+        };
+        // Each directory once, clearing what an interrupted run left there
+        let dirs: BTreeSet<_> = tiles.iter().filter_map(|tile| Some(path(tile).parent()?.to_path_buf())).collect();
+        dirs.iter().try_for_each(|dir| storage::create_output_dir(dir))?;
+        tiles.par_iter().try_for_each(|tile @ (_, (_, _, image))| -> Result<()> {
+            save_image(image, &path(tile), &encode)?;
+            Ok(())
+        })?;
+        info!("Level {} ({}x{}, {} tiles) written at {:?}", level, size, size, tiles.len(), start.elapsed());
+    }
+
+    if let Some(size) = args.viewer.preview_size(&pyramid) {
+        let preview = preview_strip(&cubemap.downsample_dynamic(size));
+        save_image(&preview, &args.output_dir.join(format!("preview.{}", extension)), &encode)?;
+        info!("Preview strip written at {:?}", start.elapsed());
+    }
+
+    for (name, config) in args.viewer.config(&pyramid, extension) {
+        write_atomic(args.output_dir.join(name), config.as_bytes())?;
+        info!("{} config written to {}", args.viewer, args.output_dir.join(name).display());
+    }
+
+    info!("Total tiling time: {:?}", start.elapsed());
+    Ok(())
+}
+
+fn run_resample(args: &ResampleArgs) -> Result<()> {
+    let format = args.format.or_else(|| OutputFormat::from_path(&args.faces[0])).unwrap_or(OutputFormat::Jpeg);
+    let encode = EncodeOptions {
+        png_compression: args.png_compression,
+        ..encode_options(format, args.quality, &args.jpeg, &args.webp, &args.avif, &args.tone)?
+    };
+    let start = Instant::now();
+
+    let images = args.faces.iter().map(|path| load_image(path)).collect::<Result<Vec<_>, _>>()?;
+    let cubemap = match images.len() {
+        1 => {
+            let img = &images[0];
+            let layout = Layout::detect(img.width(), img.height()).with_context(|| {
+                format!("{}: {}x{} is not a cross or strip layout", args.faces[0].display(), img.width(), img.height())
+            })?;
+            split_layout_dynamic(img, layout)?
+        }
+        6 => {
+            let depth = images.iter().map(PixelDepth::of).max().unwrap_or(PixelDepth::U8);
+            CubemapFaces::from_faces(images.into_iter().map(|img| depth.to_rgb(img)).collect())?
+        }
+        n => bail!("expected 6 face images or 1 cross image, got {}", n),
+    };
+    let cubemap = match args.input_convention {
+        Some(convention) => convention.unapply(&cubemap),
+        None => cubemap,
+    };
+
+    let options = CubemapOptions {
+        size: args.size.unwrap_or(cubemap.size),
+        rotation: Rotation::from_euler_degrees(args.yaw, args.pitch, args.roll),
+        projection: args.projection,
+        linear: args.linear,
+        ..CubemapOptions::default()
+    };
+    options.validate()?;
+    let cubemap = resample_cubemap_dynamic(&cubemap, &options);
+    let cubemap = match args.convention {
+        Some(convention) => convention.apply(&cubemap),
+        None => cubemap,
+    };
+    info!("Resampled to {}x{} at {:?}", options.size, options.size, start.elapsed());
+
+    let out_dir = args.output_dir.join(format!("cubemap_{}", options.size));
+    storage::create_output_dir(&out_dir)?;
+    let output = ImageOutput {
+        encode,
+        metadata: Metadata::read(&args.faces[0])?,
+        destination: &Destination::Files,
+        source: None,
+        ordered: false,
+        names: NameTemplate::default(),
+        blurhash: false,
+        report: None,
+    };
+    write_images(&cubemap, &out_dir, args.layout.layout(), &[], args.convention, &output)?;
+
+    info!("Total resampling time: {:?}", start.elapsed());
+    Ok(())
+}
+
+fn run_verify(args: &VerifyArgs) -> Result<()> {
+    let options: Vec<_> = args
+        .sizes
+        .iter()
+        .flat_map(|&size| {
+            args.filters.iter().map(move |&filter| CubemapOptions {
+                size,
+                filter,
+                ssaa: args.ssaa,
+                linear: args.linear,
+                ..CubemapOptions::default()
+            })
+        })
+        .collect();
+    for options in &options {
+        options.validate()?;
+    }
+
+    let img = load_image(&args.input)?;
+    let img = PixelDepth::of(&img).to_rgb(img);
+    if img.width() != 2 * img.height() {
+        bail!("{} is {}x{}; verify needs a 2:1 equirect panorama", args.input.display(), img.width(), img.height());
+    }
+    info!("Verifying {} ({}x{}) through a cubemap and back", args.input.display(), img.width(), img.height());
+
+    for options in &options {
+        let start = Instant::now();
+        let cubemap = equirect_to_cubemap_dynamic(&img, options);
+        let roundtrip = cubemap_to_equirect_dynamic(&cubemap, img.width());
+        let report = compare_equirect(&img, &roundtrip, args.bands);
+        println!(
+            "\nSize {}, {}: WS-PSNR {:.2} dB, WS-SSIM {:.4} (in {:?})",
+            options.size, options.filter, report.psnr, report.ssim, start.elapsed()
+        );
+        println!("  latitude        PSNR      SSIM");
+        for band in &report.bands {
+            println!("  {:+4.0} to {:+4.0}  {:6.2} dB  {:.4}", band.north, band.south, band.psnr, band.ssim);
+        }
+    }
+    Ok(())
+}
+
+fn run_view(args: &ViewArgs) -> Result<()> {
+    let start = Instant::now();
+
+    // Pitching up by 90 degrees brings the front to the bottom of the image
+    let pitch = args.tilt.map_or(args.pitch, |tilt| 90.0 - tilt);
+    let options = ViewOptions {
+        width: args.width,
+        height: args.height,
+        fov: args.fov,
+        rotation: Rotation::from_euler_degrees(args.yaw, pitch, args.roll),
+        filter: args.filter,
+        projection: args.projection,
+    };
+    options.validate()?;
+    let format = match args.format.or_else(|| OutputFormat::from_path(&args.output)) {
+        Some(format) => format,
+        None => bail!("cannot tell the output format of {}; pass --format", args.output.display()),
+    };
+    let encode = EncodeOptions {
+        png_compression: args.png_compression,
+        ..encode_options(format, args.quality, &args.jpeg, &args.webp, &args.avif, &args.tone)?
+    };
+
+    let img = load_image(&args.input)?;
+    let view = render_view_dynamic(&img, &options);
+    info!("Rendered {}x{} view at {:?}", args.width, args.height, start.elapsed());
+
+    if let Some(parent) = args.output.parent() {
+        storage::create_output_dir(parent)?;
+    }
+    save_image(&view, &args.output, &encode)?;
+
+    info!("Total rendering time: {:?}", start.elapsed());
     Ok(())
 }
 
-#[inline(always)]
-fn bilerp(c00: u8, c10: u8, c01: u8, c11: u8, fx: f32, fy: f32) -> u8 {
-    let c0 = c00 as f32 * (1.0 - fx) + c10 as f32 * fx;
-    let c1 = c01 as f32 * (1.0 - fx) + c11 as f32 * fx;
-    ((c0 * (1.0 - fy) + c1 * fy) + 0.5) as u8
+fn run_envmap(args: &EnvMapArgs) -> Result<()> {
+    let start = Instant::now();
+
+    let options = EnvMapOptions {
+        mapping: args.mapping,
+        size: args.size,
+        border: args.border,
+        overlap: args.overlap,
+        fov: args.fov.unwrap_or(120.0),
+        rotation: Rotation::from_euler_degrees(args.yaw, args.pitch, args.roll),
+        filter: args.filter,
+    };
+    options.validate()?;
+    if args.overlap > 0.0 && args.mapping != EnvMapping::DualParaboloid {
+        bail!("--overlap only applies to --mapping dual-paraboloid");
+    }
+    if args.fov.is_some() && !args.mapping.is_partial() {
+        bail!("--fov only applies to --mapping cylindrical or mercator");
+    }
+    let format = match args.format.or_else(|| OutputFormat::from_path(&args.output)) {
+        Some(format) => format,
+        None => bail!("cannot tell the output format of {}; pass --format", args.output.display()),
+    };
+    let encode = EncodeOptions {
+        png_compression: args.png_compression,
+        ..encode_options(format, args.quality, &args.jpeg, &args.webp, &args.avif, &args.tone)?
+    };
+
+    let img = load_image(&args.input)?;
+    let map = render_envmap_dynamic(&img, &options);
+    let (width, height) = options.dimensions();
+    info!("Rendered {}x{} {} map at {:?}", width, height, args.mapping, start.elapsed());
+
+    if let Some(parent) = args.output.parent() {
+        storage::create_output_dir(parent)?;
+    }
+    save_image(&map, &args.output, &encode)?;
+
+    info!("Total rendering time: {:?}", start.elapsed());
+    Ok(())
+}
+
+fn run_test_pattern(args: &TestPatternArgs) -> Result<()> {
+    let start = Instant::now();
+    let format = match args.format.or_else(|| OutputFormat::from_path(&args.output)) {
+        Some(format) => format,
+        None => bail!("cannot tell the output format of {}; pass --format", args.output.display()),
+    };
+
+    let img = DynamicImage::ImageRgb8(args.pattern.render(args.width));
+    info!("Rendered {}x{} {} pattern in {:?}", img.width(), img.height(), args.pattern, start.elapsed());
+
+    if let Some(parent) = args.output.parent() {
+        storage::create_output_dir(parent)?;
+    }
+    let encode = EncodeOptions { format, quality: args.quality, ..EncodeOptions::default() };
+    save_image(&img, &args.output, &encode)?;
+    Ok(())
+}
+
+fn run_equirect(args: &EquirectArgs) -> Result<()> {
+    let format = match args.format.or_else(|| OutputFormat::from_path(&args.output)) {
+        Some(format) => format,
+        None => bail!("cannot tell the output format of {}; pass --format", args.output.display()),
+    };
+    let encode = EncodeOptions {
+        png_compression: args.png_compression,
+        ..encode_options(format, args.quality, &args.jpeg, &args.webp, &args.avif, &args.tone)?
+    };
+    let start = Instant::now();
+
+    let images = args
+        .faces
+        .iter()
+        .map(|path| load_image(path))
+        .collect::<Result<Vec<_>, _>>()?;
+    let depth = images.iter().map(PixelDepth::of).max().unwrap_or(PixelDepth::U8);
+
+    let equirect = match depth {
+        PixelDepth::U8 => DynamicImage::from(stitch(images.into_iter().map(DynamicImage::into_rgb8).collect(), args)?),
+        PixelDepth::U16 => DynamicImage::from(stitch(images.into_iter().map(DynamicImage::into_rgb16).collect(), args)?),
+        PixelDepth::F32 => DynamicImage::from(stitch(images.into_iter().map(DynamicImage::into_rgb32f).collect(), args)?),
+    };
+    info!("Rendered {}x{} panorama at {:?}", equirect.width(), equirect.height(), start.elapsed());
+
+    if let Some(parent) = args.output.parent() {
+        storage::create_output_dir(parent)?;
+    }
+    save_image(&equirect, &args.output, &encode)?;
+
+    info!("Total conversion time: {:?}", start.elapsed());
+    Ok(())
+}
+
+fn stitch<P>(images: Vec<Buffer<P>>, args: &EquirectArgs) -> Result<Buffer<P>>
+where
+    P: Pixel + Send + Sync + 'static,
+    P::Subpixel: Channel,
+{
+    let cubemap = match images.len() {
+        1 => {
+            let img = &images[0];
+            let layout = Layout::detect(img.width(), img.height()).with_context(|| {
+                format!("{}: {}x{} is not a cross layout", args.faces[0].display(), img.width(), img.height())
+            })?;
+            split_layout(img, layout)?
+        }
+        6 => CubemapFaces::from_faces(images)?,
+        n => bail!("expected 6 face images or 1 cross image, got {}", n),
+    };
+
+    let width = args.width.unwrap_or(cubemap.size * 4);
+    Ok(cubemap_to_equirect(&cubemap, width))
 }
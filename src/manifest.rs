@@ -0,0 +1,166 @@
+use crate::storage::StoredFile;
+use rust_cube::{Convention, CubeProjection, Face, Filter, Layout};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::path::Path;
+
+/// The input a cubemap was rendered from.
+pub struct ManifestSource {
+    pub path: String,
+    pub sha256: String,
+}
+
+/// One file written under a cubemap directory.
+pub struct ManifestFile {
+    /// Path relative to the manifest, `/`-separated
+    pub name: String,
+    /// The native face the file holds, when it holds exactly one
+    pub face: Option<Face>,
+    pub bytes: u64,
+    pub sha256: String,
+    pub blurhash: Option<String>,
+}
+
+impl ManifestFile {
+    pub fn new(file: &StoredFile, dir: &Path, face: Option<Face>) -> ManifestFile {
+        let relative = file.path.strip_prefix(dir).unwrap_or(&file.path);
+        let name = relative.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/");
+        ManifestFile { name, face, bytes: file.bytes, sha256: file.sha256.clone(), blurhash: file.blurhash.clone() }
+    }
+}
+
+/// `manifest.json`: how a cubemap directory was produced and what it holds.
+pub struct Manifest<'a> {
+    pub source: &'a ManifestSource,
+    pub size: u32,
+    pub filter: Filter,
+    pub projection: CubeProjection,
+    pub convention: Option<Convention>,
+    pub layout: Option<Layout>,
+    /// Image format or container extension
+    pub format: &'a str,
+    pub quality: u8,
+    pub files: &'a [ManifestFile],
+}
+
+impl Manifest<'_> {
+    pub fn to_json(&self) -> String {
+        let files: Vec<Value> = self
+            .files
+            .iter()
+            .map(|file| {
+                let mut entry = json!({
+                    "name": file.name,
+                    "face": file.face.map(Face::name),
+                    "bytes": file.bytes,
+                    "sha256": file.sha256,
+                });
+                if let Some(blurhash) = &file.blurhash {
+                    entry["blurhash"] = json!(blurhash);
+                }
+                entry
+            })
+            .collect();
+        let manifest = json!({
+            "generator": format!("rust-cube {}", env!("CARGO_PKG_VERSION")),
+            "source": { "path": self.source.path, "sha256": self.source.sha256 },
+            "size": self.size,
+            "filter": self.filter.to_string(),
+            "projection": self.projection.to_string(),
+            "convention": self.convention.map(Convention::name),
+            "layout": self.layout.map(|layout| layout.to_string()),
+            "format": self.format,
+            "quality": self.quality,
+            "files": files,
+        });
+        pretty(&manifest)
+    }
+}
+
+// `value` indented, with a final newline
+pub fn pretty(value: &Value) -> String {
+    let mut json = serde_json::to_string_pretty(value).expect("JSON values serialize");
+    json.push('\n');
+    json
+}
+
+/// The parts of an existing manifest.json needed to check its files.
+#[derive(Debug, Deserialize)]
+pub struct ManifestRecord {
+    #[serde(rename = "source", deserialize_with = "source_sha256")]
+    pub source_sha256: String,
+    pub files: Vec<RecordedFile>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RecordedFile {
+    pub name: String,
+    pub sha256: String,
+}
+
+fn source_sha256<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    #[derive(Deserialize)]
+    struct Source {
+        sha256: String,
+    }
+    Ok(Source::deserialize(deserializer)?.sha256)
+}
+
+impl ManifestRecord {
+    /// Read a manifest written by `Manifest::to_json`; None for anything
+    /// that isn't one.
+    pub fn parse(json: &str) -> Option<ManifestRecord> {
+        serde_json::from_str(json).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifests_round_trip() {
+        let source = ManifestSource { path: "panos/\"lobby\"\\1.jpg".to_string(), sha256: "ab".repeat(32) };
+        let files = [
+            ManifestFile {
+                name: "right.jpg".to_string(),
+                face: Some(Face::Right),
+                bytes: 10,
+                sha256: "01".repeat(32),
+                blurhash: None,
+            },
+            ManifestFile {
+                name: "cross.png".to_string(),
+                face: None,
+                bytes: 20,
+                sha256: "02".repeat(32),
+                blurhash: Some("L00".to_string()),
+            },
+        ];
+        let manifest = Manifest {
+            source: &source,
+            size: 512,
+            filter: Filter::Lanczos3,
+            projection: CubeProjection::EquiAngular,
+            convention: None,
+            layout: Some(Layout::CrossHorizontal),
+            format: "png",
+            quality: 90,
+            files: &files,
+        };
+        let json = manifest.to_json();
+        let value: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["source"]["path"], source.path);
+        assert_eq!(value["filter"], Filter::Lanczos3.to_string());
+        assert_eq!(value["convention"], Value::Null);
+        assert_eq!(value["files"][0]["face"], "right");
+        assert_eq!(value["files"][1]["face"], Value::Null);
+        assert_eq!(value["files"][1]["blurhash"], "L00");
+
+        let record = ManifestRecord::parse(&json).unwrap();
+        assert_eq!(record.source_sha256, source.sha256);
+        let recorded: Vec<_> = record.files.iter().map(|file| (file.name.as_str(), file.sha256.as_str())).collect();
+        assert_eq!(recorded, [("right.jpg", files[0].sha256.as_str()), ("cross.png", files[1].sha256.as_str())]);
+        assert!(ManifestRecord::parse("{ \"source\": {} }").is_none());
+    }
+}
@@ -0,0 +1,274 @@
+use crate::{CubemapError, OutputFormat};
+use flate2::write::ZlibEncoder;
+use flate2::{Compression, Crc};
+use image::codecs::jpeg::JpegDecoder;
+use image::codecs::png::PngDecoder;
+use image::ImageDecoder;
+use std::io::{BufReader, Write};
+use std::path::Path;
+
+/// Color profile and EXIF fields carried over from the source into every
+/// output image. Only JPEG and PNG sources and outputs are supported.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub icc_profile: Option<Vec<u8>>,
+    /// TIFF-structured EXIF holding only the descriptive tags below
+    pub exif: Option<Vec<u8>>,
+}
+
+// ASCII tags worth keeping on a derived image. Orientation, dimensions,
+// thumbnails and GPS are dropped: they describe the source, not the faces.
+const IFD0_TAGS: [u16; 7] = [
+    0x010E, // ImageDescription
+    0x010F, // Make
+    0x0110, // Model
+    0x0131, // Software
+    0x0132, // DateTime
+    0x013B, // Artist
+    0x8298, // Copyright
+];
+const EXIF_TAGS: [u16; 2] = [
+    0x9003, // DateTimeOriginal
+    0x9004, // DateTimeDigitized
+];
+const EXIF_IFD_POINTER: u16 = 0x8769;
+const ASCII: u16 = 2;
+
+impl Metadata {
+    /// Read the ICC profile and selected EXIF fields of a JPEG or PNG file.
+    /// Other formats yield empty metadata.
+    pub fn read(path: &Path) -> Result<Metadata, CubemapError> {
+        let bytes = std::fs::read(path)?;
+        Metadata::from_bytes(&bytes).map_err(|source| CubemapError::Decode { path: path.to_path_buf(), source })
+    }
+
+    /// `read` for an encoded image already in memory.
+    pub fn from_bytes(bytes: &[u8]) -> image::ImageResult<Metadata> {
+        let (icc_profile, exif) = if bytes.starts_with(&[0xFF, 0xD8]) {
+            let mut decoder = JpegDecoder::new(BufReader::new(bytes))?;
+            (decoder.icc_profile(), jpeg_exif(bytes))
+        } else if bytes.starts_with(b"\x89PNG") {
+            let mut decoder = PngDecoder::new(BufReader::new(bytes))?;
+            (decoder.icc_profile(), png_chunk(bytes, b"eXIf"))
+        } else {
+            (None, None)
+        };
+        Ok(Metadata { icc_profile, exif: exif.and_then(|exif| filter_exif(&exif)) })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.icc_profile.is_none() && self.exif.is_none()
+    }
+
+    /// Splice the metadata into an encoded image. Formats other than JPEG
+    /// and PNG are returned unchanged.
+    pub fn embed(&self, encoded: Vec<u8>, format: OutputFormat) -> Result<Vec<u8>, CubemapError> {
+        if self.is_empty() {
+            return Ok(encoded);
+        }
+        match format {
+            OutputFormat::Jpeg => Ok(self.embed_jpeg(encoded)),
+            OutputFormat::Png => self.embed_png(encoded),
+            _ => Ok(encoded),
+        }
+    }
+
+    // APP1 Exif and APP2 ICC_PROFILE segments right after SOI and JFIF APP0
+    fn embed_jpeg(&self, encoded: Vec<u8>) -> Vec<u8> {
+        let mut insert_at = 2;
+        if encoded[2..4] == [0xFF, 0xE0] {
+            insert_at += 2 + u16::from_be_bytes([encoded[4], encoded[5]]) as usize;
+        }
+
+        let mut segments = Vec::new();
+        let mut segment = |marker: u8, payload: &[u8]| {
+            segments.extend([0xFF, marker]);
+            segments.extend((payload.len() as u16 + 2).to_be_bytes());
+            segments.extend(payload);
+        };
+        if let Some(exif) = &self.exif {
+            segment(0xE1, &[b"Exif\0\0".as_slice(), exif].concat());
+        }
+        if let Some(icc) = &self.icc_profile {
+            // Profiles larger than one segment are split and numbered from 1
+            let chunks: Vec<&[u8]> = icc.chunks(65519).collect();
+            for (i, chunk) in chunks.iter().enumerate() {
+                let header = [b"ICC_PROFILE\0".as_slice(), &[i as u8 + 1, chunks.len() as u8]].concat();
+                segment(0xE2, &[header.as_slice(), chunk].concat());
+            }
+        }
+
+        let mut out = encoded;
+        out.splice(insert_at..insert_at, segments);
+        out
+    }
+
+    // iCCP and eXIf chunks right after IHDR
+    fn embed_png(&self, encoded: Vec<u8>) -> Result<Vec<u8>, CubemapError> {
+        let mut chunks = Vec::new();
+        if let Some(icc) = &self.icc_profile {
+            let mut zlib = ZlibEncoder::new(b"ICC Profile\0\0".to_vec(), Compression::default());
+            zlib.write_all(icc)?;
+            // The profile name and compression method stay uncompressed
+            let data = zlib.finish()?;
+            push_png_chunk(&mut chunks, b"iCCP", &data);
+        }
+        if let Some(exif) = &self.exif {
+            push_png_chunk(&mut chunks, b"eXIf", exif);
+        }
+
+        // Signature (8) + IHDR length, type, 13 data bytes and CRC
+        let insert_at = 8 + 4 + 4 + 13 + 4;
+        let mut out = encoded;
+        out.splice(insert_at..insert_at, chunks);
+        Ok(out)
+    }
+}
+
+fn push_png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    let mut crc = Crc::new();
+    crc.update(kind);
+    crc.update(data);
+    out.extend((data.len() as u32).to_be_bytes());
+    out.extend(kind);
+    out.extend(data);
+    out.extend(crc.sum().to_be_bytes());
+}
+
+// TIFF payload of the first APP1 Exif segment
+fn jpeg_exif(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut pos = 2;
+    while pos + 4 <= bytes.len() && bytes[pos] == 0xFF {
+        let marker = bytes[pos + 1];
+        // Start of scan: no more metadata segments
+        if marker == 0xDA {
+            break;
+        }
+        let length = u16::from_be_bytes([bytes[pos + 2], bytes[pos + 3]]) as usize;
+        let payload = bytes.get(pos + 4..pos + 2 + length)?;
+        if marker == 0xE1 && payload.starts_with(b"Exif\0\0") {
+            return Some(payload[6..].to_vec());
+        }
+        pos += 2 + length;
+    }
+    None
+}
+
+fn png_chunk(bytes: &[u8], kind: &[u8; 4]) -> Option<Vec<u8>> {
+    let mut pos = 8;
+    while pos + 8 <= bytes.len() {
+        let length = u32::from_be_bytes(bytes[pos..pos + 4].try_into().ok()?) as usize;
+        let data = bytes.get(pos + 8..pos + 8 + length)?;
+        if &bytes[pos + 4..pos + 8] == kind {
+            return Some(data.to_vec());
+        }
+        pos += 12 + length;
+    }
+    None
+}
+
+// Rebuild a little-endian TIFF holding only the whitelisted ASCII tags
+fn filter_exif(tiff: &[u8]) -> Option<Vec<u8>> {
+    let reader = TiffReader::new(tiff)?;
+    let ifd0 = reader.u32(4)? as usize;
+    let mut main = reader.ascii_tags(ifd0, &IFD0_TAGS);
+    let exif = match reader.entry(ifd0, EXIF_IFD_POINTER).and_then(|(_, _, value)| reader.u32(value)) {
+        Some(offset) => reader.ascii_tags(offset as usize, &EXIF_TAGS),
+        None => Vec::new(),
+    };
+    if main.is_empty() && exif.is_empty() {
+        return None;
+    }
+    main.sort_by_key(|(tag, _)| *tag);
+
+    // Header, IFD0, the Exif IFD, then the string data they point into
+    let ifd_size = |count: usize| 2 + 12 * count + 4;
+    let main_count = main.len() + usize::from(!exif.is_empty());
+    let exif_offset = 8 + ifd_size(main_count);
+    let mut data_offset = exif_offset + if exif.is_empty() { 0 } else { ifd_size(exif.len()) };
+
+    let mut out = b"II\x2A\0\x08\0\0\0".to_vec();
+    let mut data = Vec::new();
+    let mut write_ifd = |out: &mut Vec<u8>, tags: &[(u16, Vec<u8>)], pointer: Option<u32>| {
+        out.extend((tags.len() as u16 + u16::from(pointer.is_some())).to_le_bytes());
+        for (tag, value) in tags {
+            out.extend(tag.to_le_bytes());
+            out.extend(ASCII.to_le_bytes());
+            out.extend((value.len() as u32).to_le_bytes());
+            if value.len() <= 4 {
+                let mut inline = [0u8; 4];
+                inline[..value.len()].copy_from_slice(value);
+                out.extend(inline);
+            } else {
+                out.extend((data_offset as u32).to_le_bytes());
+                data.extend(value);
+                data_offset += value.len();
+                // Values start on word boundaries
+                if value.len() % 2 == 1 {
+                    data.push(0);
+                    data_offset += 1;
+                }
+            }
+        }
+        if let Some(pointer) = pointer {
+            out.extend(EXIF_IFD_POINTER.to_le_bytes());
+            out.extend(4u16.to_le_bytes()); // LONG
+            out.extend(1u32.to_le_bytes());
+            out.extend(pointer.to_le_bytes());
+        }
+        out.extend(0u32.to_le_bytes()); // no next IFD
+    };
+    write_ifd(&mut out, &main, (!exif.is_empty()).then_some(exif_offset as u32));
+    if !exif.is_empty() {
+        write_ifd(&mut out, &exif, None);
+    }
+    out.extend(data);
+    Some(out)
+}
+
+struct TiffReader<'a> {
+    bytes: &'a [u8],
+    little_endian: bool,
+}
+
+impl<'a> TiffReader<'a> {
+    fn new(bytes: &'a [u8]) -> Option<TiffReader<'a>> {
+        let little_endian = match bytes.get(..4)? {
+            b"II\x2A\0" => true,
+            b"MM\0\x2A" => false,
+            _ => return None,
+        };
+        Some(TiffReader { bytes, little_endian })
+    }
+
+    fn u16(&self, pos: usize) -> Option<u16> {
+        let raw = self.bytes.get(pos..pos + 2)?.try_into().ok()?;
+        Some(if self.little_endian { u16::from_le_bytes(raw) } else { u16::from_be_bytes(raw) })
+    }
+
+    fn u32(&self, pos: usize) -> Option<u32> {
+        let raw = self.bytes.get(pos..pos + 4)?.try_into().ok()?;
+        Some(if self.little_endian { u32::from_le_bytes(raw) } else { u32::from_be_bytes(raw) })
+    }
+
+    // Type, count and position of the value field of `tag` in the IFD at
+    // `ifd`; the field holds the value itself when it fits in four bytes
+    fn entry(&self, ifd: usize, tag: u16) -> Option<(u16, usize, usize)> {
+        let count = self.u16(ifd)? as usize;
+        let entry = (0..count).map(|i| ifd + 2 + 12 * i).find(|&entry| self.u16(entry) == Some(tag))?;
+        Some((self.u16(entry + 2)?, self.u32(entry + 4)? as usize, entry + 8))
+    }
+
+    fn ascii_tags(&self, ifd: usize, tags: &[u16]) -> Vec<(u16, Vec<u8>)> {
+        tags.iter()
+            .filter_map(|&tag| {
+                let (kind, count, value) = self.entry(ifd, tag)?;
+                if kind != ASCII {
+                    return None;
+                }
+                let start = if count <= 4 { value } else { self.u32(value)? as usize };
+                Some((tag, self.bytes.get(start..start + count)?.to_vec()))
+            })
+            .collect()
+    }
+}
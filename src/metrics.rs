@@ -0,0 +1,114 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Upper bounds of the latency buckets in seconds
+const BUCKETS: [f64; 12] = [0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/// Where a conversion spends its time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Decode,
+    Render,
+    /// Encoding and writing the outputs
+    Encode,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 3] = [Stage::Decode, Stage::Render, Stage::Encode];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Decode => "decode",
+            Stage::Render => "render",
+            Stage::Encode => "encode",
+        }
+    }
+}
+
+/// Counters of a long-running service, in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    succeeded: AtomicU64,
+    failed: AtomicU64,
+    queued: AtomicU64,
+    stages: [Histogram; 3],
+}
+
+#[derive(Default)]
+struct Histogram {
+    // One count per bucket, not cumulative, and one past the last
+    counts: [AtomicU64; BUCKETS.len() + 1],
+    micros: AtomicU64,
+}
+
+/// A conversion waiting its turn, counted until dropped.
+pub struct Queued(Arc<Metrics>);
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        self.0.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn observe(&self, stage: Stage, elapsed: Duration) {
+        let histogram = &self.stages[stage as usize];
+        let bucket = BUCKETS.iter().position(|&bound| elapsed.as_secs_f64() <= bound).unwrap_or(BUCKETS.len());
+        histogram.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        histogram.micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn finished(&self, ok: bool) {
+        let counter = if ok { &self.succeeded } else { &self.failed };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn queue(self: &Arc<Self>) -> Queued {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        Queued(self.clone())
+    }
+
+    pub fn render(&self) -> String {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let mut lines = Vec::new();
+        header(&mut lines, "conversions_total", "counter", "Conversions finished, by result");
+        lines.push(format!("rust_cube_conversions_total{{result=\"ok\"}} {}", load(&self.succeeded)));
+        lines.push(format!("rust_cube_conversions_total{{result=\"error\"}} {}", load(&self.failed)));
+
+        header(&mut lines, "stage_seconds", "histogram", "Time spent decoding, rendering and encoding");
+        for (stage, histogram) in Stage::ALL.iter().zip(&self.stages) {
+            let name = stage.name();
+            let mut count = 0;
+            for (i, bucket) in histogram.counts.iter().enumerate() {
+                count += load(bucket);
+                let bound = BUCKETS.get(i).map_or("+Inf".to_string(), |bound| bound.to_string());
+                lines.push(format!("rust_cube_stage_seconds_bucket{{stage=\"{}\",le=\"{}\"}} {}", name, bound, count));
+            }
+            let seconds = load(&histogram.micros) as f64 / 1e6;
+            lines.push(format!("rust_cube_stage_seconds_sum{{stage=\"{}\"}} {}", name, seconds));
+            lines.push(format!("rust_cube_stage_seconds_count{{stage=\"{}\"}} {}", name, count));
+        }
+
+        header(&mut lines, "queue_depth", "gauge", "Conversions waiting to start");
+        lines.push(format!("rust_cube_queue_depth {}", load(&self.queued)));
+        if let Some(peak) = peak_resident_bytes() {
+            header(&mut lines, "peak_resident_bytes", "gauge", "Most memory the process has held at once");
+            lines.push(format!("rust_cube_peak_resident_bytes {}", peak));
+        }
+        lines.join("\n") + "\n"
+    }
+}
+
+fn header(lines: &mut Vec<String>, name: &str, kind: &str, help: &str) {
+    lines.push(format!("# HELP rust_cube_{} {}", name, help));
+    lines.push(format!("# TYPE rust_cube_{} {}", name, kind));
+}
+
+// The kernel's high-water mark of resident memory; Linux only
+fn peak_resident_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find_map(|line| line.strip_prefix("VmHWM:"))?;
+    let kilobytes: u64 = line.trim().strip_suffix("kB")?.trim().parse().ok()?;
+    Some(kilobytes * 1024)
+}
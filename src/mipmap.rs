@@ -0,0 +1,126 @@
+use crate::sampler::{premultiply, unpremultiply};
+use crate::{Buffer, Channel};
+use image::{Pixel, Primitive};
+
+/// Halve `img` with a 2x2 box filter, with premultiplied alpha. Odd edges
+/// reuse the last row/column.
+pub fn downsample_half<P>(img: &Buffer<P>) -> Buffer<P>
+where
+    P: Pixel,
+    P::Subpixel: Channel,
+{
+    let (width, height) = img.dimensions();
+    let (out_w, out_h) = ((width / 2).max(1), (height / 2).max(1));
+    let mut out: Buffer<P> = Buffer::new(out_w, out_h);
+
+    for (x, y, pixel) in out.enumerate_pixels_mut() {
+        let x0 = (2 * x).min(width - 1);
+        let y0 = (2 * y).min(height - 1);
+        let x1 = (x0 + 1).min(width - 1);
+        let y1 = (y0 + 1).min(height - 1);
+
+        let p00 = premultiply(img.get_pixel(x0, y0).channels());
+        let p10 = premultiply(img.get_pixel(x1, y0).channels());
+        let p01 = premultiply(img.get_pixel(x0, y1).channels());
+        let p11 = premultiply(img.get_pixel(x1, y1).channels());
+        let sum: [f32; 4] = std::array::from_fn(|c| p00[c] + p10[c] + p01[c] + p11[c]);
+        unpremultiply(sum.map(|value| value * 0.25), pixel.channels_mut());
+    }
+
+    out
+}
+
+/// Shrink `img` to `width` x `height` by area averaging in linear light, so
+/// sRGB content keeps its brightness, and with premultiplied alpha. Works
+/// for any ratio, not just halving.
+pub fn downsample_linear<P>(img: &Buffer<P>, width: u32, height: u32) -> Buffer<P>
+where
+    P: Pixel,
+    P::Subpixel: Channel,
+{
+    let (src_w, src_h) = img.dimensions();
+    let channels = P::CHANNEL_COUNT as usize;
+
+    // Separable box: columns first, then rows
+    let cols = box_weights(src_w, width);
+    let rows = box_weights(src_h, height);
+    let mut horizontal = vec![0.0f32; width as usize * src_h as usize * channels];
+    let mut src_row = vec![0.0f32; src_w as usize * channels];
+    let max = P::Subpixel::DEFAULT_MAX_VALUE.to_f32();
+    for (y, raw_row) in img.as_raw().chunks_exact(src_w as usize * channels).enumerate() {
+        for (linear, texel) in src_row.chunks_exact_mut(channels).zip(raw_row.chunks_exact(channels)) {
+            for (value, &channel) in linear.iter_mut().zip(texel) {
+                *value = channel.to_linear();
+            }
+            if channels == 4 {
+                linear[3] = texel[3].to_f32() / max;
+                let alpha = linear[3];
+                linear[..3].iter_mut().for_each(|value| *value *= alpha);
+            }
+        }
+        let dst_row = &mut horizontal[y * width as usize * channels..][..width as usize * channels];
+        for (x, taps) in cols.iter().enumerate() {
+            for &(sx, weight) in taps {
+                for c in 0..channels {
+                    dst_row[x * channels + c] += src_row[sx * channels + c] * weight;
+                }
+            }
+        }
+    }
+
+    let mut out: Buffer<P> = Buffer::new(width, height);
+    let row_len = width as usize * channels;
+    for (y, taps) in rows.iter().enumerate() {
+        let mut acc = vec![0.0f32; row_len];
+        for &(sy, weight) in taps {
+            for (a, v) in acc.iter_mut().zip(&horizontal[sy * row_len..][..row_len]) {
+                *a += v * weight;
+            }
+        }
+        let dst = &mut out.as_mut()[y * row_len..][..row_len];
+        for (d, a) in dst.chunks_exact_mut(channels).zip(acc.chunks_exact(channels)) {
+            let alpha = if channels == 4 { a[3] } else { 1.0 };
+            let scale = if alpha > 1e-6 { 1.0 / alpha } else { 0.0 };
+            for (c, (d, &a)) in d.iter_mut().zip(a).enumerate() {
+                *d = if c < 3 { P::Subpixel::from_linear(a * scale) } else { P::Subpixel::from_f32(a * max) };
+            }
+        }
+    }
+
+    out
+}
+
+// Source texels covered by each output texel with their normalized overlap
+fn box_weights(src: u32, dst: u32) -> Vec<Vec<(usize, f32)>> {
+    let scale = src as f64 / dst as f64;
+    (0..dst)
+        .map(|i| {
+            let start = i as f64 * scale;
+            let end = ((i + 1) as f64 * scale).min(src as f64);
+            let mut taps = Vec::new();
+            let mut s = start.floor() as usize;
+            while (s as f64) < end {
+                let overlap = (end.min(s as f64 + 1.0) - start.max(s as f64)) as f32;
+                if overlap > 0.0 {
+                    taps.push((s.min(src as usize - 1), overlap / scale as f32));
+                }
+                s += 1;
+            }
+            taps
+        })
+        .collect()
+}
+
+/// Full mip chain down to 1x1 with `img` as level 0.
+pub fn mip_chain<P>(img: Buffer<P>) -> Vec<Buffer<P>>
+where
+    P: Pixel,
+    P::Subpixel: Channel,
+{
+    let mut levels = vec![img];
+    while let Some(last) = levels.last().filter(|level| level.width() > 1 || level.height() > 1) {
+        let next = downsample_half(last);
+        levels.push(next);
+    }
+    levels
+}
@@ -0,0 +1,104 @@
+use crate::{spherical_to_direction, Buffer, Channel, CubemapError, PixelDepth};
+use image::{imageops, DynamicImage, Pixel};
+use rayon::prelude::*;
+
+/// Logo or patch composited over the nadir of a panorama, e.g. to hide the
+/// tripod.
+#[derive(Debug, Clone)]
+pub struct NadirPatch {
+    /// The patch as seen looking straight down with the front at the top of
+    /// the image (the down face's orientation); alpha is respected
+    pub image: DynamicImage,
+    /// Angular diameter of the patch in degrees
+    pub diameter: f32,
+    /// Soft edge as a fraction of the radius: 0 is a hard edge, 1 fades out
+    /// all the way from the centre
+    pub feather: f32,
+}
+
+impl NadirPatch {
+    /// Reject parameters `apply` can't honour.
+    pub fn validate(&self) -> Result<(), CubemapError> {
+        if !(self.diameter > 0.0 && self.diameter < 180.0) {
+            return Err(CubemapError::Projection(format!(
+                "nadir patch diameter must be between 0 and 180 degrees, got {}",
+                self.diameter
+            )));
+        }
+        if !(0.0..=1.0).contains(&self.feather) {
+            return Err(CubemapError::Projection(format!(
+                "nadir patch feather must be between 0 and 1, got {}",
+                self.feather
+            )));
+        }
+        Ok(())
+    }
+
+    /// Composite the patch into an equirect panorama in place. The patch is
+    /// laid flat on the ground below the camera, so it stays undistorted in
+    /// the down face, and blended in linear light.
+    pub fn apply<P>(&self, equirect: &mut Buffer<P>)
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Channel,
+    {
+        let patch = self.image.to_rgba16();
+        // Radius on a ground plane one unit below the camera
+        let radius = (self.diameter / 2.0).to_radians().tan();
+        let (width, height) = equirect.dimensions();
+        let channels = P::CHANNEL_COUNT as usize;
+        // Rows above the patch's rim are left alone
+        let first_row = ((1.0 - self.diameter / 360.0) * height as f32).floor() as usize;
+
+        equirect
+            .par_chunks_mut(width as usize * channels)
+            .enumerate()
+            .skip(first_row)
+            .for_each(|(y, row)| {
+                let v = y as f32 / height as f32;
+                for (x, pixel) in row.chunks_exact_mut(channels).enumerate() {
+                    let [dx, dy, dz] = spherical_to_direction(x as f32 / width as f32, v);
+                    if dy >= 0.0 {
+                        continue;
+                    }
+                    let (gx, gz) = (dx / -dy / radius, dz / -dy / radius);
+                    let r = (gx * gx + gz * gz).sqrt();
+                    if r >= 1.0 {
+                        continue;
+                    }
+                    // +X is to the right of the patch and +Z (front) at its top
+                    let Some(texel) = imageops::sample_bilinear(&patch, 0.5 + gx / 2.0, 0.5 - gz / 2.0) else {
+                        continue;
+                    };
+                    let alpha = self.edge(r) * texel[3].to_f32() / u16::MAX as f32;
+                    for (value, patch) in pixel.iter_mut().zip(texel.0).take(3) {
+                        let blended = value.to_linear() * (1.0 - alpha) + patch.to_linear() * alpha;
+                        *value = P::Subpixel::from_linear(blended);
+                    }
+                }
+            });
+    }
+
+    /// `apply` at the panorama's own precision; other color types are
+    /// converted to RGB first.
+    pub fn apply_dynamic(&self, equirect: &mut DynamicImage) {
+        match equirect {
+            DynamicImage::ImageRgb8(img) => self.apply(img),
+            DynamicImage::ImageRgb16(img) => self.apply(img),
+            DynamicImage::ImageRgb32F(img) => self.apply(img),
+            img => {
+                *img = PixelDepth::of(img).to_rgb(img.clone());
+                self.apply_dynamic(img);
+            }
+        }
+    }
+
+    // Opacity at distance `r` from the centre (1 at the rim)
+    fn edge(&self, r: f32) -> f32 {
+        if self.feather <= 0.0 {
+            return 1.0;
+        }
+        let t = ((1.0 - r) / self.feather).clamp(0.0, 1.0);
+        t * t * (3.0 - 2.0 * t)
+    }
+}
@@ -0,0 +1,121 @@
+use crate::{Channel, Face, Rotation};
+use std::fmt;
+use std::str::FromStr;
+
+/// What the source panorama's pixels hold, and so how filtered samples are
+/// finished.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PixelType {
+    /// Colors, used as filtered
+    #[default]
+    Color,
+    /// Unit normals in the panorama's axes (+X right, +Y up, +Z front),
+    /// stored as 0.5 + 0.5 * n in 8 and 16-bit images and as n in float
+    /// ones. Filtered samples are renormalized and turned with the cube's
+    /// rotation
+    Normal,
+    /// As `Normal`, then expressed in each face's tangent frame: +X along the
+    /// face's image x, +Y up its image and +Z out through its centre
+    FaceNormal,
+}
+
+impl PixelType {
+    pub const ALL: [PixelType; 3] = [PixelType::Color, PixelType::Normal, PixelType::FaceNormal];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PixelType::Color => "color",
+            PixelType::Normal => "normal",
+            PixelType::FaceNormal => "face-normal",
+        }
+    }
+
+    /// Matrix taking a panorama normal to the one stored in `face` of a cube
+    /// rendered with `rotation`; None for colors.
+    pub(crate) fn frame(self, face: Face, rotation: &Rotation) -> Option<[[f32; 3]; 3]> {
+        match self {
+            PixelType::Color => None,
+            // Sampling turns cube directions into panorama ones; normals go back
+            PixelType::Normal => Some(rotation.inverse().matrix()),
+            PixelType::FaceNormal => {
+                let basis = rotation.apply_basis(face.basis());
+                Some([basis.right, basis.down.map(|v| -v), basis.center])
+            }
+        }
+    }
+}
+
+impl fmt::Display for PixelType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for PixelType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        PixelType::ALL
+            .into_iter()
+            .find(|pixel_type| pixel_type.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown pixel type '{}' (expected color, normal or face-normal)", s))
+    }
+}
+
+/// Turn the normals in a row of `channels`-channel pixels by `frame` and
+/// bring them back to unit length, which interpolation and supersampling
+/// shorten. Channels after the first three (alpha) are left alone, as are
+/// vectors that averaged out to nothing.
+pub(crate) fn apply_row<T: Channel>(frame: &[[f32; 3]; 3], row: &mut [T], channels: usize) {
+    if channels < 3 {
+        return;
+    }
+    // Float images hold the components themselves, others map [-1, 1] to
+    // their full range
+    let max = Channel::to_f32(T::DEFAULT_MAX_VALUE);
+    let (scale, bias) = if max == 1.0 { (1.0, 0.0) } else { (2.0 / max, -1.0) };
+    for pixel in row.chunks_exact_mut(channels) {
+        let n: [f32; 3] = std::array::from_fn(|i| Channel::to_f32(pixel[i]) * scale + bias);
+        let turned: [f32; 3] = std::array::from_fn(|i| frame[i][0] * n[0] + frame[i][1] * n[1] + frame[i][2] * n[2]);
+        let length = (turned[0] * turned[0] + turned[1] * turned[1] + turned[2] * turned[2]).sqrt();
+        if length > 0.0 {
+            for (value, component) in pixel.iter_mut().zip(turned) {
+                *value = T::from_f32((component / length - bias) / scale);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{equirect_to_cubemap, CubemapOptions};
+
+    #[test]
+    fn normals_stay_unit_and_turn() {
+        // Columns alternating between +X and +Z, which blend to shorter vectors
+        let columns = [[1.0, 0.0, 0.0], [0.0, 0.0, 1.0]];
+        let src = image::Rgb32FImage::from_fn(64, 32, |x, _| image::Rgb(columns[x as usize % 2]));
+        let options = CubemapOptions { size: 16, pixel_type: PixelType::Normal, ..CubemapOptions::default() };
+        for face in &equirect_to_cubemap(&src, &options).faces {
+            for pixel in face.pixels() {
+                let length = pixel.0.iter().map(|v| v * v).sum::<f32>().sqrt();
+                assert!((length - 1.0).abs() < 1e-4, "length {}", length);
+            }
+        }
+
+        // Normals all facing +Z: out of the front face, into the back one
+        let src = image::RgbImage::from_pixel(64, 32, image::Rgb([128, 128, 255]));
+        let options = CubemapOptions { size: 8, pixel_type: PixelType::FaceNormal, ..CubemapOptions::default() };
+        let faces = equirect_to_cubemap(&src, &options);
+        // 128 is a little above 0, so mirrored axes round a level lower
+        let close = |pixel: [u8; 3], expected: [u8; 3]| pixel.iter().zip(expected).all(|(a, b)| a.abs_diff(b) <= 1);
+        assert!(close(faces.get(Face::Front).get_pixel(4, 4).0, [128, 128, 255]));
+        assert!(close(faces.get(Face::Back).get_pixel(4, 4).0, [128, 128, 0]));
+        // In world space a quarter turn of the cube turns them the other way
+        let rotation = Rotation::from_euler_degrees(90.0, 0.0, 0.0);
+        let options = CubemapOptions { pixel_type: PixelType::Normal, rotation, ..options };
+        let turned = equirect_to_cubemap(&src, &options).get(Face::Front).get_pixel(4, 4).0;
+        assert!(turned[2].abs_diff(128) <= 1 && (turned[0] == 0 || turned[0] == 255), "{:?}", turned);
+    }
+}
@@ -0,0 +1,159 @@
+use crate::{spherical_to_direction, Face};
+use image::RgbImage;
+use rayon::prelude::*;
+use std::f32::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+
+/// Synthetic panorama for checking conversions without sample photos.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TestPattern {
+    /// Latitude/longitude lines every 5 degrees, heavier every 15; the
+    /// equator is red, the front meridian green and the back one blue
+    #[default]
+    Grid,
+    /// Each cube face in its own colour with its initial (R, L, U, D, F, B)
+    /// upright in the middle and a 4x4 grid, so a face that comes out
+    /// mirrored, rotated or swapped is obvious
+    Faces,
+    /// Rings around the front that get finer towards the back, reaching the
+    /// panorama's resolution limit there; shows aliasing as moiré
+    ZonePlate,
+}
+
+impl TestPattern {
+    pub const ALL: [TestPattern; 3] = [TestPattern::Grid, TestPattern::Faces, TestPattern::ZonePlate];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TestPattern::Grid => "grid",
+            TestPattern::Faces => "faces",
+            TestPattern::ZonePlate => "zone-plate",
+        }
+    }
+
+    /// The pattern as a `width` x `width / 2` equirect panorama.
+    pub fn render(self, width: u32) -> RgbImage {
+        let height = (width / 2).max(1);
+        let mut img = RgbImage::new(width, height);
+        img.par_chunks_mut(width as usize * 3).enumerate().for_each(|(y, row)| {
+            for (x, pixel) in row.chunks_exact_mut(3).enumerate() {
+                let color = match self {
+                    // Hard edges, so average 2x2 samples
+                    TestPattern::Grid | TestPattern::Faces => {
+                        let mut sum = [0.0; 3];
+                        for (dx, dy) in [(0.25, 0.25), (0.75, 0.25), (0.25, 0.75), (0.75, 0.75)] {
+                            let u = (x as f32 + dx) / width as f32;
+                            let v = (y as f32 + dy) / height as f32;
+                            let sample = match self {
+                                TestPattern::Grid => grid(u, v, width),
+                                _ => faces(u, v),
+                            };
+                            sum.iter_mut().zip(sample).for_each(|(sum, value)| *sum += value / 4.0);
+                        }
+                        sum
+                    }
+                    TestPattern::ZonePlate => {
+                        zone_plate((x as f32 + 0.5) / width as f32, (y as f32 + 0.5) / height as f32, width)
+                    }
+                };
+                for (out, value) in pixel.iter_mut().zip(color) {
+                    *out = (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+                }
+            }
+        });
+        img
+    }
+}
+
+impl fmt::Display for TestPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for TestPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        TestPattern::ALL
+            .into_iter()
+            .find(|pattern| pattern.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown test pattern '{}' (expected grid, faces or zone-plate)", s))
+    }
+}
+
+fn grid(u: f32, v: f32, width: u32) -> [f32; 3] {
+    let longitude = (u - 0.5) * 360.0;
+    let latitude = 90.0 - v * 180.0;
+    // Distance in pixels from the nearest multiple of `step` degrees; a
+    // degree spans the same number of pixels along both axes
+    let pixels_per_degree = width as f32 / 360.0;
+    let distance = |angle: f32, step: f32| (angle / step - (angle / step).round()).abs() * step * pixels_per_degree;
+    let line = (width as f32 / 2048.0).max(1.0);
+
+    if latitude.abs() * pixels_per_degree < line {
+        return [0.9, 0.1, 0.1];
+    }
+    if distance(longitude, 360.0) < line {
+        return [0.1, 0.8, 0.1];
+    }
+    if distance(longitude + 180.0, 360.0) < line {
+        return [0.2, 0.3, 1.0];
+    }
+    if distance(latitude, 15.0) < line || distance(longitude, 15.0) < line {
+        return [1.0; 3];
+    }
+    if distance(latitude, 5.0) < line / 2.0 || distance(longitude, 5.0) < line / 2.0 {
+        return [0.5; 3];
+    }
+    [0.15, 0.15, 0.2]
+}
+
+// Opposite faces get complementary colours
+const FACE_COLORS: [[f32; 3]; 6] = [
+    [0.85, 0.2, 0.2],
+    [0.2, 0.8, 0.8],
+    [0.25, 0.8, 0.25],
+    [0.8, 0.25, 0.8],
+    [0.25, 0.35, 0.9],
+    [0.9, 0.85, 0.2],
+];
+
+// 5x7 initials in face order, top row first, leftmost column in bit 4
+pub(crate) const GLYPHS: [[u8; 7]; 6] = [
+    [0b11110, 0b10001, 0b10001, 0b11110, 0b10100, 0b10010, 0b10001],
+    [0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b11111],
+    [0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110],
+    [0b11110, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b11110],
+    [0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b10000],
+    [0b11110, 0b10001, 0b10001, 0b11110, 0b10001, 0b10001, 0b11110],
+];
+
+// Glyph cell size in face-plane units
+const CELL: f32 = 0.16;
+
+fn faces(u: f32, v: f32) -> [f32; 3] {
+    let (face, a, b) = Face::from_direction(spherical_to_direction(u, v));
+    if a.abs() > 0.97 || b.abs() > 0.97 {
+        return [1.0; 3];
+    }
+    let column = ((a + 2.5 * CELL) / CELL).floor();
+    let row = ((b + 3.5 * CELL) / CELL).floor();
+    if (0.0..5.0).contains(&column) && (0.0..7.0).contains(&row) {
+        if GLYPHS[face.index()][row as usize] & (0b10000 >> column as u32) != 0 {
+            return [0.05; 3];
+        }
+    } else if ((a + 1.0) * 4.0).fract() < 0.02 || ((b + 1.0) * 4.0).fract() < 0.02 {
+        return FACE_COLORS[face.index()].map(|c| c * 0.6);
+    }
+    FACE_COLORS[face.index()]
+}
+
+fn zone_plate(u: f32, v: f32, width: u32) -> [f32; 3] {
+    // Phase k * angle^2 makes the frequency grow linearly with the angle from
+    // the front, up to half a cycle per equator pixel at the back
+    let angle = spherical_to_direction(u, v)[2].clamp(-1.0, 1.0).acos();
+    let k = width as f32 / (4.0 * PI);
+    [0.5 + 0.5 * (k * angle * angle).cos(); 3]
+}
@@ -0,0 +1,289 @@
+use image::{ColorType, DynamicImage, ImageBuffer, Luma, Pixel, Primitive, Rgb, Rgba};
+use rayon::prelude::*;
+use std::fmt;
+use std::sync::OnceLock;
+
+/// Image buffer with the pixel type's own subpixel storage.
+pub type Buffer<P> = ImageBuffer<P, Vec<<P as Pixel>::Subpixel>>;
+
+/// Subpixel type the sampler can interpolate. Integer channels are
+/// filtered in f32 and rounded back; float channels pass through unclamped
+/// so HDR values above 1.0 survive.
+pub trait Channel: Primitive + Send + Sync + 'static {
+    fn to_f32(self) -> f32;
+    fn from_f32(value: f32) -> Self;
+
+    /// Linear-light value in [0, 1] (unbounded for float). Integer channels
+    /// are sRGB encoded; float channels are already linear.
+    fn to_linear(self) -> f32;
+    fn from_linear(value: f32) -> Self;
+}
+
+impl Channel for u8 {
+    #[inline(always)]
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+
+    #[inline(always)]
+    fn from_f32(value: f32) -> Self {
+        (value + 0.5).clamp(0.0, u8::MAX as f32) as u8
+    }
+
+    #[inline(always)]
+    fn to_linear(self) -> f32 {
+        srgb8_lut()[self as usize]
+    }
+
+    #[inline(always)]
+    fn from_linear(value: f32) -> Self {
+        // Nearest code in linear light: binary search between the decoded
+        // levels instead of a powf per channel
+        static MIDPOINTS: OnceLock<[f32; 255]> = OnceLock::new();
+        let midpoints = MIDPOINTS.get_or_init(|| {
+            let lut = srgb8_lut();
+            std::array::from_fn(|i| (lut[i] + lut[i + 1]) * 0.5)
+        });
+        midpoints.partition_point(|&m| m < value) as u8
+    }
+}
+
+impl Channel for u16 {
+    #[inline(always)]
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+
+    #[inline(always)]
+    fn from_f32(value: f32) -> Self {
+        (value + 0.5).clamp(0.0, u16::MAX as f32) as u16
+    }
+
+    #[inline(always)]
+    fn to_linear(self) -> f32 {
+        srgb16_lut()[self as usize]
+    }
+
+    #[inline(always)]
+    fn from_linear(value: f32) -> Self {
+        Self::from_f32(linear_to_srgb(value) * u16::MAX as f32)
+    }
+}
+
+impl Channel for f32 {
+    #[inline(always)]
+    fn to_f32(self) -> f32 {
+        self
+    }
+
+    #[inline(always)]
+    fn from_f32(value: f32) -> Self {
+        value
+    }
+
+    #[inline(always)]
+    fn to_linear(self) -> f32 {
+        self
+    }
+
+    #[inline(always)]
+    fn from_linear(value: f32) -> Self {
+        value
+    }
+}
+
+fn srgb8_lut() -> &'static [f32; 256] {
+    static LUT: OnceLock<[f32; 256]> = OnceLock::new();
+    LUT.get_or_init(|| std::array::from_fn(|i| srgb_to_linear(i as f32 / 255.0)))
+}
+
+fn srgb16_lut() -> &'static [f32] {
+    static LUT: OnceLock<Vec<f32>> = OnceLock::new();
+    LUT.get_or_init(|| (0..=u16::MAX).map(|i| srgb_to_linear(i as f32 / u16::MAX as f32)).collect())
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> f32 {
+    let value = value.clamp(0.0, 1.0);
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Decode `img` to linear-light float RGB, the working space of
+/// `CubemapOptions::linear`. Float images are already linear.
+pub(crate) fn linearize(img: &DynamicImage) -> Buffer<Rgb<f32>> {
+    fn decode<T: Channel>(raw: &[T], width: u32, height: u32) -> Buffer<Rgb<f32>> {
+        let data = raw.par_iter().map(|value| value.to_linear()).collect();
+        Buffer::from_raw(width, height, data).expect("same dimensions")
+    }
+    match img {
+        DynamicImage::ImageRgb8(buf) => decode(buf.as_raw(), buf.width(), buf.height()),
+        DynamicImage::ImageRgb16(buf) => decode(buf.as_raw(), buf.width(), buf.height()),
+        DynamicImage::ImageRgb32F(buf) => buf.clone(),
+        img => linearize(&PixelDepth::of(img).to_rgb(img.clone())),
+    }
+}
+
+/// Inverse of `linearize`: encode back to sRGB at `depth`.
+pub(crate) fn delinearize(img: Buffer<Rgb<f32>>, depth: PixelDepth) -> DynamicImage {
+    fn encode<T: Channel>(img: &Buffer<Rgb<f32>>) -> Buffer<Rgb<T>>
+    where
+        Rgb<T>: Pixel<Subpixel = T>,
+    {
+        let data = img.as_raw().par_iter().map(|&value| T::from_linear(value)).collect();
+        Buffer::from_raw(img.width(), img.height(), data).expect("same dimensions")
+    }
+    match depth {
+        PixelDepth::U8 => DynamicImage::ImageRgb8(encode(&img)),
+        PixelDepth::U16 => DynamicImage::ImageRgb16(encode(&img)),
+        PixelDepth::F32 => DynamicImage::ImageRgb32F(img),
+    }
+}
+
+/// `linearize` for single-channel gray images.
+pub(crate) fn linearize_luma(img: &DynamicImage) -> Buffer<Luma<f32>> {
+    fn decode<T: Channel>(raw: &[T], width: u32, height: u32) -> Buffer<Luma<f32>> {
+        let data = raw.par_iter().map(|value| value.to_linear()).collect();
+        Buffer::from_raw(width, height, data).expect("same dimensions")
+    }
+    match img {
+        DynamicImage::ImageLuma8(buf) => decode(buf.as_raw(), buf.width(), buf.height()),
+        DynamicImage::ImageLuma16(buf) => decode(buf.as_raw(), buf.width(), buf.height()),
+        img => decode(img.to_luma16().as_raw(), img.width(), img.height()),
+    }
+}
+
+/// Inverse of `linearize_luma`; float comes back as RGB, like `to_luma`.
+pub(crate) fn delinearize_luma(img: Buffer<Luma<f32>>, depth: PixelDepth) -> DynamicImage {
+    fn encode<T: Channel>(img: &Buffer<Luma<f32>>) -> Buffer<Luma<T>>
+    where
+        Luma<T>: Pixel<Subpixel = T>,
+    {
+        let data = img.as_raw().par_iter().map(|&value| T::from_linear(value)).collect();
+        Buffer::from_raw(img.width(), img.height(), data).expect("same dimensions")
+    }
+    match depth {
+        PixelDepth::U8 => DynamicImage::ImageLuma8(encode(&img)),
+        PixelDepth::U16 => DynamicImage::ImageLuma16(encode(&img)),
+        PixelDepth::F32 => DynamicImage::ImageRgb32F(Buffer::from_fn(img.width(), img.height(), |x, y| {
+            let value = img.get_pixel(x, y)[0];
+            Rgb([value; 3])
+        })),
+    }
+}
+
+/// `linearize` for images with alpha, which is scaled to [0, 1] but kept as
+/// it is otherwise: it was never sRGB encoded.
+pub(crate) fn linearize_rgba(img: &DynamicImage) -> Buffer<Rgba<f32>> {
+    fn decode<T: Channel>(raw: &[T], width: u32, height: u32) -> Buffer<Rgba<f32>> {
+        let max = T::DEFAULT_MAX_VALUE.to_f32();
+        let data = raw
+            .par_chunks_exact(4)
+            .flat_map_iter(|p| [p[0].to_linear(), p[1].to_linear(), p[2].to_linear(), p[3].to_f32() / max])
+            .collect();
+        Buffer::from_raw(width, height, data).expect("same dimensions")
+    }
+    match img {
+        DynamicImage::ImageRgba8(buf) => decode(buf.as_raw(), buf.width(), buf.height()),
+        DynamicImage::ImageRgba16(buf) => decode(buf.as_raw(), buf.width(), buf.height()),
+        DynamicImage::ImageRgba32F(buf) => buf.clone(),
+        img => linearize_rgba(&PixelDepth::of(img).to_rgba(img.clone())),
+    }
+}
+
+/// Inverse of `linearize_rgba`.
+pub(crate) fn delinearize_rgba(img: Buffer<Rgba<f32>>, depth: PixelDepth) -> DynamicImage {
+    fn encode<T: Channel>(img: &Buffer<Rgba<f32>>) -> Buffer<Rgba<T>>
+    where
+        Rgba<T>: Pixel<Subpixel = T>,
+    {
+        let max = T::DEFAULT_MAX_VALUE.to_f32();
+        let data = img
+            .as_raw()
+            .par_chunks_exact(4)
+            .flat_map_iter(|p| {
+                [T::from_linear(p[0]), T::from_linear(p[1]), T::from_linear(p[2]), T::from_f32(p[3] * max)]
+            })
+            .collect();
+        Buffer::from_raw(img.width(), img.height(), data).expect("same dimensions")
+    }
+    match depth {
+        PixelDepth::U8 => DynamicImage::ImageRgba8(encode(&img)),
+        PixelDepth::U16 => DynamicImage::ImageRgba16(encode(&img)),
+        PixelDepth::F32 => DynamicImage::ImageRgba32F(img),
+    }
+}
+
+/// Precision the pipeline runs at for a decoded input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PixelDepth {
+    U8,
+    U16,
+    F32,
+}
+
+impl PixelDepth {
+    pub fn of(img: &DynamicImage) -> PixelDepth {
+        match img.color() {
+            ColorType::L16 | ColorType::La16 | ColorType::Rgb16 | ColorType::Rgba16 => PixelDepth::U16,
+            ColorType::Rgb32F | ColorType::Rgba32F => PixelDepth::F32,
+            _ => PixelDepth::U8,
+        }
+    }
+
+    /// Convert `img` to RGB at this depth, reusing the buffer when possible.
+    pub fn to_rgb(self, img: DynamicImage) -> DynamicImage {
+        match (self, img) {
+            (PixelDepth::U8, img @ DynamicImage::ImageRgb8(_)) => img,
+            (PixelDepth::U16, img @ DynamicImage::ImageRgb16(_)) => img,
+            (PixelDepth::F32, img @ DynamicImage::ImageRgb32F(_)) => img,
+            (PixelDepth::U8, img) => DynamicImage::ImageRgb8(img.to_rgb8()),
+            (PixelDepth::U16, img) => DynamicImage::ImageRgb16(img.to_rgb16()),
+            (PixelDepth::F32, img) => DynamicImage::ImageRgb32F(img.to_rgb32f()),
+        }
+    }
+
+    /// Convert `img` to single-channel gray at this depth, reusing the buffer
+    /// when possible. There is no float gray buffer, so float stays RGB.
+    pub fn to_luma(self, img: DynamicImage) -> DynamicImage {
+        match (self, img) {
+            (PixelDepth::U8, img @ DynamicImage::ImageLuma8(_)) => img,
+            (PixelDepth::U16, img @ DynamicImage::ImageLuma16(_)) => img,
+            (PixelDepth::U8, img) => DynamicImage::ImageLuma8(img.to_luma8()),
+            (PixelDepth::U16, img) => DynamicImage::ImageLuma16(img.to_luma16()),
+            (PixelDepth::F32, img) => self.to_rgb(img),
+        }
+    }
+
+    /// Convert `img` to RGBA at this depth, reusing the buffer when possible.
+    pub fn to_rgba(self, img: DynamicImage) -> DynamicImage {
+        match (self, img) {
+            (PixelDepth::U8, img @ DynamicImage::ImageRgba8(_)) => img,
+            (PixelDepth::U16, img @ DynamicImage::ImageRgba16(_)) => img,
+            (PixelDepth::F32, img @ DynamicImage::ImageRgba32F(_)) => img,
+            (PixelDepth::U8, img) => DynamicImage::ImageRgba8(img.to_rgba8()),
+            (PixelDepth::U16, img) => DynamicImage::ImageRgba16(img.to_rgba16()),
+            (PixelDepth::F32, img) => DynamicImage::ImageRgba32F(img.to_rgba32f()),
+        }
+    }
+}
+
+impl fmt::Display for PixelDepth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PixelDepth::U8 => "8-bit",
+            PixelDepth::U16 => "16-bit",
+            PixelDepth::F32 => "32-bit float",
+        })
+    }
+}
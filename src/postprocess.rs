@@ -0,0 +1,128 @@
+use crate::{Face, PixelDepth};
+use image::{DynamicImage, ImageBuffer, Pixel, RgbImage};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+type Callback = dyn FnMut(Face, &mut RgbImage) + Send;
+
+/// Callback run on every face after it renders and before it's encoded, to
+/// watermark, annotate or grade it in place. Calls are serialized, but faces
+/// may arrive in any order and from worker threads. 16-bit and float faces
+/// are handed over as 8-bit and converted back afterwards, so they keep their
+/// pixel type but not their extra precision or range. Gray faces are handed
+/// over as RGB and come back gray. The callback sees only the color; faces
+/// with alpha get theirs back unchanged.
+#[derive(Clone)]
+pub struct PostProcess(Arc<Mutex<Callback>>);
+
+impl PostProcess {
+    pub fn new(callback: impl FnMut(Face, &mut RgbImage) + Send + 'static) -> PostProcess {
+        PostProcess(Arc::new(Mutex::new(callback)))
+    }
+
+    pub fn apply(&self, face: Face, img: &mut RgbImage) {
+        let mut callback = self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        callback(face, img)
+    }
+
+    pub(crate) fn apply_dynamic(&self, face: Face, img: DynamicImage) -> DynamicImage {
+        let depth = PixelDepth::of(&img);
+        if img.color().has_alpha() {
+            // Gray with alpha gains color, as every renderer's alpha faces do
+            let img = depth.to_rgba(img);
+            let mut rgb = img.to_rgb8();
+            self.apply(face, &mut rgb);
+            let mut out = depth.to_rgba(DynamicImage::ImageRgb8(rgb));
+            restore_alpha(&mut out, &img);
+            return out;
+        }
+        let gray = !img.color().has_color();
+        let mut rgb = match img {
+            DynamicImage::ImageRgb8(rgb) => rgb,
+            img => img.to_rgb8(),
+        };
+        self.apply(face, &mut rgb);
+        match gray {
+            true => depth.to_luma(DynamicImage::ImageRgb8(rgb)),
+            false => depth.to_rgb(DynamicImage::ImageRgb8(rgb)),
+        }
+    }
+}
+
+// Give `img` the alpha of `from`, both RGBA of the same depth and size
+fn restore_alpha(img: &mut DynamicImage, from: &DynamicImage) {
+    fn copy<P: Pixel>(img: &mut ImageBuffer<P, Vec<P::Subpixel>>, from: &ImageBuffer<P, Vec<P::Subpixel>>) {
+        for (pixel, original) in img.pixels_mut().zip(from.pixels()) {
+            pixel.channels_mut()[3] = original.channels()[3];
+        }
+    }
+    match (img, from) {
+        (DynamicImage::ImageRgba8(img), DynamicImage::ImageRgba8(from)) => copy(img, from),
+        (DynamicImage::ImageRgba16(img), DynamicImage::ImageRgba16(from)) => copy(img, from),
+        (DynamicImage::ImageRgba32F(img), DynamicImage::ImageRgba32F(from)) => copy(img, from),
+        _ => unreachable!("faces with alpha are RGBA"),
+    }
+}
+
+impl fmt::Debug for PostProcess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PostProcess(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{equirect_to_cubemap_dynamic, CubemapOptions, Fill, InputProjection, PanoCrop};
+
+    #[test]
+    fn hooks_run_once_per_face() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let record = seen.clone();
+        let options = CubemapOptions {
+            size: 8,
+            post_process: Some(PostProcess::new(move |face, img| {
+                record.lock().unwrap().push(face);
+                // Mark each face with its own number
+                img.put_pixel(0, 0, image::Rgb([255, 0, Face::ALL.iter().position(|&f| f == face).unwrap() as u8]));
+            })),
+            ..CubemapOptions::default()
+        };
+        let src = DynamicImage::ImageRgb8(RgbImage::from_pixel(64, 32, image::Rgb([10, 20, 30])));
+        let faces = equirect_to_cubemap_dynamic(&src, &options);
+        let seen = seen.lock().unwrap();
+        for (i, (face, img)) in Face::ALL.iter().zip(&faces.faces).enumerate() {
+            assert_eq!(seen.iter().filter(|&seen| seen == face).count(), 1, "{:?}", face);
+            let DynamicImage::ImageRgb8(img) = img else { panic!("expected RGB faces") };
+            assert_eq!(img.get_pixel(0, 0).0, [255, 0, i as u8]);
+            assert_eq!(img.get_pixel(4, 4).0, [10, 20, 30]);
+        }
+        assert_eq!(seen.len(), 6);
+    }
+
+    #[test]
+    fn hooks_keep_alpha_and_gray() {
+        let src = DynamicImage::ImageRgb8(RgbImage::from_pixel(64, 16, image::Rgb([200, 100, 50])));
+        let options = CubemapOptions {
+            size: 8,
+            input: InputProjection::PartialEquirect(PanoCrop::vertical_band(64, 90.0, 0.0)),
+            fill: Fill::TRANSPARENT,
+            post_process: Some(PostProcess::new(|_, _| {})),
+            ..CubemapOptions::default()
+        };
+        let faces = equirect_to_cubemap_dynamic(&src, &options);
+        let DynamicImage::ImageRgba8(front) = faces.get(Face::Front) else { panic!("expected RGBA faces") };
+        let DynamicImage::ImageRgba8(up) = faces.get(Face::Up) else { panic!("expected RGBA faces") };
+        assert_eq!(front.get_pixel(4, 4).0, [200, 100, 50, 255]);
+        assert_eq!(up.get_pixel(4, 4).0[3], 0);
+
+        let options = CubemapOptions { size: 8, post_process: options.post_process, ..CubemapOptions::default() };
+        let gray8 = DynamicImage::ImageLuma8(image::GrayImage::from_pixel(64, 32, image::Luma([90])));
+        let faces = equirect_to_cubemap_dynamic(&gray8, &options);
+        assert!(matches!(faces.get(Face::Back), DynamicImage::ImageLuma8(face) if face.get_pixel(3, 3).0 == [90]));
+        let gray16 = DynamicImage::ImageLuma16(image::ImageBuffer::from_pixel(64, 32, image::Luma([90 * 257])));
+        let faces = equirect_to_cubemap_dynamic(&gray16, &options);
+        let back = faces.get(Face::Back);
+        assert!(matches!(back, DynamicImage::ImageLuma16(face) if face.get_pixel(3, 3).0 == [90 * 257]));
+    }
+}
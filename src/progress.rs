@@ -0,0 +1,25 @@
+use crate::Face;
+use std::fmt;
+use std::sync::Arc;
+
+/// Callback for long renders: receives the face, the rows of it completed so
+/// far and its total row count. Called from worker threads as rows finish,
+/// so reports for one face may arrive slightly out of order.
+#[derive(Clone)]
+pub struct Progress(Arc<dyn Fn(Face, u32, u32) + Send + Sync>);
+
+impl Progress {
+    pub fn new(callback: impl Fn(Face, u32, u32) + Send + Sync + 'static) -> Progress {
+        Progress(Arc::new(callback))
+    }
+
+    pub fn report(&self, face: Face, rows_done: u32, rows_total: u32) {
+        (self.0)(face, rows_done, rows_total)
+    }
+}
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Progress(..)")
+    }
+}
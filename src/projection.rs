@@ -0,0 +1,195 @@
+use crate::{
+    direction_to_spherical, sample, spherical_to_direction, Buffer, Channel, CubeProjection, Face, Filter, Rotation,
+};
+use image::Pixel;
+use rayon::prelude::*;
+
+/// A mapping between directions and positions in an image, so any two can
+/// be converted into each other with `reproject`.
+///
+/// Positions are normalized like `sample`'s: texel `i` of a row sits at
+/// `u = i / width`, and (0, 0) is the top left corner. Directions are in
+/// world space (+X right, +Y up, +Z front) and need not be unit length.
+pub trait Projection: Sync {
+    /// Unit direction through image position (u, v), or None where the
+    /// image shows no direction
+    fn uv_to_dir(&self, u: f32, v: f32) -> Option<[f32; 3]>;
+
+    /// Image position of `dir`, or None where the image doesn't show it
+    fn dir_to_uv(&self, dir: [f32; 3]) -> Option<(f32, f32)>;
+}
+
+/// Equirectangular panorama: longitude across, latitude down, with the
+/// front in the middle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Equirectangular;
+
+impl Projection for Equirectangular {
+    fn uv_to_dir(&self, u: f32, v: f32) -> Option<[f32; 3]> {
+        Some(spherical_to_direction(u, v))
+    }
+
+    fn dir_to_uv(&self, dir: [f32; 3]) -> Option<(f32, f32)> {
+        Some(direction_to_spherical(dir))
+    }
+}
+
+/// One face of a cubemap, gnomonic or equi-angular (EAC).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CubeFace {
+    pub face: Face,
+    pub projection: CubeProjection,
+}
+
+impl Projection for CubeFace {
+    fn uv_to_dir(&self, u: f32, v: f32) -> Option<[f32; 3]> {
+        let warp = |t: f32| self.projection.warp(2.0 * t - 1.0);
+        Some(normalize(self.face.basis().direction(warp(u), warp(v))))
+    }
+
+    fn dir_to_uv(&self, dir: [f32; 3]) -> Option<(f32, f32)> {
+        let (face, a, b) = Face::from_direction(dir);
+        let unwarp = |t: f32| (self.projection.unwarp(t) + 1.0) / 2.0;
+        (face == self.face).then(|| (unwarp(a), unwarp(b)))
+    }
+}
+
+/// Equidistant (angular) fisheye looking along +Z: the angle from the axis
+/// grows linearly out to `fov / 2` degrees at the edge of a circle filling
+/// the square image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fisheye {
+    pub fov: f32,
+}
+
+impl Projection for Fisheye {
+    fn uv_to_dir(&self, u: f32, v: f32) -> Option<[f32; 3]> {
+        let (a, b) = (2.0 * u - 1.0, 2.0 * v - 1.0);
+        let r = (a * a + b * b).sqrt();
+        if r > 1.0 {
+            return None;
+        }
+        Some(from_axis(a, b, r, r * self.fov.to_radians() / 2.0))
+    }
+
+    fn dir_to_uv(&self, dir: [f32; 3]) -> Option<(f32, f32)> {
+        let (angle, a, b) = to_axis(dir);
+        let r = angle / (self.fov.to_radians() / 2.0);
+        inside(a * r, b * r)
+    }
+}
+
+/// Stereographic projection looking along +Z: conformal, with `fov`
+/// degrees across the width of the square image and more of the sphere
+/// towards the corners.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stereographic {
+    pub fov: f32,
+}
+
+impl Projection for Stereographic {
+    fn uv_to_dir(&self, u: f32, v: f32) -> Option<[f32; 3]> {
+        let (a, b) = (2.0 * u - 1.0, 2.0 * v - 1.0);
+        let r = (a * a + b * b).sqrt();
+        Some(from_axis(a, b, r, 2.0 * (r * (self.fov.to_radians() / 4.0).tan()).atan()))
+    }
+
+    fn dir_to_uv(&self, dir: [f32; 3]) -> Option<(f32, f32)> {
+        let (angle, a, b) = to_axis(dir);
+        let r = (angle / 2.0).tan() / (self.fov.to_radians() / 4.0).tan();
+        inside(a * r, b * r)
+    }
+}
+
+// Unit direction `angle` radians from +Z towards image offset (a, b) of
+// length r from the centre
+fn from_axis(a: f32, b: f32, r: f32, angle: f32) -> [f32; 3] {
+    let (sin, cos) = angle.sin_cos();
+    let (a, b) = if r > 0.0 { (a / r, b / r) } else { (0.0, 0.0) };
+    [a * sin, -b * sin, cos]
+}
+
+// Angle of `dir` from +Z and the unit image offset it lies towards
+fn to_axis(dir: [f32; 3]) -> (f32, f32, f32) {
+    let [x, y, z] = normalize(dir);
+    let planar = (x * x + y * y).sqrt();
+    let angle = z.clamp(-1.0, 1.0).acos();
+    if planar < 1e-6 {
+        return (angle, 0.0, 0.0);
+    }
+    (angle, x / planar, -y / planar)
+}
+
+// Image position for offset (a, b) from the centre, if it's in the image
+fn inside(a: f32, b: f32) -> Option<(f32, f32)> {
+    let (u, v) = ((a + 1.0) / 2.0, (b + 1.0) / 2.0);
+    ((0.0..=1.0).contains(&u) && (0.0..=1.0).contains(&v)).then_some((u, v))
+}
+
+fn normalize(v: [f32; 3]) -> [f32; 3] {
+    let length = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+    v.map(|value| value / length)
+}
+
+/// Resample `src`, an image in the `from` projection, into a `width` x
+/// `height` image in the `to` projection. Each pixel samples the direction
+/// `to` gives it, turned by `rotation`; pixels without a direction, or whose
+/// direction `src` doesn't show, stay black.
+pub fn reproject<P, F, T>(
+    src: &Buffer<P>,
+    from: &F,
+    to: &T,
+    (width, height): (u32, u32),
+    rotation: &Rotation,
+    filter: Filter,
+) -> Buffer<P>
+where
+    P: Pixel + Send + Sync,
+    P::Subpixel: Channel,
+    F: Projection + ?Sized,
+    T: Projection + ?Sized,
+{
+    let channels = P::CHANNEL_COUNT as usize;
+    let mut out: Buffer<P> = Buffer::new(width, height);
+    out.par_chunks_mut(width as usize * channels).enumerate().for_each(|(y, row)| {
+        let v = y as f32 / height as f32;
+        for (x, pixel) in row.chunks_exact_mut(channels).enumerate() {
+            let Some(dir) = to.uv_to_dir(x as f32 / width as f32, v) else {
+                continue;
+            };
+            if let Some((u, v)) = from.dir_to_uv(rotation.apply(dir)) {
+                pixel.copy_from_slice(sample(src, u, v, filter).channels());
+            }
+        }
+    });
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EnvMapOptions, EnvMapping};
+
+    #[test]
+    fn projections_round_trip() {
+        let octahedral = EnvMapOptions { size: 64, border: 4, ..EnvMapOptions::default() };
+        let paraboloid = EnvMapOptions { mapping: EnvMapping::DualParaboloid, overlap: 10.0, ..octahedral.clone() };
+        let mercator = EnvMapOptions { mapping: EnvMapping::Mercator, ..octahedral.clone() };
+        let projections: [&dyn Projection; 7] = [
+            &Equirectangular,
+            &CubeFace { face: Face::Front, projection: CubeProjection::EquiAngular },
+            &Fisheye { fov: 200.0 },
+            &Stereographic { fov: 120.0 },
+            &octahedral,
+            &paraboloid,
+            &mercator,
+        ];
+        for (i, projection) in projections.into_iter().enumerate() {
+            for (u, v) in [(0.25, 0.5), (0.3, 0.6), (0.7, 0.2)] {
+                let dir = projection.uv_to_dir(u, v).expect("inside every image");
+                let (u2, v2) = projection.dir_to_uv(dir).expect("shown by every image");
+                assert!((u - u2).abs() < 1e-4 && (v - v2).abs() < 1e-4, "projection {}: ({}, {})", i, u, v);
+            }
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use crate::{
+    equirect_to_cubemap_dynamic, load_image, save_image, Buffer, Channel, CubeProjection, CubemapError, CubemapOptions,
+    EncodeOptions, Face, Filter, OutputFormat, PixelDepth, Rotation,
+};
+use image::{DynamicImage, Pixel, Rgb};
+use numpy::ndarray::Array3;
+use numpy::{Element, IntoPyArray, PyReadonlyArray3};
+use pyo3::exceptions::{PyIOError, PyTypeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use std::path::PathBuf;
+
+impl From<CubemapError> for PyErr {
+    fn from(err: CubemapError) -> PyErr {
+        match err {
+            CubemapError::Decode { .. } | CubemapError::Io(_) => PyIOError::new_err(err.to_string()),
+            CubemapError::Projection(_) | CubemapError::Encode(_) => PyValueError::new_err(err.to_string()),
+        }
+    }
+}
+
+/// Convert an equirect panorama to six cube faces.
+///
+/// `input` is a path or an HxWx3 numpy array of uint8, uint16 or float32
+/// (linear) values. Returns a dict of face name to HxWx3 array of the same
+/// dtype, in right, left, up, down, front, back order. With `output_dir`
+/// the faces are written there as `<face>.<ext>` in `format` instead, and
+/// the dict maps face names to the files.
+#[pyfunction]
+#[pyo3(signature = (
+    input,
+    *,
+    size = 1024,
+    filter = "bilinear",
+    projection = "standard",
+    yaw = 0.0,
+    pitch = 0.0,
+    roll = 0.0,
+    ssaa = 1,
+    linear = false,
+    output_dir = None,
+    format = "jpeg",
+    quality = 95,
+))]
+#[allow(clippy::too_many_arguments)]
+fn convert<'py>(
+    py: Python<'py>,
+    input: &Bound<'py, PyAny>,
+    size: u32,
+    filter: &str,
+    projection: &str,
+    yaw: f32,
+    pitch: f32,
+    roll: f32,
+    ssaa: u32,
+    linear: bool,
+    output_dir: Option<PathBuf>,
+    format: &str,
+    quality: u8,
+) -> PyResult<Bound<'py, PyDict>> {
+    let options = CubemapOptions {
+        size,
+        filter: filter.parse::<Filter>().map_err(PyValueError::new_err)?,
+        ssaa,
+        rotation: Rotation::from_euler_degrees(yaw, pitch, roll),
+        projection: projection.parse::<CubeProjection>().map_err(PyValueError::new_err)?,
+        linear,
+        ..CubemapOptions::default()
+    };
+    options.validate()?;
+    let encode = EncodeOptions {
+        format: format.parse::<OutputFormat>().map_err(PyValueError::new_err)?,
+        quality: quality.clamp(1, 100),
+        ..EncodeOptions::default()
+    };
+
+    let img = read_input(input)?;
+    let img = PixelDepth::of(&img).to_rgb(img);
+    // Rendering doesn't touch Python objects, so let other threads run
+    let cubemap = py.allow_threads(|| equirect_to_cubemap_dynamic(&img, &options));
+
+    let faces = PyDict::new(py);
+    match output_dir {
+        Some(dir) => {
+            std::fs::create_dir_all(&dir)?;
+            for (face, img) in cubemap.iter() {
+                let path = dir.join(format!("{}.{}", face.name(), encode.format.extension()));
+                py.allow_threads(|| save_image(img, &path, &encode))?;
+                faces.set_item(face.name(), path)?;
+            }
+        }
+        None => {
+            for (face, img) in Face::iter().zip(cubemap.faces) {
+                faces.set_item(face.name(), to_array(py, img))?;
+            }
+        }
+    }
+    Ok(faces)
+}
+
+fn read_input(input: &Bound<'_, PyAny>) -> PyResult<DynamicImage> {
+    if let Ok(path) = input.extract::<PathBuf>() {
+        return Ok(load_image(&path)?);
+    }
+    if let Ok(array) = input.extract::<PyReadonlyArray3<u8>>() {
+        return from_array(array).map(DynamicImage::ImageRgb8);
+    }
+    if let Ok(array) = input.extract::<PyReadonlyArray3<u16>>() {
+        return from_array(array).map(DynamicImage::ImageRgb16);
+    }
+    if let Ok(array) = input.extract::<PyReadonlyArray3<f32>>() {
+        return from_array(array).map(DynamicImage::ImageRgb32F);
+    }
+    Err(PyTypeError::new_err("input must be a path or an HxWx3 uint8, uint16 or float32 array"))
+}
+
+fn from_array<T: Element + Channel>(array: PyReadonlyArray3<T>) -> PyResult<Buffer<Rgb<T>>>
+where
+    Rgb<T>: Pixel<Subpixel = T>,
+{
+    let view = array.as_array();
+    let (height, width, channels) = view.dim();
+    if channels != 3 {
+        return Err(PyValueError::new_err(format!("expected an HxWx3 array, got {}x{}x{}", height, width, channels)));
+    }
+    // Logical order, so strided and non-contiguous arrays work too
+    let data = view.iter().copied().collect();
+    Ok(Buffer::from_raw(width as u32, height as u32, data).expect("HxWx3 elements"))
+}
+
+fn to_array(py: Python<'_>, img: DynamicImage) -> Bound<'_, PyAny> {
+    fn array<T: Element>(py: Python<'_>, raw: Vec<T>, width: u32, height: u32) -> Bound<'_, PyAny> {
+        let shape = (height as usize, width as usize, 3);
+        Array3::from_shape_vec(shape, raw).expect("RGB face").into_pyarray(py).into_any()
+    }
+    let (width, height) = (img.width(), img.height());
+    match img {
+        DynamicImage::ImageRgb8(face) => array(py, face.into_raw(), width, height),
+        DynamicImage::ImageRgb16(face) => array(py, face.into_raw(), width, height),
+        DynamicImage::ImageRgb32F(face) => array(py, face.into_raw(), width, height),
+        img => to_array(py, PixelDepth::of(&img).to_rgb(img)),
+    }
+}
+
+/// The `rust_cubemap` Python module.
+#[pymodule]
+#[pyo3(name = "rust_cubemap")]
+fn python_module(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_function(wrap_pyfunction!(convert, module)?)?;
+    module.add("FACES", Face::ALL.map(Face::name).to_vec())?;
+    Ok(())
+}
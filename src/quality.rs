@@ -0,0 +1,138 @@
+use crate::PixelDepth;
+use image::{DynamicImage, GenericImageView};
+use rayon::prelude::*;
+
+// SSIM windows: 8x8 luma blocks every 4 pixels
+const WINDOW: usize = 8;
+const STRIDE: usize = 4;
+// Wang et al. (2004) stabilizers for a dynamic range of 1
+const C1: f64 = 0.01 * 0.01;
+const C2: f64 = 0.03 * 0.03;
+
+/// Quality of the band of latitudes from `north` down to `south` degrees.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BandQuality {
+    pub north: f32,
+    pub south: f32,
+    pub psnr: f64,
+    pub ssim: f64,
+}
+
+/// Full-reference quality of a panorama. Every figure weights a row by the
+/// cosine of its latitude, the solid angle its pixels cover (WS-PSNR and
+/// WS-SSIM), so the stretched rows near the poles count only as much as
+/// they show.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QualityReport {
+    /// In dB; infinite when the panoramas are identical
+    pub psnr: f64,
+    pub ssim: f64,
+    /// North to south
+    pub bands: Vec<BandQuality>,
+}
+
+/// Compare the equirect panorama `test` against `reference`, overall and in
+/// `bands` equal bands of latitude. PSNR is over RGB with a peak of 1.0, or
+/// the brightest reference value for float panoramas; SSIM is over luma.
+///
+/// # Panics
+///
+/// If the panoramas differ in size or `bands` is 0.
+pub fn compare_equirect(reference: &DynamicImage, test: &DynamicImage, bands: u32) -> QualityReport {
+    assert_eq!(reference.dimensions(), test.dimensions(), "panoramas of different sizes");
+    assert!(bands > 0, "no latitude bands");
+    let (width, height) = (reference.width() as usize, reference.height() as usize);
+    let bands = bands as usize;
+    let (reference_rgb, test_rgb) = (reference.to_rgb32f(), test.to_rgb32f());
+    let peak = match PixelDepth::of(reference) {
+        PixelDepth::F32 => reference_rgb.as_raw().iter().fold(1.0f32, |max, &value| max.max(value)),
+        _ => 1.0,
+    } as f64;
+
+    // Latitude weight and band of a (fractional) row
+    let weight = |y: f64| ((y / height as f64 - 0.5) * std::f64::consts::PI).cos();
+    let band = |y: f64| ((y / height as f64 * bands as f64) as usize).min(bands - 1);
+
+    let row_error: Vec<f64> = reference_rgb
+        .as_raw()
+        .par_chunks(width * 3)
+        .zip(test_rgb.as_raw().par_chunks(width * 3))
+        .map(|(a, b)| {
+            let sum: f64 = a.iter().zip(b).map(|(&a, &b)| ((a - b) as f64 / peak).powi(2)).sum();
+            sum / (width * 3) as f64
+        })
+        .collect();
+
+    let luma = |img: &[f32]| -> Vec<f32> {
+        img.par_chunks(3).map(|p| ((0.2126 * p[0] + 0.7152 * p[1] + 0.0722 * p[2]) as f64 / peak) as f32).collect()
+    };
+    let (reference_luma, test_luma) = (luma(reference_rgb.as_raw()), luma(test_rgb.as_raw()));
+    let window = WINDOW.min(width).min(height);
+    let stride = STRIDE.min(window);
+    // Mean SSIM of each row of windows, keyed by its center row
+    let window_rows: Vec<(f64, f64)> = (0..height - window + 1)
+        .into_par_iter()
+        .step_by(stride)
+        .map(|y0| {
+            let columns = (0..width - window + 1).step_by(stride);
+            let count = columns.len();
+            let sum: f64 = columns.map(|x0| ssim(&reference_luma, &test_luma, width, x0, y0, window)).sum();
+            (y0 as f64 + window as f64 / 2.0, sum / count as f64)
+        })
+        .collect();
+
+    // Summed in order so the result doesn't depend on the thread count
+    let mut error = vec![(0.0, 0.0); bands + 1];
+    for (y, mse) in row_error.iter().enumerate() {
+        let center = y as f64 + 0.5;
+        for i in [band(center), bands] {
+            error[i].0 += weight(center) * mse;
+            error[i].1 += weight(center);
+        }
+    }
+    let mut similarity = vec![(0.0, 0.0); bands + 1];
+    for &(center, value) in &window_rows {
+        for i in [band(center), bands] {
+            similarity[i].0 += weight(center) * value;
+            similarity[i].1 += weight(center);
+        }
+    }
+
+    let psnr = |(sum, weight): (f64, f64)| -10.0 * (sum / weight).log10();
+    let mean = |(sum, weight): (f64, f64)| sum / weight;
+    QualityReport {
+        psnr: psnr(error[bands]),
+        ssim: mean(similarity[bands]),
+        bands: (0..bands)
+            .map(|i| BandQuality {
+                north: 90.0 - 180.0 * i as f32 / bands as f32,
+                south: 90.0 - 180.0 * (i + 1) as f32 / bands as f32,
+                psnr: psnr(error[i]),
+                ssim: mean(similarity[i]),
+            })
+            .collect(),
+    }
+}
+
+// SSIM of the `size`x`size` window at (x0, y0)
+fn ssim(a: &[f32], b: &[f32], width: usize, x0: usize, y0: usize, size: usize) -> f64 {
+    let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+    for y in y0..y0 + size {
+        let row = y * width + x0..y * width + x0 + size;
+        for (&a, &b) in a[row.clone()].iter().zip(&b[row]) {
+            let (a, b) = (a as f64, b as f64);
+            sum_a += a;
+            sum_b += b;
+            sum_aa += a * a;
+            sum_bb += b * b;
+            sum_ab += a * b;
+        }
+    }
+    let n = (size * size) as f64;
+    let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+    let variance_a = sum_aa / n - mean_a * mean_a;
+    let variance_b = sum_bb / n - mean_b * mean_b;
+    let covariance = sum_ab / n - mean_a * mean_b;
+    ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+        / ((mean_a * mean_a + mean_b * mean_b + C1) * (variance_a + variance_b + C2))
+}
@@ -0,0 +1,238 @@
+use crate::manifest::{pretty, ManifestFile};
+use crate::storage::StoredFile;
+use anyhow::{Context, Result};
+use rust_cube::{write_atomic, Face};
+use serde_json::{json, Map, Value};
+use std::fmt;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Exit codes, kept stable for scripts. Besides these, 0 means everything
+// converted or was converted already, and 2 a command-line mistake (clap's)
+pub const EXIT_ERROR: u8 = 1; // nothing converted: bad options, unreadable lists
+pub const EXIT_SOME_FAILED: u8 = 3; // a batch where some inputs failed
+pub const EXIT_ALL_FAILED: u8 = 4; // every input failed
+
+/// How many of a run's inputs (or jobs) failed, when any did.
+#[derive(Debug)]
+pub struct Failures {
+    pub failed: usize,
+    pub total: usize,
+    /// What failed, e.g. "files failed to convert"
+    pub what: &'static str,
+}
+
+impl fmt::Display for Failures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} of {} {}", self.failed, self.total, self.what)
+    }
+}
+
+impl std::error::Error for Failures {}
+
+/// The error of a run's only input, which shows as that error.
+#[derive(Debug)]
+pub struct InputFailed(pub anyhow::Error);
+
+impl fmt::Display for InputFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for InputFailed {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.0.source()
+    }
+}
+
+/// The exit code for a run's result.
+pub fn exit_code(result: &Result<()>) -> u8 {
+    match result {
+        Ok(()) => 0,
+        Err(err) => match err.downcast_ref::<Failures>() {
+            Some(failures) if failures.failed < failures.total => EXIT_SOME_FAILED,
+            Some(_) => EXIT_ALL_FAILED,
+            None if err.is::<InputFailed>() => EXIT_ALL_FAILED,
+            None => EXIT_ERROR,
+        },
+    }
+}
+
+/// `--report`: every input of a convert run, what became of it, how long
+/// each stage took and the files it produced.
+pub struct Report {
+    start: Instant,
+    started: SystemTime,
+    inputs: Mutex<Vec<Value>>,
+}
+
+/// What one input produced, filled in as it converts.
+pub struct InputReport {
+    input: String,
+    output_root: String,
+    start: Instant,
+    state: Mutex<InputState>,
+}
+
+#[derive(Default)]
+struct InputState {
+    skipped: bool,
+    decode: Option<Duration>,
+    // Faces encoded since the last size was recorded
+    faces: Vec<(String, Duration)>,
+    sizes: Vec<Value>,
+}
+
+impl Report {
+    pub fn new() -> Report {
+        Report { start: Instant::now(), started: SystemTime::now(), inputs: Mutex::default() }
+    }
+
+    /// Record how converting `input` ended.
+    pub fn add(&self, input: InputReport, result: &Result<()>) {
+        let state = input.state.into_inner().expect("report lock");
+        let status = match result {
+            Ok(()) if state.skipped => "skipped",
+            Ok(()) => "converted",
+            Err(_) => "failed",
+        };
+        let json = json!({
+            "input": input.input,
+            "output_root": input.output_root,
+            "status": status,
+            "error": result.as_ref().err().map(|err| format!("{:#}", err)),
+            "decode_ms": state.decode.map(millis),
+            "total_ms": millis(input.start.elapsed()),
+            "sizes": state.sizes,
+        });
+        self.inputs.lock().expect("report lock").push(json);
+    }
+
+    /// Write the report to `path` for a run that ended with `result`.
+    pub fn write(&self, path: &Path, result: &Result<()>) -> Result<()> {
+        let started = self.started.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+        let json = pretty(&json!({
+            "generator": format!("rust-cube {}", env!("CARGO_PKG_VERSION")),
+            "command": std::env::args().collect::<Vec<_>>(),
+            "started": started,
+            "elapsed_ms": millis(self.start.elapsed()),
+            "exit_code": exit_code(result),
+            "error": result.as_ref().err().map(|err| format!("{:#}", err)),
+            "inputs": *self.inputs.lock().expect("report lock"),
+        }));
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+        write_atomic(path, json.as_bytes()).with_context(|| format!("cannot write the report to {}", path.display()))
+    }
+}
+
+impl InputReport {
+    pub fn new(input: &Path, output_root: &Path) -> InputReport {
+        InputReport {
+            input: input.display().to_string(),
+            output_root: output_root.display().to_string(),
+            start: Instant::now(),
+            state: Mutex::default(),
+        }
+    }
+
+    /// Nothing was left to convert.
+    pub fn skipped(&self) {
+        self.state.lock().expect("report lock").skipped = true;
+    }
+
+    pub fn decoded(&self, elapsed: Duration) {
+        self.state.lock().expect("report lock").decode = Some(elapsed);
+    }
+
+    /// `face`, named as stored, took `elapsed` to encode and write.
+    pub fn face(&self, face: &str, elapsed: Duration) {
+        self.state.lock().expect("report lock").faces.push((face.to_string(), elapsed));
+    }
+
+    /// A size is done: its settings, stage times (None where
+    /// rendering and encoding overlap) and every file written for it.
+    pub fn size(
+        &self,
+        size: u32,
+        settings: &[(&str, Value)],
+        render: Option<Duration>,
+        encode: Option<Duration>,
+        total: Duration,
+        written: &[(Option<Face>, StoredFile)],
+    ) {
+        let mut state = self.state.lock().expect("report lock");
+        let faces: Map<String, Value> =
+            std::mem::take(&mut state.faces).into_iter().map(|(face, ms)| (face, millis(ms).into())).collect();
+        let files: Vec<Value> = written
+            .iter()
+            .map(|(face, file)| {
+                let file = ManifestFile::new(file, Path::new(""), *face);
+                let face = file.face.map(Face::name);
+                json!({ "path": file.name, "face": face, "bytes": file.bytes, "sha256": file.sha256 })
+            })
+            .collect();
+        let mut entry = json!({ "size": size });
+        for (key, value) in settings {
+            entry[*key] = value.clone();
+        }
+        entry["render_ms"] = json!(render.map(millis));
+        entry["encode_ms"] = json!(encode.map(millis));
+        entry["total_ms"] = json!(millis(total));
+        entry["face_ms"] = Value::Object(faces);
+        entry["files"] = Value::Array(files);
+        state.sizes.push(entry);
+    }
+}
+
+// Milliseconds to the microsecond
+fn millis(elapsed: Duration) -> f64 {
+    (elapsed.as_secs_f64() * 1e6).round() / 1e3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn reports_are_json() {
+        let report = Report::new();
+        let input = InputReport::new(Path::new("in/\"odd\"\\name.jpg"), Path::new("out"));
+        input.decoded(Duration::from_micros(1500));
+        input.face("right.jpg", Duration::from_millis(2));
+        let file = StoredFile {
+            path: PathBuf::from("out/cubemap_8/right.jpg"),
+            bytes: 10,
+            sha256: "00".repeat(32),
+            blurhash: None,
+        };
+        let settings = [("filter", json!("bilinear")), ("convention", Value::Null)];
+        let (encode, total) = (Duration::from_millis(3), Duration::from_millis(4));
+        input.size(8, &settings, None, Some(encode), total, &[(Some(Face::Right), file)]);
+        report.add(input, &Err(anyhow::anyhow!("line one\nline two")));
+
+        let path = std::env::temp_dir().join(format!("rust-cube-report-{}.json", std::process::id()));
+        let result = Err(Failures { failed: 1, total: 1, what: "files failed to convert" }.into());
+        report.write(&path, &result).unwrap();
+        let json: Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(json["exit_code"], EXIT_ALL_FAILED);
+        assert_eq!(json["error"], "1 of 1 files failed to convert");
+        let input = &json["inputs"][0];
+        assert_eq!(input["input"], "in/\"odd\"\\name.jpg");
+        assert_eq!((&input["status"], &input["error"]), (&json!("failed"), &json!("line one\nline two")));
+        assert_eq!(input["decode_ms"], 1.5);
+        let size = &input["sizes"][0];
+        assert_eq!((&size["size"], &size["filter"]), (&json!(8), &json!("bilinear")));
+        assert_eq!(size["convention"], Value::Null);
+        assert_eq!((&size["render_ms"], &size["encode_ms"]), (&Value::Null, &json!(3.0)));
+        assert_eq!(size["face_ms"], json!({ "right.jpg": 2.0 }));
+        assert_eq!(size["files"][0]["face"], "right");
+        assert_eq!(size["files"][0]["path"], "out/cubemap_8/right.jpg");
+    }
+}
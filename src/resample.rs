@@ -0,0 +1,74 @@
+use crate::{pixel, sample_cubemap, Buffer, Channel, CubeProjection, CubemapFaces, CubemapOptions, Face, PixelDepth};
+use image::{DynamicImage, Pixel};
+use rayon::prelude::*;
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Re-render a cubemap at `options.size` with `options.rotation` and
+/// `options.projection` applied, sampling the source along each output
+/// pixel's direction. The source is expected in the standard projection.
+/// When shrinking, the source is area-averaged down to the target size
+/// first so the bilinear taps don't alias. A guard band (`options.bleed`)
+/// is filled from the neighbouring faces.
+pub fn resample_cubemap<P>(cubemap: &CubemapFaces<Buffer<P>>, options: &CubemapOptions) -> CubemapFaces<Buffer<P>>
+where
+    P: Pixel + Send + Sync,
+    P::Subpixel: Channel,
+{
+    let source = if options.size < cubemap.size {
+        Cow::Owned(cubemap.downsample(options.size))
+    } else {
+        Cow::Borrowed(cubemap)
+    };
+    if source.size == options.size
+        && options.rotation.is_identity()
+        && options.projection == CubeProjection::Standard
+        && options.bleed == 0
+    {
+        return source.into_owned();
+    }
+
+    let size = options.face_size();
+    let coords: Vec<f32> = (0..size).map(|i| options.face_coord(i)).collect();
+    let channels = P::CHANNEL_COUNT as usize;
+    let faces = Face::ALL
+        .par_iter()
+        .map(|&face| {
+            let basis = options.rotation.apply_basis(face.basis());
+            let rows_done = AtomicU32::new(0);
+            let mut buffer: Buffer<P> = Buffer::new(size, size);
+            buffer.par_chunks_mut(size as usize * channels).enumerate().for_each(|(y, row)| {
+                let b = coords[y];
+                for (x, pixel) in row.chunks_exact_mut(channels).enumerate() {
+                    let a = coords[x];
+                    let dir = std::array::from_fn(|i| basis.center[i] + a * basis.right[i] + b * basis.down[i]);
+                    pixel.copy_from_slice(sample_cubemap(&source, dir).channels());
+                }
+                if let Some(progress) = &options.progress {
+                    progress.report(face, rows_done.fetch_add(1, Ordering::Relaxed) + 1, size);
+                }
+            });
+            buffer
+        })
+        .collect();
+    CubemapFaces { size, faces }
+}
+
+/// `resample_cubemap` at the faces' own precision.
+pub fn resample_cubemap_dynamic(
+    cubemap: &CubemapFaces<DynamicImage>,
+    options: &CubemapOptions,
+) -> CubemapFaces<DynamicImage> {
+    let depth = cubemap.faces.iter().map(PixelDepth::of).max().unwrap_or(PixelDepth::U8);
+    if options.linear && depth != PixelDepth::F32 {
+        let faces = cubemap.faces.iter().map(pixel::linearize).collect();
+        let linear = CubemapFaces { size: cubemap.size, faces };
+        return resample_cubemap(&linear, options).map(|_, face| pixel::delinearize(face, depth));
+    }
+    let faces = CubemapFaces { size: cubemap.size, faces: cubemap.faces.clone() };
+    match depth {
+        PixelDepth::U8 => resample_cubemap(&faces.map(|_, f| f.into_rgb8()), options).map(|_, f| f.into()),
+        PixelDepth::U16 => resample_cubemap(&faces.map(|_, f| f.into_rgb16()), options).map(|_, f| f.into()),
+        PixelDepth::F32 => resample_cubemap(&faces.map(|_, f| f.into_rgb32f()), options).map(|_, f| f.into()),
+    }
+}
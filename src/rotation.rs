@@ -0,0 +1,82 @@
+use crate::FaceBasis;
+
+/// Orientation correction applied to every sampling direction before it
+/// is looked up in the panorama.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rotation {
+    matrix: [[f32; 3]; 3],
+}
+
+impl Rotation {
+    pub const IDENTITY: Rotation = Rotation { matrix: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]] };
+
+    /// Yaw turns the view right (+Z towards +X), pitch tilts it up (+Z
+    /// towards +Y) and roll turns +Y towards +X, applied in that order.
+    /// With `yaw = 90` the front face shows what was on the right.
+    pub fn from_euler_degrees(yaw: f32, pitch: f32, roll: f32) -> Rotation {
+        let (sy, cy) = yaw.to_radians().sin_cos();
+        let (sp, cp) = pitch.to_radians().sin_cos();
+        let (sr, cr) = roll.to_radians().sin_cos();
+        let yaw = [[cy, 0.0, sy], [0.0, 1.0, 0.0], [-sy, 0.0, cy]];
+        let pitch = [[1.0, 0.0, 0.0], [0.0, cp, sp], [0.0, -sp, cp]];
+        let roll = [[cr, sr, 0.0], [-sr, cr, 0.0], [0.0, 0.0, 1.0]];
+        Rotation { matrix: multiply(&multiply(&yaw, &pitch), &roll) }
+    }
+
+    /// The shortest rotation turning unit vector `from` onto unit vector
+    /// `to`; they must not point in opposite directions.
+    pub fn from_to(from: [f32; 3], to: [f32; 3]) -> Rotation {
+        let [x, y, z] = cross(from, to);
+        let cos: f32 = (0..3).map(|i| from[i] * to[i]).sum();
+        let skew = [[0.0, -z, y], [z, 0.0, -x], [-y, x, 0.0]];
+        let square = multiply(&skew, &skew);
+        let scale = 1.0 / (1.0 + cos);
+        Rotation {
+            matrix: std::array::from_fn(|i| {
+                std::array::from_fn(|j| (i == j) as u8 as f32 + skew[i][j] + square[i][j] * scale)
+            }),
+        }
+    }
+
+    /// `inner` followed by this rotation.
+    pub fn compose(&self, inner: &Rotation) -> Rotation {
+        Rotation { matrix: multiply(&self.matrix, &inner.matrix) }
+    }
+
+    /// The rotation that undoes this one.
+    pub fn inverse(&self) -> Rotation {
+        Rotation { matrix: std::array::from_fn(|i| std::array::from_fn(|j| self.matrix[j][i])) }
+    }
+
+    pub(crate) fn matrix(&self) -> [[f32; 3]; 3] {
+        self.matrix
+    }
+
+    pub fn is_identity(&self) -> bool {
+        *self == Rotation::IDENTITY
+    }
+
+    pub fn apply(&self, v: [f32; 3]) -> [f32; 3] {
+        std::array::from_fn(|i| self.matrix[i][0] * v[0] + self.matrix[i][1] * v[1] + self.matrix[i][2] * v[2])
+    }
+
+    /// `basis` with all three axes rotated, so per-pixel directions built
+    /// from it come out rotated for free.
+    pub fn apply_basis(&self, basis: &FaceBasis) -> FaceBasis {
+        FaceBasis { center: self.apply(basis.center), right: self.apply(basis.right), down: self.apply(basis.down) }
+    }
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Rotation::IDENTITY
+    }
+}
+
+fn multiply(a: &[[f32; 3]; 3], b: &[[f32; 3]; 3]) -> [[f32; 3]; 3] {
+    std::array::from_fn(|i| std::array::from_fn(|j| (0..3).map(|k| a[i][k] * b[k][j]).sum()))
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
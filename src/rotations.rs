@@ -0,0 +1,71 @@
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::path::Path;
+use tracing::info;
+
+// A rotations table is a CSV file with a row per panorama: its file name,
+// then yaw, pitch and roll in degrees as --yaw, --pitch and --roll take
+// them. Pitch and roll may be left out; blank lines, lines starting with #
+// and a header row are skipped:
+//
+//   file,yaw,pitch,roll
+//   IMG_0001.jpg,12.5,0.3,-1.1
+//   IMG_0002.jpg,97
+//
+// Rows match inputs by file name, or by stem for rigs that log frames
+// without an extension.
+
+/// Yaw, pitch and roll for each panorama of a shoot.
+pub struct RotationTable {
+    rows: HashMap<String, [f32; 3]>,
+}
+
+impl RotationTable {
+    pub fn load(path: &Path) -> Result<RotationTable> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("cannot read {}", path.display()))?;
+        let table = RotationTable::parse(&text).with_context(|| format!("invalid rotations in {}", path.display()))?;
+        info!("Loaded rotations for {} panoramas from {}", table.rows.len(), path.display());
+        Ok(table)
+    }
+
+    fn parse(text: &str) -> Result<RotationTable> {
+        let mut rows = HashMap::new();
+        let mut first = true;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            // Only the first row may be a header
+            let header = std::mem::replace(&mut first, false);
+            let fields: Vec<&str> = line.split(',').map(|field| field.trim().trim_matches('"')).collect();
+            if fields.len() < 2 || fields.len() > 4 || fields[0].is_empty() {
+                bail!("line {}: expected file,yaw[,pitch[,roll]]", i + 1);
+            }
+            let angles: Result<Vec<f32>, _> = fields[1..].iter().map(|field| field.parse::<f32>()).collect();
+            let angles = match angles {
+                Ok(angles) if angles.iter().all(|angle| angle.is_finite()) => angles,
+                Err(_) if header => continue,
+                _ => bail!("line {}: yaw, pitch and roll must be numbers in degrees", i + 1),
+            };
+            let mut rotation = [0.0; 3];
+            rotation[..angles.len()].copy_from_slice(&angles);
+            if rows.insert(fields[0].to_string(), rotation).is_some() {
+                bail!("line {}: {} is listed twice", i + 1, fields[0]);
+            }
+        }
+        if rows.is_empty() {
+            bail!("no panoramas listed");
+        }
+        Ok(RotationTable { rows })
+    }
+
+    /// Yaw, pitch and roll for `input`, if the table lists it.
+    pub fn get(&self, input: &Path) -> Option<[f32; 3]> {
+        let name = |part: Option<&std::ffi::OsStr>| part.map(|part| part.to_string_lossy().into_owned());
+        [name(input.file_name()), name(input.file_stem())]
+            .into_iter()
+            .flatten()
+            .find_map(|key| self.rows.get(&key).copied())
+    }
+}
@@ -0,0 +1,289 @@
+use crate::{Buffer, Channel};
+use image::Pixel;
+use std::f32::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+
+/// Reconstruction filter used to read the source panorama.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Filter {
+    Nearest,
+    #[default]
+    Bilinear,
+    /// Catmull-Rom, 4x4 taps
+    Bicubic,
+    /// Lanczos with a = 3, 6x6 taps
+    Lanczos3,
+}
+
+impl Filter {
+    pub const ALL: [Filter; 4] = [Filter::Nearest, Filter::Bilinear, Filter::Bicubic, Filter::Lanczos3];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Filter::Nearest => "nearest",
+            Filter::Bilinear => "bilinear",
+            Filter::Bicubic => "bicubic",
+            Filter::Lanczos3 => "lanczos3",
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Filter::ALL
+            .into_iter()
+            .find(|filter| filter.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown filter '{}' (expected nearest, bilinear, bicubic or lanczos3)", s))
+    }
+}
+
+/// Sample the equirect `src` at normalized coordinates (u, v). Texel `i` sits
+/// at `u = i / width`; lookups wrap around in longitude and clamp at the
+/// poles, where the rows above the first and below the last would otherwise
+/// wrap to the opposite pole.
+#[inline]
+pub fn sample<P>(src: &Buffer<P>, u: f32, v: f32, filter: Filter) -> P
+where
+    P: Pixel,
+    P::Subpixel: Channel,
+{
+    let width = src.width();
+    let height = src.height();
+    let x = (u * width as f32).rem_euclid(width as f32);
+    let y = (v * height as f32).clamp(0.0, (height - 1) as f32);
+    sample_at(src, x, y, filter)
+}
+
+/// `sample` at pixel coordinates, `x` already wrapped into [0, width] and `y`
+/// clamped to [0, height - 1].
+#[inline]
+pub(crate) fn sample_at<P>(src: &Buffer<P>, x: f32, y: f32, filter: Filter) -> P
+where
+    P: Pixel,
+    P::Subpixel: Channel,
+{
+    let width = src.width();
+    let height = src.height();
+    match filter {
+        Filter::Nearest => {
+            let x = (x + 0.5) as u32 % width;
+            let y = ((y + 0.5) as u32).min(height - 1);
+            *src.get_pixel(x, y)
+        }
+        Filter::Bilinear => bilinear(src, x, y),
+        Filter::Bicubic => separable(src, x, y, 2, catmull_rom),
+        Filter::Lanczos3 => separable(src, x, y, 3, lanczos3),
+    }
+}
+
+#[inline(always)]
+fn bilinear<P>(src: &Buffer<P>, x: f32, y: f32) -> P
+where
+    P: Pixel,
+    P::Subpixel: Channel,
+{
+    let width = src.width();
+    let height = src.height();
+
+    // rem_euclid can round up to exactly `width`
+    let x0 = x.floor() as u32 % width;
+    let y0 = y.floor() as u32;
+    let x1 = (x0 + 1) % width;
+    let y1 = (y0 + 1).min(height - 1);
+
+    let fx = x.fract();
+    let fy = y.fract();
+
+    let p00 = premultiply(src.get_pixel(x0, y0).channels());
+    let p10 = premultiply(src.get_pixel(x1, y0).channels());
+    let p01 = premultiply(src.get_pixel(x0, y1).channels());
+    let p11 = premultiply(src.get_pixel(x1, y1).channels());
+
+    let mut blended = [0.0f32; 4];
+    for (c, value) in blended.iter_mut().enumerate() {
+        let c0 = p00[c] * (1.0 - fx) + p10[c] * fx;
+        let c1 = p01[c] * (1.0 - fx) + p11[c] * fx;
+        *value = c0 * (1.0 - fy) + c1 * fy;
+    }
+    let mut out = *src.get_pixel(x0, y0);
+    unpremultiply(blended, out.channels_mut());
+    out
+}
+
+// Convolve a (2 * radius)^2 neighbourhood with a separable kernel; weights
+// are normalized so windowed kernels don't shift brightness
+fn separable<P>(src: &Buffer<P>, x: f32, y: f32, radius: i64, kernel: fn(f32) -> f32) -> P
+where
+    P: Pixel,
+    P::Subpixel: Channel,
+{
+    let width = src.width() as i64;
+    let height = src.height() as i64;
+    let x0 = x.floor() as i64;
+    let y0 = y.floor() as i64;
+
+    let taps = (2 * radius) as usize;
+    let mut wx = [0.0f32; 6];
+    let mut wy = [0.0f32; 6];
+    for i in 0..taps {
+        let offset = i as i64 - radius + 1;
+        wx[i] = kernel(x - (x0 + offset) as f32);
+        wy[i] = kernel(y - (y0 + offset) as f32);
+    }
+    let norm = wx[..taps].iter().sum::<f32>() * wy[..taps].iter().sum::<f32>();
+
+    let mut acc = [0.0f32; 4];
+    for (j, &weight_y) in wy[..taps].iter().enumerate() {
+        let sy = (y0 + j as i64 - radius + 1).clamp(0, height - 1) as u32;
+        for (i, &weight_x) in wx[..taps].iter().enumerate() {
+            let sx = (x0 + i as i64 - radius + 1).rem_euclid(width) as u32;
+            let weight = weight_x * weight_y;
+            for (total, value) in acc.iter_mut().zip(premultiply(src.get_pixel(sx, sy).channels())) {
+                *total += value * weight;
+            }
+        }
+    }
+
+    let mut out = *src.get_pixel(x0.rem_euclid(width) as u32, y0.clamp(0, height - 1) as u32);
+    unpremultiply(acc.map(|total| total / norm), out.channels_mut());
+    out
+}
+
+fn catmull_rom(t: f32) -> f32 {
+    let t = t.abs();
+    if t < 1.0 {
+        1.5 * t * t * t - 2.5 * t * t + 1.0
+    } else if t < 2.0 {
+        -0.5 * t * t * t + 2.5 * t * t - 4.0 * t + 2.0
+    } else {
+        0.0
+    }
+}
+
+fn lanczos3(t: f32) -> f32 {
+    let t = t.abs();
+    if t < 1e-6 {
+        1.0
+    } else if t < 3.0 {
+        let pt = PI * t;
+        3.0 * pt.sin() * (pt / 3.0).sin() / (pt * pt)
+    } else {
+        0.0
+    }
+}
+
+/// A texel's channels as f32 for blending. With an alpha channel (the
+/// fourth), color is multiplied by alpha first, so blends weigh each texel's
+/// color by its coverage and transparent texels don't tint their neighbours.
+#[inline(always)]
+pub(crate) fn premultiply<T: Channel>(texel: &[T]) -> [f32; 4] {
+    let mut out = [0.0f32; 4];
+    for (value, &channel) in out.iter_mut().zip(texel) {
+        *value = channel.to_f32();
+    }
+    if texel.len() == 4 {
+        let alpha = out[3] / T::DEFAULT_MAX_VALUE.to_f32();
+        out[..3].iter_mut().for_each(|value| *value *= alpha);
+    }
+    out
+}
+
+/// Store a blend of `premultiply`d texels in `out`, dividing alpha back out
+/// of the color. Fully transparent results come out black.
+#[inline(always)]
+pub(crate) fn unpremultiply<T: Channel>(mut blended: [f32; 4], out: &mut [T]) {
+    if out.len() == 4 {
+        let alpha = blended[3] / T::DEFAULT_MAX_VALUE.to_f32();
+        let scale = if alpha > 1e-6 { 1.0 / alpha } else { 0.0 };
+        blended[..3].iter_mut().for_each(|value| *value *= scale);
+    }
+    for (channel, value) in out.iter_mut().zip(blended) {
+        *channel = T::from_f32(value);
+    }
+}
+
+#[inline(always)]
+pub(crate) fn bilerp<T: Channel>(c00: T, c10: T, c01: T, c11: T, fx: f32, fy: f32) -> T {
+    let c0 = c00.to_f32() * (1.0 - fx) + c10.to_f32() * fx;
+    let c1 = c01.to_f32() * (1.0 - fx) + c11.to_f32() * fx;
+    T::from_f32(c0 * (1.0 - fy) + c1 * fy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{equirect_to_cubemap, CubemapOptions, Face};
+    use image::{Rgb, Rgba};
+
+    const ZENITH: Rgb<u8> = Rgb([255, 0, 0]);
+    const NADIR: Rgb<u8> = Rgb([0, 0, 255]);
+
+    // Gray panorama with the top row marked red and the bottom one blue
+    fn pole_pattern(width: u32, height: u32) -> Buffer<Rgb<u8>> {
+        Buffer::from_fn(width, height, |_, y| match y {
+            0 => ZENITH,
+            y if y == height - 1 => NADIR,
+            _ => Rgb([128, 128, 128]),
+        })
+    }
+
+    #[test]
+    fn poles_do_not_wrap() {
+        let src = pole_pattern(64, 32);
+        for filter in Filter::ALL {
+            for u in [0.0, 0.3, 0.999] {
+                assert_eq!(sample(&src, u, 0.0, filter), ZENITH, "{} at the zenith", filter);
+                assert_eq!(sample(&src, u, 1.0, filter), NADIR, "{} at the nadir", filter);
+                // Between the last row and the pole only the nadir colour
+                // may bleed in, never the zenith's red
+                let near_nadir = sample(&src, u, 1.0 - 0.1 / 32.0, filter);
+                assert!(near_nadir[0] <= 128, "{} near the nadir: {:?}", filter, near_nadir);
+                let near_zenith = sample(&src, u, -0.1 / 32.0, filter);
+                assert_eq!(near_zenith, ZENITH, "{} above the zenith", filter);
+            }
+        }
+    }
+
+    #[test]
+    fn longitude_wraps() {
+        // Left half black, right half white: the seam blends the last and
+        // first columns
+        let src = Buffer::from_fn(8, 4, |x, _| if x < 4 { Rgb([0u8; 3]) } else { Rgb([255; 3]) });
+        assert_eq!(sample(&src, 7.5 / 8.0, 0.5, Filter::Bilinear), Rgb([128; 3]));
+        assert_eq!(sample(&src, -0.5 / 8.0, 0.5, Filter::Bilinear), Rgb([128; 3]));
+        assert_eq!(sample(&src, -f32::EPSILON / 4.0, 0.5, Filter::Bilinear), Rgb([0; 3]));
+    }
+
+    #[test]
+    fn transparent_texels_do_not_tint() {
+        // Opaque red next to fully transparent green: halfway between them
+        // is half-covered red, with no green mixed in
+        let src = Buffer::from_fn(8, 4, |x, _| if x < 4 { Rgba([255u8, 0, 0, 255]) } else { Rgba([0, 255, 0, 0]) });
+        for filter in [Filter::Bilinear, Filter::Bicubic, Filter::Lanczos3] {
+            let edge = sample(&src, 3.5 / 8.0, 0.5, filter);
+            assert_eq!(edge[1], 0, "{}: {:?}", filter, edge);
+            assert!(edge[0] >= 254 && (100..=160).contains(&edge[3]), "{}: {:?}", filter, edge);
+        }
+    }
+
+    #[test]
+    fn up_and_down_faces_see_their_own_pole() {
+        let src = pole_pattern(256, 128);
+        for filter in Filter::ALL {
+            // An odd size puts the centre of pixel (8, 8) on the pole
+            let options = CubemapOptions { size: 17, filter, ..CubemapOptions::default() };
+            let cubemap = equirect_to_cubemap(&src, &options);
+            assert_eq!(*cubemap.get(Face::Up).get_pixel(8, 8), ZENITH, "{}", filter);
+            assert_eq!(*cubemap.get(Face::Down).get_pixel(8, 8), NADIR, "{}", filter);
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use crate::{pixel, texel_center, Buffer, CubeProjection, CubemapFaces};
+use image::{DynamicImage, Rgb};
+use rayon::prelude::*;
+
+/// Order-2 (9 coefficient) real spherical harmonics of an RGB environment,
+/// in the usual l, m order: Y00, Y1-1 (y), Y10 (z), Y11 (x), Y2-2 (xy),
+/// Y2-1 (yz), Y20 (3z^2 - 1), Y21 (xz), Y22 (x^2 - y^2). Directions are in
+/// the world frame (+X right, +Y up, +Z front).
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct SphericalHarmonics {
+    pub coefficients: [[f32; 3]; 9],
+}
+
+impl SphericalHarmonics {
+    /// Project the linear radiance of a cubemap whose faces were rendered
+    /// with `projection`, weighting every texel by its solid angle.
+    pub fn project(cubemap: &CubemapFaces<Buffer<Rgb<f32>>>, projection: CubeProjection) -> SphericalHarmonics {
+        let size = cubemap.size;
+        let (sum, weight) = cubemap
+            .iter()
+            .collect::<Vec<_>>()
+            .into_par_iter()
+            .map(|(face, img)| {
+                let basis = face.basis();
+                let mut sum = [[0.0f64; 3]; 9];
+                let mut weight = 0.0f64;
+                for (x, y, pixel) in img.enumerate_pixels() {
+                    let a = texel_center(x as f32, size);
+                    let b = texel_center(y as f32, size);
+                    let (u, v) = (projection.warp(a), projection.warp(b));
+                    let solid_angle = projection.stretch(a) * projection.stretch(b) / (1.0 + u * u + v * v).powf(1.5);
+                    for (acc, basis) in sum.iter_mut().zip(basis_functions(normalize(basis.direction(u, v)))) {
+                        for (c, value) in pixel.0.iter().enumerate() {
+                            acc[c] += (value * basis * solid_angle) as f64;
+                        }
+                    }
+                    weight += solid_angle as f64;
+                }
+                (sum, weight)
+            })
+            .collect::<Vec<_>>()
+            // Summed in face order: how rayon splits a reduce depends on the
+            // thread count, and float addition isn't associative
+            .into_iter()
+            .fold(([[0.0; 3]; 9], 0.0), |(mut sum, weight), (other, other_weight)| {
+                for (acc, add) in sum.iter_mut().zip(other) {
+                    for c in 0..3 {
+                        acc[c] += add[c];
+                    }
+                }
+                (sum, weight + other_weight)
+            });
+
+        // Normalize the discrete weights to the 4 pi of the sphere
+        let scale = 4.0 * std::f64::consts::PI / weight;
+        SphericalHarmonics { coefficients: sum.map(|acc| acc.map(|c| (c * scale) as f32)) }
+    }
+
+    /// `project` for faces of any depth; 8 and 16-bit faces are decoded to
+    /// linear light first.
+    pub fn project_dynamic(cubemap: &CubemapFaces<DynamicImage>, projection: CubeProjection) -> SphericalHarmonics {
+        let linear = CubemapFaces { size: cubemap.size, faces: cubemap.faces.iter().map(pixel::linearize).collect() };
+        SphericalHarmonics::project(&linear, projection)
+    }
+
+    /// Convolve with the clamped cosine lobe and divide by pi: evaluating the
+    /// result gives the outgoing radiance of a white Lambertian surface, the
+    /// value an irradiance map stores (Ramamoorthi and Hanrahan, 2001).
+    pub fn irradiance(&self) -> SphericalHarmonics {
+        const BAND: [f32; 9] = [1.0, 2.0 / 3.0, 2.0 / 3.0, 2.0 / 3.0, 0.25, 0.25, 0.25, 0.25, 0.25];
+        let mut coefficients = self.coefficients;
+        for (coefficient, band) in coefficients.iter_mut().zip(BAND) {
+            *coefficient = coefficient.map(|c| c * band);
+        }
+        SphericalHarmonics { coefficients }
+    }
+
+    /// Value in direction `dir` (any length).
+    pub fn eval(&self, dir: [f32; 3]) -> [f32; 3] {
+        let mut out = [0.0f32; 3];
+        for (coefficient, basis) in self.coefficients.iter().zip(basis_functions(normalize(dir))) {
+            for c in 0..3 {
+                out[c] += coefficient[c] * basis;
+            }
+        }
+        out
+    }
+
+    /// Coefficients as a JSON array of nine [r, g, b] triples.
+    pub fn to_json(&self) -> String {
+        let triples: Vec<String> =
+            self.coefficients.iter().map(|[r, g, b]| format!("[{:.8}, {:.8}, {:.8}]", r, g, b)).collect();
+        format!("[{}]", triples.join(", "))
+    }
+
+    /// Coefficients as 27 little-endian f32 values, RGB per coefficient.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.coefficients.iter().flatten().flat_map(|c| c.to_le_bytes()).collect()
+    }
+}
+
+/// The nine basis functions for a unit direction.
+fn basis_functions([x, y, z]: [f32; 3]) -> [f32; 9] {
+    [
+        0.282_095,
+        0.488_603 * y,
+        0.488_603 * z,
+        0.488_603 * x,
+        1.092_548 * x * y,
+        1.092_548 * y * z,
+        0.315_392 * (3.0 * z * z - 1.0),
+        1.092_548 * x * z,
+        0.546_274 * (x * x - y * y),
+    ]
+}
+
+fn normalize(v: [f32; 3]) -> [f32; 3] {
+    let len = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+    v.map(|c| c / len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cube_to_direction, Face};
+
+    // Cubemap whose radiance in each direction is `radiance(dir)`, unit `dir`
+    fn environment(radiance: impl Fn([f32; 3]) -> f32) -> CubemapFaces<Buffer<Rgb<f32>>> {
+        let size = 32;
+        let faces = Face::ALL
+            .iter()
+            .map(|&face| {
+                Buffer::from_fn(size, size, |x, y| Rgb([radiance(normalize(cube_to_direction(x, y, size, face))); 3]))
+            })
+            .collect();
+        CubemapFaces { size, faces }
+    }
+
+    #[test]
+    fn constant_radiance() {
+        let sh = SphericalHarmonics::project(&environment(|_| 2.0), CubeProjection::Standard);
+        // Only the constant band: 2 * sqrt(4 pi)
+        let c0 = 2.0 * (4.0 * std::f32::consts::PI).sqrt();
+        assert!((sh.coefficients[0][0] - c0).abs() < 1e-3, "{:?}", sh.coefficients[0]);
+        assert!(sh.coefficients[1..].iter().flatten().all(|c| c.abs() < 1e-3), "{:?}", sh.coefficients);
+        for dir in [[1.0, 0.0, 0.0], [0.0, -1.0, 0.0], [0.3, 0.5, -0.8]] {
+            assert!((sh.eval(dir)[0] - 2.0).abs() < 1e-3);
+            // A white surface under uniform light reflects it unchanged
+            assert!((sh.irradiance().eval(dir)[0] - 2.0).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn light_from_one_direction() {
+        // A tight lobe around +Y, then one around +X
+        let lobe = |axis: usize| environment(move |d| (40.0 * (d[axis] - 1.0)).exp());
+        let up = SphericalHarmonics::project(&lobe(1), CubeProjection::Standard);
+        let [_, y, z, x, ..] = up.coefficients.map(|c| c[0]);
+        assert!(y > 0.0 && x.abs() < 1e-3 * y && z.abs() < 1e-3 * y, "{:?}", up.coefficients);
+        let right = SphericalHarmonics::project(&lobe(0), CubeProjection::Standard);
+        let irradiance = right.irradiance();
+        let toward = irradiance.eval([1.0, 0.0, 0.0])[0];
+        for dir in [[-1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0], [0.7, 0.7, 0.0]] {
+            assert!(irradiance.eval(dir)[0] < toward, "{:?}", dir);
+        }
+        // Facing away is nearly dark: nine coefficients leave a ringing of
+        // about 1/16 of the peak there
+        assert!(irradiance.eval([-1.0, 0.0, 0.0])[0].abs() < 0.08 * toward);
+    }
+}
@@ -0,0 +1,146 @@
+use crate::{Buffer, Channel, CubemapError, PixelDepth};
+use image::{DynamicImage, Pixel, Primitive};
+use rayon::prelude::*;
+use std::str::FromStr;
+
+/// Unsharp mask, to win back the detail reprojection and bilinear filtering
+/// soften: each value moves away from a Gaussian blur of its surroundings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sharpen {
+    /// Strength: 1 adds the full difference from the blur
+    pub amount: f32,
+    /// Standard deviation of the blur in pixels
+    pub radius: f32,
+    /// Differences below this many 8-bit levels are left alone, so smooth
+    /// areas and noise aren't sharpened
+    pub threshold: f32,
+}
+
+impl Sharpen {
+    /// Reject parameters `apply` can't honour.
+    pub fn validate(&self) -> Result<(), CubemapError> {
+        if !(self.amount >= 0.0 && self.amount.is_finite()) {
+            return Err(CubemapError::Projection(format!("sharpen amount must be at least 0, got {}", self.amount)));
+        }
+        if !(self.radius > 0.0 && self.radius <= 64.0) {
+            return Err(CubemapError::Projection(format!(
+                "sharpen radius must be above 0 and at most 64 pixels, got {}",
+                self.radius
+            )));
+        }
+        if !(0.0..=255.0).contains(&self.threshold) {
+            return Err(CubemapError::Projection(format!(
+                "sharpen threshold must be between 0 and 255, got {}",
+                self.threshold
+            )));
+        }
+        Ok(())
+    }
+
+    /// Sharpen `img` in place. Edges are blurred as if their pixels repeated,
+    /// so each face is sharpened on its own; alpha is left as it is.
+    pub fn apply<P>(&self, img: &mut Buffer<P>)
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Channel,
+    {
+        let (width, height) = (img.width() as usize, img.height() as usize);
+        if width == 0 || height == 0 {
+            return;
+        }
+        let channels = P::CHANNEL_COUNT as usize;
+        let kernel = gaussian(self.radius);
+        let reach = kernel.len() as isize / 2;
+        let max = P::Subpixel::DEFAULT_MAX_VALUE.to_f32();
+        let threshold = self.threshold / 255.0 * max;
+        let row_len = width * channels;
+
+        // Separable blur: across each row, then down each column
+        let mut across = vec![0.0f32; row_len * height];
+        across.par_chunks_mut(row_len).zip(img.par_chunks(row_len)).for_each(|(out, row)| {
+            for x in 0..width {
+                for c in 0..channels {
+                    out[x * channels + c] = kernel
+                        .iter()
+                        .enumerate()
+                        .map(|(k, weight)| {
+                            let sx = (x as isize + k as isize - reach).clamp(0, width as isize - 1) as usize;
+                            weight * row[sx * channels + c].to_f32()
+                        })
+                        .sum();
+                }
+            }
+        });
+        img.par_chunks_mut(row_len).enumerate().for_each(|(y, row)| {
+            for (i, value) in row.iter_mut().enumerate() {
+                // Sharpening coverage would ring around transparent edges
+                if channels == 4 && i % 4 == 3 {
+                    continue;
+                }
+                let blurred: f32 = kernel
+                    .iter()
+                    .enumerate()
+                    .map(|(k, weight)| {
+                        let sy = (y as isize + k as isize - reach).clamp(0, height as isize - 1) as usize;
+                        weight * across[sy * row_len + i]
+                    })
+                    .sum();
+                let original = value.to_f32();
+                let detail = original - blurred;
+                if detail.abs() >= threshold {
+                    *value = P::Subpixel::from_f32((original + self.amount * detail).max(0.0));
+                }
+            }
+        });
+    }
+
+    /// `apply` at the image's own precision; other color types are converted
+    /// to RGB, or RGBA if they have alpha, first.
+    pub fn apply_dynamic(&self, img: &mut DynamicImage) {
+        match img {
+            DynamicImage::ImageRgb8(img) => self.apply(img),
+            DynamicImage::ImageRgb16(img) => self.apply(img),
+            DynamicImage::ImageRgb32F(img) => self.apply(img),
+            DynamicImage::ImageRgba8(img) => self.apply(img),
+            DynamicImage::ImageRgba16(img) => self.apply(img),
+            DynamicImage::ImageRgba32F(img) => self.apply(img),
+            DynamicImage::ImageLuma8(img) => self.apply(img),
+            DynamicImage::ImageLuma16(img) => self.apply(img),
+            img if img.color().has_alpha() => {
+                *img = PixelDepth::of(img).to_rgba(img.clone());
+                self.apply_dynamic(img);
+            }
+            img => {
+                *img = PixelDepth::of(img).to_rgb(img.clone());
+                self.apply_dynamic(img);
+            }
+        }
+    }
+}
+
+// Normalized Gaussian weights out to three standard deviations
+fn gaussian(sigma: f32) -> Vec<f32> {
+    let reach = (3.0 * sigma).ceil().max(1.0) as isize;
+    let weights: Vec<f32> = (-reach..=reach).map(|x| (-(x * x) as f32 / (2.0 * sigma * sigma)).exp()).collect();
+    let total: f32 = weights.iter().sum();
+    weights.into_iter().map(|weight| weight / total).collect()
+}
+
+impl FromStr for Sharpen {
+    type Err = String;
+
+    /// `amount,radius,threshold`, e.g. `0.5,1,2`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|value| value.trim().parse::<f32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| format!("invalid sharpen '{}': {}", s, err))?;
+        let [amount, radius, threshold] = values[..] else {
+            return Err(format!("invalid sharpen '{}' (expected amount,radius,threshold)", s));
+        };
+        let sharpen = Sharpen { amount, radius, threshold };
+        sharpen.validate().map_err(|err| err.to_string())?;
+        Ok(sharpen)
+    }
+}
@@ -0,0 +1,124 @@
+use crate::sampler::{premultiply, unpremultiply};
+use crate::{sample, Buffer, Channel, FaceBasis, Filter};
+use image::Pixel;
+use std::f32::consts::PI;
+use wide::f32x8;
+
+pub(crate) const LANES: usize = 8;
+
+/// Render up to `LANES` neighbouring face pixels of one row at once: the
+/// direction to (u, v) math runs in SIMD lanes, and so does the bilinear
+/// blend. Other filters take the vector (u, v) and sample each lane on its own.
+/// `a` holds the face-plane coordinate of each pixel's column, `b` that of the
+/// row, and `out` the pixels' raw channels.
+pub(crate) fn render_pixels<P>(
+    src: &Buffer<P>,
+    basis: &FaceBasis,
+    a: &[f32],
+    b: f32,
+    filter: Filter,
+    out: &mut [P::Subpixel],
+)
+where
+    P: Pixel,
+    P::Subpixel: Channel,
+{
+    debug_assert!(a.len() <= LANES);
+    debug_assert_eq!(out.len(), a.len() * P::CHANNEL_COUNT as usize);
+    let mut lanes = [0.0f32; LANES];
+    lanes[..a.len()].copy_from_slice(a);
+    let (u, v) = face_to_spherical_x8(f32x8::from(lanes), f32x8::splat(b), basis);
+
+    if filter == Filter::Bilinear {
+        bilinear_x8(src, u, v, out);
+    } else {
+        let (u, v) = (u.to_array(), v.to_array());
+        for (lane, pixel) in out.chunks_exact_mut(P::CHANNEL_COUNT as usize).enumerate() {
+            *P::from_slice_mut(pixel) = sample(src, u[lane], v[lane], filter);
+        }
+    }
+}
+
+// Lane-for-lane the same mapping as `basis_to_spherical`
+fn face_to_spherical_x8(a: f32x8, b: f32x8, basis: &FaceBasis) -> (f32x8, f32x8) {
+    let axis = |i: usize| f32x8::splat(basis.center[i]) + a * basis.right[i] + b * basis.down[i];
+    let (dx, dy, dz) = (axis(0), axis(1), axis(2));
+    let r = (dx * dx + dy * dy + dz * dz).sqrt();
+
+    let phi = dx.atan2(dz);
+    let theta = (dy / r).max(-f32x8::ONE).min(f32x8::ONE).acos();
+    (phi / (2.0 * PI) + 0.5, theta / PI)
+}
+
+// Same taps, weights and premultiplied alpha as the scalar bilinear filter;
+// only the corner fetches stay scalar since there is no gather for image rows
+fn bilinear_x8<P>(src: &Buffer<P>, u: f32x8, v: f32x8, out: &mut [P::Subpixel])
+where
+    P: Pixel,
+    P::Subpixel: Channel,
+{
+    let width = src.width();
+    let height = src.height();
+    let (x0, y0, fx, fy) = corners_x8(width, height, u, v);
+
+    let channels = P::CHANNEL_COUNT as usize;
+    let count = out.len() / channels;
+    let mut corners = [[[0.0f32; LANES]; 4]; 4];
+    for lane in 0..count {
+        let (x0, y0) = (x0[lane], y0[lane]);
+        let x1 = (x0 + 1) % width;
+        let y1 = (y0 + 1).min(height - 1);
+        for (corner, (sx, sy)) in [(x0, y0), (x1, y0), (x0, y1), (x1, y1)].into_iter().enumerate() {
+            for (c, value) in premultiply(src.get_pixel(sx, sy).channels()).into_iter().enumerate() {
+                corners[corner][c][lane] = value;
+            }
+        }
+    }
+
+    let one = f32x8::ONE;
+    let mut blended = [[0.0f32; LANES]; 4];
+    for c in 0..channels {
+        let [p00, p10, p01, p11] = corners.map(|corner| f32x8::from(corner[c]));
+        let c0 = p00 * (one - fx) + p10 * fx;
+        let c1 = p01 * (one - fx) + p11 * fx;
+        blended[c] = (c0 * (one - fy) + c1 * fy).to_array();
+    }
+
+    for (lane, pixel) in out.chunks_exact_mut(channels).enumerate() {
+        unpremultiply(blended.map(|channel| channel[lane]), pixel);
+    }
+}
+
+/// The bilinear taps `render_pixels` reads for up to `LANES` pixels of one
+/// row of a face on a `width` x `height` panorama: each lane's top left texel
+/// and its weights towards the right and lower neighbours.
+pub(crate) fn bilinear_taps(
+    width: u32,
+    height: u32,
+    basis: &FaceBasis,
+    a: &[f32],
+    b: f32,
+) -> ([u32; LANES], [u32; LANES], [f32; LANES], [f32; LANES]) {
+    let mut lanes = [0.0f32; LANES];
+    lanes[..a.len()].copy_from_slice(a);
+    let (u, v) = face_to_spherical_x8(f32x8::from(lanes), f32x8::splat(b), basis);
+    let (x0, y0, fx, fy) = corners_x8(width, height, u, v);
+    (x0, y0, fx.to_array(), fy.to_array())
+}
+
+// Top left texel of each lane's 2x2 neighbourhood, wrapped and clamped, and
+// the weights towards the right and lower texels
+fn corners_x8(width: u32, height: u32, u: f32x8, v: f32x8) -> ([u32; LANES], [u32; LANES], f32x8, f32x8) {
+    let x = wrap(u * width as f32, width as f32);
+    let y = (v * height as f32).max(f32x8::ZERO).min(f32x8::splat((height - 1) as f32));
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    // wrap can round up to exactly `width`
+    let (x0, y0) = (x0.to_array().map(|x0| x0 as u32 % width), y0.to_array().map(|y0| y0 as u32));
+    (x0, y0, fx, fy)
+}
+
+// rem_euclid for every lane
+fn wrap(value: f32x8, period: f32) -> f32x8 {
+    value - (value / period).floor() * period
+}
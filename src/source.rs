@@ -0,0 +1,459 @@
+use crate::{direction_to_spherical, sample, Buffer, Channel, Filter};
+use image::{Pixel, Primitive};
+use std::f32::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+
+/// How the source image covers the sphere.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum InputProjection {
+    /// 2:1 equirectangular panorama
+    #[default]
+    Equirect,
+    /// Unstitched side-by-side frame from a two-lens camera
+    DualFisheye(DualFisheye),
+    /// Photo of a chrome ball filling the image: the centre reflects the
+    /// front (+Z) and the rim the back, with +X to the right and +Y up
+    MirrorBall,
+    /// Angular map (light probe): the angle from the front grows linearly
+    /// from the centre out to the back at the rim, with +X right and +Y up
+    Angular,
+    /// Equirectangular image covering only part of the sphere, e.g. from
+    /// GPano metadata
+    PartialEquirect(PanoCrop),
+}
+
+/// Where a partial panorama sits inside the full 360x180 equirect, in
+/// pixels of the full panorama (the GPano `CroppedArea*` and `FullPano*`
+/// fields).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PanoCrop {
+    pub full_width: u32,
+    pub full_height: u32,
+    pub left: u32,
+    pub top: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// What uncovered directions of a partial source turn into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fill {
+    /// A solid sRGBA color; alpha below 255 needs an output that keeps it
+    Color([u8; 4]),
+    /// The nearest covered source pixel, smeared outwards
+    Edge,
+    /// Towards the poles of a partial equirect, the edge row blurred wider
+    /// and wider until it is one average color at the pole; elsewhere as
+    /// `Edge`
+    Inpaint,
+    /// The nearest covered pixels averaged over a cone around the
+    /// direction, a softer `Edge`
+    Blur,
+}
+
+impl Fill {
+    pub const TRANSPARENT: Fill = Fill::Color([0, 0, 0, 0]);
+
+    /// Whether every filled pixel is fully opaque.
+    pub fn is_opaque(&self) -> bool {
+        !matches!(self, Fill::Color([.., alpha]) if *alpha < 255)
+    }
+}
+
+impl Default for Fill {
+    fn default() -> Self {
+        Fill::Color([0, 0, 0, 255])
+    }
+}
+
+/// Front and back lens of a dual-fisheye frame. Unset lenses are assumed
+/// to fill the left (front) and right (back) halves of the frame with a
+/// 190 degree field of view.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct DualFisheye {
+    pub front: Option<FisheyeLens>,
+    pub back: Option<FisheyeLens>,
+}
+
+/// Equidistant fisheye lens: the angle from the lens axis grows linearly
+/// with the distance from `center`, reaching `fov / 2` at `radius`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FisheyeLens {
+    /// Image-circle centre in source pixels
+    pub center: (f32, f32),
+    /// Image-circle radius in source pixels
+    pub radius: f32,
+    /// Field of view across the image circle in degrees
+    pub fov: f32,
+}
+
+impl InputProjection {
+    pub const ALL: [InputProjection; 4] = [
+        InputProjection::Equirect,
+        InputProjection::DualFisheye(DualFisheye { front: None, back: None }),
+        InputProjection::MirrorBall,
+        InputProjection::Angular,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            InputProjection::Equirect => "equirect",
+            InputProjection::DualFisheye(_) => "dual-fisheye",
+            InputProjection::MirrorBall => "mirrorball",
+            InputProjection::Angular => "angular",
+            InputProjection::PartialEquirect(_) => "partial-equirect",
+        }
+    }
+
+    /// Normalized source (u, v) seen in direction `dir`, or `None` where the
+    /// source has no coverage.
+    pub fn direction_to_uv(&self, dir: [f32; 3], width: u32, height: u32) -> Option<(f32, f32)> {
+        match self.locate(dir, width, height) {
+            (u, v, true) => Some((u, v)),
+            _ => None,
+        }
+    }
+
+    /// Nearest covered source (u, v) to direction `dir`, and whether `dir`
+    /// itself is covered.
+    pub fn locate(&self, dir: [f32; 3], width: u32, height: u32) -> (f32, f32, bool) {
+        let covered = |(u, v): (f32, f32)| (u, v, true);
+        match self {
+            InputProjection::Equirect => covered(direction_to_spherical(dir)),
+            InputProjection::DualFisheye(lenses) => {
+                let [x, y, z] = dir;
+                // The back lens looks along -Z, which mirrors x in its image
+                let (lens, x, z) = if z >= 0.0 {
+                    (lenses.front.unwrap_or_else(|| FisheyeLens::half(0, width, height)), x, z)
+                } else {
+                    (lenses.back.unwrap_or_else(|| FisheyeLens::half(1, width, height)), -x, -z)
+                };
+                let (px, py, inside) = lens.project([x, y, z]);
+                (px / width as f32, py / height as f32, inside)
+            }
+            InputProjection::MirrorBall => {
+                // The ball normal that reflects the front-facing view ray
+                // into `dir` is halfway between the two
+                let [x, y, z] = normalize(dir);
+                let normal = [x, y, z + 1.0];
+                let length = (normal[0] * normal[0] + normal[1] * normal[1] + normal[2] * normal[2]).sqrt();
+                if length < 1e-6 {
+                    // Straight back: the whole rim, pick any point on it
+                    return covered(disk_to_uv(1.0, 0.0));
+                }
+                covered(disk_to_uv(normal[0] / length, normal[1] / length))
+            }
+            InputProjection::Angular => {
+                let [x, y, z] = normalize(dir);
+                let r = z.clamp(-1.0, 1.0).acos() / PI;
+                let planar = (x * x + y * y).sqrt();
+                if planar < 1e-6 {
+                    return covered(disk_to_uv(r, 0.0));
+                }
+                covered(disk_to_uv(x / planar * r, y / planar * r))
+            }
+            InputProjection::PartialEquirect(crop) => {
+                let (u, v) = direction_to_spherical(dir);
+                crop.locate(u, v, width, height)
+            }
+        }
+    }
+}
+
+// Unit-disk coordinates (+x right, +y up) to normalized image (u, v)
+fn disk_to_uv(x: f32, y: f32) -> (f32, f32) {
+    ((x + 1.0) / 2.0, (1.0 - y) / 2.0)
+}
+
+fn normalize(v: [f32; 3]) -> [f32; 3] {
+    let length = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+    v.map(|c| c / length)
+}
+
+impl FisheyeLens {
+    // Default lens filling half `index` (0 = left) of a side-by-side frame
+    fn half(index: u32, width: u32, height: u32) -> FisheyeLens {
+        let half = width as f32 / 2.0;
+        FisheyeLens {
+            center: (half * (index as f32 + 0.5), height as f32 / 2.0),
+            radius: (half / 2.0).min(height as f32 / 2.0),
+            fov: 190.0,
+        }
+    }
+
+    // Source pixel for a direction in lens space (+Z along the axis, +X
+    // image right, +Y image up), clamped to the image circle, and whether
+    // it was inside
+    fn project(&self, dir: [f32; 3]) -> (f32, f32, bool) {
+        let [x, y, z] = dir;
+        let planar = (x * x + y * y).sqrt();
+        let theta = planar.atan2(z);
+        let half_fov = self.fov.to_radians() / 2.0;
+        let r = theta.min(half_fov) / half_fov * self.radius;
+        let (dx, dy) = if planar > 0.0 { (x / planar, y / planar) } else { (0.0, 0.0) };
+        (self.center.0 + r * dx, self.center.1 - r * dy, theta <= half_fov)
+    }
+}
+
+impl PanoCrop {
+    /// A panorama `width` pixels across covering all 360 degrees that way
+    /// and `fov` degrees from top to bottom, its middle `center` degrees
+    /// above the horizon. Its height doesn't matter: crops work in fractions.
+    pub fn vertical_band(width: u32, fov: f32, center: f32) -> PanoCrop {
+        let full_height = (width / 2).max(1);
+        let band = ((fov / 180.0 * full_height as f32).round() as u32).clamp(1, full_height);
+        let top = ((90.0 - center - fov / 2.0) / 180.0 * full_height as f32).round().max(0.0) as u32;
+        PanoCrop { full_width: width, full_height, left: 0, top: top.min(full_height - band), width, height: band }
+    }
+
+    /// Whether the crop is the whole sphere, i.e. a plain equirect.
+    pub fn is_full(&self) -> bool {
+        self.left == 0 && self.top == 0 && self.width == self.full_width && self.height == self.full_height
+    }
+
+    // Full-panorama (u, v) to (u, v) in a `width` x `height` image of the
+    // cropped area, clamped to the nearest covered pixel. Works in fractions
+    // of the crop, so the image may have been resized since the metadata
+    // was written.
+    fn locate(&self, u: f32, v: f32, width: u32, height: u32) -> (f32, f32, bool) {
+        let (full_width, crop_width, crop_height) = (self.full_width as f32, self.width as f32, self.height as f32);
+        // Last image column and row in full-panorama pixels, so clamped
+        // samples don't wrap around to the opposite edge
+        let last_column = crop_width - crop_width / width as f32;
+        let last_row = crop_height - crop_height / height as f32;
+
+        let mut x = u * full_width - self.left as f32;
+        let mut inside = true;
+        if self.width < self.full_width {
+            x = x.rem_euclid(full_width);
+            if x > last_column {
+                inside = x < crop_width;
+                // Whichever crop edge is closer around the circle
+                x = if x - crop_width < full_width - x { last_column } else { 0.0 };
+            }
+        }
+        let y = v * self.full_height as f32 - self.top as f32;
+        inside &= (0.0..crop_height).contains(&y);
+        (x / crop_width, y.clamp(0.0, last_row) / crop_height, inside)
+    }
+
+    // How far full-panorama row `v` lies beyond the crop towards the pole on
+    // its side: 0 at the crop's edge (or inside it), 1 at the pole
+    fn beyond(&self, v: f32) -> f32 {
+        let full_height = self.full_height as f32;
+        let (top, bottom) = (self.top as f32 / full_height, (self.top + self.height) as f32 / full_height);
+        match v {
+            v if v < top => (top - v) / top,
+            v if v > bottom && bottom < 1.0 => (v - bottom) / (1.0 - bottom),
+            _ => 0.0,
+        }
+    }
+}
+
+// Samples averaged along the edge row for `Fill::Inpaint`, and over the
+// cone for `Fill::Blur`
+const INPAINT_SAMPLES: usize = 16;
+const BLUR_SAMPLES: usize = 16;
+
+// Half-angle of the `Fill::Blur` cone, in radians (about 12 degrees)
+const BLUR_RADIUS: f32 = 0.2;
+
+// `Fill::Inpaint` in direction `dir` of a partial equirect: the crop's edge
+// row averaged over a span of longitudes that widens to the full circle at
+// the pole
+fn inpaint<P>(src: &Buffer<P>, crop: &PanoCrop, dir: [f32; 3], filter: Filter) -> P
+where
+    P: Pixel,
+    P::Subpixel: Channel,
+{
+    let (u, v) = direction_to_spherical(dir);
+    let span = crop.beyond(v);
+    let (width, height) = (src.width(), src.height());
+    let edge = |u: f32| {
+        let (u, v, _) = crop.locate(u.rem_euclid(1.0), v, width, height);
+        sample(src, u, v, filter)
+    };
+    if span <= 0.0 {
+        return edge(u);
+    }
+    average((0..INPAINT_SAMPLES).map(|i| {
+        let offset = (i as f32 + 0.5) / INPAINT_SAMPLES as f32 - 0.5;
+        edge(u + offset * span)
+    }))
+}
+
+// `Fill::Blur` in direction `dir`: the nearest covered pixels of directions
+// spread over a cone around it, on a golden-angle spiral
+fn blur<P>(src: &Buffer<P>, input: &InputProjection, dir: [f32; 3], filter: Filter) -> P
+where
+    P: Pixel,
+    P::Subpixel: Channel,
+{
+    let d = normalize(dir);
+    // Any axis not parallel to `d` gives a basis across the cone
+    let axis = if d[1].abs() < 0.9 { [0.0, 1.0, 0.0] } else { [1.0, 0.0, 0.0] };
+    let a = normalize(cross(d, axis));
+    let b = cross(d, a);
+    let golden = PI * (3.0 - 5f32.sqrt());
+    average((0..BLUR_SAMPLES).map(|i| {
+        let r = BLUR_RADIUS.tan() * ((i as f32 + 0.5) / BLUR_SAMPLES as f32).sqrt();
+        let (sin, cos) = (i as f32 * golden).sin_cos();
+        let dir = [0, 1, 2].map(|k| d[k] + r * (cos * a[k] + sin * b[k]));
+        let (u, v, _) = input.locate(dir, src.width(), src.height());
+        sample(src, u, v, filter)
+    }))
+}
+
+// Mean of `pixels`, color in linear light weighted by alpha if there is one
+fn average<P>(pixels: impl Iterator<Item = P>) -> P
+where
+    P: Pixel,
+    P::Subpixel: Channel,
+{
+    let max = P::Subpixel::DEFAULT_MAX_VALUE.to_f32();
+    let mut sum = [0.0; 4];
+    let mut count = 0;
+    let mut last = None;
+    for pixel in pixels {
+        let channels = pixel.channels();
+        let alpha = channels.get(3).map_or(1.0, |alpha| alpha.to_f32() / max);
+        for (i, (total, &channel)) in sum.iter_mut().zip(channels).enumerate() {
+            *total += if i < 3 { channel.to_linear() * alpha } else { alpha };
+        }
+        count += 1;
+        last = Some(pixel);
+    }
+    let mut pixel = last.expect("averaging at least one pixel");
+    let coverage = if pixel.channels().len() == 4 { sum[3] } else { count as f32 };
+    let scale = if coverage > 1e-6 { 1.0 / coverage } else { 0.0 };
+    for (i, (channel, total)) in pixel.channels_mut().iter_mut().zip(sum).enumerate() {
+        *channel = match i {
+            3 => P::Subpixel::from_f32(total / count as f32 * max),
+            _ => P::Subpixel::from_linear(total * scale),
+        };
+    }
+    pixel
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+impl fmt::Display for InputProjection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for InputProjection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        InputProjection::ALL
+            .into_iter()
+            .find(|projection| projection.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown input projection '{}' (expected equirect, dual-fisheye, mirrorball or angular)", s))
+    }
+}
+
+impl FromStr for FisheyeLens {
+    type Err = String;
+
+    /// `x,y,radius,fov` in source pixels and degrees
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|value| value.trim().parse::<f32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| format!("invalid lens '{}': {}", s, err))?;
+        match values[..] {
+            [x, y, radius, fov] if radius > 0.0 && fov > 0.0 && fov <= 360.0 => {
+                Ok(FisheyeLens { center: (x, y), radius, fov })
+            }
+            _ => Err(format!("invalid lens '{}' (expected x,y,radius,fov with radius > 0 and fov up to 360)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Fill {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fill::Edge => f.write_str("edge"),
+            Fill::Inpaint => f.write_str("inpaint"),
+            Fill::Blur => f.write_str("blur"),
+            &Fill::TRANSPARENT => f.write_str("transparent"),
+            Fill::Color([r, g, b, 255]) => write!(f, "#{:02x}{:02x}{:02x}", r, g, b),
+            Fill::Color([r, g, b, a]) => write!(f, "#{:02x}{:02x}{:02x}{:02x}", r, g, b, a),
+        }
+    }
+}
+
+impl FromStr for Fill {
+    type Err = String;
+
+    /// `edge`, `inpaint`, `blur`, `black`, `white`, `transparent` or an
+    /// `rrggbb` / `rrggbbaa` hex color (with or without `#`)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.trim_start_matches('#');
+        match s.to_ascii_lowercase().as_str() {
+            "edge" => Ok(Fill::Edge),
+            "inpaint" => Ok(Fill::Inpaint),
+            "blur" => Ok(Fill::Blur),
+            "black" => Ok(Fill::Color([0, 0, 0, 255])),
+            "white" => Ok(Fill::Color([255, 255, 255, 255])),
+            "transparent" => Ok(Fill::TRANSPARENT),
+            _ if (hex.len() == 6 || hex.len() == 8) && hex.is_ascii() => {
+                let mut color = [255; 4];
+                for (i, channel) in color.iter_mut().take(hex.len() / 2).enumerate() {
+                    *channel = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+                        .map_err(|_| format!("invalid fill color '{}'", s))?;
+                }
+                Ok(Fill::Color(color))
+            }
+            _ => Err(format!(
+                "unknown fill '{}' (expected edge, inpaint, blur, black, white, transparent or a hex color)",
+                s
+            )),
+        }
+    }
+}
+
+/// Sample the source in direction `dir`, with `fill` where it has no
+/// coverage.
+pub(crate) fn sample_direction<P>(
+    src: &Buffer<P>,
+    input: &InputProjection,
+    fill: Fill,
+    dir: [f32; 3],
+    filter: Filter,
+) -> P
+where
+    P: Pixel,
+    P::Subpixel: Channel,
+{
+    match (input, input.locate(dir, src.width(), src.height()), fill) {
+        (_, (u, v, true), _) | (_, (u, v, false), Fill::Edge) => sample(src, u, v, filter),
+        (InputProjection::PartialEquirect(crop), _, Fill::Inpaint) => inpaint(src, crop, dir, filter),
+        (_, (u, v, false), Fill::Inpaint) => sample(src, u, v, filter),
+        (_, _, Fill::Blur) => blur(src, input, dir, filter),
+        (_, _, Fill::Color(color)) => {
+            let mut pixel = *src.get_pixel(0, 0);
+            let max = P::Subpixel::DEFAULT_MAX_VALUE.to_f32();
+            // Gray sources get the color's luminance
+            if P::CHANNEL_COUNT == 1 {
+                let linear = color.map(u8::to_linear);
+                let luma = 0.2126 * linear[0] + 0.7152 * linear[1] + 0.0722 * linear[2];
+                pixel.channels_mut()[0] = P::Subpixel::from_linear(luma);
+                return pixel;
+            }
+            for (i, (channel, value)) in pixel.channels_mut().iter_mut().zip(color).enumerate() {
+                *channel = match i {
+                    3 => P::Subpixel::from_f32(value as f32 / 255.0 * max),
+                    _ => P::Subpixel::from_linear(u8::to_linear(value)),
+                };
+            }
+            pixel
+        }
+    }
+}
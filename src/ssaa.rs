@@ -0,0 +1,86 @@
+use crate::sampler::{premultiply, unpremultiply};
+use crate::source::sample_direction;
+use crate::{basis_to_spherical, texel_center, Buffer, Channel, CubeProjection, CubemapOptions, FaceBasis};
+use image::Pixel;
+use std::f32::consts::PI;
+
+/// Average of up to `options.ssaa`^2 jittered samples over the footprint of
+/// face pixel (x, y). In adaptive mode the count per axis follows how many
+/// source texels the pixel covers, so only the minified areas near the poles
+/// and face corners pay for the extra samples.
+pub(crate) fn supersample<P>(src: &Buffer<P>, basis: &FaceBasis, x: u32, y: u32, options: &CubemapOptions) -> P
+where
+    P: Pixel,
+    P::Subpixel: Channel,
+{
+    let size = options.size as f32;
+    let a = texel_center(x as f32 - options.bleed as f32, options.size);
+    let b = texel_center(y as f32 - options.bleed as f32, options.size);
+
+    let warp = |t: f32| options.projection.warp(t);
+
+    let n = if options.ssaa_adaptive {
+        adaptive_samples(src, basis, options.projection, a, b, size).min(options.ssaa)
+    } else {
+        options.ssaa
+    };
+    if n <= 1 {
+        let dir = options.offset_direction(basis.direction(warp(a), warp(b)));
+        return sample_direction(src, &options.input, options.fill, dir, options.filter);
+    }
+
+    let mut acc = [0.0f32; 4];
+    let mut first = None;
+    for sy in 0..n {
+        for sx in 0..n {
+            // Stratified: one jittered sample per cell of an n x n grid
+            let index = sy * n + sx;
+            let jx = (sx as f32 + jitter(x, y, 2 * index)) / n as f32 - 0.5;
+            let jy = (sy as f32 + jitter(x, y, 2 * index + 1)) / n as f32 - 0.5;
+            let dir = options.offset_direction(basis.direction(warp(a + 2.0 * jx / size), warp(b + 2.0 * jy / size)));
+            let value = sample_direction(src, &options.input, options.fill, dir, options.filter);
+            for (total, channel) in acc.iter_mut().zip(premultiply(value.channels())) {
+                *total += channel;
+            }
+            first.get_or_insert(value);
+        }
+    }
+
+    let count = (n * n) as f32;
+    let mut out = first.expect("at least one sample");
+    unpremultiply(acc.map(|total| total / count), out.channels_mut());
+    out
+}
+
+// Samples per axis needed to cover the source texels under one face pixel:
+// the ratio of the pixel's solid angle to that of an equirect texel at the
+// same latitude
+fn adaptive_samples<P: Pixel>(
+    src: &Buffer<P>,
+    basis: &FaceBasis,
+    projection: CubeProjection,
+    a: f32,
+    b: f32,
+    size: f32,
+) -> u32 {
+    let (x, y) = (projection.warp(a), projection.warp(b));
+    let pixel_area = (2.0 / size).powi(2) * projection.stretch(a) * projection.stretch(b);
+    let face_solid_angle = pixel_area / (1.0 + x * x + y * y).powf(1.5);
+
+    let (_, v) = basis_to_spherical(x, y, basis);
+    let sin_theta = (v * PI).sin().max(1e-4);
+    let texel_solid_angle = (2.0 * PI / src.width() as f32) * (PI / src.height() as f32) * sin_theta;
+
+    (face_solid_angle / texel_solid_angle).sqrt().ceil().max(1.0) as u32
+}
+
+// Deterministic per-pixel jitter in [0, 1) so reruns produce identical output
+fn jitter(x: u32, y: u32, i: u32) -> f32 {
+    let mut h = x.wrapping_mul(0x8da6_b343) ^ y.wrapping_mul(0xd816_3841) ^ i.wrapping_mul(0xcb1a_b31f);
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x7feb_352d);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x846c_a68b);
+    h ^= h >> 16;
+    (h >> 8) as f32 / (1u32 << 24) as f32
+}
@@ -0,0 +1,62 @@
+use image::DynamicImage;
+use std::fmt;
+use std::str::FromStr;
+
+/// How a stereo panorama packs its two eyes into one image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StereoLayout {
+    /// Left eye above the right, each a 2:1 equirect, so the frame is square
+    TopBottom,
+    /// Left eye beside the right, so the frame is 4:1
+    SideBySide,
+}
+
+impl StereoLayout {
+    pub const ALL: [StereoLayout; 2] = [StereoLayout::TopBottom, StereoLayout::SideBySide];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            StereoLayout::TopBottom => "top-bottom",
+            StereoLayout::SideBySide => "side-by-side",
+        }
+    }
+
+    /// The layout a `width` x `height` frame of two 2:1 eyes has, if any.
+    pub fn detect(width: u32, height: u32) -> Option<StereoLayout> {
+        match (width, height) {
+            (width, height) if width == height && width % 2 == 0 => Some(StereoLayout::TopBottom),
+            (width, height) if width == 4 * height => Some(StereoLayout::SideBySide),
+            _ => None,
+        }
+    }
+
+    /// The left and right eye of `img`.
+    pub fn split(self, img: &DynamicImage) -> [DynamicImage; 2] {
+        let (width, height) = (img.width(), img.height());
+        match self {
+            StereoLayout::TopBottom => {
+                [img.crop_imm(0, 0, width, height / 2), img.crop_imm(0, height / 2, width, height / 2)]
+            }
+            StereoLayout::SideBySide => {
+                [img.crop_imm(0, 0, width / 2, height), img.crop_imm(width / 2, 0, width / 2, height)]
+            }
+        }
+    }
+}
+
+impl fmt::Display for StereoLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for StereoLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        StereoLayout::ALL
+            .into_iter()
+            .find(|layout| layout.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown stereo layout '{}' (expected top-bottom or side-by-side)", s))
+    }
+}
@@ -0,0 +1,429 @@
+use anyhow::{bail, Context, Result};
+use image::codecs::jpeg::JpegDecoder;
+use image::{DynamicImage, ImageDecoder, ImageFormat};
+use rust_cube::{
+    decode_image, encode_image_with_metadata, find_gpano, find_gpano_pose, load_image, read_gpano, read_gpano_pose,
+    remove_stale_temps, AtomicFile, EncodeOptions, Metadata, PanoCrop, PanoPose, PixelDepth, ScanlineReader,
+};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, BufWriter, Cursor, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tracing::debug;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+// Input and output locations of the convert command: local paths, zip
+// archives, stdin and stdout (`-`), or `s3://bucket/key` URLs in builds with
+// the `s3` feature. Credentials and region come from the usual AWS_*
+// environment variables.
+
+// `s3://bucket/key` split into bucket and key
+fn s3_url(path: &Path) -> Option<(&str, &str)> {
+    let rest = path.to_str()?.strip_prefix("s3://")?;
+    Some(rest.split_once('/').unwrap_or((rest, "")))
+}
+
+pub fn is_remote(path: &Path) -> bool {
+    s3_url(path).is_some()
+}
+
+/// `create_dir_all` for a directory outputs are written to, which also
+/// removes the temps of earlier runs killed while writing there.
+pub fn create_output_dir(path: &Path) -> Result<()> {
+    std::fs::create_dir_all(path).with_context(|| format!("cannot create {}", path.display()))?;
+    let removed = remove_stale_temps(path)?;
+    if removed > 0 {
+        debug!("Removed {} unfinished files of an interrupted run from {}", removed, path.display());
+    }
+    Ok(())
+}
+
+/// Whether `path` is `-`, standing for stdin or stdout.
+pub fn is_std_stream(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+/// The input panorama. Remote objects and stdin are read once and
+/// everything is parsed from memory.
+pub enum Source {
+    Local(PathBuf),
+    Remote(Vec<u8>),
+}
+
+impl Source {
+    pub fn open(path: &Path) -> Result<Source> {
+        if is_std_stream(path) {
+            let mut bytes = Vec::new();
+            io::stdin().lock().read_to_end(&mut bytes).context("failed to read the input from stdin")?;
+            return Ok(Source::Remote(bytes));
+        }
+        match s3_url(path) {
+            Some((bucket, key)) => Ok(Source::Remote(s3::get(bucket, key)?)),
+            None => Ok(Source::Local(path.to_path_buf())),
+        }
+    }
+
+    pub fn load_image(&self) -> Result<DynamicImage> {
+        match self {
+            Source::Local(path) => Ok(load_image(path)?),
+            Source::Remote(bytes) => Ok(decode_image(bytes)?),
+        }
+    }
+
+    /// Width, height, precision and channels from the header, without
+    /// decoding; None where the header can't be read this way, leaving the
+    /// error to the decode. Formats that don't stream are taken to be 8-bit,
+    /// or float for HDR and EXR, and RGB unless they are JPEGs.
+    pub fn probe(&self) -> Option<(u32, u32, PixelDepth, u8)> {
+        if let Source::Local(path) = self {
+            if let Ok(reader) = ScanlineReader::open(path) {
+                return Some((reader.width(), reader.height(), reader.depth(), reader.channels()));
+            }
+        }
+        match self {
+            Source::Local(path) => header(image::io::Reader::open(path).ok()?),
+            Source::Remote(bytes) => header(image::io::Reader::new(Cursor::new(bytes))),
+        }
+    }
+
+    /// The panorama's rows, decoded as they're read; `path` names the
+    /// input in errors.
+    pub fn scanlines(&self, path: &Path) -> Result<ScanlineReader> {
+        match self {
+            Source::Local(path) => Ok(ScanlineReader::open(path)?),
+            Source::Remote(bytes) => Ok(ScanlineReader::from_memory(bytes.clone(), path)?),
+        }
+    }
+
+    pub fn metadata(&self) -> Result<Metadata> {
+        match self {
+            Source::Local(path) => Ok(Metadata::read(path)?),
+            Source::Remote(bytes) => Ok(Metadata::from_bytes(bytes)?),
+        }
+    }
+
+    pub fn sha256(&self) -> Result<String> {
+        match self {
+            Source::Local(path) => {
+                let mut hasher = Sha256::new();
+                io::copy(&mut File::open(path)?, &mut hasher)?;
+                Ok(hex(&hasher.finalize()))
+            }
+            Source::Remote(bytes) => Ok(sha256_hex(bytes)),
+        }
+    }
+
+    pub fn gpano(&self) -> Result<Option<PanoCrop>> {
+        match self {
+            Source::Local(path) => Ok(read_gpano(path)?),
+            Source::Remote(bytes) => Ok(find_gpano(bytes)),
+        }
+    }
+
+    pub fn gpano_pose(&self) -> Result<Option<PanoPose>> {
+        match self {
+            Source::Local(path) => Ok(read_gpano_pose(path)?),
+            Source::Remote(bytes) => Ok(find_gpano_pose(bytes)),
+        }
+    }
+}
+
+// Size from the header `reader` reads, at the precision its format usually has
+fn header<R: io::BufRead + Seek>(reader: image::io::Reader<R>) -> Option<(u32, u32, PixelDepth, u8)> {
+    let reader = reader.with_guessed_format().ok()?;
+    let depth = match reader.format()? {
+        ImageFormat::Hdr | ImageFormat::OpenExr => PixelDepth::F32,
+        _ => PixelDepth::U8,
+    };
+    // A JPEG's frame header tells gray from color
+    if reader.format() == Some(ImageFormat::Jpeg) {
+        let decoder = JpegDecoder::new(reader.into_inner()).ok()?;
+        let (width, height) = decoder.dimensions();
+        return Some((width, height, depth, decoder.color_type().channel_count()));
+    }
+    let (width, height) = reader.into_dimensions().ok()?;
+    Some((width, height, depth, 3))
+}
+
+/// The last panorama decoded from a local file, with what identified the
+/// file then: its canonical path, size and modification time.
+#[derive(Default)]
+pub struct LastDecoded(Mutex<Option<(PathBuf, u64, SystemTime, DynamicImage)>>);
+
+impl LastDecoded {
+    /// `source` decoded, or the kept copy if it is the same unchanged file.
+    pub fn load(&self, source: &Source) -> Result<DynamicImage> {
+        let Source::Local(path) = source else { return source.load_image() };
+        let identity = std::fs::canonicalize(path)
+            .and_then(|path| Ok((std::fs::metadata(&path)?, path)))
+            .and_then(|(meta, path)| Ok((path, meta.len(), meta.modified()?)));
+        let Ok((path, len, modified)) = identity else { return source.load_image() };
+        let mut last = self.0.lock().expect("last decoded lock");
+        if let Some((_, _, _, img)) = last.as_ref().filter(|last| (&last.0, last.1, last.2) == (&path, len, modified)) {
+            return Ok(img.clone());
+        }
+        // Let the old panorama go before decoding the new one
+        *last = None;
+        let img = source.load_image()?;
+        *last = Some((path, len, modified, img.clone()));
+        Ok(img)
+    }
+}
+
+/// The contents of `path`, or None if there's no such file.
+pub fn read_if_exists(path: &Path) -> Result<Option<Vec<u8>>> {
+    if let Some((bucket, key)) = s3_url(path) {
+        return s3::get_if_exists(bucket, key);
+    }
+    match std::fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// A file the converter wrote, for the manifest.
+pub struct StoredFile {
+    pub path: PathBuf,
+    pub bytes: u64,
+    pub sha256: String,
+    /// Placeholder for a face image, when asked for
+    pub blurhash: Option<String>,
+}
+
+/// Where the converter writes: files under local or S3 paths, or entries of
+/// a single zip archive or tar stream, named by their path relative to the
+/// output root.
+pub enum Destination {
+    Files,
+    Zip(Box<Mutex<ZipWriter<OutputFile>>>),
+    Tar(Box<Mutex<tar::Builder<BufWriter<io::Stdout>>>>),
+}
+
+impl Destination {
+    /// An archive at `path`; `finish` completes it.
+    pub fn zip(path: &Path) -> Result<Destination> {
+        let file = OutputFile::open(path)?;
+        Ok(Destination::Zip(Box::new(Mutex::new(ZipWriter::new(file)))))
+    }
+
+    /// A tar stream on stdout, for pipelines; `finish` ends it.
+    pub fn tar_stdout() -> Result<Destination> {
+        if io::stdout().is_terminal() {
+            bail!("--output - writes a tar stream; redirect stdout to a file or another command");
+        }
+        let builder = tar::Builder::new(BufWriter::with_capacity(65536, io::stdout()));
+        Ok(Destination::Tar(Box::new(Mutex::new(builder))))
+    }
+
+    /// `create_dir_all`; object storage and archives have no directories to
+    /// create.
+    pub fn create_dir_all(&self, path: &Path) -> Result<()> {
+        if matches!(self, Destination::Files) && !is_remote(path) {
+            create_output_dir(path)?;
+        }
+        Ok(())
+    }
+
+    /// Write `data` to `path` in one go.
+    pub fn write(&self, path: &Path, data: &[u8]) -> Result<StoredFile> {
+        match self {
+            Destination::Files => {
+                let mut file = OutputFile::open(path)?;
+                file.write_all(data)?;
+                file.finish()?;
+            }
+            Destination::Zip(zip) => {
+                let name = path.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/");
+                // Images are compressed already; deflating them again only costs time
+                let method = match path.extension().and_then(|ext| ext.to_str()) {
+                    Some("jpg" | "png" | "webp" | "avif" | "ktx2") => CompressionMethod::Stored,
+                    _ => CompressionMethod::Deflated,
+                };
+                let options = SimpleFileOptions::default()
+                    .compression_method(method)
+                    .large_file(data.len() as u64 >= u32::MAX as u64);
+                let mut zip = zip.lock().expect("no panics while holding the lock");
+                zip.start_file(name, options)?;
+                zip.write_all(data)?;
+            }
+            Destination::Tar(tar) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(data.len() as u64);
+                header.set_mode(0o644);
+                let mut tar = tar.lock().expect("no panics while holding the lock");
+                tar.append_data(&mut header, path, data)?;
+            }
+        }
+        Ok(StoredFile { path: path.to_path_buf(), bytes: data.len() as u64, sha256: sha256_hex(data), blurhash: None })
+    }
+
+    /// Encode `img` to `path`; remote and archived faces are stored as soon
+    /// as each one is encoded.
+    pub fn save_image(
+        &self,
+        img: &DynamicImage,
+        path: &Path,
+        encode: &EncodeOptions,
+        metadata: &Metadata,
+    ) -> Result<StoredFile> {
+        self.write(path, &encode_to_vec(img, encode, metadata)?)
+    }
+
+    /// Write the archive's central directory, or the tar stream's end.
+    pub fn finish(self) -> Result<()> {
+        match self {
+            Destination::Files => Ok(()),
+            Destination::Zip(zip) => (*zip).into_inner().expect("no panics while holding the lock").finish()?.finish(),
+            Destination::Tar(tar) => {
+                let mut stdout = (*tar).into_inner().expect("no panics while holding the lock").into_inner()?;
+                Ok(stdout.flush()?)
+            }
+        }
+    }
+}
+
+/// `img` encoded in memory.
+pub fn encode_to_vec(img: &DynamicImage, encode: &EncodeOptions, metadata: &Metadata) -> Result<Vec<u8>> {
+    let mut data = Cursor::new(Vec::new());
+    encode_image_with_metadata(img, encode, metadata, &mut data)?;
+    Ok(data.into_inner())
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// A file streamed to disk under a temporary name and renamed by `finish`,
+/// or collected in memory and uploaded by `finish`.
+pub enum OutputFile {
+    Local(AtomicFile),
+    Remote(PathBuf, Cursor<Vec<u8>>),
+}
+
+impl OutputFile {
+    fn open(path: &Path) -> Result<OutputFile> {
+        if is_remote(path) {
+            return Ok(OutputFile::Remote(path.to_path_buf(), Cursor::new(Vec::new())));
+        }
+        Ok(OutputFile::Local(AtomicFile::create(path)?))
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            OutputFile::Local(file) => file.commit()?,
+            OutputFile::Remote(path, data) => {
+                let (bucket, key) = s3_url(&path).expect("remote paths are S3 URLs");
+                s3::put(bucket, key, data.into_inner())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputFile::Local(writer) => writer.write(buf),
+            OutputFile::Remote(_, data) => data.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputFile::Local(writer) => writer.flush(),
+            OutputFile::Remote(_, data) => data.flush(),
+        }
+    }
+}
+
+impl Seek for OutputFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            OutputFile::Local(writer) => writer.seek(pos),
+            OutputFile::Remote(_, data) => data.seek(pos),
+        }
+    }
+}
+
+#[cfg(feature = "s3")]
+mod s3 {
+    use anyhow::{Context, Result};
+    use object_store::aws::AmazonS3Builder;
+    use object_store::path::Path as ObjectPath;
+    use object_store::ObjectStore;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex, OnceLock};
+    use tokio::runtime::Runtime;
+
+    // Object storage calls are async; the converter blocks on them from
+    // whichever thread is writing
+    fn runtime() -> &'static Runtime {
+        static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+        RUNTIME.get_or_init(|| {
+            tokio::runtime::Builder::new_multi_thread().enable_all().build().expect("failed to start the IO runtime")
+        })
+    }
+
+    // One client per bucket, shared by every upload
+    fn store(bucket: &str) -> Result<Arc<dyn ObjectStore>> {
+        static STORES: OnceLock<Mutex<HashMap<String, Arc<dyn ObjectStore>>>> = OnceLock::new();
+        let mut stores = STORES.get_or_init(Default::default).lock().expect("no panics while holding the lock");
+        if let Some(store) = stores.get(bucket) {
+            return Ok(store.clone());
+        }
+        let store: Arc<dyn ObjectStore> = Arc::new(
+            AmazonS3Builder::from_env()
+                .with_bucket_name(bucket)
+                .build()
+                .with_context(|| format!("cannot open bucket {}", bucket))?,
+        );
+        stores.insert(bucket.to_string(), store.clone());
+        Ok(store)
+    }
+
+    pub fn get(bucket: &str, key: &str) -> Result<Vec<u8>> {
+        get_if_exists(bucket, key)?.with_context(|| format!("s3://{}/{} does not exist", bucket, key))
+    }
+
+    pub fn get_if_exists(bucket: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        let store = store(bucket)?;
+        match runtime().block_on(async { store.get(&ObjectPath::from(key)).await?.bytes().await }) {
+            Ok(bytes) => Ok(Some(bytes.to_vec())),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("failed to download s3://{}/{}", bucket, key)),
+        }
+    }
+
+    pub fn put(bucket: &str, key: &str, data: Vec<u8>) -> Result<()> {
+        let store = store(bucket)?;
+        runtime()
+            .block_on(store.put(&ObjectPath::from(key), data.into()))
+            .with_context(|| format!("failed to upload s3://{}/{}", bucket, key))?;
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "s3"))]
+mod s3 {
+    use anyhow::{bail, Result};
+
+    pub fn get(bucket: &str, key: &str) -> Result<Vec<u8>> {
+        bail!("s3://{}/{} needs a build with the s3 feature", bucket, key)
+    }
+
+    pub fn get_if_exists(bucket: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        bail!("s3://{}/{} needs a build with the s3 feature", bucket, key)
+    }
+
+    pub fn put(bucket: &str, key: &str, _data: Vec<u8>) -> Result<()> {
+        bail!("s3://{}/{} needs a build with the s3 feature", bucket, key)
+    }
+}
@@ -0,0 +1,656 @@
+use crate::sampler::sample_at;
+use crate::{
+    direction_to_spherical, normal, pixel, Buffer, Channel, CubemapError, CubemapFaces, CubemapOptions, Face,
+    InputProjection, PixelDepth,
+};
+use image::error::{DecodingError, ImageFormatHint, UnsupportedError, UnsupportedErrorKind};
+use image::{DynamicImage, ImageBuffer, ImageError, ImageFormat, Pixel, Rgb};
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Cursor, Seek};
+use std::path::{Path, PathBuf};
+use tiff::decoder::{ChunkType, Decoder as TiffDecoder, DecodingResult, Limits};
+
+// Source rows kept above and below each band for the filter taps (Lanczos3
+// reaches 3 rows out)
+const MARGIN: u32 = 3;
+// PNG rows decoded per `next_rows`
+const PNG_ROWS: u32 = 64;
+
+trait Input: BufRead + Seek + Send {}
+
+impl<T: BufRead + Seek + Send> Input for T {}
+
+/// Equirect panorama decoded a band of rows at a time, top to bottom, so a
+/// render never holds the whole image. Reads strip and tiled TIFF (BigTIFF
+/// included) and non-interlaced PNG; other formats only decode whole.
+pub struct ScanlineReader {
+    path: PathBuf,
+    format: ImageFormat,
+    width: u32,
+    height: u32,
+    decoder: RowDecoder,
+    // Next row `next_rows` hands out
+    row: u32,
+}
+
+// One per reader, so the variants' sizes don't matter
+#[allow(clippy::large_enum_variant)]
+enum RowDecoder {
+    Tiff { decoder: TiffDecoder<Box<dyn Input>>, color: tiff::ColorType, tiled: bool, chunk: u32 },
+    Png(png::Reader<Box<dyn Input>>),
+}
+
+impl ScanlineReader {
+    pub fn open(path: &Path) -> Result<ScanlineReader, CubemapError> {
+        let file = File::open(path).map_err(|err| decode_error(path, ImageError::IoError(err)))?;
+        ScanlineReader::new(Box::new(BufReader::new(file)), path)
+    }
+
+    /// Read an encoded panorama already in memory; `path` only names it in
+    /// errors.
+    pub fn from_memory(bytes: Vec<u8>, path: &Path) -> Result<ScanlineReader, CubemapError> {
+        ScanlineReader::new(Box::new(Cursor::new(bytes)), path)
+    }
+
+    fn new(input: Box<dyn Input>, path: &Path) -> Result<ScanlineReader, CubemapError> {
+        let (format, decoder, width, height) = open_decoder(input).map_err(|err| decode_error(path, err))?;
+        Ok(ScanlineReader { path: path.to_path_buf(), format, width, height, decoder, row: 0 })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Precision the rows decode to.
+    pub fn depth(&self) -> PixelDepth {
+        let bits = match &self.decoder {
+            RowDecoder::Tiff { color, .. } => match *color {
+                tiff::ColorType::Gray(bits)
+                | tiff::ColorType::GrayA(bits)
+                | tiff::ColorType::RGB(bits)
+                | tiff::ColorType::RGBA(bits)
+                | tiff::ColorType::Palette(bits)
+                | tiff::ColorType::CMYK(bits)
+                | tiff::ColorType::YCbCr(bits) => bits,
+            },
+            RowDecoder::Png(reader) => reader.output_color_type().1 as u8,
+        };
+        match bits {
+            32 => PixelDepth::F32,
+            16 => PixelDepth::U16,
+            _ => PixelDepth::U8,
+        }
+    }
+
+    /// Channels the rows decode to; float gray spreads over RGB.
+    pub fn channels(&self) -> u8 {
+        match &self.decoder {
+            RowDecoder::Tiff { color, .. } => match *color {
+                tiff::ColorType::Gray(32) => 3,
+                tiff::ColorType::Gray(_) => 1,
+                tiff::ColorType::GrayA(_) => 2,
+                tiff::ColorType::RGBA(_) => 4,
+                _ => 3,
+            },
+            RowDecoder::Png(reader) => reader.output_color_type().0.samples() as u8,
+        }
+    }
+
+    /// The next band of rows, as many as the file stores together (a strip,
+    /// a row of tiles); None past the last row.
+    pub fn next_rows(&mut self) -> Result<Option<DynamicImage>, CubemapError> {
+        if self.row >= self.height {
+            return Ok(None);
+        }
+        let width = self.width;
+        let band = match &mut self.decoder {
+            RowDecoder::Tiff { decoder, color, tiled, chunk } => {
+                let rows = decoder.chunk_data_dimensions(*chunk).1;
+                tiff_rows(decoder, *color, *tiled, chunk, width)
+                    .and_then(|samples| tiff_image(samples, *color, width, rows))
+                    .map_err(|err| decoding(ImageFormat::Tiff, err))
+            }
+            RowDecoder::Png(reader) => png_rows(reader, width, PNG_ROWS.min(self.height - self.row)),
+        };
+        let band = band.map_err(|err| decode_error(&self.path, err))?;
+        self.row += band.height();
+        Ok(Some(band))
+    }
+
+    /// Decode every row not yet handed out into one image, e.g. a whole
+    /// BigTIFF that image's own TIFF adapter (capped at 512MB) refuses.
+    pub fn read_image(mut self) -> Result<DynamicImage, CubemapError> {
+        self.rest().map_err(|err| decode_error(&self.path, err))
+    }
+
+    fn rest(&mut self) -> Result<DynamicImage, ImageError> {
+        let (width, remaining) = (self.width, self.height - self.row);
+        let image = match &mut self.decoder {
+            RowDecoder::Tiff { decoder, color, tiled, chunk } => {
+                let tiff_error = |err| decoding(ImageFormat::Tiff, err);
+                let mut rows = 0;
+                let mut all: Option<DecodingResult> = None;
+                while rows < remaining {
+                    let band_rows = decoder.chunk_data_dimensions(*chunk).1;
+                    let band = tiff_rows(decoder, *color, *tiled, chunk, width).map_err(tiff_error)?;
+                    match &mut all {
+                        None => {
+                            let mut band = band;
+                            reserve(&mut band, remaining / band_rows);
+                            all = Some(band);
+                        }
+                        Some(all) => append(all, band).map_err(tiff_error)?,
+                    }
+                    rows += band_rows;
+                }
+                let all = all.ok_or_else(|| decoding(ImageFormat::Tiff, "no rows left"))?;
+                tiff_image(all, *color, width, remaining).map_err(tiff_error)?
+            }
+            RowDecoder::Png(reader) => png_rows(reader, width, remaining)?,
+        };
+        self.row = self.height;
+        Ok(image)
+    }
+}
+
+// Sniff the format and read the header up to the first row
+fn open_decoder(mut input: Box<dyn Input>) -> Result<(ImageFormat, RowDecoder, u32, u32), ImageError> {
+    let format = guess_format(input.fill_buf().map_err(ImageError::IoError)?)?;
+    let (decoder, width, height) = match format {
+        ImageFormat::Tiff => {
+            let tiff_error = |err| decoding(ImageFormat::Tiff, err);
+            // The defaults cap a strip at 256MB; single-strip BigTIFFs run to several GB
+            let mut decoder = TiffDecoder::new(input).map_err(tiff_error)?.with_limits(Limits::unlimited());
+            let (width, height) = decoder.dimensions().map_err(tiff_error)?;
+            let color = decoder.colortype().map_err(tiff_error)?;
+            let tiled = decoder.get_chunk_type() == ChunkType::Tile;
+            if !tiled && decoder.strip_count().map_err(tiff_error)? != height.div_ceil(decoder.chunk_dimensions().1) {
+                return Err(unsupported(ImageFormat::Tiff, "planar TIFF"));
+            }
+            (RowDecoder::Tiff { decoder, color, tiled, chunk: 0 }, width, height)
+        }
+        ImageFormat::Png => {
+            let png_error = |err| decoding(ImageFormat::Png, err);
+            let mut decoder = png::Decoder::new(input);
+            // Palettes and low bit depths to 8-bit, transparency to alpha
+            decoder.set_transformations(png::Transformations::EXPAND);
+            let reader = decoder.read_info().map_err(png_error)?;
+            if reader.info().interlaced {
+                return Err(unsupported(ImageFormat::Png, "streaming interlaced PNG"));
+            }
+            let (width, height) = reader.info().size();
+            (RowDecoder::Png(reader), width, height)
+        }
+        format => {
+            let message = format!("streaming {:?} panoramas (only TIFF and PNG stream)", format);
+            return Err(unsupported(format, &message));
+        }
+    };
+    Ok((format, decoder, width, height))
+}
+
+/// `image::guess_format`, which also knows BigTIFF's magic.
+pub(crate) fn guess_format(bytes: &[u8]) -> Result<ImageFormat, ImageError> {
+    match bytes.get(..4) {
+        Some(b"II+\0") | Some(b"MM\0+") => Ok(ImageFormat::Tiff),
+        _ => image::guess_format(bytes),
+    }
+}
+
+/// A whole TIFF already in memory, decoded without image's 512MB cap.
+pub(crate) fn decode_tiff(bytes: &[u8]) -> Result<DynamicImage, ImageError> {
+    let (format, decoder, width, height) = open_decoder(Box::new(Cursor::new(bytes.to_vec())))?;
+    ScanlineReader { path: PathBuf::new(), format, width, height, decoder, row: 0 }.rest()
+}
+
+// Room for `bands` more bands the size of `samples`
+fn reserve(samples: &mut DecodingResult, bands: u32) {
+    match samples {
+        DecodingResult::U8(data) => data.reserve(data.len() * bands as usize),
+        DecodingResult::U16(data) => data.reserve(data.len() * bands as usize),
+        DecodingResult::F32(data) => data.reserve(data.len() * bands as usize),
+        _ => {}
+    }
+}
+
+fn append(all: &mut DecodingResult, band: DecodingResult) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    match (all, band) {
+        (DecodingResult::U8(all), DecodingResult::U8(band)) => all.extend_from_slice(&band),
+        (DecodingResult::U16(all), DecodingResult::U16(band)) => all.extend_from_slice(&band),
+        (DecodingResult::F32(all), DecodingResult::F32(band)) => all.extend_from_slice(&band),
+        _ => return Err("mixed sample types".into()),
+    }
+    Ok(())
+}
+
+fn decode_error(path: &Path, source: ImageError) -> CubemapError {
+    CubemapError::Decode { path: path.to_path_buf(), source }
+}
+
+fn decoding(format: ImageFormat, err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> ImageError {
+    ImageError::Decoding(DecodingError::new(ImageFormatHint::Exact(format), err))
+}
+
+fn unsupported(format: ImageFormat, what: &str) -> ImageError {
+    ImageError::Unsupported(UnsupportedError::from_format_and_kind(
+        ImageFormatHint::Exact(format),
+        UnsupportedErrorKind::GenericFeature(what.to_string()),
+    ))
+}
+
+// Samples of the next strip, or the next row of tiles stitched side by side
+fn tiff_rows(
+    decoder: &mut TiffDecoder<Box<dyn Input>>,
+    color: tiff::ColorType,
+    tiled: bool,
+    chunk: &mut u32,
+    width: u32,
+) -> Result<DecodingResult, Box<dyn std::error::Error + Send + Sync>> {
+    if !tiled {
+        let samples = decoder.read_chunk(*chunk)?;
+        *chunk += 1;
+        return Ok(samples);
+    }
+
+    let tile_width = decoder.chunk_dimensions().0;
+    let across = width.div_ceil(tile_width);
+    let rows = decoder.chunk_data_dimensions(*chunk).1;
+    let mut band = None;
+    for i in 0..across {
+        let columns = decoder.chunk_data_dimensions(*chunk + i).0 as usize;
+        let tile = decoder.read_chunk(*chunk + i)?;
+        let left = (i * tile_width) as usize;
+        let band = band.get_or_insert_with(|| match &tile {
+            DecodingResult::U16(_) => DecodingResult::U16(Vec::new()),
+            DecodingResult::F32(_) => DecodingResult::F32(Vec::new()),
+            _ => DecodingResult::U8(Vec::new()),
+        });
+        match (band, tile) {
+            (DecodingResult::U8(band), DecodingResult::U8(tile)) => place(band, &tile, width, rows, left, columns),
+            (DecodingResult::U16(band), DecodingResult::U16(tile)) => place(band, &tile, width, rows, left, columns),
+            (DecodingResult::F32(band), DecodingResult::F32(tile)) => place(band, &tile, width, rows, left, columns),
+            _ => return Err(format!("{:?} samples", color).into()),
+        }
+    }
+    *chunk += across;
+    Ok(band.expect("at least one tile"))
+}
+
+// Copy a tile `columns` pixels wide into the band at column `left`
+fn place<T: Copy + Default>(band: &mut Vec<T>, tile: &[T], width: u32, rows: u32, left: usize, columns: usize) {
+    let channels = tile.len() / (columns * rows as usize);
+    let row_len = width as usize * channels;
+    band.resize(row_len * rows as usize, T::default());
+    for (y, tile_row) in tile.chunks_exact(columns * channels).enumerate() {
+        let start = y * row_len + left * channels;
+        band[start..start + tile_row.len()].copy_from_slice(tile_row);
+    }
+}
+
+fn tiff_image(
+    samples: DecodingResult,
+    color: tiff::ColorType,
+    width: u32,
+    rows: u32,
+) -> Result<DynamicImage, Box<dyn std::error::Error + Send + Sync>> {
+    use tiff::ColorType::*;
+    let image = match (color, samples) {
+        (Gray(8), DecodingResult::U8(data)) => ImageBuffer::from_raw(width, rows, data).map(DynamicImage::ImageLuma8),
+        (GrayA(8), DecodingResult::U8(data)) => ImageBuffer::from_raw(width, rows, data).map(DynamicImage::ImageLumaA8),
+        (RGB(8), DecodingResult::U8(data)) => ImageBuffer::from_raw(width, rows, data).map(DynamicImage::ImageRgb8),
+        (RGBA(8), DecodingResult::U8(data)) => ImageBuffer::from_raw(width, rows, data).map(DynamicImage::ImageRgba8),
+        (Gray(16), DecodingResult::U16(data)) => {
+            ImageBuffer::from_raw(width, rows, data).map(DynamicImage::ImageLuma16)
+        }
+        (GrayA(16), DecodingResult::U16(data)) => {
+            ImageBuffer::from_raw(width, rows, data).map(DynamicImage::ImageLumaA16)
+        }
+        (RGB(16), DecodingResult::U16(data)) => ImageBuffer::from_raw(width, rows, data).map(DynamicImage::ImageRgb16),
+        (RGBA(16), DecodingResult::U16(data)) => {
+            ImageBuffer::from_raw(width, rows, data).map(DynamicImage::ImageRgba16)
+        }
+        // image has no float gray, so spread it over RGB
+        (Gray(32), DecodingResult::F32(data)) => {
+            let data = data.iter().flat_map(|&value| [value; 3]).collect();
+            ImageBuffer::from_raw(width, rows, data).map(DynamicImage::ImageRgb32F)
+        }
+        (RGB(32), DecodingResult::F32(data)) => ImageBuffer::from_raw(width, rows, data).map(DynamicImage::ImageRgb32F),
+        (RGBA(32), DecodingResult::F32(data)) => {
+            ImageBuffer::from_raw(width, rows, data).map(DynamicImage::ImageRgba32F)
+        }
+        (color, _) => return Err(format!("{:?} samples", color).into()),
+    };
+    image.ok_or_else(|| "short strip".into())
+}
+
+fn png_rows(reader: &mut png::Reader<Box<dyn Input>>, width: u32, rows: u32) -> Result<DynamicImage, ImageError> {
+    let (color, depth) = reader.output_color_type();
+    let mut data = Vec::with_capacity(reader.output_line_size(width) * rows as usize);
+    for _ in 0..rows {
+        match reader.next_row().map_err(|err| decoding(ImageFormat::Png, err))? {
+            Some(row) => data.extend_from_slice(row.data()),
+            None => return Err(decoding(ImageFormat::Png, "image ends early")),
+        }
+    }
+    // 16-bit samples are big-endian
+    let wide = || data.chunks_exact(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect::<Vec<_>>();
+    let image = match (color, depth) {
+        (png::ColorType::Grayscale, png::BitDepth::Eight) => {
+            ImageBuffer::from_raw(width, rows, data).map(DynamicImage::ImageLuma8)
+        }
+        (png::ColorType::GrayscaleAlpha, png::BitDepth::Eight) => {
+            ImageBuffer::from_raw(width, rows, data).map(DynamicImage::ImageLumaA8)
+        }
+        (png::ColorType::Rgb, png::BitDepth::Eight) => {
+            ImageBuffer::from_raw(width, rows, data).map(DynamicImage::ImageRgb8)
+        }
+        (png::ColorType::Rgba, png::BitDepth::Eight) => {
+            ImageBuffer::from_raw(width, rows, data).map(DynamicImage::ImageRgba8)
+        }
+        (png::ColorType::Grayscale, png::BitDepth::Sixteen) => {
+            ImageBuffer::from_raw(width, rows, wide()).map(DynamicImage::ImageLuma16)
+        }
+        (png::ColorType::GrayscaleAlpha, png::BitDepth::Sixteen) => {
+            ImageBuffer::from_raw(width, rows, wide()).map(DynamicImage::ImageLumaA16)
+        }
+        (png::ColorType::Rgb, png::BitDepth::Sixteen) => {
+            ImageBuffer::from_raw(width, rows, wide()).map(DynamicImage::ImageRgb16)
+        }
+        (png::ColorType::Rgba, png::BitDepth::Sixteen) => {
+            ImageBuffer::from_raw(width, rows, wide()).map(DynamicImage::ImageRgba16)
+        }
+        (color, depth) => return Err(unsupported(ImageFormat::Png, &format!("{:?} {:?}-bit PNG", color, depth))),
+    };
+    image.ok_or_else(|| decoding(ImageFormat::Png, "short row"))
+}
+
+/// `equirect_to_cubemap_dynamic` for a panorama too large to decode whole.
+/// The source is read top to bottom in bands of about `window_bytes` of
+/// decoded rows, and each face pixel is rendered while the band its
+/// direction falls in is loaded; only the band and the faces stay in
+/// memory. Supports full equirect inputs without supersampling.
+pub fn equirect_to_cubemap_streaming(
+    mut reader: ScanlineReader,
+    options: &CubemapOptions,
+    window_bytes: usize,
+) -> Result<CubemapFaces<DynamicImage>, CubemapError> {
+    options.validate()?;
+    if options.ssaa > 1 || options.input != InputProjection::Equirect {
+        return Err(CubemapError::Projection(
+            "streaming renders full equirect panoramas without supersampling".to_string(),
+        ));
+    }
+    let depth = reader.depth();
+    options.install(|| {
+        let faces = if options.linear && depth != PixelDepth::F32 {
+            render(&mut reader, options, window_bytes, |band| pixel::linearize(&band))?
+                .map(|_, face| pixel::delinearize(face, depth))
+        } else {
+            match depth {
+                PixelDepth::U8 => {
+                    render(&mut reader, options, window_bytes, DynamicImage::into_rgb8)?.map(|_, f| f.into())
+                }
+                PixelDepth::U16 => {
+                    render(&mut reader, options, window_bytes, DynamicImage::into_rgb16)?.map(|_, f| f.into())
+                }
+                PixelDepth::F32 => render::<Rgb<f32>>(&mut reader, options, window_bytes, DynamicImage::into_rgb32f)?
+                    .map(|_, f| f.into()),
+            }
+        };
+        Ok(faces.map(|face, img| options.post_process(face, img)))
+    })
+}
+
+fn render<P>(
+    reader: &mut ScanlineReader,
+    options: &CubemapOptions,
+    window_bytes: usize,
+    convert: impl Fn(DynamicImage) -> Buffer<P>,
+) -> Result<CubemapFaces<Buffer<P>>, CubemapError>
+where
+    P: Pixel + Send + Sync,
+    P::Subpixel: Channel,
+{
+    let (width, height) = (reader.width(), reader.height());
+    let size = options.face_size();
+    let channels = P::CHANNEL_COUNT as usize;
+    let row_len = width as usize * channels;
+    let row_bytes = row_len * std::mem::size_of::<P::Subpixel>();
+    let band_rows = (window_bytes / row_bytes).saturating_sub(2 * MARGIN as usize).max(1) as u32;
+
+    let bases = Face::ALL.map(|face| options.rotation.apply_basis(face.basis()));
+    let frames = Face::ALL.map(|face| options.pixel_type.frame(face, &options.rotation));
+    let coords: Vec<f32> = (0..size).map(|i| options.face_coord(i)).collect();
+    // Source pixel a face-plane point samples, wrapped and clamped like `sample`
+    let locate = |face: usize, a: f32, b: f32| {
+        let (u, v) = direction_to_spherical(options.offset_direction(bases[face].direction(a, b)));
+        ((u * width as f32).rem_euclid(width as f32), (v * height as f32).clamp(0.0, (height - 1) as f32))
+    };
+    // Source rows each face row reaches, so bands can skip the rows they don't
+    let spans: Vec<Vec<(f32, f32)>> = (0..6)
+        .map(|face| {
+            coords
+                .par_iter()
+                .map(|&b| {
+                    coords.iter().fold((f32::MAX, f32::MIN), |(top, bottom), &a| {
+                        let y = locate(face, a, b).1;
+                        (top.min(y), bottom.max(y))
+                    })
+                })
+                .collect()
+        })
+        .collect();
+
+    let mut faces: Vec<Buffer<P>> = (0..6).map(|_| Buffer::new(size, size)).collect();
+    let mut window: Vec<P::Subpixel> = Vec::new();
+    let mut window_first = 0;
+    let mut start = 0;
+    while start < height {
+        let end = (start + band_rows).min(height);
+        // Read ahead to the last row the band's filter taps reach and drop
+        // the rows behind its first
+        while window_first + ((window.len() / row_len) as u32) < (end + MARGIN).min(height) {
+            match reader.next_rows()? {
+                Some(rows) => window.extend_from_slice(convert(rows).as_raw()),
+                None => break,
+            }
+        }
+        let keep = start.saturating_sub(MARGIN).max(window_first);
+        window.drain(..(keep - window_first) as usize * row_len);
+        window_first = keep;
+        let rows = (window.len() / row_len) as u32;
+        if window_first + rows < end {
+            return Err(decode_error(&reader.path, decoding(reader.format, "image ends early")));
+        }
+        let src: Buffer<P> = ImageBuffer::from_raw(width, rows, window).expect("whole rows");
+
+        // Each pixel renders in the one band its source row falls in
+        let (top, bottom) = (start as f32, if end == height { f32::INFINITY } else { end as f32 });
+        faces.par_iter_mut().zip(&spans).enumerate().for_each(|(face, (buffer, spans))| {
+            buffer.par_chunks_mut(size as usize * channels).zip(spans).enumerate().for_each(
+                |(y, (row, &(first, last)))| {
+                    if last < top || first >= bottom {
+                        return;
+                    }
+                    for (pixel, &a) in row.chunks_exact_mut(channels).zip(&coords) {
+                        let (x, sy) = locate(face, a, coords[y]);
+                        if (top..bottom).contains(&sy) {
+                            *P::from_slice_mut(pixel) = sample_at(&src, x, sy - window_first as f32, options.filter);
+                            options.grade.apply_row(pixel, channels);
+                            if let Some(frame) = &frames[face] {
+                                normal::apply_row(frame, pixel, channels);
+                            }
+                        }
+                    }
+                },
+            );
+        });
+        if let Some(progress) = &options.progress {
+            for (face, spans) in Face::ALL.into_iter().zip(&spans) {
+                progress.report(face, spans.iter().filter(|&&(_, last)| last < bottom).count() as u32, size);
+            }
+        }
+        window = src.into_raw();
+        start = end;
+    }
+
+    Ok(CubemapFaces { size, faces })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decode_image, equirect_to_cubemap_dynamic, Filter, Rotation};
+    use image::RgbImage;
+
+    // How the TIFF's rows are stored: strips of this many rows, or square
+    // tiles this wide
+    enum Chunks {
+        Strips(u32),
+        Tiles(u32),
+    }
+
+    // Uncompressed 8-bit RGB TIFF of `img`, or BigTIFF if `big`, built tag
+    // by tag
+    fn tiff(img: &RgbImage, chunks: Chunks, big: bool) -> Vec<u8> {
+        let (width, height) = img.dimensions();
+        let row = |y: u32, x: u32, columns: u32| {
+            let start = ((y * width + x) * 3) as usize;
+            &img.as_raw()[start..start + (columns * 3) as usize]
+        };
+        // Tiles are stored whole, padded past the right and bottom edges
+        let data: Vec<Vec<u8>> = match chunks {
+            Chunks::Strips(rows) => (0..height)
+                .step_by(rows as usize)
+                .map(|top| (top..(top + rows).min(height)).flat_map(|y| row(y, 0, width).to_vec()).collect())
+                .collect(),
+            Chunks::Tiles(tile) => (0..height.div_ceil(tile))
+                .flat_map(|ty| (0..width.div_ceil(tile)).map(move |tx| (tx, ty)))
+                .map(|(tx, ty)| {
+                    let mut data = vec![0u8; (tile * tile * 3) as usize];
+                    for y in 0..tile.min(height - ty * tile) {
+                        let columns = tile.min(width - tx * tile);
+                        let start = (y * tile * 3) as usize;
+                        let src = row(ty * tile + y, tx * tile, columns);
+                        data[start..start + src.len()].copy_from_slice(src);
+                    }
+                    data
+                })
+                .collect(),
+        };
+
+        // BigTIFF widens counts and offsets to 64 bits
+        let (header, word): (&[u8], usize) = if big { (b"II\x2b\0\x08\0\0\0", 8) } else { (b"II\x2a\0", 4) };
+        let mut out = header.to_vec();
+        out.resize(header.len() + word, 0);
+        let mut offsets = Vec::new();
+        for chunk in &data {
+            offsets.push(out.len() as u64);
+            out.extend_from_slice(chunk);
+        }
+        let counts: Vec<u64> = data.iter().map(|chunk| chunk.len() as u64).collect();
+        const SHORT: u16 = 3;
+        const LONG: u16 = 4;
+        const LONG8: u16 = 16;
+        let offset = if big { LONG8 } else { LONG };
+        let mut entries: Vec<(u16, u16, Vec<u64>)> = vec![
+            (256, LONG, vec![width as u64]),
+            (257, LONG, vec![height as u64]),
+            (258, SHORT, vec![8, 8, 8]),
+            (259, SHORT, vec![1]),
+            (262, SHORT, vec![2]),
+            (277, SHORT, vec![3]),
+            (284, SHORT, vec![1]),
+        ];
+        match chunks {
+            Chunks::Strips(rows) => {
+                entries.extend([(273, offset, offsets), (278, LONG, vec![rows as u64]), (279, offset, counts)]);
+            }
+            Chunks::Tiles(tile) => {
+                entries.extend([(322, LONG, vec![tile as u64]), (323, LONG, vec![tile as u64])]);
+                entries.extend([(324, offset, offsets), (325, offset, counts)]);
+            }
+        }
+        entries.sort_by_key(|entry| entry.0);
+
+        out.resize(out.len().next_multiple_of(word), 0);
+        let ifd = out.len() as u64;
+        out.splice(header.len()..header.len() + word, ifd.to_le_bytes()[..word].iter().copied());
+        // Values that don't fit in an entry follow the directory
+        let count_len = if big { 8 } else { 2 };
+        let mut overflow_at = ifd + (count_len + (4 + 2 * word) * entries.len() + word) as u64;
+        let mut overflow = Vec::new();
+        out.extend_from_slice(&(entries.len() as u64).to_le_bytes()[..count_len]);
+        for (tag, kind, values) in &entries {
+            let bytes: Vec<u8> = match *kind {
+                SHORT => values.iter().flat_map(|&v| (v as u16).to_le_bytes()).collect(),
+                LONG => values.iter().flat_map(|&v| (v as u32).to_le_bytes()).collect(),
+                _ => values.iter().flat_map(|&v| v.to_le_bytes()).collect(),
+            };
+            out.extend(tag.to_le_bytes());
+            out.extend(kind.to_le_bytes());
+            out.extend_from_slice(&(values.len() as u64).to_le_bytes()[..word]);
+            if bytes.len() <= word {
+                out.extend_from_slice(&bytes);
+                out.resize(out.len() + word - bytes.len(), 0);
+            } else {
+                out.extend_from_slice(&overflow_at.to_le_bytes()[..word]);
+                overflow_at += bytes.len() as u64;
+                overflow.extend_from_slice(&bytes);
+            }
+        }
+        out.resize(out.len() + word, 0);
+        out.extend_from_slice(&overflow);
+        out
+    }
+
+    fn png(img: &RgbImage) -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(img.clone()).write_to(&mut out, ImageFormat::Png).unwrap();
+        out.into_inner()
+    }
+
+    #[test]
+    fn streamed_bands_match_whole_renders() {
+        // 60 x 30 with 16-pixel tiles leaves partial tiles on the right and
+        // bottom edges
+        let img = RgbImage::from_fn(60, 30, |x, y| image::Rgb([(x * 4) as u8, (y * 8) as u8, (x * y % 251) as u8]));
+        let inputs = [
+            ("strips", tiff(&img, Chunks::Strips(7), false)),
+            ("tiles", tiff(&img, Chunks::Tiles(16), false)),
+            ("png", png(&img)),
+        ];
+        for filter in [Filter::Bilinear, Filter::Lanczos3] {
+            let options = CubemapOptions {
+                size: 12,
+                filter,
+                rotation: Rotation::from_euler_degrees(20.0, 35.0, 0.0),
+                ..CubemapOptions::default()
+            };
+            let expected = equirect_to_cubemap_dynamic(&DynamicImage::ImageRgb8(img.clone()), &options);
+            for (name, data) in &inputs {
+                assert_eq!(decode_image(data).unwrap(), DynamicImage::ImageRgb8(img.clone()), "{}", name);
+                let reader = ScanlineReader::from_memory(data.clone(), Path::new(name)).unwrap();
+                // Ten rows at a time: four rows per band between the margins
+                let streamed = equirect_to_cubemap_streaming(reader, &options, 10 * 60 * 3).unwrap();
+                assert_eq!(streamed.faces, expected.faces, "{} with {}", name, filter);
+            }
+        }
+    }
+
+    #[test]
+    fn bigtiff_decodes_whole() {
+        let img = RgbImage::from_fn(40, 20, |x, y| image::Rgb([(x * 6) as u8, (y * 12) as u8, 77]));
+        for (name, chunks) in [("strips", Chunks::Strips(20)), ("tiles", Chunks::Tiles(16))] {
+            let data = tiff(&img, chunks, true);
+            assert_eq!(&data[..4], b"II+\0");
+            assert_eq!(guess_format(&data).unwrap(), ImageFormat::Tiff);
+            assert_eq!(decode_image(&data).unwrap(), DynamicImage::ImageRgb8(img.clone()), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use crate::{CubemapError, CubemapFaces, Face, PixelDepth};
+use image::DynamicImage;
+use std::io::Write;
+
+// Element type of the samples, as NumPy and safetensors spell it
+struct DType {
+    numpy: &'static str,
+    safetensors: &'static str,
+}
+
+const U8: DType = DType { numpy: "|u1", safetensors: "U8" };
+const U16: DType = DType { numpy: "<u2", safetensors: "U16" };
+const F32: DType = DType { numpy: "<f4", safetensors: "F32" };
+
+// The samples of `img` at its own precision, little-endian and row by row,
+// with their type and channel count. Gray keeps its one channel and alpha
+// its fourth; gray with alpha is widened to RGBA
+fn samples(img: &DynamicImage) -> (DType, u32, Vec<u8>) {
+    let depth = PixelDepth::of(img);
+    if matches!(img, DynamicImage::ImageLumaA8(_) | DynamicImage::ImageLumaA16(_)) {
+        return samples(&depth.to_rgba(img.clone()));
+    }
+    let channels = img.color().channel_count() as u32;
+    let bytes = img.as_bytes();
+    match depth {
+        PixelDepth::U8 => (U8, channels, bytes.to_vec()),
+        PixelDepth::U16 => {
+            let le = bytes.chunks_exact(2).flat_map(|b| u16::from_ne_bytes([b[0], b[1]]).to_le_bytes()).collect();
+            (U16, channels, le)
+        }
+        PixelDepth::F32 => {
+            let le = bytes.chunks_exact(4).flat_map(|b| f32::from_ne_bytes([b[0], b[1], b[2], b[3]]).to_le_bytes());
+            (F32, channels, le.collect())
+        }
+    }
+}
+
+/// Write a face as a NumPy `.npy` array of shape (height, width, channels)
+/// at its own precision: `uint8`, `uint16` or `float32`, so float faces keep
+/// every value the renderer produced.
+pub fn write_npy<W: Write>(img: &DynamicImage, mut writer: W) -> Result<(), CubemapError> {
+    let (dtype, channels, data) = samples(img);
+    let header = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': ({}, {}, {}), }}",
+        dtype.numpy, img.height(), img.width(), channels
+    );
+    // Magic, version 1.0 and the header's length come first; the header is
+    // padded with spaces and a newline so the data starts 64-byte aligned
+    let length = (10 + header.len() + 1).next_multiple_of(64) - 10;
+    writer.write_all(b"\x93NUMPY\x01\x00")?;
+    writer.write_all(&(length as u16).to_le_bytes())?;
+    writer.write_all(format!("{:<width$}\n", header, width = length - 1).as_bytes())?;
+    writer.write_all(&data)?;
+    Ok(())
+}
+
+/// Write cubemap levels as one `.safetensors` file holding a tensor of shape
+/// (6, size, size, channels) per level, faces in `Face::ALL` order, at the
+/// faces' own precision. A lone level is named `faces`; several, e.g. from
+/// `prefilter_specular`, are `level0`, `level1` and so on.
+pub fn write_safetensors<W: Write>(levels: &[CubemapFaces<DynamicImage>], mut writer: W) -> Result<(), CubemapError> {
+    let mut entries = Vec::new();
+    let mut data = Vec::new();
+    for (i, level) in levels.iter().enumerate() {
+        let name = if levels.len() == 1 { "faces".to_string() } else { format!("level{}", i) };
+        let start = data.len();
+        let mut layout = None;
+        for (face, img) in level.iter() {
+            let (dtype, channels, samples) = samples(img);
+            let this = (dtype.safetensors, img.width(), img.height(), channels);
+            if *layout.get_or_insert(this) != this {
+                return Err(CubemapError::Projection(format!(
+                    "face {} of {} doesn't match the others' size and sample type",
+                    face, name
+                )));
+            }
+            data.extend(samples);
+        }
+        let Some((dtype, width, height, channels)) = layout else { continue };
+        entries.push(format!(
+            "\"{}\":{{\"dtype\":\"{}\",\"shape\":[{},{},{},{}],\"data_offsets\":[{},{}]}}",
+            name, dtype, level.faces.len(), height, width, channels, start, data.len()
+        ));
+    }
+    let faces: Vec<&str> = Face::ALL.iter().map(|face| face.name()).collect();
+    let header = format!("{{\"__metadata__\":{{\"faces\":\"{}\"}},{}}}", faces.join(","), entries.join(","));
+    // Spaces pad the header so the data starts 8-byte aligned
+    let length = header.len().next_multiple_of(8);
+    writer.write_all(&(length as u64).to_le_bytes())?;
+    writer.write_all(format!("{:<width$}", header, width = length).as_bytes())?;
+    writer.write_all(&data)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{equirect_to_cubemap_dynamic, CubemapOptions, Filter};
+
+    #[test]
+    fn tensors_keep_float_samples() {
+        let src = image::Rgb32FImage::from_fn(64, 32, |x, y| image::Rgb([x as f32 * 0.1, y as f32 * 2.5, -1.0]));
+        let options = CubemapOptions { size: 4, filter: Filter::Nearest, ..CubemapOptions::default() };
+        let cubemap = equirect_to_cubemap_dynamic(&DynamicImage::ImageRgb32F(src), &options);
+
+        let mut npy = Vec::new();
+        write_npy(cubemap.get(Face::Front), &mut npy).unwrap();
+        let header = u16::from_le_bytes([npy[8], npy[9]]) as usize + 10;
+        assert_eq!(header % 64, 0);
+        let dict = String::from_utf8_lossy(&npy[..header]);
+        assert!(dict.contains("'descr': '<f4', 'fortran_order': False, 'shape': (4, 4, 3)"), "{}", dict);
+        let front = cubemap.get(Face::Front).as_bytes();
+        assert_eq!(npy[header..].len(), front.len());
+        let first = f32::from_le_bytes(npy[header..header + 4].try_into().unwrap());
+        assert_eq!(first, f32::from_ne_bytes(front[..4].try_into().unwrap()));
+
+        let mut safetensors = Vec::new();
+        write_safetensors(std::slice::from_ref(&cubemap), &mut safetensors).unwrap();
+        let length = u64::from_le_bytes(safetensors[..8].try_into().unwrap()) as usize;
+        assert_eq!(length % 8, 0);
+        let header = String::from_utf8_lossy(&safetensors[8..8 + length]);
+        assert!(header.contains("\"faces\":{\"dtype\":\"F32\",\"shape\":[6,4,4,3],\"data_offsets\":[0,1152]}"));
+        assert_eq!(safetensors.len(), 8 + length + 1152);
+    }
+}
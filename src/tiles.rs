@@ -0,0 +1,249 @@
+use crate::{CubemapFaces, Face};
+use image::{DynamicImage, GenericImage};
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Web viewer a tile pyramid is laid out for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TileViewer {
+    /// `{face}/l{level}/{row}/l{level}_{face}_{row}_{col}` with 1-based
+    /// rows and columns, plus `tour.xml`
+    #[default]
+    Krpano,
+    /// `{level}/{face}{row}_{col}` with 0-based rows and columns, plus
+    /// `config.json`
+    Pannellum,
+    /// `{face}/{level}/{row}/{col}` with 0-based rows and columns, plus
+    /// `preview.jpg`, `data.js` and `config.json`
+    Marzipano,
+}
+
+impl TileViewer {
+    pub const ALL: [TileViewer; 3] = [TileViewer::Krpano, TileViewer::Pannellum, TileViewer::Marzipano];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TileViewer::Krpano => "krpano",
+            TileViewer::Pannellum => "pannellum",
+            TileViewer::Marzipano => "marzipano",
+        }
+    }
+
+    /// Single-letter face id used in tile paths
+    pub fn face_id(self, face: Face) -> &'static str {
+        match face {
+            Face::Front => "f",
+            Face::Right => "r",
+            Face::Back => "b",
+            Face::Left => "l",
+            Face::Up => "u",
+            Face::Down => "d",
+        }
+    }
+
+    /// Path of a tile relative to the output root. `level` is 1-based with
+    /// level 1 the smallest; `row` and `col` are 0-based.
+    pub fn tile_path(self, face: Face, level: usize, row: u32, col: u32, extension: &str) -> PathBuf {
+        let id = self.face_id(face);
+        match self {
+            TileViewer::Krpano => PathBuf::from(format!(
+                "{id}/l{level}/{row}/l{level}_{id}_{row}_{col}.{extension}",
+                row = row + 1,
+                col = col + 1,
+            )),
+            TileViewer::Pannellum => PathBuf::from(format!("{level}/{id}{row}_{col}.{extension}")),
+            TileViewer::Marzipano => PathBuf::from(format!("{id}/{level}/{row}/{col}.{extension}")),
+        }
+    }
+
+    /// Level sizes for a face of about `face_size`. Marzipano wants every
+    /// level to be a whole number of tiles, so its face size is rounded to
+    /// `tile_size` times a power of two and the halving stops at one tile.
+    pub fn pyramid(self, face_size: u32, tile_size: u32, levels: Option<u32>) -> TilePyramid {
+        match self {
+            TileViewer::Marzipano => {
+                let tiles = (face_size as f32 / tile_size as f32).max(1.0).log2().round() as u32;
+                let levels = levels.unwrap_or(tiles + 1).min(tiles + 1);
+                TilePyramid::new(tile_size << tiles, tile_size, Some(levels))
+            }
+            _ => TilePyramid::new(face_size, tile_size, levels),
+        }
+    }
+
+    /// Face size of the low-resolution preview strip, for viewers that load
+    /// one before the tiles
+    pub fn preview_size(self, pyramid: &TilePyramid) -> Option<u32> {
+        match self {
+            TileViewer::Marzipano => Some(pyramid.level_sizes[0].min(256)),
+            _ => None,
+        }
+    }
+
+    /// Viewer configuration files (name and contents) for `pyramid`.
+    pub fn config(self, pyramid: &TilePyramid, extension: &str) -> Vec<(&'static str, String)> {
+        match self {
+            TileViewer::Krpano => {
+                let sizes: Vec<String> = pyramid.level_sizes.iter().map(u32::to_string).collect();
+                let xml = format!(
+                    concat!(
+                        "<krpano>\n",
+                        "  <view hlookat=\"0\" vlookat=\"0\" fovtype=\"MFOV\" fov=\"90\" />\n",
+                        "  <image>\n",
+                        "    <cube url=\"%s/l%l/%v/l%l_%s_%v_%h.{}\" multires=\"{},{}\" />\n",
+                        "  </image>\n",
+                        "</krpano>\n"
+                    ),
+                    extension,
+                    pyramid.tile_size,
+                    sizes.join(","),
+                );
+                vec![("tour.xml", xml)]
+            }
+            TileViewer::Pannellum => {
+                let json = format!(
+                    concat!(
+                        "{{\n",
+                        "  \"type\": \"multires\",\n",
+                        "  \"multiRes\": {{\n",
+                        "    \"basePath\": \".\",\n",
+                        "    \"path\": \"/%l/%s%y_%x\",\n",
+                        "    \"extension\": \"{}\",\n",
+                        "    \"tileResolution\": {},\n",
+                        "    \"maxLevel\": {},\n",
+                        "    \"cubeResolution\": {}\n",
+                        "  }}\n",
+                        "}}\n"
+                    ),
+                    extension,
+                    pyramid.tile_size,
+                    pyramid.level_sizes.len(),
+                    pyramid.face_size(),
+                );
+                vec![("config.json", json)]
+            }
+            TileViewer::Marzipano => {
+                // The preview strip is Marzipano's fallback-only level 0
+                let preview = self.preview_size(pyramid).expect("marzipano has a preview");
+                let mut levels = vec![format!(
+                    "        {{ \"tileSize\": {preview}, \"size\": {preview}, \"fallbackOnly\": true }}"
+                )];
+                levels.extend(pyramid.level_sizes.iter().map(|size| {
+                    format!("        {{ \"tileSize\": {}, \"size\": {} }}", pyramid.tile_size, size)
+                }));
+                let json = format!(
+                    concat!(
+                        "{{\n",
+                        "  \"scenes\": [\n",
+                        "    {{\n",
+                        "      \"id\": \"0-panorama\",\n",
+                        "      \"name\": \"panorama\",\n",
+                        "      \"urlTemplate\": \"{{f}}/{{z}}/{{y}}/{{x}}.{}\",\n",
+                        "      \"previewUrl\": \"preview.{}\",\n",
+                        "      \"levels\": [\n{}\n      ],\n",
+                        "      \"faceSize\": {},\n",
+                        "      \"initialViewParameters\": {{ \"yaw\": 0, \"pitch\": 0, \"fov\": {} }}\n",
+                        "    }}\n",
+                        "  ]\n",
+                        "}}\n"
+                    ),
+                    extension,
+                    extension,
+                    levels.join(",\n"),
+                    pyramid.face_size(),
+                    std::f32::consts::FRAC_PI_2,
+                );
+                let data = format!("var APP_DATA = {};", json.trim_end());
+                vec![("config.json", json), ("data.js", data + "\n")]
+            }
+        }
+    }
+}
+
+impl fmt::Display for TileViewer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for TileViewer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        TileViewer::ALL
+            .into_iter()
+            .find(|viewer| viewer.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown viewer '{}' (expected krpano, pannellum or marzipano)", s))
+    }
+}
+
+/// Face sizes of a multiresolution pyramid, smallest level first. Each
+/// level is half the next one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TilePyramid {
+    pub tile_size: u32,
+    pub level_sizes: Vec<u32>,
+}
+
+impl TilePyramid {
+    /// Halve `face_size` until a level fits in a single tile, or for exactly
+    /// `levels` levels when given.
+    pub fn new(face_size: u32, tile_size: u32, levels: Option<u32>) -> TilePyramid {
+        let mut level_sizes = vec![face_size];
+        loop {
+            let last = *level_sizes.last().expect("at least one level");
+            let done = match levels {
+                Some(levels) => level_sizes.len() as u32 >= levels,
+                None => last <= tile_size,
+            };
+            if done || last < 2 {
+                break;
+            }
+            level_sizes.push(last / 2);
+        }
+        level_sizes.reverse();
+        TilePyramid { tile_size, level_sizes }
+    }
+
+    /// Size of the full-resolution level
+    pub fn face_size(&self) -> u32 {
+        *self.level_sizes.last().expect("at least one level")
+    }
+
+    /// Tiles per side for a face of `size`
+    pub fn tiles_per_side(&self, size: u32) -> u32 {
+        size.div_ceil(self.tile_size)
+    }
+}
+
+/// Cut a face into `tile_size` tiles as (row, column, tile); edge tiles are
+/// cropped to what remains.
+pub fn cut_tiles(face: &DynamicImage, tile_size: u32) -> Vec<(u32, u32, DynamicImage)> {
+    let (width, height) = (face.width(), face.height());
+    let mut tiles = Vec::new();
+    for row in 0..height.div_ceil(tile_size) {
+        for col in 0..width.div_ceil(tile_size) {
+            let (x, y) = (col * tile_size, row * tile_size);
+            let tile = face.crop_imm(x, y, tile_size.min(width - x), tile_size.min(height - y));
+            tiles.push((row, col, tile));
+        }
+    }
+    tiles
+}
+
+/// Stack the faces into a vertical preview strip in Marzipano's default
+/// back, down, front, left, right, up order.
+pub fn preview_strip(cubemap: &CubemapFaces<DynamicImage>) -> DynamicImage {
+    const ORDER: [Face; 6] = [Face::Back, Face::Down, Face::Front, Face::Left, Face::Right, Face::Up];
+    let size = cubemap.size;
+    let first = cubemap.get(Face::Back);
+    let mut strip = match first {
+        DynamicImage::ImageRgb16(_) => DynamicImage::new_rgb16(size, size * 6),
+        DynamicImage::ImageRgb32F(_) => DynamicImage::new_rgb32f(size, size * 6),
+        _ => DynamicImage::new_rgb8(size, size * 6),
+    };
+    for (i, face) in ORDER.into_iter().enumerate() {
+        strip.copy_from(cubemap.get(face), 0, i as u32 * size).expect("face fits in the strip");
+    }
+    strip
+}
@@ -0,0 +1,119 @@
+use crate::{Buffer, Channel, PixelDepth};
+use image::{DynamicImage, Pixel, Rgb, Rgba};
+use rayon::prelude::*;
+use std::fmt;
+use std::str::FromStr;
+
+/// Curve that brings linear HDR values into the displayable [0, 1] range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToneMapper {
+    /// x / (1 + x) per channel, then sRGB encoded
+    Reinhard,
+    /// Narkowicz's fit of the ACES filmic curve, then sRGB encoded
+    Aces,
+    /// Clip at 1.0 and encode with a plain power-law gamma
+    Gamma,
+}
+
+impl ToneMapper {
+    pub const ALL: [ToneMapper; 3] = [ToneMapper::Reinhard, ToneMapper::Aces, ToneMapper::Gamma];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ToneMapper::Reinhard => "reinhard",
+            ToneMapper::Aces => "aces",
+            ToneMapper::Gamma => "gamma",
+        }
+    }
+}
+
+impl fmt::Display for ToneMapper {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ToneMapper {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ToneMapper::ALL
+            .into_iter()
+            .find(|mapper| mapper.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown tone mapper '{}' (expected reinhard, aces or gamma)", s))
+    }
+}
+
+/// How float images are brought down to 8 or 16 bits on encode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ToneMap {
+    pub mapper: ToneMapper,
+    /// Exposure adjustment in stops, applied before the curve
+    pub exposure: f32,
+    /// Encoding gamma of `ToneMapper::Gamma`; the filmic curves always
+    /// encode to sRGB
+    pub gamma: f32,
+}
+
+impl Default for ToneMap {
+    fn default() -> Self {
+        ToneMap { mapper: ToneMapper::Aces, exposure: 0.0, gamma: 2.2 }
+    }
+}
+
+impl ToneMap {
+    /// Tone map a linear float image to `depth`; float stays as it is.
+    pub fn apply(&self, img: &Buffer<Rgb<f32>>, depth: PixelDepth) -> DynamicImage {
+        match depth {
+            PixelDepth::U8 => DynamicImage::ImageRgb8(self.quantize(img)),
+            PixelDepth::U16 => DynamicImage::ImageRgb16(self.quantize(img)),
+            PixelDepth::F32 => DynamicImage::ImageRgb32F(img.clone()),
+        }
+    }
+
+    /// `apply` for images with alpha, which is clamped to [0, 1] rather than
+    /// tone mapped.
+    pub fn apply_rgba(&self, img: &Buffer<Rgba<f32>>, depth: PixelDepth) -> DynamicImage {
+        match depth {
+            PixelDepth::U8 => DynamicImage::ImageRgba8(self.quantize(img)),
+            PixelDepth::U16 => DynamicImage::ImageRgba16(self.quantize(img)),
+            PixelDepth::F32 => DynamicImage::ImageRgba32F(img.clone()),
+        }
+    }
+
+    // The fourth channel of `P` is alpha; `Q` is `P` with `T` channels
+    fn quantize<P, Q, T>(&self, img: &Buffer<P>) -> Buffer<Q>
+    where
+        P: Pixel<Subpixel = f32>,
+        Q: Pixel<Subpixel = T>,
+        T: Channel,
+    {
+        let scale = self.exposure.exp2();
+        let max = T::DEFAULT_MAX_VALUE.to_f32();
+        let inv_gamma = 1.0 / self.gamma;
+        let alpha = P::CHANNEL_COUNT == 4;
+        let data = img
+            .as_raw()
+            .par_iter()
+            .enumerate()
+            .map(|(i, &value)| {
+                if alpha && i % 4 == 3 {
+                    return T::from_f32(value.clamp(0.0, 1.0) * max);
+                }
+                let x = (value * scale).max(0.0);
+                match self.mapper {
+                    ToneMapper::Reinhard => T::from_linear(x / (1.0 + x)),
+                    ToneMapper::Aces => T::from_linear(aces(x)),
+                    ToneMapper::Gamma => T::from_f32(x.min(1.0).powf(inv_gamma) * max),
+                }
+            })
+            .collect();
+        Buffer::from_raw(img.width(), img.height(), data).expect("same dimensions")
+    }
+}
+
+// Krzysztof Narkowicz, "ACES Filmic Tone Mapping Curve" (2016)
+fn aces(x: f32) -> f32 {
+    let (a, b, c, d, e) = (2.51, 0.03, 2.43, 0.59, 0.14);
+    ((x * (a * x + b)) / (x * (c * x + d) + e)).clamp(0.0, 1.0)
+}
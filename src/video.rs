@@ -0,0 +1,268 @@
+use crate::cli::VideoArgs;
+use crate::storage;
+use anyhow::{bail, Context, Result};
+use image::{DynamicImage, RgbImage};
+use rayon::prelude::*;
+use rust_cube::{
+    assemble_layout_dynamic, equirect_to_cubemap_dynamic, save_image, CubemapOptions, EncodeOptions, Face, LutCache,
+    Rotation,
+};
+use serde_json::Value;
+use std::io::{ErrorKind, Read, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::info;
+
+// Frames travel through pipes as raw 8-bit RGB: ffmpeg decodes the input
+// into one, and with --video encodes the packed cubemaps from another
+
+/// Convert the frames of `args.input` one batch of `--jobs` frames at a
+/// time; each frame still renders its faces on the shared pool.
+pub fn run_video(args: &VideoArgs) -> Result<()> {
+    let start = Instant::now();
+    let layout = args.layout.layout();
+    if args.video.is_some() && layout.is_none() {
+        bail!("--video needs a --layout to pack each frame's faces into one picture");
+    }
+    let options = CubemapOptions {
+        size: args.size,
+        filter: args.filter,
+        rotation: Rotation::from_euler_degrees(args.yaw, args.pitch, args.roll),
+        projection: args.projection,
+        // Every frame has the same size, so one set of tables serves them all
+        lut: Some(Arc::new(LutCache::new())),
+        ..CubemapOptions::default()
+    };
+    options.validate()?;
+    let encode = EncodeOptions { format: args.format, quality: args.quality, ..EncodeOptions::default() };
+
+    let (width, height, rate) = probe(&args.ffmpeg, &args.input)?;
+    info!("Converting {}x{} video at {} frames per second", width, height, rate);
+    if let Some(output) = &args.output {
+        storage::create_output_dir(output)?;
+    }
+    let mut decoder = Command::new(&args.ffmpeg)
+        .args(["-v", "error", "-nostdin", "-i"])
+        .arg(&args.input)
+        .args(["-map", "0:v:0", "-vf", &format!("select=not(mod(n\\,{}))", args.every), "-fps_mode", "passthrough"])
+        .args(["-f", "rawvideo", "-pix_fmt", "rgb24", "-"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .map(Running::new)
+        .with_context(|| format!("cannot run {}", args.ffmpeg.display()))?;
+    let mut frames = decoder.child().stdout.take().expect("stdout is piped");
+
+    let (cols, rows) = layout.map_or((1, 1), |layout| layout.grid());
+    let mut encoder = match &args.video {
+        Some(path) => Some(spawn_encoder(args, path, (cols * args.size, rows * args.size), &rate)?),
+        None => None,
+    };
+
+    let frame_len = width as usize * height as usize * 3;
+    let mut converted = 0;
+    loop {
+        // A batch of frames in, converted side by side, then out in order
+        let mut batch = Vec::new();
+        while batch.len() < args.jobs as usize {
+            let mut frame = vec![0; frame_len];
+            if !read_frame(&mut frames, &mut frame)? {
+                break;
+            }
+            let image = RgbImage::from_raw(width, height, frame).expect("frame holds width x height pixels");
+            batch.push(((converted + batch.len()) as u32 * args.every, DynamicImage::ImageRgb8(image)));
+        }
+        if batch.is_empty() {
+            break;
+        }
+        let pictures = batch
+            .par_iter()
+            .map(|(number, frame)| {
+                let cubemap = equirect_to_cubemap_dynamic(frame, &options);
+                let picture = layout.map(|layout| assemble_layout_dynamic(&cubemap, layout));
+                if let Some(output) = &args.output {
+                    let name = format!("frame_{:06}", number);
+                    match &picture {
+                        Some(picture) => {
+                            let path = output.join(format!("{}.{}", name, encode.format.extension()));
+                            save_image(picture, &path, &encode)?;
+                        }
+                        None => {
+                            let dir = output.join(&name);
+                            storage::create_output_dir(&dir)?;
+                            for (face, img) in Face::ALL.iter().zip(&cubemap.faces) {
+                                let path = dir.join(format!("{}.{}", face.name(), encode.format.extension()));
+                                save_image(img, &path, &encode)?;
+                            }
+                        }
+                    }
+                }
+                Ok(picture)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if let Some((_, stdin)) = &mut encoder {
+            for picture in pictures.iter().flatten() {
+                stdin.write_all(picture.to_rgb8().as_raw()).context("ffmpeg stopped reading frames")?;
+            }
+        }
+        converted += batch.len();
+        info!("Converted {} frames at {:?}", converted, start.elapsed());
+    }
+
+    decoder.wait("decoding")?;
+    if let Some((encoder, stdin)) = encoder {
+        // Closing its input tells the encoder the video is complete
+        drop(stdin);
+        encoder.wait("encoding")?;
+        info!("Video {} written", args.video.as_ref().expect("encoder writes --video").display());
+    }
+    info!("Total conversion time for {} frames: {:?}", converted, start.elapsed());
+    Ok(())
+}
+
+// Width, height and frame rate (as ffmpeg writes it, e.g. 30000/1001) of the
+// first video stream, as its frames are decoded
+fn probe(ffmpeg: &Path, input: &Path) -> Result<(u32, u32, String)> {
+    let ffprobe = ffmpeg.with_file_name("ffprobe");
+    let output = Command::new(&ffprobe)
+        .args(["-v", "error", "-select_streams", "v:0", "-show_entries"])
+        .arg("stream=width,height,r_frame_rate:stream_side_data=rotation:stream_tags=rotate")
+        .args(["-of", "json"])
+        .arg(input)
+        .output()
+        .with_context(|| format!("cannot run {}", ffprobe.display()))?;
+    if !output.status.success() {
+        bail!("cannot read {}: {}", input.display(), String::from_utf8_lossy(&output.stderr).trim());
+    }
+    let probed: Value = serde_json::from_slice(&output.stdout).context("ffprobe wrote no JSON")?;
+    let Some(stream) = probed["streams"].get(0) else {
+        bail!("{} has no video stream", input.display());
+    };
+    let Some((width, height)) = decoded_size(stream) else {
+        bail!("ffprobe reported a {}x{} video in {}", stream["width"], stream["height"], input.display());
+    };
+    let rate = stream["r_frame_rate"].as_str().unwrap_or("25/1").to_string();
+    Ok((width, height, rate))
+}
+
+// A probed stream's frame size once ffmpeg has turned it upright, which
+// swaps the sides of streams played a quarter turn round. The rotation is
+// in the display matrix, or in older files the rotate tag.
+fn decoded_size(stream: &Value) -> Option<(u32, u32)> {
+    let width = u32::try_from(stream["width"].as_u64()?).ok()?;
+    let height = u32::try_from(stream["height"].as_u64()?).ok()?;
+    let rotation = stream["side_data_list"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find_map(|data| data["rotation"].as_i64())
+        .or_else(|| stream["tags"]["rotate"].as_str().and_then(|rotate| rotate.parse().ok()))
+        .unwrap_or(0);
+    Some(if rotation.rem_euclid(180) == 90 { (height, width) } else { (width, height) })
+}
+
+fn spawn_encoder(
+    args: &VideoArgs,
+    path: &Path,
+    (width, height): (u32, u32),
+    rate: &str,
+) -> Result<(Running, ChildStdin)> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // Every Nth frame plays at 1/N the rate so the video keeps its length
+    let (numerator, denominator) = rate.split_once('/').unwrap_or((rate, "1"));
+    let denominator = denominator.parse::<u64>().unwrap_or(1) * args.every as u64;
+    let rate = format!("{}/{}", numerator, denominator);
+    let mut command = Command::new(&args.ffmpeg);
+    command
+        .args(["-v", "error", "-y", "-f", "rawvideo", "-pix_fmt", "rgb24"])
+        .args(["-s", &format!("{}x{}", width, height), "-framerate", &rate, "-i", "-"])
+        .arg("-i")
+        .arg(&args.input)
+        .args(["-map", "0:v", "-map", "1:a?", "-c:a", "copy", "-pix_fmt", "yuv420p"]);
+    if let Some(crf) = args.crf {
+        command.args(["-crf", &crf.to_string()]);
+    }
+    let mut child = command
+        .arg(path)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map(Running::new)
+        .with_context(|| format!("cannot run {}", args.ffmpeg.display()))?;
+    let stdin = child.child().stdin.take().expect("stdin is piped");
+    Ok((child, stdin))
+}
+
+// Fill `frame` from the pipe; false at the end of the video
+fn read_frame(pipe: &mut impl Read, frame: &mut [u8]) -> Result<bool> {
+    let mut filled = 0;
+    while filled < frame.len() {
+        match pipe.read(&mut frame[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => bail!("the video ended part way through a frame"),
+            Ok(read) => filled += read,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(true)
+}
+
+// An ffmpeg process, killed and waited on if dropped before `wait` so a
+// conversion that fails part way leaves none running
+struct Running(Option<Child>);
+
+impl Running {
+    fn new(child: Child) -> Running {
+        Running(Some(child))
+    }
+
+    fn child(&mut self) -> &mut Child {
+        self.0.as_mut().expect("only wait takes the child")
+    }
+
+    fn wait(mut self, task: &str) -> Result<()> {
+        let status = self.0.take().expect("only wait takes the child").wait()?;
+        if !status.success() {
+            bail!("ffmpeg failed {} the video ({})", task, status);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        if let Some(mut child) = self.0.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn rotated_streams_decode_upright() {
+        let stream = |extra: Value| {
+            let mut stream = json!({ "width": 1920, "height": 1080 });
+            stream.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+            stream
+        };
+        let matrix = |degrees: i64| {
+            json!({ "side_data_list": [{ "side_data_type": "Display Matrix", "rotation": degrees }] })
+        };
+        for degrees in [-90, 90, -270, 270] {
+            assert_eq!(decoded_size(&stream(matrix(degrees))), Some((1080, 1920)), "{}", degrees);
+        }
+        assert_eq!(decoded_size(&stream(matrix(180))), Some((1920, 1080)));
+        assert_eq!(decoded_size(&stream(json!({ "tags": { "rotate": "90" } }))), Some((1080, 1920)));
+        let spherical = json!({ "side_data_list": [{ "side_data_type": "Spherical Mapping" }] });
+        assert_eq!(decoded_size(&stream(spherical)), Some((1920, 1080)));
+        assert_eq!(decoded_size(&json!({ "width": 1920 })), None);
+    }
+}
@@ -0,0 +1,162 @@
+use crate::{
+    basis_to_spherical, direction_to_spherical, sample, Buffer, Channel, CubemapError, Face, FaceBasis, Filter,
+    PixelDepth, Rotation,
+};
+use image::{DynamicImage, Pixel};
+use rayon::prelude::*;
+use std::fmt;
+use std::str::FromStr;
+
+/// How a view maps image positions to directions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ViewProjection {
+    /// Rectilinear: straight lines stay straight, limited to under 180 degrees
+    #[default]
+    Perspective,
+    /// Equidistant (angular) fisheye: the angle from the view axis grows
+    /// linearly with the distance from the image centre, inside a circle
+    /// that fills the shorter side. A 180 degree fisheye aimed at the
+    /// zenith is a dome master.
+    Fisheye,
+}
+
+impl ViewProjection {
+    pub const ALL: [ViewProjection; 2] = [ViewProjection::Perspective, ViewProjection::Fisheye];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ViewProjection::Perspective => "perspective",
+            ViewProjection::Fisheye => "fisheye",
+        }
+    }
+}
+
+impl fmt::Display for ViewProjection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ViewProjection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ViewProjection::ALL
+            .into_iter()
+            .find(|projection| projection.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown view projection '{}' (expected perspective or fisheye)", s))
+    }
+}
+
+/// A flat perspective camera looking into the panorama.
+#[derive(Debug, Clone)]
+pub struct ViewOptions {
+    pub width: u32,
+    pub height: u32,
+    /// Horizontal field of view in degrees; the vertical one follows from
+    /// the aspect ratio. For a fisheye, the angle across the image circle.
+    pub fov: f32,
+    /// Where the camera looks; identity faces the centre of the panorama
+    pub rotation: Rotation,
+    pub filter: Filter,
+    pub projection: ViewProjection,
+}
+
+impl Default for ViewOptions {
+    fn default() -> Self {
+        ViewOptions {
+            width: 1280,
+            height: 720,
+            fov: 90.0,
+            rotation: Rotation::IDENTITY,
+            filter: Filter::Bilinear,
+            projection: ViewProjection::Perspective,
+        }
+    }
+}
+
+impl ViewOptions {
+    pub fn validate(&self) -> Result<(), CubemapError> {
+        if self.width == 0 || self.height == 0 {
+            return Err(CubemapError::Projection("view size must be at least 1x1".to_string()));
+        }
+        let max_fov = match self.projection {
+            ViewProjection::Perspective => 180.0,
+            ViewProjection::Fisheye => 360.0,
+        };
+        if !(self.fov > 0.0 && self.fov < max_fov) {
+            return Err(CubemapError::Projection(format!(
+                "{} field of view must be between 0 and {}, got {}",
+                self.projection, max_fov, self.fov
+            )));
+        }
+        Ok(())
+    }
+
+    // The front face's basis, stretched to the field of view and rotated
+    fn basis(&self) -> FaceBasis {
+        let half_width = (self.fov.to_radians() / 2.0).tan();
+        let half_height = half_width * self.height as f32 / self.width as f32;
+        let front = FaceBasis { center: [0.0, 0.0, 1.0], right: [half_width, 0.0, 0.0], down: [0.0, -half_height, 0.0] };
+        self.rotation.apply_basis(&front)
+    }
+}
+
+/// Render a perspective view: one arbitrary "virtual face" of the panorama.
+pub fn render_view<P>(src: &Buffer<P>, options: &ViewOptions) -> Buffer<P>
+where
+    P: Pixel + Send + Sync,
+    P::Subpixel: Channel,
+{
+    let (width, height) = (options.width, options.height);
+    let channels = P::CHANNEL_COUNT as usize;
+    let mut view: Buffer<P> = Buffer::new(width, height);
+    match options.projection {
+        ViewProjection::Perspective => {
+            let basis = options.basis();
+            view.par_chunks_mut(width as usize * channels).enumerate().for_each(|(y, row)| {
+                let b = 2.0 * y as f32 / height as f32 - 1.0;
+                for (x, pixel) in row.chunks_exact_mut(channels).enumerate() {
+                    let a = 2.0 * x as f32 / width as f32 - 1.0;
+                    let (u, v) = basis_to_spherical(a, b, &basis);
+                    pixel.copy_from_slice(sample(src, u, v, options.filter).channels());
+                }
+            });
+        }
+        ViewProjection::Fisheye => {
+            let axes = options.rotation.apply_basis(Face::Front.basis());
+            // The image circle spans the shorter side; pixels outside stay black
+            let radius = width.min(height) as f32 / 2.0;
+            let half_fov = options.fov.to_radians() / 2.0;
+            view.par_chunks_mut(width as usize * channels).enumerate().for_each(|(y, row)| {
+                let b = (y as f32 + 0.5 - height as f32 / 2.0) / radius;
+                for (x, pixel) in row.chunks_exact_mut(channels).enumerate() {
+                    let a = (x as f32 + 0.5 - width as f32 / 2.0) / radius;
+                    let r = (a * a + b * b).sqrt();
+                    if r > 1.0 {
+                        continue;
+                    }
+                    // Rotate away from the axis by an angle proportional to r
+                    let (sin, cos) = (r * half_fov).sin_cos();
+                    let (a, b) = if r > 0.0 { (a / r, b / r) } else { (0.0, 0.0) };
+                    let dir = std::array::from_fn(|i| {
+                        axes.center[i] * cos + (a * axes.right[i] + b * axes.down[i]) * sin
+                    });
+                    let (u, v) = direction_to_spherical(dir);
+                    pixel.copy_from_slice(sample(src, u, v, options.filter).channels());
+                }
+            });
+        }
+    }
+    view
+}
+
+/// `render_view` at the panorama's own precision.
+pub fn render_view_dynamic(src: &DynamicImage, options: &ViewOptions) -> DynamicImage {
+    match src {
+        DynamicImage::ImageRgb8(img) => render_view(img, options).into(),
+        DynamicImage::ImageRgb16(img) => render_view(img, options).into(),
+        DynamicImage::ImageRgb32F(img) => render_view(img, options).into(),
+        img => render_view_dynamic(&PixelDepth::of(img).to_rgb(img.clone()), options),
+    }
+}
@@ -0,0 +1,111 @@
+use crate::{
+    decode_image, encode_image, equirect_to_cubemap_dynamic, CubeProjection, CubemapOptions, EncodeOptions, Filter,
+    OutputFormat, PixelDepth, Rotation,
+};
+use image::DynamicImage;
+use js_sys::{Array, ArrayBuffer, Object, Reflect, Uint8Array};
+use std::io::Cursor;
+use std::str::FromStr;
+use wasm_bindgen::prelude::*;
+
+// What each face comes back as
+enum FaceFormat {
+    Encoded(OutputFormat),
+    // Raw RGBA8 pixels, ready for texImage2D
+    Rgba,
+}
+
+/// Convert an equirect panorama held in `input` (any format the crate
+/// decodes) to six cube faces. Rayon has no threads to spawn in the
+/// browser, so this runs on the calling thread; call it from a worker to
+/// keep the page responsive.
+///
+/// `options` is a plain object and every field is optional:
+/// - `size`: face size in pixels (1024)
+/// - `format`: `"png"` (the default), `"jpeg"`, `"tiff"`, `"exr"`, `"hdr"`, or
+///   `"rgba"` for raw 8-bit RGBA pixels
+/// - `quality`: JPEG quality (95)
+/// - `filter`: `"nearest"`, `"bilinear"` (the default), `"bicubic"` or `"lanczos3"`
+/// - `projection`: `"standard"` (the default) or `"eac"`
+/// - `yaw`, `pitch`, `roll`: view rotation in degrees
+///
+/// Returns an array of `{ face, width, height, data }` objects in right,
+/// left, up, down, front, back order, with `data` a `Uint8Array`.
+#[wasm_bindgen]
+pub fn convert(input: &ArrayBuffer, options: &JsValue) -> Result<Array, JsError> {
+    let format = match string(options, "format")? {
+        Some(format) if format.eq_ignore_ascii_case("rgba") => FaceFormat::Rgba,
+        Some(format) => FaceFormat::Encoded(OutputFormat::from_str(&format).map_err(|err| JsError::new(&err))?),
+        None => FaceFormat::Encoded(OutputFormat::Png),
+    };
+    let encode = EncodeOptions {
+        quality: number(options, "quality")?.map_or(95, |quality| quality.clamp(1.0, 100.0) as u8),
+        ..EncodeOptions::default()
+    };
+    let angle = |name| number(options, name).map(|angle| angle.unwrap_or(0.0) as f32);
+    let cubemap_options = CubemapOptions {
+        size: number(options, "size")?.map_or(1024, |size| size as u32),
+        filter: parse::<Filter>(options, "filter")?.unwrap_or_default(),
+        rotation: Rotation::from_euler_degrees(angle("yaw")?, angle("pitch")?, angle("roll")?),
+        projection: parse::<CubeProjection>(options, "projection")?.unwrap_or_default(),
+        ..CubemapOptions::default()
+    };
+    cubemap_options.validate()?;
+
+    let img = decode(&Uint8Array::new(input).to_vec())?;
+    let img = PixelDepth::of(&img).to_rgb(img);
+    let cubemap = equirect_to_cubemap_dynamic(&img, &cubemap_options);
+
+    let faces = Array::new();
+    for (face, img) in cubemap.iter() {
+        let data = match format {
+            FaceFormat::Rgba => img.to_rgba8().into_raw(),
+            FaceFormat::Encoded(format) => {
+                let mut out = Cursor::new(Vec::new());
+                encode_image(img, &EncodeOptions { format, ..encode }, &mut out)?;
+                out.into_inner()
+            }
+        };
+        let entry = Object::new();
+        set(&entry, "face", &face.name().into())?;
+        set(&entry, "width", &img.width().into())?;
+        set(&entry, "height", &img.height().into())?;
+        set(&entry, "data", &Uint8Array::from(data.as_slice()).into())?;
+        faces.push(&entry);
+    }
+    Ok(faces)
+}
+
+fn decode(bytes: &[u8]) -> Result<DynamicImage, JsError> {
+    decode_image(bytes).map_err(|err| JsError::new(&format!("failed to decode input: {}", err)))
+}
+
+// Option `name`, or None when it's missing, null or undefined
+fn field(options: &JsValue, name: &str) -> Result<Option<JsValue>, JsError> {
+    if options.is_undefined() || options.is_null() {
+        return Ok(None);
+    }
+    let value = Reflect::get(options, &name.into()).map_err(|_| JsError::new("options must be an object"))?;
+    Ok((!value.is_undefined() && !value.is_null()).then_some(value))
+}
+
+fn number(options: &JsValue, name: &str) -> Result<Option<f64>, JsError> {
+    field(options, name)?
+        .map(|value| value.as_f64().ok_or_else(|| JsError::new(&format!("option '{}' must be a number", name))))
+        .transpose()
+}
+
+fn string(options: &JsValue, name: &str) -> Result<Option<String>, JsError> {
+    field(options, name)?
+        .map(|value| value.as_string().ok_or_else(|| JsError::new(&format!("option '{}' must be a string", name))))
+        .transpose()
+}
+
+fn parse<T: FromStr<Err = String>>(options: &JsValue, name: &str) -> Result<Option<T>, JsError> {
+    string(options, name)?.map(|value| value.parse().map_err(|err: String| JsError::new(&err))).transpose()
+}
+
+fn set(target: &Object, key: &str, value: &JsValue) -> Result<(), JsError> {
+    Reflect::set(target, &key.into(), value).map_err(|_| JsError::new("failed to build the result object"))?;
+    Ok(())
+}
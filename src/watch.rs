@@ -0,0 +1,92 @@
+use crate::cli::ConvertArgs;
+use crate::storage::Destination;
+use crate::rotations::RotationTable;
+use crate::{convert_file, expand_input, Renderer};
+use anyhow::{bail, Context, Result};
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+/// Convert panoramas as they appear under `dir`. A file is picked up once it
+/// has seen no events for the debounce period and its size has stopped
+/// changing, so uploads that are still being written are left alone.
+pub fn run_watch(dir: &Path, cli: &ConvertArgs, renderer: &Renderer, rotations: Option<&RotationTable>) -> Result<()> {
+    let dir = dir.canonicalize().with_context(|| format!("cannot watch {}", dir.display()))?;
+    std::fs::create_dir_all(&cli.output_dir)?;
+    let output_dir = cli.output_dir.canonicalize()?;
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.watch(&dir, RecursiveMode::Recursive)?;
+    info!("Watching {} for new panoramas (Ctrl-C to stop)", dir.display());
+
+    let debounce = Duration::from_millis(cli.debounce_ms);
+    // Last event time and size seen for every file waiting to settle
+    let mut pending: HashMap<PathBuf, (Instant, u64)> = HashMap::new();
+    loop {
+        match rx.recv_timeout(debounce / 4) {
+            Ok(Ok(event)) => {
+                if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    for path in event.paths {
+                        // Our own output may live inside the watched tree
+                        if path.starts_with(&output_dir) || image::ImageFormat::from_path(&path).is_err() {
+                            continue;
+                        }
+                        if let Some(meta) = std::fs::metadata(&path).ok().filter(|meta| meta.is_file()) {
+                            pending.insert(path, (Instant::now(), meta.len()));
+                        }
+                    }
+                }
+            }
+            Ok(Err(err)) => warn!("Watch error: {}", err),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => bail!("file watcher stopped"),
+        }
+
+        let settled: Vec<PathBuf> = pending
+            .iter()
+            .filter(|(_, (last_event, _))| last_event.elapsed() >= debounce)
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in settled {
+            let (_, len) = pending.remove(&path).expect("settled file is pending");
+            match std::fs::metadata(&path) {
+                // Still growing without emitting events; give it another period
+                Ok(meta) if meta.len() != len => {
+                    pending.insert(path, (Instant::now(), meta.len()));
+                }
+                Ok(_) => {
+                    let output_root = cli.output_dir.join(expand_template(&cli.output_template, &path, &dir));
+                    let result = renderer
+                        .pool
+                        .install(|| convert_file(&path, &output_root, cli, renderer, &Destination::Files, rotations));
+                    if let Err(err) = result {
+                        error!(input = %path.display(), "Failed to convert {}: {:#}", path.display(), err);
+                    }
+                }
+                // Removed or renamed away before it settled
+                Err(_) => {}
+            }
+        }
+    }
+}
+
+// Output directory for `input` relative to the output root. Placeholders:
+// {stem}, {name}, {ext} and {dir}, the input's directory inside the watched one
+fn expand_template(template: &str, input: &Path, watch_dir: &Path) -> PathBuf {
+    let dir = input
+        .parent()
+        .and_then(|parent| parent.strip_prefix(watch_dir).ok())
+        .map(|dir| dir.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let expanded = expand_input(template, input).replace("{dir}", &dir);
+    // Keep the result under the output root even when {dir} is empty
+    Path::new(&expanded)
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect()
+}